@@ -0,0 +1,238 @@
+//! Admin API.
+//!
+//! Requests below the `[admin] route` are handled here, after the
+//! user has been authenticated and found in `[admin] users`. They do
+//! not count against `max-request-rate`, so that the admin API still
+//! works when the server is flooded.
+//!
+use std::time::UNIX_EPOCH;
+
+use http::{Method, StatusCode};
+use serde_json::{json, Value};
+
+use crate::idle::IdleReaper;
+use crate::limits::{MemoryBudget, RateLimiter, UserLimiter, WriteLimiter};
+use crate::locks::{LockEntry, LockManager};
+use crate::metrics::Metrics;
+use crate::shed::Shedder;
+use crate::statcache::StatCache;
+use crate::transfers::Transfers;
+
+// log target for actions that change state.
+pub const AUDIT: &str = "webdav_server::audit";
+
+/// What the admin API gives access to.
+pub struct AdminState<'a> {
+    pub locks:        &'a LockManager,
+    pub userlimiter:  &'a UserLimiter,
+    pub statcaches:   &'a [Option<StatCache>],
+    pub idle:         &'a IdleReaper,
+    pub metrics:      &'a Metrics,
+    pub ratelimiter:  &'a RateLimiter,
+    pub writes:       &'a WriteLimiter,
+    pub memory:       &'a MemoryBudget,
+    pub shedder:      &'a Shedder,
+    pub transfers:    &'a Transfers,
+    /// The capabilities document, if `[server] capabilities` is set.
+    pub capabilities: Option<Value>,
+}
+
+/// Handle an admin request. `path` is the path below the admin route.
+pub fn handle(
+    method: &Method,
+    path: &str,
+    query: Option<&str>,
+    state: &AdminState,
+    user: &str,
+) -> Result<Value, StatusCode>
+{
+    let locks = state.locks;
+    let mut token = None;
+    let mut fs_path = None;
+    for (k, v) in url::form_urlencoded::parse(query.unwrap_or("").as_bytes()) {
+        match k.as_ref() {
+            "token" => token = Some(v.into_owned()),
+            "path" => fs_path = Some(v.into_owned()),
+            _ => return Err(StatusCode::BAD_REQUEST),
+        }
+    }
+
+    match path.trim_end_matches('/') {
+        "/locks" => {},
+        "/users" if *method == Method::GET => return Ok(json!(state.userlimiter.counts())),
+        "/users" => return Err(StatusCode::METHOD_NOT_ALLOWED),
+        "/statcache" if *method == Method::GET => return Ok(statcache_to_json(state.statcaches)),
+        "/statcache" => return Err(StatusCode::METHOD_NOT_ALLOWED),
+        "/connections" if *method == Method::GET => {
+            let stats = state.idle.stats();
+            return Ok(json!({ "open": stats.open, "reaped_idle": stats.reaped }));
+        },
+        "/connections" => return Err(StatusCode::METHOD_NOT_ALLOWED),
+        "/metrics" if *method == Method::GET => {
+            return Ok(json!({
+                "aborted_downloads": state.metrics.aborted_downloads(),
+                "slow_requests": state.metrics.slow_requests(),
+                "request_tokens": state.ratelimiter.tokens(),
+                "active_writes": state.writes.active(),
+                "queued_writes": state.writes.waiting(),
+                "memory_used": state.memory.used(),
+                "shed_requests": state.shedder.shed(),
+                "locks_per_user": locks.per_user(),
+            }));
+        },
+        "/metrics" => return Err(StatusCode::METHOD_NOT_ALLOWED),
+        "/transfers" if *method == Method::GET => return Ok(state.transfers.list()),
+        "/transfers" => return Err(StatusCode::METHOD_NOT_ALLOWED),
+        "/capabilities" if *method == Method::GET => {
+            return state.capabilities.clone().ok_or(StatusCode::NOT_FOUND);
+        },
+        "/capabilities" => return Err(StatusCode::METHOD_NOT_ALLOWED),
+        _ => return Err(StatusCode::NOT_FOUND),
+    }
+
+    match *method {
+        Method::GET => {
+            let entries = locks.list();
+            let entries = match fs_path {
+                Some(p) => entries.into_iter().filter(|e| is_below(&e.fs_path(), &p)).collect(),
+                None => entries,
+            };
+            Ok(Value::Array(entries.iter().map(lock_to_json).collect()))
+        },
+        Method::DELETE => {
+            let released = match (token, fs_path) {
+                (Some(token), None) => locks.release_token(&token),
+                (None, Some(p)) if p.starts_with('/') => locks.release_path(&p),
+                _ => return Err(StatusCode::BAD_REQUEST),
+            };
+            for e in &released {
+                info!(
+                    target: AUDIT,
+                    "admin: {} released lock {} on {} (principal {:?})",
+                    user,
+                    e.lock.token,
+                    e.fs_path(),
+                    e.lock.principal
+                );
+            }
+            Ok(Value::Array(released.iter().map(lock_to_json).collect()))
+        },
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+// is `path` equal to, or below, `top`.
+fn is_below(path: &str, top: &str) -> bool {
+    let top = top.trim_end_matches('/');
+    path.strip_prefix(top)
+        .map(|rest| rest.is_empty() || rest.starts_with('/'))
+        .unwrap_or(false)
+}
+
+// stat cache counters, per location (by index in the config file).
+fn statcache_to_json(statcaches: &[Option<StatCache>]) -> Value {
+    let stats = statcaches.iter().enumerate().filter_map(|(idx, sc)| {
+        let stats = sc.as_ref()?.stats();
+        Some(json!({
+            "location": idx,
+            "hits": stats.hits,
+            "misses": stats.misses,
+            "entries": stats.entries,
+        }))
+    });
+    Value::Array(stats.collect())
+}
+
+fn lock_to_json(e: &LockEntry) -> Value {
+    let owner = e.lock.owner.as_ref().and_then(|o| {
+        let mut buf = Vec::new();
+        let cfg = xmltree::EmitterConfig::new().write_document_declaration(false);
+        o.write_with_config(&mut buf, cfg).ok()?;
+        String::from_utf8(buf).ok()
+    });
+    let expires = e
+        .lock
+        .timeout_at
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+    json!({
+        "token": e.lock.token,
+        "path": e.fs_path(),
+        "principal": e.lock.principal,
+        "owner": owner,
+        "shared": e.lock.shared,
+        "deep": e.lock.deep,
+        "expires": expires,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webdav_handler::davpath::DavPath;
+
+    #[test]
+    fn test_admin_locks() {
+        let locks = LockManager::new();
+        let ul = UserLimiter::new(None);
+        let idle = IdleReaper::new(None);
+        let metrics = Metrics::new();
+        let ratelimiter = RateLimiter::new(None, None);
+        let writes = WriteLimiter::new(None);
+        let memory = MemoryBudget::new(None);
+        let shedder = Shedder::default();
+        let transfers = Transfers::new();
+        let state = AdminState {
+            locks:        &locks,
+            userlimiter:  &ul,
+            statcaches:   &[],
+            idle:         &idle,
+            metrics:      &metrics,
+            ratelimiter:  &ratelimiter,
+            writes:       &writes,
+            memory:       &memory,
+            shedder:      &shedder,
+            transfers:    &transfers,
+            capabilities: Some(json!({ "dav": ["1"] })),
+        };
+        let ls = locks.locksystem("/home/a");
+        let path = DavPath::new("/file").unwrap();
+        let lock = ls.lock(&path, Some("a"), None, None, false, false).unwrap();
+
+        let list = handle(&Method::GET, "/locks", None, &state, "root").unwrap();
+        assert_eq!(list[0]["path"], "/home/a/file");
+        assert_eq!(list[0]["token"], lock.token.as_str());
+
+        let list = handle(&Method::GET, "/locks", Some("path=/home/b"), &state, "root").unwrap();
+        assert_eq!(list.as_array().unwrap().len(), 0);
+
+        assert_eq!(
+            handle(&Method::DELETE, "/locks", None, &state, "root"),
+            Err(StatusCode::BAD_REQUEST)
+        );
+        let query = format!("token={}", lock.token);
+        let released = handle(&Method::DELETE, "/locks", Some(&query), &state, "root").unwrap();
+        assert_eq!(released.as_array().unwrap().len(), 1);
+        assert!(locks.list().is_empty());
+        assert!(ls.check(&path, None, false, false, vec![]).is_ok());
+
+        let conns = handle(&Method::GET, "/connections", None, &state, "root").unwrap();
+        assert_eq!(conns, json!({ "open": 0, "reaped_idle": 0 }));
+        let counters = handle(&Method::GET, "/metrics", None, &state, "root").unwrap();
+        let expected = json!({
+            "aborted_downloads": 0,
+            "slow_requests": {},
+            "request_tokens": null,
+            "active_writes": 0,
+            "queued_writes": 0,
+            "memory_used": 0,
+            "shed_requests": {},
+            "locks_per_user": { "a": 0 },
+        });
+        assert_eq!(counters, expected);
+        let caps = handle(&Method::GET, "/capabilities", None, &state, "root").unwrap();
+        assert_eq!(caps, json!({ "dav": ["1"] }));
+        let state = AdminState { capabilities: None, ..state };
+        assert_eq!(handle(&Method::GET, "/capabilities", None, &state, "root"), Err(StatusCode::NOT_FOUND));
+    }
+}
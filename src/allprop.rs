@@ -0,0 +1,132 @@
+//! PROPFIND allprop.
+//!
+//! An allprop PROPFIND (or one without a body, which RFC 4918, section
+//! 9.1 says is the same) returns the dead properties of a resource along
+//! with the live ones. Dead properties are whatever clients stored with
+//! PROPPATCH, which may be more than the operator wants everyone with read
+//! access to walk through. With `allprop` in a location:
+//!
+//! - "allow": the default, as it always was.
+//! - "forbid": allprop is refused with "403 Forbidden". A client has to
+//!   ask for properties by name, or with propname.
+//! - "live": allprop is answered as if it asked for the live properties
+//!   of RFC 4918, section 15 by name, and the properties in its
+//!   DAV:include. Dead properties are only returned when asked for.
+//!   RFC 4918 lets a server leave out properties from allprop, and says
+//!   that clients should not rely on it for anything but the live
+//!   properties it defines. Those that a resource does not have are in a
+//!   "404 Not Found" propstat, as with any request by name.
+//!
+//! An explicit prop or propname is always passed on as it is.
+//!
+use xmltree::{Element, XMLNode};
+
+/// The live properties defined in RFC 4918.
+pub const LIVE: &[&str] = &[
+    "creationdate",
+    "displayname",
+    "getcontentlanguage",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "lockdiscovery",
+    "resourcetype",
+    "supportedlock",
+];
+
+// An element in DAV:, with the same prefix as the request.
+fn dav(name: &str, prefix: &Option<String>) -> Element {
+    let mut e = Element::new(name);
+    e.namespace = Some("DAV:".to_string());
+    e.prefix = prefix.clone();
+    e
+}
+
+fn is_dav(e: &Element, name: &str) -> bool {
+    e.name == name && e.namespace.as_deref() == Some("DAV:")
+}
+
+fn elements(e: &Element) -> impl Iterator<Item = &Element> {
+    e.children.iter().filter_map(|n| n.as_element())
+}
+
+/// Is the body of this PROPFIND an allprop.
+pub fn is_allprop(body: &[u8]) -> bool {
+    if body.iter().all(|b| b.is_ascii_whitespace()) {
+        return true;
+    }
+    match Element::parse(body) {
+        Ok(tree) => is_dav(&tree, "propfind") && elements(&tree).any(|e| is_dav(e, "allprop")),
+        Err(_) => false,
+    }
+}
+
+/// The body of a PROPFIND that asks for the live properties by name,
+/// instead of allprop, and for those in its DAV:include.
+pub fn live(body: &[u8]) -> Vec<u8> {
+    let tree = Element::parse(body).ok().filter(|t| is_dav(t, "propfind"));
+    let prefix = tree.as_ref().map(|t| t.prefix.clone()).unwrap_or_else(|| Some("D".to_string()));
+    let mut prop = dav("prop", &prefix);
+    for name in LIVE {
+        prop.children.push(XMLNode::Element(dav(name, &prefix)));
+    }
+    let mut propfind = match tree {
+        Some(tree) => {
+            let include = elements(&tree).filter(|e| is_dav(e, "include")).flat_map(elements);
+            for e in include {
+                if !(e.namespace.as_deref() == Some("DAV:") && LIVE.contains(&e.name.as_str())) {
+                    prop.children.push(XMLNode::Element(e.clone()));
+                }
+            }
+            let mut tree = tree;
+            tree.children.clear();
+            tree
+        },
+        None => {
+            let mut propfind = dav("propfind", &prefix);
+            let mut ns = xmltree::Namespace::empty();
+            ns.put("D", "DAV:");
+            propfind.namespaces = Some(ns);
+            propfind
+        },
+    };
+    propfind.children.push(XMLNode::Element(prop));
+    let mut body = Vec::new();
+    propfind.write(&mut body).expect("write to Vec");
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn props(body: &[u8]) -> Vec<(Option<String>, String)> {
+        let tree = Element::parse(body).unwrap();
+        assert!(is_dav(&tree, "propfind"));
+        let prop = elements(&tree).find(|e| is_dav(e, "prop")).unwrap();
+        elements(prop).map(|e| (e.namespace.clone(), e.name.clone())).collect()
+    }
+
+    #[test]
+    fn test_allprop() {
+        assert!(is_allprop(b""));
+        assert!(is_allprop(b" \r\n"));
+        assert!(is_allprop(br#"<D:propfind xmlns:D="DAV:"><D:allprop/></D:propfind>"#));
+        assert!(!is_allprop(br#"<D:propfind xmlns:D="DAV:"><D:propname/></D:propfind>"#));
+        assert!(!is_allprop(br#"<propfind xmlns="DAV:"><prop><getetag/></prop></propfind>"#));
+        assert!(!is_allprop(br#"<propfind xmlns="urn:x"><allprop/></propfind>"#));
+
+        let live_props = |body: &[u8]| -> Vec<String> { props(body).into_iter().map(|(_, n)| n).collect() };
+        assert_eq!(live_props(&live(b"")), LIVE);
+        assert_eq!(live_props(&live(br#"<propfind xmlns="DAV:"><allprop/></propfind>"#)), LIVE);
+
+        let body = concat!(
+            r#"<D:propfind xmlns:D="DAV:" xmlns:X="urn:x"><D:allprop/>"#,
+            r#"<D:include><X:color/><D:getetag/></D:include></D:propfind>"#
+        );
+        let props = props(&live(body.as_bytes()));
+        assert_eq!(props.len(), LIVE.len() + 1);
+        assert_eq!(props.last().unwrap(), &(Some("urn:x".to_string()), "color".to_string()));
+    }
+}
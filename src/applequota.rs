@@ -0,0 +1,185 @@
+//! Apple quota properties.
+//!
+//! macOS (WebDAVFS, under the Finder) asks for the quota properties of
+//! an old draft as well as the RFC 4331 ones: DAV:quota, the size of the
+//! filesystem, and DAV:quotaused, how much of it is used, both in blocks
+//! of 512 bytes. The webdav handler does not know them and says "404 Not
+//! Found", and then the Finder shows the wrong free space.
+//!
+//! With `macos-compat` in a location, `add_props` fills them in, in the
+//! PROPFIND response, from the same quota that DAV:quota-available-bytes
+//! and DAV:quota-used-bytes come from. Like statfs(), they are the same
+//! for every resource: the quota of the user, or the whole filesystem.
+//! If the size is not known, DAV:quota stays "not found". Only clients
+//! that ask for the properties get them.
+//!
+use bytes::Bytes;
+use hyper::body::HttpBody;
+use xmltree::Element;
+
+// The unit of DAV:quota and DAV:quotaused.
+const BLOCK: u64 = 512;
+
+// How the webdav handler writes what it does not know, and what is left
+// of its 404 propstat when we took all of them out.
+const QUOTA: &str = "<D:quota></D:quota>";
+const QUOTAUSED: &str = "<D:quotaused></D:quotaused>";
+const EMPTY_404: &str = concat!(
+    "<D:propstat><D:prop></D:prop>",
+    "<D:status>HTTP/1.1 404 Not Found</D:status></D:propstat>"
+);
+const OK_STATUS: &str = "</D:prop><D:status>HTTP/1.1 200 OK</D:status>";
+const END: &str = "</D:response>";
+
+/// Does the body of a PROPFIND ask for DAV:quota or DAV:quotaused.
+pub fn wants(body: &[u8]) -> bool {
+    let tree = match Element::parse(body) {
+        Ok(tree) => tree,
+        Err(_) => return false,
+    };
+    let dav = |e: &Element| e.namespace.as_deref() == Some("DAV:");
+    tree.children
+        .iter()
+        .filter_map(|n| n.as_element())
+        .filter(|e| dav(e) && e.name == "prop")
+        .flat_map(|e| e.children.iter().filter_map(|n| n.as_element()))
+        .any(|e| dav(e) && (e.name == "quota" || e.name == "quotaused"))
+}
+
+// Remove the first `what` from `s`.
+fn take(s: &mut String, what: &str) -> bool {
+    match s.find(what) {
+        Some(pos) => {
+            s.replace_range(pos..pos + what.len(), "");
+            true
+        },
+        None => false,
+    }
+}
+
+// Fill in the properties in one DAV:response.
+fn fix_response(response: &str, used: u64, total: Option<u64>) -> String {
+    let mut out = response.to_string();
+    let mut props = String::new();
+    if let Some(total) = total {
+        if take(&mut out, QUOTA) {
+            props.push_str(&format!("<D:quota>{}</D:quota>", total / BLOCK));
+        }
+    }
+    if take(&mut out, QUOTAUSED) {
+        props.push_str(&format!("<D:quotaused>{}</D:quotaused>", used / BLOCK));
+    }
+    if props.is_empty() {
+        return out;
+    }
+    take(&mut out, EMPTY_404);
+    match out.find(OK_STATUS) {
+        Some(pos) => out.insert_str(pos, &props),
+        None => {
+            let propstat = format!(
+                "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>",
+                props
+            );
+            let pos = out.rfind(END).unwrap_or(out.len());
+            out.insert_str(pos, &propstat);
+        },
+    }
+    out
+}
+
+// Fill in the properties in every DAV:response in `data`.
+fn fix(data: &[u8], used: u64, total: Option<u64>) -> Bytes {
+    let data = match std::str::from_utf8(data) {
+        Ok(data) => data,
+        Err(_) => return Bytes::copy_from_slice(data),
+    };
+    let out: String = data
+        .split_inclusive(END)
+        .map(|response| fix_response(response, used, total))
+        .collect();
+    Bytes::from(out)
+}
+
+/// Add DAV:quota and DAV:quotaused to the body of a PROPFIND response,
+/// while it is streamed. `used` and `total` are in bytes.
+pub fn add_props(body: hyper::Body, used: u64, total: Option<u64>) -> hyper::Body {
+    // a DAV:response that is not complete yet waits for the next chunk.
+    let stream = futures::stream::unfold(Some((body, Vec::new())), move |state| {
+        async move {
+            let (mut body, mut data) = state?;
+            match body.data().await {
+                Some(Ok(chunk)) => data.extend_from_slice(&chunk),
+                Some(Err(e)) => return Some((Err(e), None)),
+                None if data.is_empty() => return None,
+                None => return Some((Ok(fix(&data, used, total)), None)),
+            }
+            let end = data.windows(END.len()).rposition(|w| w == END.as_bytes());
+            let rest = data.split_off(end.map(|pos| pos + END.len()).unwrap_or(0));
+            Some((Ok(fix(&data, used, total)), Some((body, rest))))
+        }
+    });
+    hyper::Body::wrap_stream(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wants() {
+        let body = br#"<D:propfind xmlns:D="DAV:"><D:prop><D:quota/><D:getetag/></D:prop></D:propfind>"#;
+        assert!(wants(body));
+        let body = br#"<propfind xmlns="DAV:"><prop><quotaused/></prop></propfind>"#;
+        assert!(wants(body));
+        let body = br#"<D:propfind xmlns:D="DAV:" xmlns:X="urn:x"><D:prop><X:quota/></D:prop></D:propfind>"#;
+        assert!(!wants(body));
+        assert!(!wants(br#"<D:propfind xmlns:D="DAV:"><D:allprop/></D:propfind>"#));
+        assert!(!wants(b""));
+    }
+
+    #[tokio::test]
+    async fn test_add_props() {
+        let response = |ok: &str, missing: &str| {
+            let mut r = "<D:response><D:href>/f</D:href>".to_string();
+            if !ok.is_empty() {
+                r.push_str(&format!("<D:propstat><D:prop>{}{}", ok, OK_STATUS));
+                r.push_str("</D:propstat>");
+            }
+            if !missing.is_empty() {
+                r.push_str(&format!("<D:propstat><D:prop>{}</D:prop>", missing));
+                r.push_str("<D:status>HTTP/1.1 404 Not Found</D:status></D:propstat>");
+            }
+            r + END
+        };
+        let size = "<D:quota-used-bytes>4</D:quota-used-bytes>";
+        let other = "<D:getetag></D:getetag>";
+
+        // in the 200 propstat, and an empty 404 propstat is gone.
+        let both = format!("{}{}", QUOTA, QUOTAUSED);
+        let props = "<D:quota>4</D:quota><D:quotaused>2</D:quotaused>";
+        let expected = response(&(size.to_string() + props), "");
+        assert_eq!(fix_response(&response(size, &both), 1024, Some(2048)), expected);
+        // other missing properties stay missing.
+        let missing = format!("{}{}", QUOTAUSED, other);
+        let expected = response(&(size.to_string() + "<D:quotaused>2</D:quotaused>"), other);
+        assert_eq!(fix_response(&response(size, &missing), 1024, Some(2048)), expected);
+        // a new 200 propstat, and no size: no DAV:quota.
+        let expected = response("", QUOTA).replace(END, "") +
+            "<D:propstat><D:prop><D:quotaused>2</D:quotaused></D:prop>" +
+            "<D:status>HTTP/1.1 200 OK</D:status></D:propstat>" +
+            END;
+        assert_eq!(fix_response(&response("", &both), 1024, None), expected);
+        // and nothing if they were not asked for.
+        assert_eq!(fix_response(&response(size, other), 1024, Some(2048)), response(size, other));
+
+        // streamed, split in the middle of a response.
+        let two = response(size, &both).repeat(2);
+        let data = format!("<D:multistatus>{}</D:multistatus>", two);
+        let (a, b) = data.split_at(data.len() / 2 + 3);
+        let chunks: Vec<Result<_, std::io::Error>> = vec![Ok(a.to_string()), Ok(b.to_string())];
+        let body = add_props(hyper::Body::wrap_stream(futures::stream::iter(chunks)), 1024, Some(2048));
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        let fixed = response(&(size.to_string() + props), "");
+        assert_eq!(body, format!("<D:multistatus>{}{}</D:multistatus>", fixed, fixed));
+    }
+}
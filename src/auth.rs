@@ -1,57 +1,326 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
 use std::io;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::config::{AuthType, Config, Location};
+#[cfg(feature = "pam")]
+use crate::config::CacheExpiry;
+use crate::config::{Accounts, AuthType, Config};
+use crate::context::RequestContext;
+use crate::limits::FailureDelay;
+use crate::metrics::Metrics;
+#[cfg(feature = "pam")]
+use crate::pamauth::{PamError, PamRequest};
+use crate::unixuser::User;
 
 use headers::{authorization::Basic, Authorization, HeaderMapExt};
 use http::status::StatusCode;
 
 type HttpRequest = http::Request<hyper::Body>;
 
+/// Log target for failed authentications.
+pub const TARGET: &str = "webdav_server::auth";
+
+/// Who the client is.
+///
+/// Authentication only fills in the username. The account lookup
+/// (acct-type) resolves the rest, and that result is cached.
+#[derive(Debug, Clone, Default)]
+pub struct Identity {
+    pub username: String,
+    pub uid:      Option<u32>,
+    pub gid:      Option<u32>,
+    pub groups:   Vec<u32>,
+    pub home:     Option<PathBuf>,
+}
+
+impl Identity {
+    /// Identity with just a username.
+    pub fn new(username: impl Into<String>) -> Identity {
+        Identity {
+            username: username.into(),
+            ..Identity::default()
+        }
+    }
+
+    /// uid, gid and supplementary groups, if known.
+    pub fn ugid(&self) -> Option<(u32, u32, &[u32])> {
+        match (self.uid, self.gid) {
+            (Some(uid), Some(gid)) => Some((uid, gid, self.groups.as_slice())),
+            _ => None,
+        }
+    }
+}
+
+/// The authenticated user of a request, in its extensions.
+///
+/// It is only there after a successful authentication: anonymous
+/// requests, and requests that failed to authenticate, do not have it.
+/// With an account lookup (acct-type), the uid, gid and home directory
+/// are filled in too. Code that runs after authentication (the webdav
+/// handler, hooks) reads it with `authenticated`, instead of looking at
+/// the credentials again.
+#[derive(Debug, Clone)]
+pub struct Authenticated(pub Identity);
+
+/// The authenticated user of `req`, see `Authenticated`.
+pub fn authenticated<B>(req: &http::Request<B>) -> Option<&Identity> {
+    req.extensions().get::<Authenticated>().map(|a| &a.0)
+}
+
+impl From<User> for Identity {
+    fn from(user: User) -> Identity {
+        Identity {
+            username: user.name,
+            uid:      Some(user.uid),
+            gid:      Some(user.gid),
+            groups:   user.groups,
+            home:     Some(user.dir),
+        }
+    }
+}
+
+/// Broad classification of a failed PAM authentication.
+#[cfg_attr(not(feature = "pam"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PamErrorKind {
+    /// Bad password, unknown user, expired account, etc.
+    AuthFailed,
+    /// The authentication backend (LDAP, Kerberos, ..) could not be reached.
+    Transport,
+    /// A module is busy or asked us to try again later.
+    Busy,
+}
+
+#[cfg_attr(not(feature = "pam"), allow(dead_code))]
+impl PamErrorKind {
+    /// Classify a PAM error.
+    #[cfg(feature = "pam")]
+    pub fn from_pam(err: &PamError) -> PamErrorKind {
+        use pam_sys::PamReturnCode::*;
+        match err.code {
+            AUTHINFO_UNAVAIL => PamErrorKind::Transport,
+            AUTHTOK_LOCK_BUSY | TRY_AGAIN | CONV_AGAIN | INCOMPLETE => PamErrorKind::Busy,
+            _ => PamErrorKind::AuthFailed,
+        }
+    }
+
+    /// Is this a temporary failure, i.e. should the client retry later.
+    pub fn is_transient(&self) -> bool {
+        *self != PamErrorKind::AuthFailed
+    }
+
+    /// The HTTP status to return to the client.
+    pub fn status(&self) -> StatusCode {
+        if self.is_transient() {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::UNAUTHORIZED
+        }
+    }
+}
+
+/// Authentication state of one connection.
+///
+/// Clients send the same Basic credentials with every request. As
+/// long as they do not change, we remember who authenticated on this
+/// connection, and skip the authentication backend. Only a hash of the
+/// credentials is kept. The state goes away with the connection.
+///
+/// On a TLS connection, it also holds the user that the client
+/// certificate maps to, if any (see `clientcert`).
+///
+/// This is stored in the request extensions by the connection handler.
+#[derive(Debug, Clone, Default)]
+pub struct ConnAuth {
+    hasher:      RandomState,
+    last:        Arc<Mutex<Option<(u64, Identity)>>>,
+    client_cert: Option<String>,
+}
+
+impl ConnAuth {
+    pub fn new() -> ConnAuth {
+        ConnAuth::default()
+    }
+
+    /// State of a connection with a client certificate that maps to `user`.
+    pub fn with_client_cert(user: Option<String>) -> ConnAuth {
+        ConnAuth {
+            client_cert: user,
+            ..ConnAuth::default()
+        }
+    }
+
+    fn key(&self, parts: &[&str]) -> u64 {
+        self.hasher.hash_one(parts)
+    }
+
+    // Identity that authenticated with these credentials before.
+    fn get(&self, key: u64) -> Option<Identity> {
+        let mut last = self.last.lock().unwrap();
+        match *last {
+            Some((k, ref id)) if k == key => Some(id.clone()),
+            // different credentials: forget the old ones.
+            _ => {
+                *last = None;
+                None
+            },
+        }
+    }
+
+    fn set(&self, key: u64, id: &Identity) {
+        *self.last.lock().unwrap() = Some((key, id.clone()));
+    }
+}
+
 #[derive(Clone)]
 pub struct Auth {
-    config: Arc<Config>,
+    config:  Arc<Config>,
+    // only PAM logins are counted, for now.
+    #[cfg_attr(not(feature = "pam"), allow(dead_code))]
+    metrics:  Metrics,
+    delay:    FailureDelay,
+    min_time: Option<Duration>,
 }
 
 impl Auth {
     pub fn new(config: Arc<Config>) -> io::Result<Auth> {
+        #[cfg(feature = "pam")]
         if let Some(timeout) = config.pam.cache_timeout {
             crate::cache::cached::set_pamcache_timeout(timeout);
         }
-        Ok(Auth { config })
+        #[cfg(feature = "pam")]
+        if config.pam.cache_expiry == Some(CacheExpiry::Sliding) {
+            let lifetime = config.pam.cache_lifetime.unwrap_or(crate::cache::cached::DEFAULT_CACHE_LIFETIME);
+            crate::cache::cached::set_pamcache_sliding(lifetime);
+        }
+        #[cfg(feature = "pam")]
+        if let Some(backoff) = config.pam.failure_backoff {
+            crate::cache::cached::set_pamfail_timeout(backoff);
+        }
+        #[cfg(feature = "pam")]
+        if let Some(timeout) = config.pam.timeout {
+            crate::cache::cached::set_pam_timeout(timeout);
+        }
+        #[cfg(feature = "pam")]
+        if let Some(max) = config.pam.max_pending {
+            crate::cache::cached::set_pam_max_pending(max);
+        }
+        let server = &config.server;
+        let delay = FailureDelay::new(server.auth_fail_delay, server.auth_fail_delay_max);
+        let min_time = server.auth_fail_min_time.map(Duration::from_secs_f64);
+        Ok(Auth {
+            config,
+            metrics: Metrics::new(),
+            delay,
+            min_time,
+        })
+    }
+
+    /// Count authentications in `metrics`.
+    pub fn with_metrics(self, metrics: Metrics) -> Auth {
+        Auth { metrics, ..self }
+    }
+
+    fn auth_type<'a>(&'a self, accounts: &'a Accounts) -> Option<&'a AuthType> {
+        accounts.auth_type.as_ref().or(self.config.accounts.auth_type.as_ref())
+    }
+
+    /// Did the client send credentials: an Authorization header, or
+    /// with the tls-client-cert auth-type, a client certificate.
+    pub fn has_credentials(&self, req: &HttpRequest, accounts: &Accounts) -> bool {
+        match self.auth_type(accounts) {
+            Some(AuthType::ClientCert) => client_cert(req).is_some(),
+            _ => req.headers().typed_get::<Authorization<Basic>>().is_some(),
+        }
     }
 
     // authenticate user.
     pub async fn auth<'a>(
         &'a self,
         req: &'a HttpRequest,
-        location: &Location,
-        _remote_ip: SocketAddr,
-    ) -> Result<String, StatusCode> {
+        accounts: &Accounts,
+        ctx: &RequestContext,
+    ) -> Result<Identity, StatusCode> {
+        let start = Instant::now();
+
+        // match the auth type.
+        let auth_type = self.auth_type(accounts);
+
+        // the client certificate was mapped when the connection was set up.
+        if let Some(AuthType::ClientCert) = auth_type {
+            return match client_cert(req) {
+                Some(user) => Ok(Identity::new(user)),
+                None => {
+                    debug!("auth: no client certificate, or it does not map to a user");
+                    Err(StatusCode::FORBIDDEN)
+                },
+            };
+        }
+
         // we must have a login/pass
         let basic = match req.headers().typed_get::<Authorization<Basic>>() {
             Some(Authorization(basic)) => basic,
-            _ => return Err(StatusCode::UNAUTHORIZED),
+            _ => {
+                debug!("auth: no credentials");
+                return Err(StatusCode::UNAUTHORIZED);
+            }
         };
         let user = basic.username();
         let pass = basic.password();
 
-        // match the auth type.
-        let auth_type = location
-            .accounts
-            .auth_type
-            .as_ref()
-            .or(self.config.accounts.auth_type.as_ref());
-        match auth_type {
-            #[cfg(feature = "pam")]
-            Some(&AuthType::Pam) => self.auth_pam(req, user, pass, _remote_ip).await,
-            Some(&AuthType::HtPasswd(ref ht)) => self.auth_htpasswd(user, pass, ht.as_str()).await,
-            None => {
-                debug!("need authentication, but auth-type is not set");
-                Err(StatusCode::UNAUTHORIZED)
+        // Did these credentials authenticate on this connection before?
+        // The X-Forwarded-For header is part of the key, since a proxy
+        // can send requests from different clients over one connection.
+        let conn = req.extensions().get::<ConnAuth>();
+        let xff = req.headers().get("x-forwarded-for").and_then(|s| s.to_str().ok());
+        let key = conn.map(|c| c.key(&[&format!("{:?}", auth_type), user, pass, xff.unwrap_or("")]));
+        if let (Some(conn), Some(key)) = (conn, key) {
+            if let Some(id) = conn.get(key) {
+                return Ok(id);
+            }
+        }
+
+        // The backends get the time that is left for this request.
+        let backend = async {
+            match auth_type {
+                #[cfg(feature = "pam")]
+                Some(&AuthType::Pam) => self.auth_pam(req, user, pass, ctx.remote_ip).await,
+                Some(AuthType::HtPasswd(ht)) => self.auth_htpasswd(user, pass, ht.as_str()).await,
+                Some(AuthType::ClientCert) => Err(StatusCode::FORBIDDEN),
+                None => {
+                    debug!("need authentication, but auth-type is not set");
+                    Err(StatusCode::UNAUTHORIZED)
+                }
             }
+        };
+        let res = match ctx.run(backend).await {
+            Ok(res) => res,
+            Err(e) => {
+                debug!("auth: authentication for {} did not finish: {:?}", user, e);
+                Err(e.status())
+            }
+        };
+        if let (Some(conn), Some(key), Ok(id)) = (conn, key, res.as_ref()) {
+            conn.set(key, id);
         }
+        match res.as_ref() {
+            Ok(_) => self.delay.succeeded(user),
+            Err(status) => {
+                info!(target: TARGET, "auth: {} from {}: failed ({})", user, ctx.remote_ip.ip(), status);
+                // only for wrong credentials, not when the backend is down.
+                // An unknown user fails faster than a wrong password, so
+                // with auth-failure-min-time both take at least that long.
+                if *status == StatusCode::UNAUTHORIZED {
+                    let pad = self.min_time.map(|t| t.saturating_sub(start.elapsed()));
+                    tokio::time::sleep(pad.unwrap_or_default() + self.delay.failed(user)).await;
+                }
+            },
+        }
+        res
     }
 
     // authenticate user using PAM.
@@ -62,35 +331,39 @@ impl Auth {
         user: &'a str,
         pass: &'a str,
         remote_ip: SocketAddr,
-    ) -> Result<String, StatusCode> {
+    ) -> Result<Identity, StatusCode> {
         // stringify the remote IP address.
-        let ip = remote_ip.ip();
-        let ip_string = if ip.is_loopback() {
-            // if it's loopback, take the value from the x-forwarded-for
-            // header, if present.
-            req.headers()
-                .get("x-forwarded-for")
-                .and_then(|s| s.to_str().ok())
-                .and_then(|s| s.split(',').next())
-                .map(|s| s.trim().to_owned())
-        } else {
-            Some(match ip {
-                std::net::IpAddr::V4(ip) => ip.to_string(),
-                std::net::IpAddr::V6(ip) => ip.to_string(),
-            })
-        };
-        let ip_ref = ip_string.as_ref().map(|s| s.as_str());
+        let ip_string = forwarded_ip(req.headers(), remote_ip.ip()).map(|ip| ip.to_string());
+        let ip_ref = ip_string.as_deref();
 
         // authenticate.
         let service = self.config.pam.service.as_str();
-        match crate::cache::cached::pam_auth(service, user, pass, ip_ref).await {
-            Ok(_) => Ok(user.to_string()),
-            Err(_) => {
+        let mut pam_req = PamRequest::new(service, user, pass).rhost(ip_ref);
+        for (name, value) in &self.config.pam.items {
+            pam_req = match pam_req.item(name, value) {
+                Ok(r) => r,
+                Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+            };
+        }
+        let start = Instant::now();
+        let res = crate::cache::cached::pam_auth(pam_req).await;
+        let sink = self.metrics.sink();
+        sink.observe_histogram("webdav_pam_auth_duration_seconds", &[], start.elapsed().as_secs_f64());
+        let result = match res.as_ref().map_err(PamErrorKind::from_pam) {
+            Ok(_) => "ok",
+            Err(kind) if kind.is_transient() => "unavailable",
+            Err(_) => "failed",
+        };
+        sink.incr_counter("webdav_pam_auth_total", &[("result", result)], 1);
+        match res {
+            Ok(_) => Ok(Identity::new(user)),
+            Err(e) => {
+                let kind = PamErrorKind::from_pam(&e);
                 debug!(
-                    "auth_pam({}): authentication for {} ({:?}) failed",
-                    service, user, ip_ref
+                    "auth_pam({}): authentication for {} ({:?}) failed: {} ({:?})",
+                    service, user, ip_ref, e, kind
                 );
-                Err(StatusCode::UNAUTHORIZED)
+                Err(kind.status())
             }
         }
     }
@@ -101,7 +374,7 @@ impl Auth {
         user: &'a str,
         pass: &'a str,
         section: &'a str,
-    ) -> Result<String, StatusCode> {
+    ) -> Result<Identity, StatusCode> {
         // Get the htpasswd.WHATEVER section from the config file.
         let file = match self.config.htpasswd.get(section) {
             Some(section) => section.htpasswd.as_str(),
@@ -127,7 +400,7 @@ impl Auth {
                 let mut fields = line.split(':');
                 if let (Some(htuser), Some(htpass)) = (fields.next(), fields.next()) {
                     if htuser == user && pwhash::unix::verify(pass, htpass) {
-                        return Ok(user.to_string());
+                        return Ok(Identity::new(user));
                     }
                 }
             }
@@ -137,3 +410,93 @@ impl Auth {
         })
     }
 }
+
+// The user that the client certificate of the connection maps to.
+fn client_cert(req: &HttpRequest) -> Option<&str> {
+    req.extensions().get::<ConnAuth>()?.client_cert.as_deref()
+}
+
+/// Canonical form of a client address: IPv4-mapped IPv6 addresses,
+/// as seen on a dual-stack listener, are turned back into IPv4.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip6) => {
+            match ip6.to_ipv4_mapped() {
+                Some(ip4) => IpAddr::V4(ip4),
+                None => ip,
+            }
+        },
+        ip => ip,
+    }
+}
+
+/// The address of the client. If it is loopback (a reverse proxy on
+/// this host), the first address in X-Forwarded-For, or None.
+pub fn forwarded_ip(headers: &http::HeaderMap, remote_ip: IpAddr) -> Option<IpAddr> {
+    let ip = canonical_ip(remote_ip);
+    if !ip.is_loopback() {
+        return Some(ip);
+    }
+    headers
+        .get("x-forwarded-for")
+        .and_then(|s| s.to_str().ok())
+        .and_then(|s| s.split(',').next())
+        .and_then(parse_forwarded_ip)
+}
+
+// Parse an address from the X-Forwarded-For header. Some proxies
+// add the port, and/or put an IPv6 address between brackets.
+fn parse_forwarded_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    let ip = match s.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => {
+            match s.parse::<SocketAddr>() {
+                Ok(sa) => sa.ip(),
+                Err(_) => s.strip_prefix('[')?.strip_suffix(']')?.parse::<IpAddr>().ok()?,
+            }
+        },
+    };
+    Some(canonical_ip(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_ip() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(canonical_ip(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(canonical_ip(ip("2001:db8::1")), ip("2001:db8::1"));
+        assert_eq!(canonical_ip(ip("::1")).to_string(), "::1");
+
+        assert_eq!(parse_forwarded_ip(" 2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_forwarded_ip("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_forwarded_ip("[2001:db8::1]:443"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_forwarded_ip("192.0.2.1:80"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_forwarded_ip("::ffff:192.0.2.1"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_forwarded_ip("unknown"), None);
+    }
+
+    #[test]
+    fn test_conn_auth() {
+        let conn = ConnAuth::new();
+        let key1 = conn.key(&["pam", "user", "pass", ""]);
+        let key2 = conn.key(&["pam", "user", "other", ""]);
+        assert!(conn.get(key1).is_none());
+        conn.set(key1, &Identity::new("user"));
+        assert_eq!(conn.get(key1).map(|id| id.username), Some("user".to_string()));
+        assert!(conn.get(key2).is_none());
+        // a change of credentials forgets the old ones.
+        assert!(conn.get(key1).is_none());
+    }
+
+    #[test]
+    fn test_pam_error_status() {
+        assert_eq!(PamErrorKind::AuthFailed.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(PamErrorKind::Transport.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(PamErrorKind::Busy.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!PamErrorKind::AuthFailed.is_transient());
+    }
+}
@@ -0,0 +1,251 @@
+//! Localized directory index.
+//!
+//! The webdav handler has a built-in HTML index, in English. If a location
+//! has `autoindex-templates` or `autoindex-language` set, we render the
+//! index ourselves from a handlebars template instead, picked by the
+//! Accept-Language header of the client. The response has a
+//! `Content-Language` header, and `Vary: Accept-Language` (see `vary`).
+//!
+//! An English template is built in (examples/autoindex.en.hbs). Its
+//! variables:
+//!
+//! - `path`: the path of the directory (decoded)
+//! - `parent`: true if there is a parent directory
+//! - `entries`: list of `name`, `href`, `dir` (bool), `modified`, `size`
+//!
+use std::time::UNIX_EPOCH;
+
+use futures::StreamExt;
+use handlebars::Handlebars;
+use percent_encoding::{percent_encode, AsciiSet, CONTROLS};
+use serde_json::json;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavFileSystem, FsError, ReadDirMeta};
+
+use crate::config::Location;
+
+// Characters that are encoded in a path segment.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+const DEFAULT_LANGUAGE: &str = "en";
+const EN_TEMPLATE: &str = include_str!("../examples/autoindex.en.hbs");
+
+/// The templates of one location.
+pub struct Templates {
+    hbs:      Handlebars<'static>,
+    langs:    Vec<String>,
+    fallback: String,
+}
+
+/// A rendered index.
+pub struct Index {
+    pub language: String,
+    pub html:     String,
+}
+
+impl Templates {
+    /// Load the templates of a location. `None` if it uses the built-in index.
+    pub fn load(location: &Location) -> Result<Option<Templates>, String> {
+        if location.autoindex_tmpl.is_empty() && location.autoindex_lang.is_none() {
+            return Ok(None);
+        }
+        let mut hbs = Handlebars::new();
+        hbs.register_template_string(DEFAULT_LANGUAGE, EN_TEMPLATE)
+            .map_err(|e| e.to_string())?;
+        let mut langs = vec![DEFAULT_LANGUAGE.to_string()];
+        for (lang, file) in &location.autoindex_tmpl {
+            let lang = lang.to_lowercase();
+            let tmpl = std::fs::read_to_string(file).map_err(|e| format!("{}: {}", file, e))?;
+            hbs.register_template_string(&lang, tmpl)
+                .map_err(|e| format!("{}: {}", file, e))?;
+            if !langs.contains(&lang) {
+                langs.push(lang);
+            }
+        }
+        let fallback = location
+            .autoindex_lang
+            .as_deref()
+            .unwrap_or(DEFAULT_LANGUAGE)
+            .to_lowercase();
+        if !langs.contains(&fallback) {
+            return Err(format!("autoindex-language: no template for {}", fallback));
+        }
+        Ok(Some(Templates { hbs, langs, fallback }))
+    }
+
+    /// Pick a language for an Accept-Language header.
+    pub fn negotiate(&self, accept_language: Option<&str>) -> &str {
+        let mut ranges: Vec<(&str, f32)> = accept_language
+            .unwrap_or("")
+            .split(',')
+            .filter_map(|r| {
+                let mut parts = r.split(';');
+                let tag = parts.next()?.trim();
+                let q = parts
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .next()
+                    .map(|q| q.trim().parse::<f32>().unwrap_or(0.0))
+                    .unwrap_or(1.0);
+                Some((tag, q)).filter(|(tag, q)| !tag.is_empty() && *q > 0.0)
+            })
+            .collect();
+        // stable sort, so equal q values keep the order of the header.
+        ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+        let primary = |tag: &str| tag.split('-').next().unwrap_or("").to_string();
+        for (tag, _) in ranges {
+            let tag = tag.to_lowercase();
+            if tag == "*" {
+                break;
+            }
+            let found = self.langs.iter().find(|l| **l == tag).or_else(|| {
+                // "en-US" or "en-GB" can have "en", and "en" can have "en-gb".
+                self.langs.iter().find(|l| primary(l) == primary(&tag))
+            });
+            if let Some(lang) = found {
+                return lang;
+            }
+        }
+        &self.fallback
+    }
+
+    /// Render the index of a directory. `path` is relative to the root
+    /// of `fs`, `href_prefix` goes in front of it in links. Errors in the
+    /// template are logged, and returned as `GeneralFailure`.
+    pub async fn render(
+        &self,
+        fs: &dyn DavFileSystem,
+        path: &DavPath,
+        href_prefix: &str,
+        accept_language: Option<&str>,
+    ) -> Result<Index, FsError>
+    {
+        let mut entries = Vec::new();
+        let mut dir = fs.read_dir(path, ReadDirMeta::Data).await?;
+        while let Some(dirent) = dir.next().await {
+            let name = dirent.name();
+            if name.starts_with(b".") {
+                continue;
+            }
+            let meta = match dirent.metadata().await {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            let mut href = format!("{}{}{}", href_prefix, path.as_url_string(), encode_segment(&name));
+            if meta.is_dir() {
+                href.push('/');
+            }
+            let modified = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| time::at(time::Timespec::new(d.as_secs() as i64, 0)))
+                .and_then(|tm| tm.strftime("%Y-%m-%d %H:%M").ok().map(|t| t.to_string()))
+                .unwrap_or_default();
+            let name = String::from_utf8_lossy(&name).to_string();
+            entries.push((meta.is_dir(), name, href, modified, meta.len()));
+        }
+        // directories first, then by name.
+        entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(dir, name, href, modified, size)| {
+                json!({
+                    "name": if dir { format!("{}/", name) } else { name },
+                    "href": href,
+                    "dir": dir,
+                    "modified": modified,
+                    "size": display_size(size),
+                })
+            })
+            .collect();
+
+        let display_path = format!("{}{}", href_prefix, path.as_url_string());
+        let display_path = percent_encoding::percent_decode_str(&display_path).decode_utf8_lossy();
+        let data = json!({
+            "path": display_path,
+            "parent": path.as_bytes() != b"/",
+            "entries": entries,
+        });
+        let language = self.negotiate(accept_language).to_string();
+        let html = self.hbs.render(&language, &data).map_err(|e| {
+            error!("autoindex: {}: template {}: {}", display_path, language, e);
+            FsError::GeneralFailure
+        })?;
+        Ok(Index { language, html })
+    }
+}
+
+/// The first of the index files `names` that is in directory `dir`.
+pub async fn find_index(fs: &dyn DavFileSystem, dir: &DavPath, names: &[String]) -> Option<String> {
+    for name in names {
+        let path = format!("{}{}", dir.as_url_string(), encode_segment(name.as_bytes()));
+        let path = match DavPath::new(&path) {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+        if fs.metadata(&path).await.map(|m| m.is_file()).unwrap_or(false) {
+            return Some(name.clone());
+        }
+    }
+    None
+}
+
+/// Percent-encode a file name for use in a path.
+pub fn encode_segment(name: &[u8]) -> String {
+    percent_encode(name, SEGMENT).to_string()
+}
+
+// Size in bytes, or KiB, MiB, ...
+fn display_size(size: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return size.to_string();
+    }
+    let mut size = size as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates(langs: &[&str], fallback: &str) -> Templates {
+        Templates {
+            hbs:      Handlebars::new(),
+            langs:    langs.iter().map(|l| l.to_string()).collect(),
+            fallback: fallback.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let t = templates(&["en", "nl", "pt-br"], "en");
+        assert_eq!(t.negotiate(None), "en");
+        assert_eq!(t.negotiate(Some("nl")), "nl");
+        assert_eq!(t.negotiate(Some("NL-be, en;q=0.5")), "nl");
+        assert_eq!(t.negotiate(Some("de, en;q=0.8, nl;q=0.9")), "nl");
+        assert_eq!(t.negotiate(Some("pt")), "pt-br");
+        assert_eq!(t.negotiate(Some("nl;q=0, de")), "en");
+        assert_eq!(t.negotiate(Some("*")), "en");
+        assert_eq!(templates(&["en", "de"], "de").negotiate(Some("fr")), "de");
+        assert_eq!(display_size(1536), "1.5 KiB");
+    }
+}
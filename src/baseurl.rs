@@ -0,0 +1,111 @@
+//! Public base URL.
+//!
+//! Behind a reverse proxy, the URL that the client uses is not always the
+//! URL that we see. The proxy might strip a path prefix (the client uses
+//! `https://example.com/dav/alice/`, we get `/alice/`), or talk plain http
+//! to us while the client uses https.
+//!
+//! `public-url` in the `[server]` section tells us the scheme, host and
+//! path prefix that clients use. The path prefix is added to the hrefs in
+//! responses and removed from the Destination header of requests. Redirects
+//! (`Location`) are made absolute if the scheme and host are known.
+//!
+//! Requests from a proxy that we trust (one that connects from a loopback
+//! address, like for X-Forwarded-For) can set the scheme and host per request
+//! with the X-Forwarded-Proto and X-Forwarded-Host headers.
+//!
+use http::{HeaderMap, Uri};
+
+/// Scheme, host and path prefix that clients use.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BaseUrl {
+    scheme: Option<String>,
+    host:   Option<String>,
+    prefix: String,
+}
+
+impl BaseUrl {
+    /// Parse a URL like `https://example.com/dav`, or just a path prefix like `/dav`.
+    pub fn parse(url: &str) -> Result<BaseUrl, String> {
+        let uri = url.parse::<Uri>().map_err(|e| e.to_string())?;
+        if uri.query().is_some() {
+            return Err("cannot have a query".to_string());
+        }
+        let (scheme, host) = match (uri.scheme_str(), uri.authority()) {
+            (Some(scheme), Some(host)) => (Some(scheme.to_string()), Some(host.to_string())),
+            (None, None) if url.starts_with('/') => (None, None),
+            _ => return Err("must be an absolute URL or a path".to_string()),
+        };
+        Ok(BaseUrl {
+            scheme,
+            host,
+            prefix: uri.path().trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// The base URL of a request. X-Forwarded-Proto and X-Forwarded-Host
+    /// are only used if the request came from a trusted proxy.
+    pub fn for_request(public: Option<&BaseUrl>, headers: &HeaderMap, trusted: bool) -> BaseUrl {
+        let mut base = public.cloned().unwrap_or_default();
+        if trusted {
+            let header = |name: &str| {
+                let value = headers.get(name)?.to_str().ok()?;
+                let value = value.split(',').next()?.trim();
+                Some(value.to_string()).filter(|v| !v.is_empty())
+            };
+            if let Some(scheme) = header("x-forwarded-proto") {
+                base.scheme = Some(scheme.to_lowercase());
+            }
+            if let Some(host) = header("x-forwarded-host") {
+                base.host = Some(host);
+            }
+        }
+        base
+    }
+
+    /// The path prefix, without a trailing slash. Empty if there is none.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Remove the prefix from a path as the client sees it.
+    pub fn strip<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(self.prefix.as_str())?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+
+    /// Absolute URL for a path, if we know the scheme and host.
+    pub fn absolute(&self, path: &str) -> Option<String> {
+        match (self.scheme.as_ref(), self.host.as_ref()) {
+            (Some(scheme), Some(host)) => Some(format!("{}://{}{}", scheme, host, path)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_url() {
+        let base = BaseUrl::parse("https://example.com/dav/").unwrap();
+        assert_eq!(base.prefix(), "/dav");
+        assert_eq!(base.strip("/dav/alice/"), Some("/alice/"));
+        assert_eq!(base.strip("/davx/alice/"), None);
+        assert_eq!(base.absolute("/dav/a/"), Some("https://example.com/dav/a/".to_string()));
+        assert_eq!(BaseUrl::parse("/dav").unwrap().absolute("/dav/a/"), None);
+        assert!(BaseUrl::parse("example.com/dav").is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-proto", "HTTPS".parse().unwrap());
+        headers.insert("x-forwarded-host", "proxy.example.com, other".parse().unwrap());
+        let base = BaseUrl::for_request(None, &headers, true);
+        assert_eq!(base.absolute("/a/"), Some("https://proxy.example.com/a/".to_string()));
+        assert_eq!(BaseUrl::for_request(None, &headers, false).absolute("/a/"), None);
+    }
+}
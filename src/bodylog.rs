@@ -0,0 +1,176 @@
+//! Request and response body log, for debugging clients.
+//!
+//! With `log-bodies` in `[server]`, the request and response bodies of
+//! those methods are logged at debug level, with log target
+//! "webdav_server::bodies", up to `log-body-max` bytes each. Only
+//! methods with XML bodies can be logged (PROPFIND, PROPPATCH, LOCK,
+//! MKCOL, REPORT), never file content (GET, PUT).
+//!
+//! The request line has the headers, with the values of Authorization,
+//! Proxy-Authorization and Cookie replaced by "[redacted]". A body is
+//! logged when it has been read or sent (or dropped), on one line, with
+//! newlines and other control characters escaped. The response is sent
+//! after the request is done, so the request ID is added here and not by
+//! `requestid::Logger`, as in `slowlog`.
+//!
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::Stream;
+use http::HeaderMap;
+
+/// log target.
+pub const TARGET: &str = "webdav_server::bodies";
+
+/// Default of `log-body-max`.
+pub const DEFAULT_MAX: usize = 2048;
+
+/// The methods that can be logged.
+pub const METHODS: &[&str] = &["PROPFIND", "PROPPATCH", "LOCK", "MKCOL", "REPORT"];
+
+// Headers that are never logged.
+const REDACTED: &[&str] = &["authorization", "proxy-authorization", "cookie"];
+
+#[derive(Debug, Clone)]
+pub struct BodyLog {
+    methods: Vec<String>,
+    max:     usize,
+}
+
+impl BodyLog {
+    /// From `log-bodies` and `log-body-max`. `None` if no methods are
+    /// logged, an error for methods that cannot be.
+    pub fn new(methods: &[String], max: Option<usize>) -> Result<Option<BodyLog>, String> {
+        let methods = methods
+            .iter()
+            .map(|m| {
+                let m = m.to_ascii_uppercase();
+                match METHODS.contains(&m.as_str()) {
+                    true => Ok(m),
+                    false => Err(format!("{}: cannot be logged (only {})", m, METHODS.join(", "))),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if methods.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(BodyLog {
+            methods,
+            max: max.unwrap_or(DEFAULT_MAX),
+        }))
+    }
+
+    /// Are the bodies of this method logged.
+    pub fn wants(&self, method: &http::Method) -> bool {
+        self.methods.iter().any(|m| m == method.as_str())
+    }
+
+    /// Wrap the request body, it is logged with the headers.
+    pub fn request(&self, req: http::Request<hyper::Body>) -> http::Request<hyper::Body> {
+        let what = format!(
+            "request: {} {} ({})",
+            req.method(),
+            req.uri(),
+            headers(req.headers())
+        );
+        req.map(|body| self.tap(body, what))
+    }
+
+    /// Wrap the response body of the request `id`.
+    pub fn response(&self, body: hyper::Body, id: &str, method: &http::Method, path: &str) -> hyper::Body {
+        self.tap(body, format!("[{}] response: {} {}", id, method, path))
+    }
+
+    fn tap(&self, body: hyper::Body, what: String) -> hyper::Body {
+        hyper::Body::wrap_stream(Tap {
+            body,
+            what,
+            max: self.max,
+            buf: Vec::new(),
+            len: 0,
+        })
+    }
+}
+
+// "name: value, ..." without the credentials.
+fn headers(headers: &HeaderMap) -> String {
+    let header = |(name, value): (&http::header::HeaderName, &http::HeaderValue)| {
+        let value = match REDACTED.contains(&name.as_str()) {
+            true => "[redacted]".to_string(),
+            false => String::from_utf8_lossy(value.as_bytes()).escape_debug().to_string(),
+        };
+        format!("{}: {}", name, value)
+    };
+    headers.iter().map(header).collect::<Vec<_>>().join(", ")
+}
+
+struct Tap {
+    body: hyper::Body,
+    what: String,
+    max:  usize,
+    // the first `max` bytes.
+    buf:  Vec<u8>,
+    len:  u64,
+}
+
+impl Tap {
+    fn message(&self) -> String {
+        let more = if self.len > self.buf.len() as u64 { "..." } else { "" };
+        let body = String::from_utf8_lossy(&self.buf);
+        format!("body: {}: {} bytes: {}{}", self.what, self.len, body.escape_debug(), more)
+    }
+}
+
+impl Stream for Tap {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &res {
+            let room = this.max - this.buf.len();
+            this.buf.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            this.len += chunk.len() as u64;
+        }
+        res
+    }
+}
+
+impl Drop for Tap {
+    fn drop(&mut self) {
+        debug!(target: TARGET, "{}", self.message());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bodylog() {
+        let strings = |l: &[&str]| l.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(BodyLog::new(&[], None).unwrap().is_none());
+        assert!(BodyLog::new(&strings(&["propfind", "PUT"]), None).is_err());
+        let log = BodyLog::new(&strings(&["propfind"]), Some(8)).unwrap().unwrap();
+        assert!(log.wants(&http::Method::from_bytes(b"PROPFIND").unwrap()));
+        assert!(!log.wants(&http::Method::GET));
+
+        let mut tap = Tap {
+            body: hyper::Body::from("<a>\n</a>\n<b/>"),
+            what: "request: PROPFIND /".to_string(),
+            max:  8,
+            buf:  Vec::new(),
+            len:  0,
+        };
+        while let Some(chunk) = futures::StreamExt::next(&mut tap).await {
+            chunk.unwrap();
+        }
+        assert_eq!(tap.message(), r"body: request: PROPFIND /: 13 bytes: <a>\n</a>...");
+
+        let mut map = HeaderMap::new();
+        map.insert("depth", "1".parse().unwrap());
+        map.insert("authorization", "Basic YWxpY2U6c2VjcmV0".parse().unwrap());
+        assert_eq!(headers(&map), "depth: 1, authorization: [redacted]");
+    }
+}
@@ -0,0 +1,311 @@
+//! Multiple ranges in one GET.
+//!
+//! The webdav handler serves a single range fine, but its `multipart/byteranges`
+//! responses use a fixed boundary and bare LF line endings, and it neither
+//! merges overlapping ranges nor limits how many there are. So a request
+//! with more than one range is handled in two steps:
+//!
+//! - the handler is asked for one range that covers all of them (from
+//!   the lowest start to the end of the last range, or the end of the file).
+//!   It still does the conditional headers and the metadata. If-Range
+//!   has been checked before (see `precondition`).
+//! - its 206 response is cut into the parts. Overlapping and adjacent
+//!   ranges are merged first, and ranges that start past the end of the file
+//!   are left out. If one range remains it is sent as a normal 206.
+//!
+//! A `Range` header with more than `MAX_RANGES` ranges is refused.
+//!
+use std::collections::VecDeque;
+
+use bytes::{Buf, Bytes};
+use futures::StreamExt;
+use http::header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE};
+use http::{Response, StatusCode};
+
+/// Maximum number of ranges in a Range header.
+pub const MAX_RANGES: usize = 64;
+
+/// One range from the Range header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Spec {
+    /// `start-end`
+    FromTo(u64, u64),
+    /// `start-`
+    From(u64),
+    /// `-count`
+    Last(u64),
+}
+
+/// Parse the value of a Range header. Returns `None` if it is not a
+/// valid `bytes` range; the header is then ignored.
+pub fn parse(value: &str) -> Option<Vec<Spec>> {
+    let value = value.trim();
+    if value.len() < 6 || !value[..6].eq_ignore_ascii_case("bytes=") {
+        return None;
+    }
+    let mut specs = Vec::new();
+    for range in value[6..].split(',').map(|r| r.trim()).filter(|r| !r.is_empty()) {
+        let (start, end) = range.split_at(range.find('-')?);
+        let (start, end) = (start.trim(), end[1..].trim());
+        let spec = match (start.is_empty(), end.is_empty()) {
+            (false, false) => {
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                if end < start {
+                    return None;
+                }
+                Spec::FromTo(start, end)
+            },
+            (false, true) => Spec::From(start.parse().ok()?),
+            (true, false) => Spec::Last(end.parse().ok()?),
+            (true, true) => return None,
+        };
+        specs.push(spec);
+    }
+    if specs.is_empty() {
+        return None;
+    }
+    Some(specs)
+}
+
+/// The one range that covers all of `specs`, as a Range header value.
+pub fn hull(specs: &[Spec]) -> String {
+    let mut start = u64::MAX;
+    let mut end = Some(0);
+    for spec in specs {
+        match *spec {
+            Spec::FromTo(s, e) => {
+                start = start.min(s);
+                end = end.map(|end| end.max(e));
+            },
+            Spec::From(s) => {
+                start = start.min(s);
+                end = None;
+            },
+            Spec::Last(_) => {
+                start = 0;
+                end = None;
+            },
+        }
+    }
+    match end {
+        Some(end) => format!("bytes={}-{}", start, end),
+        None => format!("bytes={}-", start),
+    }
+}
+
+/// The ranges (first and last byte) in a file of `len` bytes, in order,
+/// with overlapping and adjacent ranges merged.
+pub fn resolve(specs: &[Spec], len: u64) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = specs
+        .iter()
+        .filter_map(|spec| {
+            let (start, end) = match *spec {
+                Spec::FromTo(s, e) => (s, e.min(len.saturating_sub(1))),
+                Spec::From(s) => (s, len.saturating_sub(1)),
+                Spec::Last(0) => return None,
+                Spec::Last(n) => (len.saturating_sub(n), len.saturating_sub(1)),
+            };
+            if start >= len {
+                return None;
+            }
+            Some((start, end))
+        })
+        .collect();
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+// "bytes 100-199/1000" => (100, 1000)
+fn content_range(value: &str) -> Option<(u64, u64)> {
+    let value = value.strip_prefix("bytes ")?;
+    let (range, len) = value.split_at(value.find('/')?);
+    let start = range.split('-').next()?.parse().ok()?;
+    Some((start, len[1..].parse().ok()?))
+}
+
+// A piece of the response body.
+enum Item {
+    Literal(Bytes),
+    Range(u64, u64),
+}
+
+/// Turn the handler's response for the `hull` of `specs` into the
+/// response for `specs`. Anything but a 206 (a 304, a 416) is
+/// returned unchanged.
+pub fn split(resp: Response<hyper::Body>, specs: &[Spec]) -> Response<hyper::Body> {
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        return resp;
+    }
+    let hdr = resp.headers().get(CONTENT_RANGE).and_then(|v| v.to_str().ok());
+    let (offset, len) = match hdr.and_then(content_range) {
+        Some(r) => r,
+        None => return resp,
+    };
+    let ranges = resolve(specs, len);
+    let (mut parts, body) = resp.into_parts();
+
+    let items = match ranges.len() {
+        0 => {
+            // only "-0" ranges.
+            let mut resp = Response::new(hyper::Body::empty());
+            *resp.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            let value = format!("bytes */{}", len);
+            resp.headers_mut().insert(CONTENT_RANGE, value.parse().unwrap());
+            return resp;
+        },
+        1 => {
+            let (start, end) = ranges[0];
+            let value = format!("bytes {}-{}/{}", start, end, len);
+            parts.headers.insert(CONTENT_RANGE, value.parse().unwrap());
+            vec![Item::Range(start, end)]
+        },
+        _ => {
+            let content_type = parts.headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+            let content_type = content_type.unwrap_or("application/octet-stream").to_string();
+            let boundary = uuid::Uuid::new_v4().to_simple().to_string();
+            let mut items = Vec::new();
+            for &(start, end) in &ranges {
+                let hdrs = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, content_type, start, end, len
+                );
+                items.push(Item::Literal(Bytes::from(hdrs)));
+                items.push(Item::Range(start, end));
+            }
+            items.push(Item::Literal(Bytes::from(format!("\r\n--{}--\r\n", boundary))));
+            parts.headers.remove(CONTENT_RANGE);
+            let value = format!("multipart/byteranges; boundary={}", boundary);
+            parts.headers.insert(CONTENT_TYPE, value.parse().unwrap());
+            items
+        },
+    };
+
+    let length: u64 = items
+        .iter()
+        .map(|item| {
+            match item {
+                Item::Literal(b) => b.len() as u64,
+                Item::Range(start, end) => end - start + 1,
+            }
+        })
+        .sum();
+    parts.headers.insert(CONTENT_LENGTH, length.into());
+
+    // Walk through the body of the hull, and send the parts of it that we want.
+    let state = (body, offset, Bytes::new(), VecDeque::from(items));
+    let stream = futures::stream::unfold(state, |(mut body, mut pos, mut chunk, mut items)| {
+        async move {
+            loop {
+                let (start, end) = match items.pop_front()? {
+                    Item::Literal(b) => return Some((Ok(b), (body, pos, chunk, items))),
+                    Item::Range(start, end) => (start, end),
+                };
+                if pos + (chunk.len() as u64) <= start {
+                    pos += chunk.len() as u64;
+                    chunk = match body.next().await? {
+                        Ok(c) => c,
+                        Err(e) => return Some((Err(e), (body, pos, Bytes::new(), VecDeque::new()))),
+                    };
+                    items.push_front(Item::Range(start, end));
+                    continue;
+                }
+                if pos < start {
+                    chunk.advance((start - pos) as usize);
+                    pos = start;
+                }
+                let n = chunk.len().min((end - start + 1) as usize);
+                let data = chunk.split_to(n);
+                pos += n as u64;
+                if pos <= end {
+                    items.push_front(Item::Range(pos, end));
+                }
+                return Some((Ok(data), (body, pos, chunk, items)));
+            }
+        }
+    });
+    Response::from_parts(parts, hyper::Body::wrap_stream(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        use Spec::*;
+        assert_eq!(parse("bytes=0-9, 20-, -5"), Some(vec![FromTo(0, 9), From(20), Last(5)]));
+        assert_eq!(parse("Bytes = 1-2"), None);
+        assert_eq!(parse("bytes=5-1"), None);
+        assert_eq!(parse("items=0-1"), None);
+        assert_eq!(parse("bytes=-"), None);
+        assert_eq!(hull(&[FromTo(10, 19), FromTo(40, 49)]), "bytes=10-49");
+        assert_eq!(hull(&[FromTo(10, 19), From(40)]), "bytes=10-");
+        assert_eq!(hull(&[FromTo(10, 19), Last(5)]), "bytes=0-");
+    }
+
+    #[test]
+    fn test_coalesce() {
+        use Spec::*;
+        // overlapping, adjacent, out of order.
+        let specs = [FromTo(50, 59), FromTo(0, 9), FromTo(5, 14), FromTo(15, 19)];
+        assert_eq!(resolve(&specs, 100), vec![(0, 19), (50, 59)]);
+        // contained, suffix, clamped, past the end.
+        let specs = [FromTo(0, 50), FromTo(10, 20), Last(10), FromTo(95, 200), From(150)];
+        assert_eq!(resolve(&specs, 100), vec![(0, 50), (90, 99)]);
+        assert_eq!(resolve(&[Last(0), FromTo(100, 110)], 100), vec![]);
+    }
+
+    async fn get(specs: &[Spec], data: &'static [u8]) -> Response<hyper::Body> {
+        // what the webdav handler returns for the hull, in small chunks.
+        let start: usize = hull(specs)[6..].split('-').next().unwrap().parse().unwrap();
+        let chunks = data[start..].chunks(3).map(|c| Ok::<_, std::io::Error>(Bytes::from(c)));
+        let resp = Response::builder()
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CONTENT_TYPE, "text/plain")
+            .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, data.len() - 1, data.len()))
+            .body(hyper::Body::wrap_stream(futures::stream::iter(chunks)))
+            .unwrap();
+        split(resp, specs)
+    }
+
+    #[tokio::test]
+    async fn test_split() {
+        use Spec::*;
+        let data = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+        // two ranges that merge into one.
+        let resp = get(&[FromTo(2, 5), FromTo(4, 8)], data).await;
+        assert_eq!(resp.headers()["content-range"], "bytes 2-8/36");
+        assert_eq!(resp.headers()["content-length"], "7");
+        assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "2345678");
+
+        // three ranges.
+        let resp = get(&[FromTo(20, 21), FromTo(1, 3), Last(2)], data).await;
+        assert!(resp.headers().get("content-range").is_none());
+        let ct = resp.headers()["content-type"].to_str().unwrap().to_string();
+        let boundary = ct.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let length: usize = resp.headers()["content-length"].to_str().unwrap().parse().unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body.len(), length);
+        let expected = concat!(
+            "\r\n--B\r\nContent-Type: text/plain\r\nContent-Range: bytes 1-3/36\r\n\r\n123",
+            "\r\n--B\r\nContent-Type: text/plain\r\nContent-Range: bytes 20-21/36\r\n\r\nkl",
+            "\r\n--B\r\nContent-Type: text/plain\r\nContent-Range: bytes 34-35/36\r\n\r\nyz",
+            "\r\n--B--\r\n"
+        );
+        assert_eq!(body, expected.replace("--B", &format!("--{}", boundary)));
+
+        // not a 206: unchanged.
+        let resp = Response::new(hyper::Body::from(&data[..]));
+        let resp = split(resp, &[FromTo(1, 2), FromTo(5, 6)]);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), &data[..]);
+    }
+}
@@ -12,10 +12,13 @@ pub struct Cache<K, V> {
     intern: Mutex<Intern<K, V>>,
 }
 
+// With `sliding`, the time in `fifo` is that of the last hit, and the
+// time in `map` that of the insert, for the absolute cap.
 struct Intern<K, V> {
     maxsize: usize,
     maxage: Duration,
-    map: HashMap<K, Arc<V>>,
+    sliding: Option<Duration>,
+    map: HashMap<K, (Arc<V>, Instant)>,
     fifo: VecDeque<(Instant, K)>,
 }
 
@@ -24,6 +27,7 @@ impl<K: Hash + Eq + Clone, V> Cache<K, V> {
         let i = Intern {
             maxsize: 0,
             maxage: Duration::new(0, 0),
+            sliding: None,
             map: HashMap::new(),
             fifo: VecDeque::new(),
         };
@@ -44,6 +48,14 @@ impl<K: Hash + Eq + Clone, V> Cache<K, V> {
         self
     }
 
+    /// A hit extends the life of an entry by `maxage` again, up to
+    /// `lifetime` after it was inserted.
+    #[allow(dead_code)]
+    pub fn sliding(self, lifetime: Duration) -> Self {
+        self.intern.lock().unwrap().sliding = Some(lifetime);
+        self
+    }
+
     fn expire(&self, m: &mut Intern<K, V>) {
         let mut n = m.fifo.len();
         if m.maxsize > 0 && n >= m.maxsize {
@@ -60,8 +72,8 @@ impl<K: Hash + Eq + Clone, V> Cache<K, V> {
             }
         }
         for x in n..m.fifo.len() {
-            let &(_, ref key) = m.fifo.get(x).unwrap();
-            m.map.remove(&key);
+            let (_, key) = m.fifo.get(x).unwrap();
+            m.map.remove(key);
         }
         m.fifo.truncate(n);
     }
@@ -71,23 +83,75 @@ impl<K: Hash + Eq + Clone, V> Cache<K, V> {
         self.expire(&mut *m);
         let av = Arc::new(val);
         let ac = av.clone();
-        m.map.insert(key.clone(), av);
-        m.fifo.push_front((Instant::now(), key));
+        let now = Instant::now();
+        m.map.insert(key.clone(), (av, now));
+        m.fifo.push_front((now, key));
         ac
     }
 
+    /// Remove all entries. Returns how many there were.
+    pub fn clear(&self) -> usize {
+        let mut m = self.intern.lock().unwrap();
+        let n = m.map.len();
+        m.map.clear();
+        m.fifo.clear();
+        n
+    }
+
     // see https://doc.rust-lang.org/book/first-edition/borrow-and-asref.html
-    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<Arc<V>>
+    pub fn get<Q>(&self, key: &Q) -> Option<Arc<V>>
     where
         K: Borrow<Q>,
-        Q: Hash + Eq,
+        Q: Hash + Eq + ?Sized,
     {
         let mut m = self.intern.lock().unwrap();
         self.expire(&mut *m);
-        if let Some(v) = m.map.get(key) {
-            return Some(v.clone());
+        let (v, created) = m.map.get(key).map(|(v, t)| (v.clone(), *t))?;
+        if let Some(lifetime) = m.sliding {
+            let now = Instant::now();
+            let pos = m.fifo.iter().position(|(_, k)| k.borrow() == key);
+            if now.duration_since(created) > lifetime {
+                m.map.remove(key);
+                if let Some(pos) = pos {
+                    m.fifo.remove(pos);
+                }
+                return None;
+            }
+            if let Some((_, k)) = pos.and_then(|pos| m.fifo.remove(pos)) {
+                m.fifo.push_front((now, k));
+            }
+        }
+        Some(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sliding() {
+        let step = Duration::from_millis(200);
+        let cache = Cache::new().maxage(Duration::from_millis(300));
+        cache.insert("a", 1);
+        std::thread::sleep(step);
+        assert!(cache.get("a").is_some());
+        std::thread::sleep(step);
+        assert!(cache.get("a").is_none());
+
+        // a hit extends it, up to the lifetime.
+        let cache = Cache::new()
+            .maxage(Duration::from_millis(300))
+            .sliding(Duration::from_millis(700));
+        cache.insert("a", 1);
+        for _ in 0..3 {
+            std::thread::sleep(step);
+            assert!(cache.get("a").is_some());
         }
-        None
+        std::thread::sleep(step);
+        assert!(cache.get("a").is_none());
+        cache.insert("a", 2);
+        assert_eq!(cache.get("a").as_deref(), Some(&2));
     }
 }
 
@@ -99,32 +163,91 @@ pub(crate) mod cached {
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
 
+    use crate::auth::Identity;
+    #[cfg(feature = "pam")]
+    use crate::auth::PamErrorKind;
     use crate::cache;
-    use crate::unixuser::{self, User};
+    #[cfg(feature = "pam")]
+    use crate::pamauth::{PamError, PamPhase, PamRequest};
+    use crate::unixuser::User;
     use lazy_static::lazy_static;
+    #[cfg(feature = "pam")]
+    use pam_sys::PamReturnCode;
+    #[cfg(feature = "pam")]
+    use tokio::sync::Semaphore;
 
     struct Timeouts {
         pwcache: Duration,
+        #[cfg(feature = "pam")]
         pamcache: Duration,
+        #[cfg(feature = "pam")]
+        pamfail:  Duration,
+        #[cfg(feature = "pam")]
+        pamlife:  Option<Duration>,
+        #[cfg(feature = "pam")]
+        pamauth:  Option<Duration>,
+        // Not a timeout, but set at the same time.
+        #[cfg(feature = "pam")]
+        pampending: usize,
     }
 
     lazy_static! {
         static ref TIMEOUTS: Mutex<Timeouts> = Mutex::new(Timeouts {
             pwcache: Duration::new(120, 0),
+            #[cfg(feature = "pam")]
             pamcache: Duration::new(120, 0),
+            #[cfg(feature = "pam")]
+            pamfail:  Duration::new(DEFAULT_FAILURE_BACKOFF, 0),
+            #[cfg(feature = "pam")]
+            pamlife:  None,
+            #[cfg(feature = "pam")]
+            pamauth:  None,
+            #[cfg(feature = "pam")]
+            pampending: DEFAULT_MAX_PENDING,
         });
-        static ref PWCACHE: cache::Cache<String, unixuser::User> = new_pwcache();
+        static ref PWCACHE: cache::Cache<String, Identity> = new_pwcache();
+    }
+
+    #[cfg(feature = "pam")]
+    lazy_static! {
         static ref PAMCACHE: cache::Cache<u64, String> = new_pamcache();
+        static ref PAMFAILCACHE: cache::Cache<u64, PamError> = new_pamfailcache();
+        // PAM calls in progress, including those we gave up on.
+        static ref PAMPENDING: Arc<Semaphore> = Arc::new(Semaphore::new(TIMEOUTS.lock().unwrap().pampending));
     }
 
-    fn new_pwcache() -> cache::Cache<String, unixuser::User> {
+    /// Default of `[pam] failure-backoff` (secs).
+    #[cfg(feature = "pam")]
+    pub const DEFAULT_FAILURE_BACKOFF: u64 = 5;
+
+    /// Default of `[pam] max-pending`.
+    #[cfg(feature = "pam")]
+    pub const DEFAULT_MAX_PENDING: usize = 32;
+
+    /// Default of `[pam] cache-max-lifetime` (secs).
+    #[cfg(feature = "pam")]
+    pub const DEFAULT_CACHE_LIFETIME: u64 = 3600;
+
+    fn new_pwcache() -> cache::Cache<String, Identity> {
         let timeouts = TIMEOUTS.lock().unwrap();
         cache::Cache::new().maxage(timeouts.pwcache)
     }
 
+    #[cfg(feature = "pam")]
     fn new_pamcache() -> cache::Cache<u64, String> {
         let timeouts = TIMEOUTS.lock().unwrap();
-        cache::Cache::new().maxage(timeouts.pamcache)
+        let cache = cache::Cache::new().maxage(timeouts.pamcache);
+        match timeouts.pamlife {
+            Some(lifetime) => cache.sliding(lifetime),
+            None => cache,
+        }
+    }
+
+    // Failed logins. Bounded, since anyone can fill it.
+    #[cfg(feature = "pam")]
+    fn new_pamfailcache() -> cache::Cache<u64, PamError> {
+        let timeouts = TIMEOUTS.lock().unwrap();
+        cache::Cache::new().maxage(timeouts.pamfail).maxsize(10000)
     }
 
     pub(crate) fn set_pwcache_timeout(secs: usize) {
@@ -138,47 +261,177 @@ pub(crate) mod cached {
         timeouts.pamcache = Duration::new(secs as u64, 0);
     }
 
+    /// Extend cached PAM logins on every hit, up to `secs` after the
+    /// login that PAM checked.
+    #[cfg(feature = "pam")]
+    pub(crate) fn set_pamcache_sliding(secs: u64) {
+        let mut timeouts = TIMEOUTS.lock().unwrap();
+        timeouts.pamlife = Some(Duration::from_secs(secs));
+    }
+
+    #[cfg(feature = "pam")]
+    pub(crate) fn set_pamfail_timeout(secs: usize) {
+        let mut timeouts = TIMEOUTS.lock().unwrap();
+        timeouts.pamfail = Duration::new(secs as u64, 0);
+    }
+
+    /// Give up on PAM calls after `secs`.
+    #[cfg(feature = "pam")]
+    pub(crate) fn set_pam_timeout(secs: u64) {
+        let mut timeouts = TIMEOUTS.lock().unwrap();
+        timeouts.pamauth = Some(Duration::from_secs(secs));
+    }
+
+    /// At most `max` PAM calls in progress.
+    #[cfg(feature = "pam")]
+    pub(crate) fn set_pam_max_pending(max: usize) {
+        let mut timeouts = TIMEOUTS.lock().unwrap();
+        timeouts.pampending = max;
+    }
+
+    // The failure of the same credentials from the same client in the
+    // last failure-backoff secs.
+    #[cfg(feature = "pam")]
+    fn recent_failure(key: u64) -> Option<PamError> {
+        PAMFAILCACHE.get(&key).map(|e| (*e).clone())
+    }
+
+    // Remember a failure. Temporary failures (backend down) are not
+    // remembered, the next try might work.
+    #[cfg(feature = "pam")]
+    fn remember_failure(key: u64, err: &PamError) {
+        let backoff = TIMEOUTS.lock().unwrap().pamfail;
+        if backoff > Duration::ZERO && !PamErrorKind::from_pam(err).is_transient() {
+            PAMFAILCACHE.insert(key, err.clone());
+        }
+    }
+
+    /// Authenticate through PAM.
+    ///
+    /// Successes are cached for cache-timeout secs (since the login, or
+    /// with a sliding cache-expiry since the last hit, up to
+    /// cache-max-lifetime after the login). A failure is not
+    /// passed to PAM again for failure-backoff secs if the client sends
+    /// the exact same credentials: a client that retries a wrong password
+    /// in a loop would otherwise make pam_tally2 or pam_faillock lock the
+    /// account. Different credentials always go to PAM.
+    ///
+    /// At most max-pending PAM calls are in progress, the calls that
+    /// timed out but did not return yet included.
     #[cfg(feature = "pam")]
-    pub async fn pam_auth<'a>(
-        service: &'a str,
-        user: &'a str,
-        pass: &'a str,
-        remip: Option<&'a str>,
-    ) -> Result<(), pam::PamError> {
+    pub async fn pam_auth(req: PamRequest) -> Result<(), PamError> {
         use std::collections::hash_map::DefaultHasher;
         use std::hash::{Hash, Hasher};
 
         let mut s = DefaultHasher::new();
-        service.hash(&mut s);
-        user.hash(&mut s);
-        pass.hash(&mut s);
-        remip.as_ref().hash(&mut s);
+        req.service.hash(&mut s);
+        req.user.hash(&mut s);
+        req.pass.hash(&mut s);
+        req.rhost.hash(&mut s);
+        for (item_type, value) in &req.items {
+            (*item_type as i32).hash(&mut s);
+            value.hash(&mut s);
+        }
         let key = s.finish();
 
         if let Some(cache_user) = PAMCACHE.get(&key) {
-            if user == cache_user.as_str() {
+            if req.user == cache_user.as_str() {
                 return Ok(());
             }
         }
 
-        let mut pam_auth = pam::Authenticator::with_password(service).expect("Failed to init PAM client.");
-        pam_auth.get_handler().set_credentials(&*user, &*pass);
-        match pam_auth.authenticate() {
-            Err(e) => Err(e),
+        if let Some(e) = recent_failure(key) {
+            debug!("pam_auth: {}: same credentials failed just now, not asking PAM again", req.user);
+            return Err(e);
+        }
+
+        let permit = match PAMPENDING.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!("pam_auth: {}: max-pending PAM calls in progress, not calling PAM", req.user);
+                return Err(PamError::new(PamPhase::Pending, PamReturnCode::TRY_AGAIN));
+            },
+        };
+        let timeout = TIMEOUTS.lock().unwrap().pamauth;
+        let res = match timeout {
+            Some(timeout) => req.clone().authenticate_timeout(timeout, permit).await,
+            None => {
+                let _permit = permit;
+                tokio::task::block_in_place(|| req.authenticate())
+            },
+        };
+        match res {
+            Err(e) => {
+                remember_failure(key, &e);
+                Err(e)
+            },
             Ok(()) => {
-                PAMCACHE.insert(key, user.to_owned());
+                PAMCACHE.insert(key, req.user);
                 Ok(())
             }
         }
     }
 
-    pub async fn unixuser(username: &str, with_groups: bool) -> Result<Arc<User>, io::Error> {
+    pub async fn unixuser(username: &str, with_groups: bool) -> Result<Arc<Identity>, io::Error> {
         if let Some(pwd) = PWCACHE.get(username) {
             return Ok(pwd);
         }
         match User::by_name_async(username, with_groups).await {
             Err(e) => Err(e),
-            Ok(pwd) => Ok(PWCACHE.insert(username.to_owned(), pwd)),
+            Ok(pwd) => Ok(PWCACHE.insert(username.to_owned(), pwd.into())),
+        }
+    }
+
+    /// Forget all cached accounts and logins. Returns how many there were.
+    pub fn flush() -> usize {
+        let n = PWCACHE.clear();
+        #[cfg(feature = "pam")]
+        let n = n + PAMCACHE.clear() + PAMFAILCACHE.clear();
+        n
+    }
+
+    /// An account that is not in the passwd database, for tests.
+    #[cfg(test)]
+    pub fn insert_unixuser(pwd: Identity) {
+        PWCACHE.insert(pwd.username.clone(), pwd);
+    }
+
+    /// Look up `usernames` (and their groups) so that they are in the
+    /// cache before their first request. Returns how many were found.
+    pub async fn warm(usernames: &[String], with_groups: bool) -> usize {
+        let mut warmed = 0;
+        for username in usernames {
+            match unixuser(username, with_groups).await {
+                Ok(_) => warmed += 1,
+                Err(e) => warn!("unix: warm-cache: {}: {}", username, e),
+            }
+        }
+        info!("unix: warm-cache: {} of {} accounts cached", warmed, usernames.len());
+        warmed
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn test_warm() {
+            let names = vec!["root".to_string(), "no-such-user-webdav-test".to_string()];
+            assert_eq!(warm(&names, true).await, 1);
+            assert!(PWCACHE.get("root").is_some());
+            assert!(PWCACHE.get("no-such-user-webdav-test").is_none());
+        }
+
+        #[cfg(feature = "pam")]
+        #[test]
+        fn test_failure_backoff() {
+            let failed = PamError::new(PamPhase::Authenticate, PamReturnCode::AUTH_ERR);
+            remember_failure(1, &failed);
+            assert_eq!(recent_failure(1), Some(failed));
+            assert_eq!(recent_failure(2), None);
+            // the backend was down, try again next time.
+            remember_failure(3, &PamError::new(PamPhase::Authenticate, PamReturnCode::AUTHINFO_UNAVAIL));
+            assert_eq!(recent_failure(3), None);
         }
     }
 }
@@ -0,0 +1,150 @@
+//! What the server can do, for clients and admins.
+//!
+//! With `[server] capabilities = "public"`, a GET of
+//! `/.well-known/webdav-capabilities` returns a JSON document with the
+//! features that are on, without authentication. With "admin", it is only
+//! in the admin API, as ROUTE/capabilities. It is made from the
+//! configuration for every request.
+//!
+//! The document has the DAV compliance classes, the authentication
+//! methods, the limits, and for every location its routes, methods and
+//! optional features ("is locking on here?"). Directories, user names,
+//! and the other details of the configuration are not in it.
+//!
+use serde_json::{json, Value};
+use webdav_handler::{DavMethod, DavMethodSet};
+
+use crate::config::{AllProp, Auth, AuthType, Config, Handler, Location};
+use crate::limits;
+
+/// Where the public document lives.
+pub const PATH: &str = "/.well-known/webdav-capabilities";
+
+// What the webdav handler puts in the DAV: header.
+const DAV_CLASSES: &[&str] = &["1", "2", "3"];
+
+const METHODS: &[(&str, DavMethod)] = &[
+    ("GET", DavMethod::Get),
+    ("HEAD", DavMethod::Head),
+    ("OPTIONS", DavMethod::Options),
+    ("PROPFIND", DavMethod::PropFind),
+    ("PROPPATCH", DavMethod::PropPatch),
+    ("PUT", DavMethod::Put),
+    ("PATCH", DavMethod::Patch),
+    ("MKCOL", DavMethod::MkCol),
+    ("COPY", DavMethod::Copy),
+    ("MOVE", DavMethod::Move),
+    ("DELETE", DavMethod::Delete),
+    ("LOCK", DavMethod::Lock),
+    ("UNLOCK", DavMethod::Unlock),
+];
+
+fn auth_type(auth_type: Option<&AuthType>) -> Option<&'static str> {
+    match auth_type? {
+        #[cfg(feature = "pam")]
+        AuthType::Pam => Some("pam"),
+        AuthType::HtPasswd(_) => Some("htpasswd"),
+        AuthType::ClientCert => Some("tls-client-cert"),
+    }
+}
+
+fn location(config: &Config, location: &Location) -> Value {
+    // the default of the handler, see Server::handle.
+    let methods = location.methods.unwrap_or(DavMethodSet::from_vec(vec!["GET", "HEAD"]).unwrap());
+    let names: Vec<_> = METHODS.iter().filter(|(_, m)| methods.contains(*m)).map(|(n, _)| *n).collect();
+    let auth = match location.auth {
+        Some(Auth::True) => "true",
+        Some(Auth::False) => "false",
+        Some(Auth::Write) => "write",
+        Some(Auth::Opportunistic) | None => "opportunistic",
+    };
+    let auth_type = location.accounts.auth_type.as_ref().or(config.accounts.auth_type.as_ref());
+    let filesystem = matches!(location.handler, Handler::Filesystem);
+    json!({
+        "route": location.route,
+        "methods": names,
+        "auth": auth,
+        "auth_type": self::auth_type(auth_type),
+        "locking": methods.contains(DavMethod::Lock),
+        "ranges": filesystem && location.ranges.unwrap_or(true) && methods.contains(DavMethod::Get),
+        "precompressed": location.precompressed,
+        "autoindex": location.autoindex,
+        "auto_mkcol": location.auto_mkcol,
+        "extended_mkcol": location.extended_mkcol,
+        "chunked_uploads": location.chunked_uploads.is_some(),
+        "verify_checksum": location.verify_checksum,
+        "trash": location.trash.is_some(),
+        "fsync": location.fsync,
+        "one_filesystem": location.one_filesystem,
+        "write_windows": !location.write_windows.is_empty(),
+        "macos_compat": location.macos_compat,
+        "read_ahead": location.read_ahead,
+        "probe_cache": location.probe_cache.is_some(),
+        "allprop": location.allprop.unwrap_or(AllProp::Allow) == AllProp::Allow,
+    })
+}
+
+/// The capabilities document.
+pub fn capabilities(config: &Config) -> Value {
+    let server = &config.server;
+    json!({
+        "dav": DAV_CLASSES,
+        "tls": !server.tls_listen.is_empty(),
+        "auth_type": auth_type(config.accounts.auth_type.as_ref()),
+        "limits": {
+            "max_header_size": server.max_header_size.unwrap_or(limits::DEFAULT_MAX_HEADER_SIZE),
+            "max_headers": server.max_headers.unwrap_or(limits::MAX_HEADER_COUNT),
+            "max_uri_length": server.max_uri_length.unwrap_or(limits::DEFAULT_MAX_URI_LENGTH),
+            "max_xml_body": server.max_xml_body,
+            "max_depth": server.max_depth,
+            "propfind_max_responses": server.propfind_responses,
+            "max_path_length": server.max_path_length.unwrap_or(limits::DEFAULT_MAX_PATH_LENGTH),
+            "max_path_segments": server.max_path_segments.unwrap_or(limits::DEFAULT_MAX_PATH_SEGMENTS),
+            "max_request_rate": server.max_request_rate,
+            "max_requests_per_user": server.max_requests_per_user,
+            "max_concurrent_writes": server.max_writes.unwrap_or(limits::DEFAULT_MAX_WRITES),
+            "memory_budget": server.memory_budget,
+            "lock_default_timeout": config.locks.default_timeout,
+            "lock_max_timeout": config.locks.max_timeout,
+            "max_locks_per_user": config.locks.max_per_user,
+            "max_locks_per_path": config.locks.max_per_path,
+        },
+        "locations": config.location.iter().map(|l| location(config, l)).collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(location: &str) -> Config {
+        let toml = format!(
+            "[server]\nmax-depth = 1\n[[location]]\nroute = [ \"/*path\" ]\nhandler = \"filesystem\"\n\
+             directory = \"/srv\"\n{}\n",
+            location
+        );
+        toml::from_str(&toml).unwrap()
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let caps = capabilities(&config(""));
+        assert_eq!(caps["dav"], json!(["1", "2", "3"]));
+        assert_eq!(caps["limits"]["max_depth"], 1);
+        assert_eq!(caps["limits"]["max_headers"], 100);
+        let loc = &caps["locations"][0];
+        assert_eq!(loc["route"], json!(["/*path"]));
+        assert_eq!(loc["methods"], json!(["GET", "HEAD"]));
+        assert_eq!(loc["locking"], false);
+        assert_eq!(loc["precompressed"], false);
+        assert!(!caps.to_string().contains("/srv"));
+
+        let location = "methods = [ \"webdav-rw\" ]\nprecompressed = true\nauth = \"true\"";
+        let caps = capabilities(&config(location));
+        let loc = &caps["locations"][0];
+        assert_eq!(loc["locking"], true);
+        assert_eq!(loc["precompressed"], true);
+        assert_eq!(loc["auth"], "true");
+        assert!(loc["methods"].as_array().unwrap().contains(&json!("LOCK")));
+    }
+}
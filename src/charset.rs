@@ -0,0 +1,111 @@
+//! Default charset for text files.
+//!
+//! The webdav handler picks the Content-Type from the file extension,
+//! without a charset. Browsers then guess, and often guess latin-1 for
+//! UTF-8 text. With `charset` set for a location, text types (text/*,
+//! JSON, JavaScript, XML) get `; charset=...` added, unless they already
+//! have one. Other types are left alone.
+//!
+//! With `charset-sniff`, the first bytes of a GET of the whole file
+//! are checked for a byte order mark first. A UTF-16 BOM gives
+//! utf-16le or utf-16be, a UTF-8 BOM utf-8.
+//!
+use futures::StreamExt;
+
+/// Is this a text type, that can have a charset.
+pub fn is_text(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    let (kind, sub) = match mime.split_once('/') {
+        Some(t) => t,
+        None => return false,
+    };
+    kind == "text" ||
+        sub.ends_with("+xml") ||
+        sub.ends_with("+json") ||
+        (kind == "application" && ["json", "javascript", "xml", "ecmascript"].contains(&sub))
+}
+
+/// The charset that the byte order mark at the start of `data` says.
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xef, 0xbb, 0xbf]) {
+        Some("utf-8")
+    } else if data.starts_with(&[0xff, 0xfe]) {
+        Some("utf-16le")
+    } else if data.starts_with(&[0xfe, 0xff]) {
+        Some("utf-16be")
+    } else {
+        None
+    }
+}
+
+// The Content-Type with the charset, if it needs one.
+fn with_charset(content_type: &str, charset: &str) -> Option<String> {
+    let has_charset = content_type
+        .split(';')
+        .skip(1)
+        .any(|p| p.trim().to_ascii_lowercase().starts_with("charset="));
+    if !is_text(content_type) || has_charset {
+        return None;
+    }
+    Some(format!("{}; charset={}", content_type, charset))
+}
+
+/// Add the charset to the Content-Type of a response to GET or HEAD.
+pub async fn apply(
+    resp: hyper::Response<hyper::Body>,
+    charset: &str,
+    sniff_bom: bool,
+) -> hyper::Response<hyper::Body>
+{
+    let status = resp.status();
+    let content_type = resp.headers().get("content-type").and_then(|v| v.to_str().ok());
+    let served = status == http::StatusCode::OK || status == http::StatusCode::PARTIAL_CONTENT;
+    if !served || !content_type.map(is_text).unwrap_or(false) {
+        return resp;
+    }
+
+    // look at the first chunk of the body, and put it back.
+    let (mut parts, body) = resp.into_parts();
+    let mut charset = charset;
+    let body = if sniff_bom && status == http::StatusCode::OK {
+        let mut body = body;
+        let first = body.next().await;
+        if let Some(Ok(chunk)) = first.as_ref() {
+            charset = sniff(chunk).unwrap_or(charset);
+        }
+        hyper::Body::wrap_stream(futures::stream::iter(first).chain(body))
+    } else {
+        body
+    };
+
+    let content_type = parts.headers.get("content-type").and_then(|v| v.to_str().ok());
+    if let Some(value) = content_type.and_then(|ct| with_charset(ct, charset)) {
+        parts.headers.insert("content-type", value.parse().unwrap());
+    }
+    hyper::Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_charset() {
+        assert!(is_text("text/plain"));
+        assert!(is_text("Text/HTML; q=1"));
+        assert!(is_text("application/json"));
+        assert!(is_text("image/svg+xml"));
+        assert!(!is_text("image/jpeg"));
+        assert!(!is_text("application/octet-stream"));
+        assert!(!is_text("text"));
+
+        assert_eq!(with_charset("text/plain", "utf-8").as_deref(), Some("text/plain; charset=utf-8"));
+        assert_eq!(with_charset("text/plain; charset=latin1", "utf-8"), None);
+        assert_eq!(with_charset("image/png", "utf-8"), None);
+
+        assert_eq!(sniff(b"\xff\xfeh\0i\0"), Some("utf-16le"));
+        assert_eq!(sniff(b"\xfe\xff\0h\0i"), Some("utf-16be"));
+        assert_eq!(sniff(b"\xef\xbb\xbfhi"), Some("utf-8"));
+        assert_eq!(sniff(b"hi"), None);
+    }
+}
@@ -0,0 +1,188 @@
+//! Checking uploads against a checksum from the client.
+//!
+//! With `verify-checksum`, a PUT with a `Content-MD5` (RFC 1864) or a
+//! `Digest` header (RFC 3230, the `md5` and `sha-256` algorithms) is
+//! checked while the body is written. This is an `UploadFilter`, so the
+//! body goes to a temporary file, and the target is only replaced if the
+//! checksum matches. If it does not, the client gets "400 Bad Request".
+//!
+//! A header that cannot be parsed is a "400 Bad Request" too, before
+//! anything is written. Digest algorithms we do not know are ignored,
+//! and a PUT without a checksum is stored as it is. Like the other
+//! upload filters, partial PUTs (Content-Range) are not checked.
+//!
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use futures::future::{BoxFuture, FutureExt};
+use http::{HeaderMap, StatusCode};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use webdav_handler::davpath::DavPath;
+
+use crate::uploadfilter::{UploadFilter, UploadScan, Verdict};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 16,
+            Algorithm::Sha256 => 32,
+        }
+    }
+}
+
+/// The checksums that the client sent with a PUT.
+#[derive(Debug)]
+pub struct Checksum {
+    expected: Vec<(Algorithm, Vec<u8>)>,
+    mismatch: Arc<AtomicBool>,
+}
+
+// Decode a base64 checksum.
+fn decode(alg: Algorithm, value: &str) -> Result<(Algorithm, Vec<u8>), StatusCode> {
+    match base64::decode(value.trim()) {
+        Ok(value) if value.len() == alg.len() => Ok((alg, value)),
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+impl Checksum {
+    /// The checksums in the headers of a request. `None` if there are
+    /// none, "400 Bad Request" if a header is not valid.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Checksum>, StatusCode> {
+        let mut expected = Vec::new();
+        for value in headers.get_all("content-md5") {
+            let value = value.to_str().map_err(|_| StatusCode::BAD_REQUEST)?;
+            expected.push(decode(Algorithm::Md5, value)?);
+        }
+        for value in headers.get_all("digest") {
+            let value = value.to_str().map_err(|_| StatusCode::BAD_REQUEST)?;
+            for item in value.split(',') {
+                let (alg, value) = item.split_once('=').ok_or(StatusCode::BAD_REQUEST)?;
+                let alg = match alg.trim().to_ascii_lowercase().as_str() {
+                    "md5" => Algorithm::Md5,
+                    "sha-256" => Algorithm::Sha256,
+                    _ => continue,
+                };
+                expected.push(decode(alg, value)?);
+            }
+        }
+        if expected.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Checksum {
+            expected,
+            mismatch: Arc::new(AtomicBool::new(false)),
+        }))
+    }
+
+    /// Did the upload not match the checksum.
+    pub fn mismatch(&self) -> bool {
+        self.mismatch.load(Ordering::SeqCst)
+    }
+}
+
+impl UploadFilter for Checksum {
+    fn start<'a>(&'a self, _path: &'a DavPath) -> BoxFuture<'a, io::Result<Box<dyn UploadScan>>> {
+        let has = |alg| self.expected.iter().any(|e| e.0 == alg);
+        let scan = ChecksumScan {
+            md5:      if has(Algorithm::Md5) { Some(Md5::new()) } else { None },
+            sha256:   if has(Algorithm::Sha256) { Some(Sha256::new()) } else { None },
+            expected: self.expected.clone(),
+            mismatch: self.mismatch.clone(),
+        };
+        futures::future::ready(Ok(Box::new(scan) as Box<dyn UploadScan>)).boxed()
+    }
+}
+
+struct ChecksumScan {
+    md5:      Option<Md5>,
+    sha256:   Option<Sha256>,
+    expected: Vec<(Algorithm, Vec<u8>)>,
+    mismatch: Arc<AtomicBool>,
+}
+
+impl UploadScan for ChecksumScan {
+    fn data<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, io::Result<Verdict>> {
+        if let Some(md5) = self.md5.as_mut() {
+            md5.update(data);
+        }
+        if let Some(sha256) = self.sha256.as_mut() {
+            sha256.update(data);
+        }
+        futures::future::ready(Ok(Verdict::Allow)).boxed()
+    }
+
+    fn finish(self: Box<Self>) -> BoxFuture<'static, io::Result<Verdict>> {
+        let md5 = self.md5.map(|d| d.finalize().to_vec());
+        let sha256 = self.sha256.map(|d| d.finalize().to_vec());
+        let bad = self.expected.iter().find(|(alg, value)| {
+            let actual = match alg {
+                Algorithm::Md5 => md5.as_ref(),
+                Algorithm::Sha256 => sha256.as_ref(),
+            };
+            actual != Some(value)
+        });
+        let verdict = match bad {
+            Some((alg, _)) => {
+                self.mismatch.store(true, Ordering::SeqCst);
+                Verdict::Deny(format!("{:?} checksum mismatch", alg))
+            },
+            None => Verdict::Allow,
+        };
+        futures::future::ready(Ok(verdict)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checksum(headers: &[(&'static str, &str)]) -> Result<Option<Checksum>, StatusCode> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        Checksum::from_headers(&map)
+    }
+
+    async fn verify(checksum: &Checksum, chunks: &[&[u8]]) -> Verdict {
+        let mut scan = checksum.start(&DavPath::new("/file").unwrap()).await.unwrap();
+        for chunk in chunks {
+            assert_eq!(scan.data(chunk).await.unwrap(), Verdict::Allow);
+        }
+        scan.finish().await.unwrap()
+    }
+
+    // md5 and sha-256 of "hello world".
+    const MD5: &str = "XrY7u+Ae7tCTyyK7j1rNww==";
+    const SHA256: &str = "uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=";
+
+    #[tokio::test]
+    async fn test_checksum() {
+        let md5 = checksum(&[("content-md5", MD5)]).unwrap().unwrap();
+        assert_eq!(verify(&md5, &[b"hello ", b"world"]).await, Verdict::Allow);
+        assert!(!md5.mismatch());
+        assert!(matches!(verify(&md5, &[b"hello world!"]).await, Verdict::Deny(_)));
+        assert!(md5.mismatch());
+
+        let digest = format!("unixsum=30, sha-256={}", SHA256);
+        let sha256 = checksum(&[("digest", &digest)]).unwrap().unwrap();
+        assert_eq!(verify(&sha256, &[b"hello world"]).await, Verdict::Allow);
+        let both = checksum(&[("content-md5", MD5), ("digest", &digest)]).unwrap().unwrap();
+        assert!(matches!(verify(&both, &[b"hello"]).await, Verdict::Deny(_)));
+
+        assert!(checksum(&[]).unwrap().is_none());
+        assert!(checksum(&[("digest", "unixsum=30")]).unwrap().is_none());
+        assert_eq!(checksum(&[("content-md5", "not base64")]).unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(checksum(&[("digest", &format!("md5={}", SHA256))]).unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(checksum(&[("digest", "sha-256")]).unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+}
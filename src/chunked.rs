@@ -0,0 +1,245 @@
+//! Chunked uploads, as done by the ownCloud and Nextcloud clients.
+//!
+//! Large files are uploaded in pieces, and put together on the server.
+//! We implement the upload part of Nextcloud's "chunking v2" (which is
+//! also what the older "new chunking" of ownCloud 10 clients does),
+//! below the upload directory NAME of a location:
+//!
+//! - `MKCOL NAME/ID/` starts an upload. ID is chosen by the client.
+//! - `PUT NAME/ID/N` stores a chunk. N is a number; the chunks are put
+//!   together in the order of their numbers, which do not have to be
+//!   contiguous (v2 uses 1 to 10000, ownCloud zero-padded offsets).
+//! - `MOVE NAME/ID/.file` with a `Destination` in the same location
+//!   puts the chunks together, and removes the upload. If the client sends
+//!   `OC-Total-Length`, the size has to match. The response is a "201
+//!   Created" or "204 No Content", with the new `ETag` and `OC-ETag`.
+//!   A locked destination is a "423 Locked", unless the `If:` header
+//!   has its tokens, as for any other MOVE.
+//! - `DELETE NAME/ID/` aborts the upload.
+//!
+//! The chunks are plain files in the upload directory, so everything but
+//! the MOVE is left to the webdav handler. The file is put together in a
+//! temporary file next to the destination (`.chunked-UUID.tmp`), which
+//! is renamed to the destination when it is complete. That file goes
+//! through the upload filter like the body of a PUT, and the MOVE is
+//! checked for `deny-upload-types` and `verify-checksum` as a PUT is.
+//! Uploads that have not been touched for a while are removed by the
+//! `Sweeper`.
+//!
+//! Not implemented: the `X-OC-Mtime` header (the file gets the time of
+//! the MOVE), and the v1 chunking of old clients (`FILE-chunking-ID-N-I`).
+//!
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use futures::StreamExt;
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavFileSystem, FsError, OpenOptions, ReadDirMeta};
+
+use crate::fserror;
+
+// Chunks are copied in pieces of this size.
+const READ_SIZE: usize = 65536;
+
+// Segments of a path.
+fn segments(path: &DavPath) -> Vec<String> {
+    let url = path.as_url_string();
+    url.split('/').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect()
+}
+
+/// Is `path` the upload directory `name`, or something in it.
+pub fn in_uploads(name: &str, path: &DavPath) -> bool {
+    segments(path).first().map(|s| s == name).unwrap_or(false)
+}
+
+/// If `path` is the `.file` of an upload in `name`, the directory
+/// of the upload.
+pub fn upload_dir(name: &str, path: &DavPath) -> Option<DavPath> {
+    match &segments(path)[..] {
+        [dir, id, file] if dir == name && file == ".file" => DavPath::new(&format!("/{}/{}/", dir, id)).ok(),
+        _ => None,
+    }
+}
+
+// The status of a failed filesystem call, as for a MOVE.
+fn status(e: FsError) -> StatusCode {
+    fserror::fs_status(&e, &http::Method::from_bytes(b"MOVE").unwrap())
+}
+
+/// The chunks of an upload, in order, with their sizes. A name that is
+/// not a number is a "400 Bad Request". Dotfiles are skipped.
+pub async fn chunks(fs: &dyn DavFileSystem, upload: &DavPath) -> Result<Vec<(DavPath, u64)>, StatusCode> {
+    let mut chunks = Vec::new();
+    let mut dir = fs.read_dir(upload, ReadDirMeta::Data).await.map_err(status)?;
+    while let Some(dirent) = dir.next().await {
+        let name = dirent.name();
+        if name.starts_with(b".") {
+            continue;
+        }
+        let number = std::str::from_utf8(&name)
+            .ok()
+            .filter(|n| n.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|n| n.parse::<u64>().ok());
+        let meta = dirent.metadata().await.map_err(status)?;
+        let number = match number {
+            Some(number) if meta.is_file() => number,
+            _ => {
+                debug!("chunked: {:?}: not a chunk: {:?}", upload, String::from_utf8_lossy(&name));
+                return Err(StatusCode::BAD_REQUEST);
+            },
+        };
+        // the name is all digits, it needs no encoding.
+        let path = format!("{}{}", upload.as_url_string(), String::from_utf8_lossy(&name));
+        let path = DavPath::new(&path).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        chunks.push((number, path, meta.len()));
+    }
+    chunks.sort_by_key(|c| c.0);
+    Ok(chunks.into_iter().map(|(_, path, len)| (path, len)).collect())
+}
+
+/// Put the chunks of `upload` together into `dest`, and remove the upload.
+pub async fn assemble(
+    fs: Box<dyn DavFileSystem>,
+    upload: &DavPath,
+    dest: &DavPath,
+    total: Option<u64>,
+) -> Result<(), StatusCode>
+{
+    let chunks = chunks(&*fs, upload).await?;
+    let size: u64 = chunks.iter().map(|c| c.1).sum();
+    if let Some(total) = total.filter(|t| *t != size) {
+        debug!("chunked: {:?}: {} bytes, OC-Total-Length is {}", upload, size, total);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let url = dest.as_url_string();
+    let dir = url.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("");
+    let tmp = format!("{}/.chunked-{}.tmp", dir, uuid::Uuid::new_v4().to_simple());
+    let tmp = DavPath::new(&tmp).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // truncate, so that the upload filter and the checksum see the file.
+    let options = OpenOptions {
+        write: true,
+        create_new: true,
+        truncate: true,
+        ..OpenOptions::default()
+    };
+    let mut file = match fs.open(&tmp, options).await {
+        Ok(file) => file,
+        // the parent of the destination does not exist.
+        Err(FsError::NotFound) => return Err(StatusCode::CONFLICT),
+        Err(e) => return Err(status(e)),
+    };
+    let mut guard = TmpFile {
+        fs:   fs.clone(),
+        path: tmp.clone(),
+        done: false,
+    };
+
+    let read = OpenOptions {
+        read: true,
+        ..OpenOptions::default()
+    };
+    for (path, _) in &chunks {
+        let mut chunk = fs.open(path, read).await.map_err(status)?;
+        loop {
+            let data = chunk.read_bytes(READ_SIZE).await.map_err(status)?;
+            if data.is_empty() {
+                break;
+            }
+            file.write_bytes(data).await.map_err(status)?;
+        }
+    }
+    file.flush().await.map_err(status)?;
+    drop(file);
+    fs.rename(&tmp, dest).await.map_err(status)?;
+    guard.done = true;
+
+    for (path, _) in &chunks {
+        let _ = fs.remove_file(path).await;
+    }
+    if let Err(e) = fs.remove_dir(upload).await {
+        debug!("chunked: {:?}: cannot remove: {:?}", upload, e);
+    }
+    Ok(())
+}
+
+// The temporary file, removed if the assembly does not complete.
+struct TmpFile {
+    fs:   Box<dyn DavFileSystem>,
+    path: DavPath,
+    done: bool,
+}
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        if !self.done {
+            let (fs, path) = (self.fs.clone(), self.path.clone());
+            if let Ok(rt) = tokio::runtime::Handle::try_current() {
+                rt.spawn(async move {
+                    let _ = fs.remove_file(&path).await;
+                });
+            }
+        }
+    }
+}
+
+/// Remove the uploads in `dir` that have not changed for `expiry`.
+pub fn purge(dir: &Path, expiry: Duration) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let now = SystemTime::now();
+    for entry in entries.flatten() {
+        let modified = match entry.metadata().and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(_) => continue,
+        };
+        if now.duration_since(modified).unwrap_or_default() < expiry {
+            continue;
+        }
+        let res = match entry.file_type() {
+            Ok(t) if t.is_dir() => std::fs::remove_dir_all(entry.path()),
+            _ => std::fs::remove_file(entry.path()),
+        };
+        match res {
+            Ok(_) => debug!("chunked: purged {:?}", entry.path()),
+            Err(e) => warn!("chunked: purge {:?}: {}", entry.path(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths() {
+        let path = |p: &str| DavPath::new(p).unwrap();
+        assert!(in_uploads("uploads", &path("/uploads/")));
+        assert!(in_uploads("uploads", &path("/uploads/123/1")));
+        assert!(!in_uploads("uploads", &path("/uploads2/123")));
+        assert!(!in_uploads("uploads", &path("/dir/uploads/123")));
+
+        let dir = upload_dir("uploads", &path("/uploads/web%20file/.file")).unwrap();
+        assert_eq!(dir.as_url_string(), "/uploads/web%20file/");
+        assert!(upload_dir("uploads", &path("/uploads/123/1")).is_none());
+        assert!(upload_dir("uploads", &path("/uploads/.file")).is_none());
+        assert!(upload_dir("uploads", &path("/other/123/.file")).is_none());
+    }
+
+    #[test]
+    fn test_purge() {
+        let dir = std::env::temp_dir().join(format!("webdav-test-{}", uuid::Uuid::new_v4().to_simple()));
+        std::fs::create_dir_all(dir.join("old")).unwrap();
+        std::fs::write(dir.join("old/1"), "data").unwrap();
+        crate::crossfs::tests::set_mtime(&dir.join("old"), 1_000_000_000);
+        std::fs::create_dir_all(dir.join("new")).unwrap();
+
+        purge(&dir, Duration::from_secs(3600));
+        assert!(!dir.join("old").exists());
+        assert!(dir.join("new").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
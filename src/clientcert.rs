@@ -0,0 +1,447 @@
+//! TLS client certificates.
+//!
+//! With `tls_client_ca` set, TLS clients can present a certificate that
+//! is signed by one of those CAs (with `tls_client_auth = "required"`
+//! they must). rustls verifies the certificate during the handshake. It
+//! is then mapped to a username, once per connection, by the rules in
+//! `[[client-cert.map]]`. A rule looks at one field of the certificate:
+//!
+//! - `cn`, `uid`, or `oid:1.2.3.4`: an attribute of the subject
+//! - `san-email`, `san-dns`, `san-uri`: a subjectAltName of that type
+//!
+//! and optionally matches it against a regex, and builds the username
+//! from the capture groups. The first rule that gives a username wins.
+//! Without rules, the username is the CN. With `check-user`, the user
+//! must also exist (getpwnam, so through NSS).
+//!
+//! The username is kept in the `ConnAuth` of the connection, where the
+//! `tls-client-cert` auth-type finds it. After that it is the same as a
+//! user that logged in with PAM: acct-type, the :user route parameter,
+//! setuid. When client certificates are required, a connection with a
+//! certificate that does not map to a user is closed before the first
+//! request.
+//!
+//! We only parse what we need from the certificate, with a minimal DER
+//! reader. The certificate was verified before, by webpki.
+//!
+use regex::Regex;
+
+use crate::config;
+
+// OIDs of subject attributes.
+const OID_CN: &str = "2.5.4.3";
+const OID_UID: &str = "0.9.2342.19200300.100.1.1";
+const OID_SUBJECT_ALT_NAME: &str = "2.5.29.17";
+
+// Context tags of the GeneralName types.
+const SAN_EMAIL: u8 = 1;
+const SAN_DNS: u8 = 2;
+const SAN_URI: u8 = 6;
+
+// The certificate field that a rule looks at.
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    // attribute of the subject, by OID.
+    Subject(String),
+    // subjectAltName of one type.
+    AltName(u8),
+}
+
+impl Field {
+    fn parse(s: &str) -> Result<Field, String> {
+        let field = match s {
+            "cn" => Field::Subject(OID_CN.to_string()),
+            "uid" => Field::Subject(OID_UID.to_string()),
+            "san-email" => Field::AltName(SAN_EMAIL),
+            "san-dns" => Field::AltName(SAN_DNS),
+            "san-uri" => Field::AltName(SAN_URI),
+            _ => {
+                let valid = |oid: &str| {
+                    oid.split('.').count() > 1 && oid.split('.').all(|p| p.parse::<u64>().is_ok())
+                };
+                match s.strip_prefix("oid:") {
+                    Some(oid) if valid(oid) => Field::Subject(oid.to_string()),
+                    _ => return Err(format!("{}: unknown field", s)),
+                }
+            },
+        };
+        Ok(field)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    field:    Field,
+    regex:    Option<Regex>,
+    username: String,
+}
+
+impl Rule {
+    fn apply(&self, value: &str) -> Option<String> {
+        let username = match self.regex {
+            Some(ref regex) => {
+                let caps = regex.captures(value)?;
+                let mut username = String::new();
+                caps.expand(&self.username, &mut username);
+                username
+            },
+            None => value.to_string(),
+        };
+        Some(username).filter(|u| valid_username(u))
+    }
+}
+
+// Not empty, and nothing that can do harm in a path or a log line.
+fn valid_username(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && !name.chars().any(|c| c.is_control())
+}
+
+/// Maps client certificates to usernames.
+#[derive(Debug, Clone)]
+pub struct CertMap {
+    rules:      Vec<Rule>,
+    check_user: bool,
+}
+
+impl CertMap {
+    /// Compile the `[client-cert]` section.
+    pub fn new(cfg: &config::ClientCert) -> Result<CertMap, String> {
+        let mut rules = Vec::new();
+        for map in &cfg.map {
+            let field = Field::parse(&map.field)?;
+            let regex = match map.regex.as_ref() {
+                Some(r) => Some(Regex::new(r).map_err(|e| format!("{}: {}", r, e))?),
+                None => None,
+            };
+            let username = match (map.username.as_ref(), regex.as_ref()) {
+                (Some(u), Some(_)) => u.clone(),
+                (Some(_), None) => return Err(format!("{}: username needs a regex", map.field)),
+                (None, Some(r)) if r.captures_len() > 1 => "${1}".to_string(),
+                (None, _) => "${0}".to_string(),
+            };
+            rules.push(Rule { field, regex, username });
+        }
+        if rules.is_empty() {
+            rules.push(Rule {
+                field:    Field::Subject(OID_CN.to_string()),
+                regex:    None,
+                username: "${0}".to_string(),
+            });
+        }
+        Ok(CertMap {
+            rules,
+            check_user: cfg.check_user,
+        })
+    }
+
+    /// The username for a (DER) certificate, from the first rule that
+    /// gives one.
+    pub fn map(&self, cert: &[u8]) -> Option<String> {
+        let fields = match Fields::parse(cert) {
+            Some(f) => f,
+            None => {
+                debug!("client-cert: cannot parse certificate");
+                return None;
+            },
+        };
+        self.rules.iter().find_map(|rule| {
+            match rule.field {
+                Field::Subject(ref oid) => {
+                    let mut values = fields.subject.iter().filter(|(o, _)| o == oid);
+                    values.find_map(|(_, v)| rule.apply(v))
+                },
+                Field::AltName(tag) => {
+                    let mut values = fields.alt_names.iter().filter(|(t, _)| *t == tag);
+                    values.find_map(|(_, v)| rule.apply(v))
+                },
+            }
+        })
+    }
+
+    /// Same as `map`, and with `check-user`, the user must exist.
+    pub async fn username(&self, cert: &[u8]) -> Option<String> {
+        let username = self.map(cert)?;
+        if self.check_user {
+            if let Err(e) = crate::cache::cached::unixuser(&username, false).await {
+                debug!("client-cert: user {}: {}", username, e);
+                return None;
+            }
+        }
+        Some(username)
+    }
+}
+
+// The parts of a certificate that we look at.
+#[derive(Debug, Default)]
+struct Fields {
+    // (OID, value) of the subject attributes.
+    subject:   Vec<(String, String)>,
+    // (context tag, value) of the subjectAltNames.
+    alt_names: Vec<(u8, String)>,
+}
+
+impl Fields {
+    fn parse(cert: &[u8]) -> Option<Fields> {
+        // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+        let (_, cert) = Der::new(cert).expect(0x30)?;
+        let (_, tbs) = Der::new(cert).expect(0x30)?;
+        let mut tbs = Der::new(tbs);
+
+        // [0] version (optional), serialNumber, signature, issuer, validity.
+        if tbs.peek() == Some(0xa0) {
+            tbs.next()?;
+        }
+        for _ in 0..4 {
+            tbs.next()?;
+        }
+
+        let mut fields = Fields::default();
+        let (_, subject) = tbs.expect(0x30)?;
+        let mut rdns = Der::new(subject);
+        while !rdns.is_empty() {
+            // RelativeDistinguishedName ::= SET OF AttributeTypeAndValue
+            let (_, rdn) = rdns.expect(0x31)?;
+            let mut rdn = Der::new(rdn);
+            while !rdn.is_empty() {
+                let (_, atv) = rdn.expect(0x30)?;
+                let mut atv = Der::new(atv);
+                let (_, oid) = atv.expect(0x06)?;
+                let (tag, value) = atv.next()?;
+                if let (Some(oid), Some(value)) = (oid_string(oid), string(tag, value)) {
+                    fields.subject.push((oid, value));
+                }
+            }
+        }
+
+        // subjectPublicKeyInfo, then optional [1], [2], and [3] extensions.
+        tbs.expect(0x30)?;
+        while let Some((tag, data)) = tbs.next() {
+            if tag != 0xa3 {
+                continue;
+            }
+            let (_, exts) = Der::new(data).expect(0x30)?;
+            let mut exts = Der::new(exts);
+            while !exts.is_empty() {
+                // Extension ::= SEQUENCE { extnID, critical BOOLEAN DEFAULT FALSE, extnValue }
+                let (_, ext) = exts.expect(0x30)?;
+                let mut ext = Der::new(ext);
+                let (_, oid) = ext.expect(0x06)?;
+                if ext.peek() == Some(0x01) {
+                    ext.next()?;
+                }
+                let (_, value) = ext.expect(0x04)?;
+                if oid_string(oid).as_deref() != Some(OID_SUBJECT_ALT_NAME) {
+                    continue;
+                }
+                let (_, names) = Der::new(value).expect(0x30)?;
+                let mut names = Der::new(names);
+                while let Some((tag, name)) = names.next() {
+                    // the string types are primitive: [1], [2] and [6] IA5String.
+                    let tag = tag ^ 0x80;
+                    if [SAN_EMAIL, SAN_DNS, SAN_URI].contains(&tag) {
+                        if let Ok(name) = std::str::from_utf8(name) {
+                            fields.alt_names.push((tag, name.to_string()));
+                        }
+                    }
+                }
+            }
+        }
+        Some(fields)
+    }
+}
+
+// Dotted form of an OID.
+fn oid_string(der: &[u8]) -> Option<String> {
+    let (first, rest) = der.split_first()?;
+    let mut parts = vec![u64::from(first / 40), u64::from(first % 40)];
+    let mut n = 0u64;
+    for (i, b) in rest.iter().enumerate() {
+        n = n.checked_mul(128)? | u64::from(b & 0x7f);
+        if b & 0x80 == 0 {
+            parts.push(n);
+            n = 0;
+        } else if i == rest.len() - 1 {
+            return None;
+        }
+    }
+    Some(parts.iter().map(|p| p.to_string()).collect::<Vec<_>>().join("."))
+}
+
+// Value of a DirectoryString (or IA5String).
+fn string(tag: u8, data: &[u8]) -> Option<String> {
+    match tag {
+        // UTF8String, PrintableString, TeletexString, IA5String.
+        0x0c | 0x13 | 0x14 | 0x16 => std::str::from_utf8(data).ok().map(|s| s.to_string()),
+        // BMPString.
+        0x1e => {
+            let chunks = data.chunks_exact(2);
+            if !chunks.remainder().is_empty() {
+                return None;
+            }
+            let units: Vec<u16> = chunks.map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+            String::from_utf16(&units).ok()
+        },
+        _ => None,
+    }
+}
+
+// Reads DER values one by one. Only single-byte tags, which is all
+// a certificate has.
+struct Der<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(data: &'a [u8]) -> Der<'a> {
+        Der { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    // (tag, value) of the next element.
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.data.split_first()?;
+        let (&len, mut rest) = rest.split_first()?;
+        let len = if len < 0x80 {
+            len as usize
+        } else {
+            let n = (len & 0x7f) as usize;
+            if n == 0 || n > 4 || rest.len() < n {
+                return None;
+            }
+            let len = rest[..n].iter().fold(0usize, |l, b| l << 8 | *b as usize);
+            rest = &rest[n..];
+            len
+        };
+        if rest.len() < len {
+            return None;
+        }
+        self.data = &rest[len..];
+        Some((tag, &rest[..len]))
+    }
+
+    // the next element, which must have this tag.
+    fn expect(&mut self, tag: u8) -> Option<(u8, &'a [u8])> {
+        self.next().filter(|(t, _)| *t == tag)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use tokio_rustls::rustls::internal::pemfile;
+
+    // Issued by "CN=Example CA". Subject O=Example, serialNumber=E1234,
+    // CN=alice. subjectAltName email:alice@example.com,
+    // DNS:alice.users.example.com, URI:https://id.example.com/alice.
+    pub const ALICE: &str = "-----BEGIN CERTIFICATE-----
+MIIB4zCCAYqgAwIBAgIUMHw3anMDX/1lilr/fY9f3xXMTm4wCgYIKoZIzj0EAwIw
+FTETMBEGA1UEAwwKRXhhbXBsZSBDQTAgFw0yNjEwMTQwNjMxMzdaGA8yMTI2MDky
+MDA2MzEzN1owMjEQMA4GA1UECgwHRXhhbXBsZTEOMAwGA1UEBRMFRTEyMzQxDjAM
+BgNVBAMMBWFsaWNlMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEkMEKyiGIyaVf
+6o0cI09ekEgSgplwEpIzLFnOzZge3mTyRksUeUE0hPeSRuzR2ha8l/wZ2W55YqsE
+346U5uNMP6OBmDCBlTBTBgNVHREETDBKgRFhbGljZUBleGFtcGxlLmNvbYIXYWxp
+Y2UudXNlcnMuZXhhbXBsZS5jb22GHGh0dHBzOi8vaWQuZXhhbXBsZS5jb20vYWxp
+Y2UwHQYDVR0OBBYEFN3SF189ZblH8Ny+BHmJVrO8/Sl+MB8GA1UdIwQYMBaAFOCA
+B4LR0Qzo5gdwd/PMNeWniduIMAoGCCqGSM49BAMCA0cAMEQCIA6adaNcDddtm8lg
+7MUw0t1afR3BAZxO111hk5Ip/vETAiBY5XIYMY6LCDqDFctp/Ng2n1P22dlUV+K1
+mHTRCxj+Nw==
+-----END CERTIFICATE-----
+";
+
+    // Self-signed. Subject UID=bob, CN=Bob Büilder, no subjectAltName.
+    const BOB: &str = "-----BEGIN CERTIFICATE-----
+MIIBrzCCAVWgAwIBAgIUGhegTak/r4Ar7kCyLNQoLe3JduMwCgYIKoZIzj0EAwIw
+LDETMBEGCgmSJomT8ixkAQEMA2JvYjEVMBMGA1UEAwwMQm9iIELDvGlsZGVyMCAX
+DTI2MTAxNDA2MzEyNFoYDzIxMjYwOTIwMDYzMTI0WjAsMRMwEQYKCZImiZPyLGQB
+AQwDYm9iMRUwEwYDVQQDDAxCb2IgQsO8aWxkZXIwWTATBgcqhkjOPQIBBggqhkjO
+PQMBBwNCAAR/NA2ij84OgXuaJAeR4ZedIxfW6mzejS9VHT+xIMBVeJJNt0sSnhg6
+RA8CofG0AejBtsOvxeZj2VSXkxfLKRUio1MwUTAdBgNVHQ4EFgQUi0dvyHFxqvK3
+6GhMLc7DIAcNLCUwHwYDVR0jBBgwFoAUi0dvyHFxqvK36GhMLc7DIAcNLCUwDwYD
+VR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBFAiEA3B/9o2yxmnia5T0kvcR5
+uo670J+l6/06E9mOgV1kJz4CICkSYXYpkStyQ6icCiB9gaosywxWnExMQjd8EoR2
+Xj8b
+-----END CERTIFICATE-----
+";
+
+    pub fn der(pem: &str) -> Vec<u8> {
+        pemfile::certs(&mut pem.as_bytes()).unwrap().remove(0).0
+    }
+
+    fn certmap(toml: &str) -> Result<CertMap, String> {
+        CertMap::new(&toml::from_str(toml).unwrap())
+    }
+
+    #[test]
+    fn test_fields() {
+        let alice = Fields::parse(&der(ALICE)).unwrap();
+        let subject: Vec<_> = alice.subject.iter().map(|(o, v)| (o.as_str(), v.as_str())).collect();
+        assert_eq!(subject, [("2.5.4.10", "Example"), ("2.5.4.5", "E1234"), ("2.5.4.3", "alice")]);
+        assert_eq!(alice.alt_names, [
+            (SAN_EMAIL, "alice@example.com".to_string()),
+            (SAN_DNS, "alice.users.example.com".to_string()),
+            (SAN_URI, "https://id.example.com/alice".to_string()),
+        ]);
+
+        let bob = Fields::parse(&der(BOB)).unwrap();
+        assert_eq!(bob.subject[0], (OID_UID.to_string(), "bob".to_string()));
+        assert_eq!(bob.subject[1], (OID_CN.to_string(), "Bob Büilder".to_string()));
+        assert!(bob.alt_names.is_empty());
+
+        assert!(Fields::parse(&der(ALICE)[..100]).is_none());
+    }
+
+    #[test]
+    fn test_certmap() {
+        let (alice, bob) = (der(ALICE), der(BOB));
+
+        // default: the CN.
+        let map = certmap("").unwrap();
+        assert_eq!(map.map(&alice).as_deref(), Some("alice"));
+        assert_eq!(map.map(&bob).as_deref(), Some("Bob Büilder"));
+
+        // the first rule that matches wins.
+        let map = certmap(
+            r#"
+            [[map]]
+            field = "san-email"
+            regex = '^([a-z]+)@example\.com$'
+            [[map]]
+            field = "uid"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(map.map(&alice).as_deref(), Some("alice"));
+        assert_eq!(map.map(&bob).as_deref(), Some("bob"));
+
+        // a transform, and an OID.
+        let map = certmap(
+            r#"
+            [[map]]
+            field = "oid:2.5.4.5"
+            regex = '^E([0-9]+)$'
+            username = "emp$1"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(map.map(&alice).as_deref(), Some("emp1234"));
+        assert_eq!(map.map(&bob), None);
+
+        // no valid username.
+        let map = certmap("[[map]]\nfield = \"san-uri\"\n").unwrap();
+        assert_eq!(map.map(&alice), None);
+        let map = certmap("[[map]]\nfield = \"cn\"\nregex = 'bob'\n").unwrap();
+        assert_eq!(map.map(&bob), None);
+
+        assert!(certmap("[[map]]\nfield = \"subject\"\n").is_err());
+        assert!(certmap("[[map]]\nfield = \"oid:cn\"\n").is_err());
+        assert!(certmap("[[map]]\nfield = \"cn\"\nregex = '('\n").is_err());
+        assert!(certmap("[[map]]\nfield = \"cn\"\nusername = \"x\"\n").is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! Fewer, larger chunks for PROPFIND responses.
+//!
+//! The webdav handler streams a PROPFIND response: it sends every
+//! `<D:response>` element as a chunk of its own, a few hundred bytes.
+//! hyper writes every chunk to the socket right away, so a listing of a
+//! large directory becomes a write(2) and a small TCP segment per entry
+//! (and with TLS, a record per entry).
+//!
+//! `coalesce` collects the chunks until there are `size` bytes, and
+//! then sends them as one. So that a slow walk of a directory (a network
+//! filesystem, a cold disk) still makes progress at the client, what has
+//! been collected is also sent when the first byte of it has waited for
+//! `interval`. A larger buffer means fewer writes and less overhead per
+//! entry, a longer interval fewer small writes when the walk is slow; both
+//! make the client see the first entries later.
+//!
+//! While it collects, a buffer of `size` bytes counts against the memory
+//! budget. If the budget is used up, the response waits before it starts
+//! a new buffer.
+//!
+use std::time::Duration;
+
+use bytes::BytesMut;
+use hyper::body::HttpBody;
+
+use crate::limits::MemoryBudget;
+
+/// Default for `propfind-buffer` (bytes).
+pub const DEFAULT_BUFFER: usize = 16384;
+
+/// Default for `propfind-flush-interval` (secs).
+pub const DEFAULT_INTERVAL: f64 = 0.2;
+
+/// Send the chunks of `body` in chunks of at least `size` bytes, or what
+/// there is after `interval`. A `size` of 0 leaves the body as it is.
+pub fn coalesce(body: hyper::Body, size: usize, interval: Duration, memory: &MemoryBudget) -> hyper::Body {
+    if size == 0 {
+        return body;
+    }
+    let memory = memory.clone();
+    let stream = futures::stream::unfold(Some((body, BytesMut::new())), move |state| {
+        let memory = memory.clone();
+        async move {
+            let (mut body, mut buf) = state?;
+            let mut deadline = tokio::time::Instant::now();
+            let mut held = None;
+            loop {
+                let next = if buf.is_empty() {
+                    body.data().await
+                } else {
+                    // data() can be cancelled, no data is lost.
+                    match tokio::time::timeout_at(deadline, body.data()).await {
+                        Ok(next) => next,
+                        Err(_) => return Some((Ok(buf.split().freeze()), Some((body, buf)))),
+                    }
+                };
+                match next {
+                    Some(Ok(chunk)) => {
+                        if buf.is_empty() {
+                            held = Some(memory.reserve(size).await);
+                            deadline = tokio::time::Instant::now() + interval;
+                        }
+                        buf.extend_from_slice(&chunk);
+                        if buf.len() >= size {
+                            drop(held);
+                            return Some((Ok(buf.split().freeze()), Some((body, buf))));
+                        }
+                    },
+                    Some(Err(e)) => return Some((Err(e), None)),
+                    None if buf.is_empty() => return None,
+                    None => return Some((Ok(buf.freeze()), None)),
+                }
+            }
+        }
+    });
+    hyper::Body::wrap_stream(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let memory = MemoryBudget::new(None);
+        let chunks: Vec<Result<_, std::io::Error>> = (0..10).map(|_| Ok(vec![b'x'; 100])).collect();
+        let body = hyper::Body::wrap_stream(futures::stream::iter(chunks));
+        let mut body = coalesce(body, 250, Duration::from_secs(10), &memory);
+        let mut sizes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            sizes.push(chunk.unwrap().len());
+        }
+        assert_eq!(sizes, vec![300, 300, 300, 100]);
+
+        // a slow producer: what there is goes out after the interval.
+        let (mut tx, body) = hyper::Body::channel();
+        let mut body = coalesce(body, 250, Duration::from_millis(50), &memory);
+        tx.send_data(vec![b'x'; 100].into()).await.unwrap();
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.data()).await.unwrap();
+        assert_eq!(chunk.unwrap().unwrap().len(), 100);
+        tx.send_data(vec![b'x'; 300].into()).await.unwrap();
+        assert_eq!(body.data().await.unwrap().unwrap().len(), 300);
+        drop(tx);
+        assert!(body.data().await.is_none());
+        assert_eq!(memory.used(), 0);
+
+        // a new buffer waits for memory.
+        let memory = MemoryBudget::new(Some(300));
+        let (mut tx, body) = hyper::Body::channel();
+        let mut body = coalesce(body, 250, Duration::from_secs(10), &memory);
+        let mut other = memory.reservation();
+        assert!(other.grow(100));
+        tx.send_data(vec![b'x'; 100].into()).await.unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(50), body.data()).await;
+        assert!(waiting.is_err());
+        drop(other);
+        tx.send_data(vec![b'x'; 200].into()).await.unwrap();
+        assert_eq!(body.data().await.unwrap().unwrap().len(), 300);
+        assert_eq!(memory.used(), 0);
+    }
+}
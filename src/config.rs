@@ -7,44 +7,169 @@ use std::{fs, io};
 use enum_from_str::ParseEnumVariantError;
 use enum_from_str_derive::FromStr;
 use serde::{Deserialize, Deserializer};
-use toml;
 use webdav_handler::DavMethodSet;
 
 use crate::router::Router;
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
-    pub server:   Server,
+    pub server:      Server,
     #[serde(default)]
-    pub accounts: Accounts,
+    pub accounts:    Accounts,
+    #[serde(default)]
+    pub pam:         Pam,
+    #[serde(default)]
+    pub htpasswd:    HashMap<String, HtPasswd>,
+    #[serde(default)]
+    pub unix:        Unix,
     #[serde(default)]
-    pub pam:      Pam,
+    pub locks:       Locks,
     #[serde(default)]
-    pub htpasswd: HashMap<String, HtPasswd>,
+    pub admin:       Admin,
+    #[serde(rename = "client-cert", default)]
+    pub client_cert: ClientCert,
     #[serde(default)]
-    pub unix:     Unix,
+    pub metrics:     Metrics,
+    #[serde(rename = "error-pages", default)]
+    pub error_pages: HashMap<String, String>,
     #[serde(default)]
-    pub location: Vec<Location>,
+    pub location:    Vec<Location>,
     #[serde(skip)]
-    pub router:   Router<usize>,
+    pub router:      Router<usize>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Server {
     #[serde(default)]
-    pub listen:         OneOrManyAddr,
+    pub listen:                OneOrManyAddr,
+    #[serde(default)]
+    pub tls_listen:            OneOrManyAddr,
+    #[serde(default)]
+    pub tls_key:               Option<String>,
+    #[serde(default)]
+    pub tls_cert:              Option<String>,
+    #[serde(default)]
+    pub tls_session_cache:     Option<usize>,
     #[serde(default)]
-    pub tls_listen:     OneOrManyAddr,
+    pub tls_ticket_lifetime:   Option<u32>,
     #[serde(default)]
-    pub tls_key:        Option<String>,
+    pub tls_ocsp:              Option<String>,
     #[serde(default)]
-    pub tls_cert:       Option<String>,
+    pub tls_ocsp_refresh:      Option<u64>,
+    #[serde(default)]
+    pub tls_client_ca:         Option<String>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub tls_client_auth:       Option<ClientAuth>,
     //#[serde(deserialize_with = "deserialize_user", default)]
-    pub uid:            Option<u32>,
+    pub uid:                   Option<u32>,
     //#[serde(deserialize_with = "deserialize_group", default)]
-    pub gid:            Option<u32>,
+    pub gid:                   Option<u32>,
     #[serde(default)]
-    pub identification: Option<String>,
+    pub identification:        Option<String>,
+    #[serde(rename = "powered-by", default)]
+    pub powered_by:            Option<String>,
+    #[serde(rename = "ipv6-only", default)]
+    pub ipv6_only:             Option<bool>,
+    #[serde(rename = "allow-from", default)]
+    pub allow_from:            Vec<String>,
+    #[serde(rename = "deny-from", default)]
+    pub deny_from:             Vec<String>,
+    #[serde(rename = "max-requests-per-user", default)]
+    pub max_requests_per_user: Option<usize>,
+    #[serde(default)]
+    pub chroot:                Option<String>,
+    #[serde(rename = "max-xml-body", default)]
+    pub max_xml_body:          Option<usize>,
+    #[serde(rename = "max-proppatch-props", default)]
+    pub max_proppatch_props:   Option<usize>,
+    #[serde(rename = "max-depth", default)]
+    pub max_depth:             Option<u32>,
+    #[serde(rename = "max-header-size", default)]
+    pub max_header_size:       Option<usize>,
+    #[serde(rename = "max-headers", default)]
+    pub max_headers:           Option<usize>,
+    #[serde(rename = "max-uri-length", default)]
+    pub max_uri_length:        Option<usize>,
+    #[serde(rename = "strict-paths", default)]
+    pub strict_paths:          bool,
+    #[serde(rename = "max-path-length", default)]
+    pub max_path_length:       Option<usize>,
+    #[serde(rename = "max-path-segments", default)]
+    pub max_path_segments:     Option<usize>,
+    #[serde(rename = "max-upload-buffer", default)]
+    pub max_upload_buffer:     Option<usize>,
+    #[serde(rename = "memory-budget", default)]
+    pub memory_budget:         Option<usize>,
+    #[serde(rename = "propfind-buffer", default)]
+    pub propfind_buffer:       Option<usize>,
+    #[serde(rename = "propfind-flush-interval", default)]
+    pub propfind_flush:        Option<f64>,
+    #[serde(rename = "propfind-max-responses", default)]
+    pub propfind_responses:    Option<usize>,
+    #[serde(rename = "max-concurrent-writes", default)]
+    pub max_writes:            Option<usize>,
+    #[serde(rename = "write-queue-timeout", default)]
+    pub write_queue_timeout:   Option<u64>,
+    #[serde(rename = "shed-connections", default)]
+    pub shed_connections:      Option<usize>,
+    #[serde(rename = "shed-memory", default)]
+    pub shed_memory:           Option<usize>,
+    #[serde(rename = "shed-writes", default)]
+    pub shed_writes:           Option<usize>,
+    #[serde(rename = "shed-retry-after", default)]
+    pub shed_retry_after:      Option<u64>,
+    #[serde(rename = "max-request-rate", default)]
+    pub max_request_rate:      Option<f64>,
+    #[serde(rename = "request-burst", default)]
+    pub request_burst:         Option<u32>,
+    #[serde(rename = "auth-failure-delay", default)]
+    pub auth_fail_delay:       Option<f64>,
+    #[serde(rename = "auth-failure-delay-max", default)]
+    pub auth_fail_delay_max:   Option<f64>,
+    #[serde(rename = "auth-failure-min-time", default)]
+    pub auth_fail_min_time:    Option<f64>,
+    #[serde(rename = "public-url", default)]
+    pub public_url:            Option<String>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub capabilities:          Option<Capabilities>,
+    #[serde(rename = "unexpected-body", deserialize_with = "deserialize_opt_enum", default)]
+    pub unexpected_body:       Option<UnexpectedBody>,
+    #[serde(rename = "duplicate-headers", deserialize_with = "deserialize_opt_enum", default)]
+    pub duplicate_headers:     Option<DuplicateHeaders>,
+    #[serde(rename = "dav-prefix", default)]
+    pub dav_prefix:            Option<String>,
+    #[serde(rename = "keep-alive", default)]
+    pub keep_alive:            Option<bool>,
+    #[serde(rename = "idle-timeout", default)]
+    pub idle_timeout:          Option<u64>,
+    #[serde(rename = "slow-request", default)]
+    pub slow_request:          Option<u64>,
+    #[serde(rename = "log-bodies", default)]
+    pub log_bodies:            Vec<String>,
+    #[serde(rename = "log-body-max", default)]
+    pub log_body_max:          Option<usize>,
+    #[serde(rename = "request-timeout", default)]
+    pub request_timeout:       Option<u64>,
+    #[serde(rename = "shutdown-timeout", default)]
+    pub shutdown_timeout:      Option<u64>,
+    #[serde(rename = "shutdown-conn-timeout", default)]
+    pub shutdown_conn_timeout: Option<u64>,
+    #[serde(rename = "listen-backlog", default)]
+    pub listen_backlog:        Option<i32>,
+    #[serde(rename = "reuse-port", default)]
+    pub reuse_port:            Option<bool>,
+    #[serde(rename = "worker-threads", default)]
+    pub worker_threads:        Option<usize>,
+    #[serde(rename = "blocking-threads", default)]
+    pub blocking_threads:      Option<usize>,
+    #[serde(rename = "tcp-nodelay", default)]
+    pub tcp_nodelay:           Option<bool>,
+    #[serde(rename = "tcp-keepalive", default)]
+    pub tcp_keepalive:         Option<u64>,
+    #[serde(rename = "tcp-keepalive-interval", default)]
+    pub tcp_keepalive_intvl:   Option<u64>,
+    #[serde(rename = "tcp-keepalive-retries", default)]
+    pub tcp_keepalive_retries: Option<u32>,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -58,11 +183,29 @@ pub struct Accounts {
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
+#[cfg_attr(not(feature = "pam"), allow(dead_code))]
 pub struct Pam {
-    pub service:       String,
+    pub service:         String,
     #[serde(rename = "cache-timeout")]
-    pub cache_timeout: Option<usize>,
-    pub threads:       Option<usize>,
+    pub cache_timeout:   Option<usize>,
+    #[serde(rename = "cache-expiry", deserialize_with = "deserialize_opt_enum", default)]
+    pub cache_expiry:    Option<CacheExpiry>,
+    #[serde(rename = "cache-max-lifetime", default)]
+    pub cache_lifetime:  Option<u64>,
+    #[allow(dead_code)]
+    pub threads:         Option<usize>,
+    #[serde(rename = "failure-backoff")]
+    pub failure_backoff: Option<usize>,
+    #[serde(rename = "retry-after")]
+    pub retry_after:     Option<u64>,
+    #[serde(default)]
+    pub timeout:         Option<u64>,
+    #[serde(rename = "max-pending", default)]
+    pub max_pending:     Option<usize>,
+    #[serde(default)]
+    pub items:           HashMap<String, String>,
+    #[serde(rename = "self-test", default)]
+    pub self_test:       Option<bool>,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -78,6 +221,87 @@ pub struct Unix {
     pub min_uid:       Option<u32>,
     #[serde(rename = "supplementary-groups", default)]
     pub aux_groups:    bool,
+    #[serde(rename = "warm-cache", default)]
+    pub warm_cache:    Vec<String>,
+    #[serde(rename = "nss-retries", default)]
+    pub nss_retries:   Option<u32>,
+    #[serde(rename = "nss-retry-delay", default)]
+    pub nss_delay:     Option<f64>,
+}
+
+impl Location {
+    /// `etag-strength`, or "weak" for the older `etag-weak`.
+    pub fn etag_strength(&self) -> EtagStrength {
+        match self.etag_strength {
+            Some(strength) => strength,
+            None if self.etag_weak => EtagStrength::Weak,
+            None => EtagStrength::Strong,
+        }
+    }
+}
+
+impl Unix {
+    /// How to retry failed group lookups.
+    pub fn retry(&self) -> crate::unixuser::Retry {
+        let default = crate::unixuser::Retry::default();
+        crate::unixuser::Retry {
+            retries: self.nss_retries.unwrap_or(default.retries),
+            delay:   self.nss_delay.map(std::time::Duration::from_secs_f64).unwrap_or(default.delay),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ClientCert {
+    #[serde(rename = "check-user", default)]
+    pub check_user: bool,
+    #[serde(default)]
+    pub map:        Vec<ClientCertMap>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ClientCertMap {
+    pub field:    String,
+    #[serde(default)]
+    pub regex:    Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Locks {
+    #[serde(rename = "default-timeout", default)]
+    pub default_timeout: Option<u64>,
+    #[serde(rename = "max-timeout", default)]
+    pub max_timeout:     Option<u64>,
+    #[serde(rename = "sweep-interval", default)]
+    pub sweep_interval:  Option<u64>,
+    #[serde(rename = "max-per-user", default)]
+    pub max_per_user:    Option<usize>,
+    #[serde(rename = "max-per-path", default)]
+    pub max_per_path:    Option<usize>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub store:           Option<LockStoreType>,
+    #[serde(default)]
+    pub file:            Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Metrics {
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub sink: Option<MetricsSink>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Admin {
+    #[serde(default)]
+    pub route:    Option<String>,
+    #[serde(default)]
+    pub users:    Vec<String>,
+    #[serde(default)]
+    pub socket:   Option<String>,
+    #[serde(default, flatten)]
+    pub accounts: Accounts,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -94,13 +318,25 @@ pub struct Location {
     pub handler:          Handler,
     #[serde(default)]
     pub setuid:           bool,
+    #[serde(rename = "shared-users", default)]
+    pub shared_users:     Vec<String>,
+    #[serde(rename = "shared-groups", default)]
+    pub shared_groups:    Vec<String>,
+    #[serde(rename = "shared-owner", default)]
+    pub shared_owner:     Option<String>,
     pub directory:        String,
+    #[serde(rename = "create-home", default)]
+    pub create_home:      bool,
     #[serde(default, alias = "hide-symlinks")]
     pub hide_symlinks:    Option<bool>,
-    #[serde(default)]
-    pub indexfile:        Option<String>,
+    #[serde(deserialize_with = "deserialize_strings", default)]
+    pub indexfile:        Vec<String>,
     #[serde(default)]
     pub autoindex:        bool,
+    #[serde(rename = "autoindex-templates", default)]
+    pub autoindex_tmpl:   HashMap<String, String>,
+    #[serde(rename = "autoindex-language", default)]
+    pub autoindex_lang:   Option<String>,
     #[serde(
         rename = "case-insensitive",
         deserialize_with = "deserialize_opt_enum",
@@ -109,6 +345,88 @@ pub struct Location {
     pub case_insensitive: Option<CaseInsensitive>,
     #[serde(deserialize_with = "deserialize_opt_enum", default)]
     pub on_notfound:      Option<OnNotfound>,
+    #[serde(default)]
+    pub precompressed:    bool,
+    #[serde(default)]
+    pub ranges:           Option<bool>,
+    #[serde(rename = "put-range", deserialize_with = "deserialize_opt_enum", default)]
+    pub put_range:        Option<PutRange>,
+    #[serde(rename = "stat-cache", default)]
+    pub stat_cache:       Option<u64>,
+    #[serde(rename = "probe-cache", default)]
+    pub probe_cache:      Option<u64>,
+    #[serde(rename = "root-probe-interval", default)]
+    pub root_probe:       Option<u64>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub allprop:          Option<AllProp>,
+    #[serde(default)]
+    pub trash:            Option<String>,
+    #[serde(rename = "trash-retention", default)]
+    pub trash_retention:  Option<u64>,
+    #[serde(rename = "chunked-uploads", default)]
+    pub chunked_uploads:  Option<String>,
+    #[serde(rename = "chunked-upload-expiry", default)]
+    pub upload_expiry:    Option<u64>,
+    #[serde(rename = "deny-upload", default)]
+    pub deny_upload:      Vec<String>,
+    #[serde(rename = "allow-upload", default)]
+    pub allow_upload:     Vec<String>,
+    #[serde(rename = "deny-upload-types", default)]
+    pub deny_types:       Vec<String>,
+    #[serde(
+        rename = "content-disposition",
+        deserialize_with = "deserialize_opt_enum",
+        default
+    )]
+    pub disposition:      Option<Disposition>,
+    #[serde(default)]
+    pub charset:          Option<String>,
+    #[serde(rename = "charset-sniff", default)]
+    pub charset_sniff:    bool,
+    #[serde(rename = "cache-control", default)]
+    pub cache_control:    Option<String>,
+    #[serde(rename = "extended-mkcol", default)]
+    pub extended_mkcol:   bool,
+    #[serde(rename = "etag-inode", default)]
+    pub etag_inode:       bool,
+    #[serde(rename = "etag-weak", default)]
+    pub etag_weak:        bool,
+    #[serde(rename = "etag-strength", deserialize_with = "deserialize_opt_enum", default)]
+    pub etag_strength:    Option<EtagStrength>,
+    #[serde(rename = "one-filesystem", default)]
+    pub one_filesystem:   bool,
+    #[serde(default)]
+    pub fsync:            bool,
+    #[serde(rename = "macos-compat", default)]
+    pub macos_compat:     bool,
+    #[serde(rename = "read-ahead", default)]
+    pub read_ahead:       bool,
+    #[serde(rename = "read-ahead-window", default)]
+    pub read_window:      Option<usize>,
+    #[serde(rename = "auto-mkcol", default)]
+    pub auto_mkcol:       bool,
+    #[serde(rename = "auto-mkcol-max-depth", default)]
+    pub auto_mkcol_depth: Option<usize>,
+    #[serde(rename = "upload-filter", default)]
+    pub upload_filter:    Option<String>,
+    #[serde(rename = "verify-checksum", default)]
+    pub verify_checksum:  bool,
+    #[serde(rename = "access-errors", deserialize_with = "deserialize_opt_enum", default)]
+    pub access_errors:    Option<AccessErrors>,
+    #[serde(rename = "allow-create", default)]
+    pub allow_create:     Option<bool>,
+    #[serde(rename = "allow-overwrite", default)]
+    pub allow_overwrite:  Option<bool>,
+    #[serde(rename = "allow-delete", default)]
+    pub allow_delete:     Option<bool>,
+    #[serde(rename = "allow-mkcol", default)]
+    pub allow_mkcol:      Option<bool>,
+    #[serde(rename = "write-windows", default)]
+    pub write_windows:    Vec<String>,
+    #[serde(rename = "write-windows-timezone", default)]
+    pub write_timezone:   Option<String>,
+    #[serde(rename = "windows-names", deserialize_with = "deserialize_opt_enum", default)]
+    pub windows_names:    Option<WindowsNames>,
 }
 
 #[derive(FromStr, Debug, Clone, Copy)]
@@ -136,6 +454,55 @@ pub enum AuthType {
     #[cfg(feature = "pam")]
     Pam,
     HtPasswd(String),
+    ClientCert,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum LockStoreType {
+    #[from_str = "memory"]
+    Memory,
+    #[from_str = "file"]
+    File,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum MetricsSink {
+    #[from_str = "none"]
+    None,
+    #[from_str = "prometheus"]
+    Prometheus,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum Capabilities {
+    #[from_str = "public"]
+    Public,
+    #[from_str = "admin"]
+    Admin,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum UnexpectedBody {
+    #[from_str = "drain"]
+    Drain,
+    #[from_str = "reject"]
+    Reject,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum DuplicateHeaders {
+    #[from_str = "reject"]
+    Reject,
+    #[from_str = "allow"]
+    Allow,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum ClientAuth {
+    #[from_str = "optional"]
+    Optional,
+    #[from_str = "required"]
+    Required,
 }
 
 #[derive(FromStr, Debug, Clone, Copy)]
@@ -154,6 +521,42 @@ pub enum CaseInsensitive {
     False,
 }
 
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum CacheExpiry {
+    #[from_str = "absolute"]
+    Absolute,
+    #[from_str = "sliding"]
+    Sliding,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum EtagStrength {
+    #[from_str = "strong"]
+    Strong,
+    #[from_str = "weak"]
+    Weak,
+    #[from_str = "mixed"]
+    Mixed,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum PutRange {
+    #[from_str = "ignore"]
+    Ignore,
+    #[from_str = "reject"]
+    Reject,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum AllProp {
+    #[from_str = "allow"]
+    Allow,
+    #[from_str = "forbid"]
+    Forbid,
+    #[from_str = "live"]
+    Live,
+}
+
 #[derive(FromStr, Debug, Clone, Copy)]
 pub enum OnNotfound {
     #[from_str = "continue"]
@@ -162,6 +565,32 @@ pub enum OnNotfound {
     Return,
 }
 
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum AccessErrors {
+    #[from_str = "accurate"]
+    Accurate,
+    #[from_str = "opaque"]
+    Opaque,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum WindowsNames {
+    #[from_str = "allow"]
+    Allow,
+    #[from_str = "reject"]
+    Reject,
+    #[from_str = "escape"]
+    Escape,
+}
+
+#[derive(FromStr, Debug, Clone, Copy)]
+pub enum Disposition {
+    #[from_str = "attachment"]
+    Attachment,
+    #[from_str = "inline"]
+    Inline,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum OneOrManyAddr {
@@ -202,7 +631,7 @@ pub fn deserialize_user<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error
 where D: Deserializer<'de> {
     let s = String::deserialize(deserializer)?;
     s.parse::<u32>()
-        .map(|v| Some(v))
+        .map(Some)
         .map_err(serde::de::Error::custom)
 }
 
@@ -211,7 +640,7 @@ pub fn deserialize_group<'de, D>(deserializer: D) -> Result<Option<u32>, D::Erro
 where D: Deserializer<'de> {
     let s = String::deserialize(deserializer)?;
     s.parse::<u32>()
-        .map(|v| Some(v))
+        .map(Some)
         .map_err(serde::de::Error::custom)
 }
 
@@ -219,21 +648,39 @@ pub fn deserialize_methodset<'de, D>(deserializer: D) -> Result<Option<DavMethod
 where D: Deserializer<'de> {
     let m = Vec::<String>::deserialize(deserializer)?;
     DavMethodSet::from_vec(m)
-        .map(|v| Some(v))
+        .map(Some)
         .map_err(serde::de::Error::custom)
 }
 
+// A string, or a list of strings.
+pub fn deserialize_strings<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where D: Deserializer<'de> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => Ok(vec![s]),
+        OneOrMany::Many(v) => Ok(v),
+    }
+}
+
 pub fn deserialize_authtype<'de, D>(deserializer: D) -> Result<Option<AuthType>, D::Error>
 where D: Deserializer<'de> {
     let s = String::deserialize(deserializer)?;
-    if s.starts_with("htpasswd.") {
-        return Ok(Some(AuthType::HtPasswd(s[9..].to_string())));
+    if let Some(name) = s.strip_prefix("htpasswd.") {
+        return Ok(Some(AuthType::HtPasswd(name.to_string())));
     }
     #[cfg(feature = "pam")]
     if &s == "pam" {
         return Ok(Some(AuthType::Pam));
     }
-    if s == "" {
+    if &s == "tls-client-cert" {
+        return Ok(Some(AuthType::ClientCert));
+    }
+    if s.is_empty() {
         return Ok(None);
     }
     Err(serde::de::Error::custom("unknown auth-type"))
@@ -281,7 +728,7 @@ pub fn build_routes(cfg: &str, config: &mut Config) -> io::Result<()> {
     let mut builder = Router::builder();
     for (idx, location) in config.location.iter().enumerate() {
         for r in &location.route {
-            if let Err(e) = builder.add(r, location.methods.clone(), idx) {
+            if let Err(e) = builder.add(r, location.methods, idx) {
                 let msg = format!("{}: [[location]][{}]: route {}: {}", cfg, idx, r, e);
                 return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
             }
@@ -294,12 +741,44 @@ pub fn build_routes(cfg: &str, config: &mut Config) -> io::Result<()> {
 pub fn check(cfg: &str, config: &Config) {
     #[cfg(feature = "pam")]
     if let Some(AuthType::Pam) = config.accounts.auth_type {
-        if config.pam.service == "" {
+        if config.pam.service.is_empty() {
             eprintln!("{}: missing section [pam]", cfg);
             exit(1);
         }
     }
+    #[cfg(feature = "pam")]
+    for name in config.pam.items.keys() {
+        if crate::pamauth::item_type(name).is_none() {
+            eprintln!("{}: [pam]: items: {}: unknown or not allowed", cfg, name);
+            exit(1);
+        }
+    }
+    if config.pam.timeout == Some(0) {
+        eprintln!("{}: [pam]: timeout: must be at least 1", cfg);
+        exit(1);
+    }
+    if config.pam.max_pending == Some(0) {
+        eprintln!("{}: [pam]: max-pending: must be at least 1", cfg);
+        exit(1);
+    }
+    if config.pam.cache_lifetime == Some(0) {
+        eprintln!("{}: [pam]: cache-max-lifetime: must be at least 1", cfg);
+        exit(1);
+    }
+    if config.pam.cache_lifetime.is_some() && config.pam.cache_expiry != Some(CacheExpiry::Sliding) {
+        eprintln!("{}: [pam]: cache-max-lifetime: needs cache-expiry = \"sliding\"", cfg);
+        exit(1);
+    }
 
+    let ids = [("identification", &config.server.identification), ("powered-by", &config.server.powered_by)];
+    for (name, value) in ids.iter() {
+        if let Some(value) = value {
+            if http::HeaderValue::from_str(value).is_err() {
+                eprintln!("{}: [server]: {}: not a valid header value", cfg, name);
+                exit(1);
+            }
+        }
+    }
     if config.server.listen.is_empty() && config.server.tls_listen.is_empty() {
         eprintln!("{}: [server]: at least one of listen or tls_listen must be set", cfg);
         exit(1);
@@ -314,8 +793,333 @@ pub fn check(cfg: &str, config: &Config) {
             exit(1);
         }
     }
+    if let Some(max) = config.server.max_depth.filter(|m| crate::depth::Depth::from_max(*m).is_none()) {
+        eprintln!("{}: [server]: max-depth: {}: must be 0 or 1", cfg, max);
+        exit(1);
+    }
+    if config.server.max_header_size == Some(0) {
+        eprintln!("{}: [server]: max-header-size: must be at least 1", cfg);
+        exit(1);
+    }
+    if config.server.max_uri_length == Some(0) {
+        eprintln!("{}: [server]: max-uri-length: must be at least 1", cfg);
+        exit(1);
+    }
+    if let Some(prefix) = config.server.dav_prefix.as_deref().filter(|p| !crate::nsprefix::valid(p)) {
+        eprintln!("{}: [server]: dav-prefix: {:?}: not a valid XML prefix", cfg, prefix);
+        exit(1);
+    }
+    if let Err(e) = crate::ipacl::IpAcl::new(&config.server.allow_from, &config.server.deny_from) {
+        eprintln!("{}: [server]: allow-from / deny-from: {}", cfg, e);
+        exit(1);
+    }
+    if config.server.max_path_length == Some(0) {
+        eprintln!("{}: [server]: max-path-length: must be at least 1", cfg);
+        exit(1);
+    }
+    if let Some(max) = config.server.max_headers {
+        if max == 0 || max > crate::limits::MAX_HEADER_COUNT {
+            eprintln!(
+                "{}: [server]: max-headers: {}: must be between 1 and {}",
+                cfg,
+                max,
+                crate::limits::MAX_HEADER_COUNT
+            );
+            exit(1);
+        }
+    }
+    if config.server.tls_client_auth.is_some() && config.server.tls_client_ca.is_none() {
+        eprintln!("{}: [server]: tls_client_auth: needs tls_client_ca", cfg);
+        exit(1);
+    }
+    let accounts = config
+        .location
+        .iter()
+        .map(|l| &l.accounts)
+        .chain(Some(&config.accounts))
+        .chain(Some(&config.admin.accounts));
+    let client_cert = accounts
+        .filter_map(|a| a.auth_type.as_ref())
+        .any(|t| matches!(t, AuthType::ClientCert));
+    if client_cert && config.server.tls_client_ca.is_none() {
+        eprintln!("{}: auth-type tls-client-cert: needs [server] tls_client_ca", cfg);
+        exit(1);
+    }
+    if let Err(e) = crate::clientcert::CertMap::new(&config.client_cert) {
+        eprintln!("{}: [client-cert]: {}", cfg, e);
+        exit(1);
+    }
+    if let Some(key) = config.error_pages.keys().find(|k| !crate::errorpage::valid_key(k)) {
+        eprintln!("{}: [error-pages]: {}: expected a status code (400-599), 4xx or 5xx", cfg, key);
+        exit(1);
+    }
+    if let Err(e) = crate::errorpage::ErrorPages::load(&config.error_pages) {
+        eprintln!("{}: [error-pages]: {}", cfg, e);
+        exit(1);
+    }
+
+    if config.server.chroot.is_some() {
+        if config.server.uid.is_none() || config.server.gid.is_none() {
+            eprintln!("{}: [server]: chroot: uid and gid must be set", cfg);
+            exit(1);
+        }
+        if config.location.iter().any(|l| l.setuid) {
+            eprintln!("{}: [server]: chroot: cannot be combined with setuid locations", cfg);
+            exit(1);
+        }
+    }
+
+    if config.server.max_xml_body.map(|m| m > crate::xmlbody::MAX_BODY).unwrap_or(false) {
+        eprintln!(
+            "{}: [server]: max-xml-body: cannot be larger than {}",
+            cfg,
+            crate::xmlbody::MAX_BODY
+        );
+        exit(1);
+    }
+
+    if config.server.tls_ticket_lifetime.map(|l| l > crate::tls::DEFAULT_TICKET_LIFETIME).unwrap_or(false) {
+        eprintln!(
+            "{}: [server]: tls_ticket_lifetime: cannot be larger than {}",
+            cfg,
+            crate::tls::DEFAULT_TICKET_LIFETIME
+        );
+        exit(1);
+    }
+
+    if let Some(max) = config.server.max_upload_buffer {
+        if max == 0 || max > u32::MAX as usize {
+            eprintln!("{}: [server]: max-upload-buffer: must be between 1 and {}", cfg, u32::MAX);
+            exit(1);
+        }
+    }
+
+    if config.server.memory_budget == Some(0) {
+        eprintln!("{}: [server]: memory-budget: must be larger than 0", cfg);
+        exit(1);
+    }
+
+    if config.server.propfind_responses == Some(0) {
+        eprintln!("{}: [server]: propfind-max-responses: must be at least 1", cfg);
+        exit(1);
+    }
+    if let Some(interval) = config.server.propfind_flush {
+        if !(interval > 0.0 && interval <= 60.0) {
+            eprintln!("{}: [server]: propfind-flush-interval: must be larger than 0 and at most 60", cfg);
+            exit(1);
+        }
+    }
+    if config.server.max_writes.map(|max| max > 1_000_000).unwrap_or(false) {
+        eprintln!("{}: [server]: max-concurrent-writes: must be at most 1000000", cfg);
+        exit(1);
+    }
+    let shed = [
+        ("shed-connections", config.server.shed_connections),
+        ("shed-memory", config.server.shed_memory),
+        ("shed-writes", config.server.shed_writes),
+    ];
+    if let Some((name, _)) = shed.iter().find(|(_, max)| *max == Some(0)) {
+        eprintln!("{}: [server]: {}: must be at least 1", cfg, name);
+        exit(1);
+    }
+
+    if let Err(e) = crate::bodylog::BodyLog::new(&config.server.log_bodies, None) {
+        eprintln!("{}: [server]: log-bodies: {}", cfg, e);
+        exit(1);
+    }
+    if config.server.log_body_max == Some(0) {
+        eprintln!("{}: [server]: log-body-max: must be at least 1", cfg);
+        exit(1);
+    }
+
+    if let Some(rate) = config.server.max_request_rate {
+        if !(rate > 0.0 && rate.is_finite()) {
+            eprintln!("{}: [server]: max-request-rate: must be larger than 0", cfg);
+            exit(1);
+        }
+    }
+    if config.server.request_burst.is_some() && config.server.max_request_rate.is_none() {
+        eprintln!("{}: [server]: request-burst: needs max-request-rate", cfg);
+        exit(1);
+    }
+    let delays = [
+        ("auth-failure-delay", config.server.auth_fail_delay),
+        ("auth-failure-delay-max", config.server.auth_fail_delay_max),
+        ("auth-failure-min-time", config.server.auth_fail_min_time),
+    ];
+    for (name, delay) in delays.iter() {
+        if delay.map(|d| !(d > 0.0 && d <= 3600.0)).unwrap_or(false) {
+            eprintln!("{}: [server]: {}: must be larger than 0 and at most 3600", cfg, name);
+            exit(1);
+        }
+    }
+    if config.server.auth_fail_delay_max.is_some() && config.server.auth_fail_delay.is_none() {
+        eprintln!("{}: [server]: auth-failure-delay-max: needs auth-failure-delay", cfg);
+        exit(1);
+    }
+
+    if config.unix.nss_delay.map(|d| !(0.0..=60.0).contains(&d)).unwrap_or(false) {
+        eprintln!("{}: [unix]: nss-retry-delay: must be between 0 and 60", cfg);
+        exit(1);
+    }
+
+    for location in &config.location {
+        if let Err(e) = crate::autoindex::Templates::load(location) {
+            eprintln!("{}: [[location]] {:?}: {}", cfg, location.route, e);
+            exit(1);
+        }
+        let token = |c: char| c.is_ascii_alphanumeric() || "-_.:".contains(c);
+        if location.charset.as_ref().map(|c| c.is_empty() || !c.chars().all(token)).unwrap_or(false) {
+            eprintln!("{}: [[location]] {:?}: charset: invalid", cfg, location.route);
+            exit(1);
+        }
+        if let Some(Err(e)) = location.upload_filter.as_deref().map(crate::uploadfilter::from_config) {
+            eprintln!("{}: [[location]] {:?}: upload-filter: {}", cfg, location.route, e);
+            exit(1);
+        }
+        if location.etag_weak && location.etag_strength.is_some() {
+            eprintln!("{}: [[location]] {:?}: etag-weak: use etag-strength = \"weak\"", cfg, location.route);
+            exit(1);
+        }
+        if location.create_home && !location.directory.starts_with('~') {
+            let route = &location.route;
+            eprintln!("{}: [[location]] {:?}: create-home: directory does not start with \"~\"", cfg, route);
+            exit(1);
+        }
+        if location.root_probe == Some(0) {
+            eprintln!("{}: [[location]] {:?}: root-probe-interval: must be at least 1", cfg, location.route);
+            exit(1);
+        }
+        if location.auto_mkcol_depth == Some(0) {
+            eprintln!("{}: [[location]] {:?}: auto-mkcol-max-depth: must be at least 1", cfg, location.route);
+            exit(1);
+        }
+        let timezone = location.write_timezone.as_deref();
+        if let Err(e) = crate::timewindow::WriteWindows::new(&location.write_windows, timezone) {
+            eprintln!("{}: [[location]] {:?}: write-windows: {}", cfg, location.route, e);
+            exit(1);
+        }
+        if let Some(cc) = location.cache_control.as_deref() {
+            if cc.trim().is_empty() || http::HeaderValue::from_str(cc).is_err() {
+                eprintln!("{}: [[location]] {:?}: cache-control: invalid", cfg, location.route);
+                exit(1);
+            }
+        }
+        if location.probe_cache == Some(0) {
+            eprintln!("{}: [[location]] {:?}: probe-cache: must be at least 1", cfg, location.route);
+            exit(1);
+        }
+        if location.read_window == Some(0) {
+            eprintln!("{}: [[location]] {:?}: read-ahead-window: must be at least 1", cfg, location.route);
+            exit(1);
+        }
+    }
+
+    if let Some(url) = config.server.public_url.as_ref() {
+        if let Err(e) = crate::baseurl::BaseUrl::parse(url) {
+            eprintln!("{}: [server]: public-url: {}: {}", cfg, url, e);
+            exit(1);
+        }
+    }
+
+    let keepalive = [
+        ("tcp-keepalive", config.server.tcp_keepalive),
+        ("tcp-keepalive-interval", config.server.tcp_keepalive_intvl),
+        ("tcp-keepalive-retries", config.server.tcp_keepalive_retries.map(u64::from)),
+    ];
+    for (name, value) in keepalive.iter() {
+        if *value == Some(0) {
+            eprintln!("{}: [server]: {}: must be at least 1", cfg, name);
+            exit(1);
+        }
+        if value.is_some() && config.server.tcp_keepalive.is_none() {
+            eprintln!("{}: [server]: {}: needs tcp-keepalive", cfg, name);
+            exit(1);
+        }
+    }
+    if config.server.listen_backlog.map(|b| b < 1).unwrap_or(false) {
+        eprintln!("{}: [server]: listen-backlog: must be at least 1", cfg);
+        exit(1);
+    }
+    for (name, value) in &[
+        ("worker-threads", config.server.worker_threads),
+        ("blocking-threads", config.server.blocking_threads),
+    ] {
+        if *value == Some(0) {
+            eprintln!("{}: [server]: {}: must be at least 1", cfg, name);
+            exit(1);
+        }
+    }
+    for (name, value) in &[
+        ("max-per-user", config.locks.max_per_user),
+        ("max-per-path", config.locks.max_per_path),
+    ] {
+        if *value == Some(0) {
+            eprintln!("{}: [locks]: {}: must be at least 1", cfg, name);
+            exit(1);
+        }
+    }
+    if config.locks.store == Some(LockStoreType::File) && config.locks.file.is_none() {
+        eprintln!("{}: [locks]: store = \"file\" needs a file", cfg);
+        exit(1);
+    }
+
+    if let Some(route) = config.admin.route.as_ref() {
+        if !route.starts_with('/') {
+            eprintln!("{}: [admin]: route must start with /", cfg);
+            exit(1);
+        }
+        if config.admin.users.is_empty() {
+            eprintln!("{}: [admin]: route set, but no users", cfg);
+            exit(1);
+        }
+    }
+    if config.server.capabilities == Some(Capabilities::Admin) && config.admin.route.is_none() {
+        eprintln!("{}: [server]: capabilities = \"admin\": needs [admin] route", cfg);
+        exit(1);
+    }
 
     for (idx, location) in config.location.iter().enumerate() {
+        let ci = matches!(location.case_insensitive, Some(CaseInsensitive::True) | Some(CaseInsensitive::Ms));
+        if location.stat_cache.is_some() && ci {
+            eprintln!(
+                "{}: [[location]][{}]: stat-cache cannot be combined with case-insensitive",
+                cfg, idx
+            );
+            exit(1);
+        }
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-';
+        let plain_name = |n: &str| !n.is_empty() && n != "." && n != ".." && n.chars().all(valid);
+        if let Some(trash) = location.trash.as_ref() {
+            if !plain_name(trash) {
+                eprintln!("{}: [[location]][{}]: trash: must be a plain directory name", cfg, idx);
+                exit(1);
+            }
+        }
+        if let Some(uploads) = location.chunked_uploads.as_ref() {
+            if !plain_name(uploads) {
+                eprintln!(
+                    "{}: [[location]][{}]: chunked-uploads: must be a plain directory name",
+                    cfg, idx
+                );
+                exit(1);
+            }
+            if location.trash.as_ref() == Some(uploads) {
+                eprintln!("{}: [[location]][{}]: chunked-uploads: same as trash", cfg, idx);
+                exit(1);
+            }
+        }
+        if location.shared_owner.is_some() {
+            if !location.setuid {
+                eprintln!("{}: [[location]][{}]: shared-owner: needs setuid", cfg, idx);
+                exit(1);
+            }
+            if !crate::shared::is_shared(location) {
+                let what = "needs shared-users or shared-groups";
+                eprintln!("{}: [[location]][{}]: shared-owner: {}", cfg, idx, what);
+                exit(1);
+            }
+        }
         if location.setuid {
             if !crate::suid::has_thread_switch_ugid() {
                 eprintln!(
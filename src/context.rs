@@ -0,0 +1,180 @@
+//! Per-request context.
+//!
+//! A `RequestContext` is created when a request comes in, and passed
+//! down to everything that handles it. It carries:
+//!
+//! - the client address
+//! - the request ID, see `requestid`
+//! - the identity of the user, once authenticated
+//! - the public base URL, see `baseurl`
+//! - a deadline, from `request-timeout` in the `[server]` section
+//! - a cancellation token, which is cancelled when the request is done
+//! - the request headers that the response depends on, see `vary`
+//! - where the time went, for the slow request log (see `slowlog`)
+//! - whether the location is a shared folder, see `shared`
+//!
+//! Operations that might take long run through `RequestContext::run`.
+//! It stops waiting for the operation when the deadline has passed.
+//! Note that this only works at await points: code that blocks the
+//! thread (like reading a htpasswd file) is not interrupted, the deadline
+//! is checked when it is done.
+//!
+//! Work that does not run as part of the request future (a spawned task,
+//! a thread) can hold on to a `CancelToken`, and stop when it is cancelled.
+//!
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use http::StatusCode;
+use tokio::sync::Notify;
+
+use crate::auth::Identity;
+use crate::baseurl::BaseUrl;
+use crate::slowlog::Timing;
+use crate::vary::Vary;
+
+/// Why an operation did not finish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expired {
+    /// The deadline of the request passed.
+    Deadline,
+    /// The request was cancelled.
+    Cancelled,
+}
+
+impl Expired {
+    /// The status code to send to the client.
+    pub fn status(&self) -> StatusCode {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Cancellation token. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<CancelState>);
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    notify:    Notify,
+}
+
+impl CancelToken {
+    /// Cancel, and wake up everyone waiting in `cancelled()`.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Wait until cancelled.
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.0.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// State of one request.
+#[derive(Debug)]
+pub struct RequestContext {
+    pub remote_ip:  SocketAddr,
+    pub request_id: String,
+    pub identity:   Option<Identity>,
+    pub base_url:   BaseUrl,
+    pub vary:       Vary,
+    pub timing:     Timing,
+    pub shared:     bool,
+    pub version:    http::Version,
+    pub keep_alive: bool,
+    deadline:       Option<Instant>,
+    cancel:         CancelToken,
+}
+
+impl RequestContext {
+    /// New context, with a deadline `timeout` from now.
+    pub fn new(remote_ip: SocketAddr, timeout: Option<Duration>) -> RequestContext {
+        RequestContext {
+            remote_ip,
+            request_id: String::new(),
+            identity: None,
+            base_url: BaseUrl::default(),
+            vary: Vary::default(),
+            timing: Timing::new(),
+            shared: false,
+            version: http::Version::HTTP_11,
+            keep_alive: true,
+            deadline: timeout.map(|t| Instant::now() + t),
+            cancel: CancelToken::default(),
+        }
+    }
+
+    /// Time left until the deadline, `None` if there is no deadline.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    /// The cancellation token of this request.
+    #[allow(dead_code)]
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Run an operation, within the deadline of the request.
+    pub async fn run<F: Future>(&self, fut: F) -> Result<F::Output, Expired> {
+        if self.cancel.is_cancelled() {
+            return Err(Expired::Cancelled);
+        }
+        let deadline = async {
+            match self.remaining() {
+                Some(left) => tokio::time::sleep(left).await,
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::select! {
+            biased;
+            res = fut => Ok(res),
+            _ = deadline => Err(Expired::Deadline),
+            _ = self.cancel.cancelled() => Err(Expired::Cancelled),
+        }
+    }
+}
+
+impl Drop for RequestContext {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_deadline() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let ctx = RequestContext::new(addr, None);
+        assert_eq!(ctx.run(async { 42 }).await, Ok(42));
+
+        let ctx = RequestContext::new(addr, Some(Duration::from_millis(20)));
+        assert_eq!(ctx.run(async { 42 }).await, Ok(42));
+        let slow = tokio::time::sleep(Duration::from_secs(5));
+        assert_eq!(ctx.run(slow).await, Err(Expired::Deadline));
+
+        let ctx = RequestContext::new(addr, None);
+        let token = ctx.cancel_token();
+        let waiter = tokio::spawn(async move { token.cancelled().await });
+        drop(ctx);
+        waiter.await.unwrap();
+    }
+}
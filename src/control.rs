@@ -0,0 +1,168 @@
+//! Control socket.
+//!
+//! With `[admin] socket`, the server listens on a unix socket for control
+//! commands, next to (or instead of) the admin API over HTTP. The socket
+//! is created before privileges are dropped, with mode 0600, so only its
+//! owner (root, usually) can connect. Connections from other users are
+//! closed right away; the uid of the peer is checked as well.
+//!
+//! The protocol is a line of text per command, and a line of JSON per
+//! answer. Errors are `{"error": "..."}`.
+//!
+//! - `list-locks [PATH]`: the locks, below PATH (a filesystem path).
+//! - `release-lock TOKEN`: release a lock, returns what was released.
+//! - `metrics`: the counters of the admin API's /metrics.
+//! - `flush-cache`: empty the stat caches, the probe caches and the
+//!   unix account and PAM caches. Returns how many entries were dropped.
+//! - `drain`: stop accepting connections, and shut down as on SIGTERM.
+//! - `help`: the commands.
+//!
+//! There is no reload: the configuration is only read at startup.
+//!
+use std::fs::Permissions;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+pub const COMMANDS: &[&str] = &[
+    "list-locks [PATH]",
+    "release-lock TOKEN",
+    "metrics",
+    "flush-cache",
+    "drain",
+    "help",
+];
+
+/// A command, from one line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    ListLocks(Option<String>),
+    ReleaseLock(String),
+    Metrics,
+    FlushCache,
+    Drain,
+    Help,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let cmd = words.next().ok_or("empty command")?;
+        let arg = words.next().map(|s| s.to_string());
+        if words.next().is_some() {
+            return Err(format!("{}: too many arguments", cmd));
+        }
+        let command = match (cmd, arg) {
+            ("list-locks", arg) => Command::ListLocks(arg),
+            ("release-lock", Some(token)) => Command::ReleaseLock(token),
+            ("metrics", None) => Command::Metrics,
+            ("flush-cache", None) => Command::FlushCache,
+            ("drain", None) => Command::Drain,
+            ("help", None) => Command::Help,
+            ("release-lock", None) => return Err("release-lock: needs a token".to_string()),
+            ("metrics" | "flush-cache" | "drain" | "help", Some(_)) => {
+                return Err(format!("{}: no arguments", cmd));
+            },
+            (cmd, _) => return Err(format!("{}: unknown command", cmd)),
+        };
+        Ok(command)
+    }
+}
+
+pub struct ControlSocket {
+    listener: UnixListener,
+    // the uid that created it, before dropping privileges.
+    owner:    u32,
+}
+
+impl ControlSocket {
+    /// Create the socket at `path`, owner only. A socket that is left
+    /// over from an earlier run is removed, anything else at `path` is
+    /// an error.
+    pub fn bind(path: &str) -> io::Result<ControlSocket> {
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "exists and is not a socket")),
+            Err(_) => {},
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, Permissions::from_mode(0o600))?;
+        Ok(ControlSocket {
+            listener,
+            owner: nix::unistd::geteuid().as_raw(),
+        })
+    }
+
+    /// Answer the commands, with `handler`. It gets the uid of the peer,
+    /// for the audit log.
+    pub async fn serve<F>(self, handler: F)
+    where
+        F: Fn(Command, u32) -> Value + Clone + Send + Sync + 'static,
+    {
+        loop {
+            let stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("control: accept: {}", e);
+                    continue;
+                },
+            };
+            let uid = match stream.peer_cred() {
+                Ok(cred) if cred.uid() == 0 || cred.uid() == self.owner => cred.uid(),
+                Ok(cred) => {
+                    warn!("control: refused connection from uid {}", cred.uid());
+                    continue;
+                },
+                Err(e) => {
+                    warn!("control: peer credentials: {}", e);
+                    continue;
+                },
+            };
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                if let Err(e) = connection(stream, uid, handler).await {
+                    debug!("control: uid {}: {}", uid, e);
+                }
+            });
+        }
+    }
+}
+
+async fn connection<F>(stream: UnixStream, uid: u32, handler: F) -> io::Result<()>
+where
+    F: Fn(Command, u32) -> Value,
+{
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let answer = match Command::parse(&line) {
+            Ok(command) => handler(command, uid),
+            Err(e) => json!({ "error": e }),
+        };
+        let mut answer = answer.to_string();
+        answer.push('\n');
+        write.write_all(answer.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Command::parse("list-locks"), Ok(Command::ListLocks(None)));
+        assert_eq!(Command::parse(" list-locks /home/a "), Ok(Command::ListLocks(Some("/home/a".into()))));
+        assert_eq!(Command::parse("release-lock tok"), Ok(Command::ReleaseLock("tok".into())));
+        assert_eq!(Command::parse("flush-cache"), Ok(Command::FlushCache));
+        assert!(Command::parse("release-lock").is_err() && Command::parse("flush-cache now").is_err());
+        assert!(Command::parse("reload").is_err() && Command::parse("list-locks a b").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! MOVE across filesystems.
+//!
+//! MOVE is a rename(2), which fails with EXDEV when the source and the
+//! destination are on different filesystems (a mount point below the
+//! directory that is served, or a symlink to another disk). Then we do
+//! what mv(1) does: copy, and remove the source. A plain copy loses the
+//! metadata, so for every file, directory and symlink we also copy:
+//!
+//! - the modification and access times
+//! - the permission bits
+//! - the owner and group, as far as we are allowed to: as root all of
+//!   it, as the user itself only a group that the user is a member of
+//! - the extended attributes
+//!
+//! Dead properties are dropped. The filesystem does not have a property
+//! store (`have_props` is false, so PROPFIND never returns any), so
+//! there is nothing to copy; `one-filesystem` in the config says so.
+//!
+//! The tree is copied to a temporary name next to the destination,
+//! `.move-UUID.tmp`, and renamed into place when it is complete. If
+//! anything fails before that, the copy is removed and the source is
+//! left alone. Special files (fifos, sockets, devices) are refused.
+//!
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+/// Move `from` to `to`, which is on a different filesystem. This
+/// is blocking, and runs with the uid/gid of the filesystem.
+pub fn move_tree(from: &Path, to: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    let dir = to.parent().ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+    let tmp = dir.join(format!(".move-{}.tmp", uuid::Uuid::new_v4().to_simple()));
+
+    let res = copy_tree(from, &tmp, &meta).and_then(|_| {
+        match fs::rename(&tmp, to) {
+            // like rename(2) in LocalFs: a directory can replace a file.
+            Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) && meta.is_dir() => {
+                fs::remove_file(to)?;
+                fs::rename(&tmp, to)
+            },
+            res => res,
+        }
+    });
+    if let Err(e) = res {
+        let _ = match fs::symlink_metadata(&tmp) {
+            Ok(m) if m.is_dir() => fs::remove_dir_all(&tmp),
+            Ok(_) => fs::remove_file(&tmp),
+            Err(_) => Ok(()),
+        };
+        return Err(e);
+    }
+
+    if meta.is_dir() {
+        fs::remove_dir_all(from)
+    } else {
+        fs::remove_file(from)
+    }
+}
+
+// Copy one file, symlink or directory tree.
+fn copy_tree(from: &Path, to: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    let ftype = meta.file_type();
+    if ftype.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            // DirEntry::metadata() does not follow symlinks.
+            let meta = entry.metadata()?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()), &meta)?;
+        }
+    } else if ftype.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+    } else if ftype.is_file() {
+        fs::copy(from, to)?;
+    } else {
+        debug!("move_tree: {:?}: not a file, directory or symlink", from);
+        return Err(io::Error::from_raw_os_error(libc::EXDEV));
+    }
+    copy_meta(from, to, meta)
+}
+
+fn cpath(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// Copy xattrs, owner, mode and times, in that order: xattrs need
+// write access, and chown clears the setuid and setgid bits.
+fn copy_meta(from: &Path, to: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    let is_link = meta.file_type().is_symlink();
+    let cto = cpath(to)?;
+    if !is_link {
+        fs::set_permissions(to, fs::Permissions::from_mode(meta.mode() & 0o7777 | 0o200))?;
+    }
+    copy_xattrs(&cpath(from)?, &cto)?;
+
+    if unsafe { libc::lchown(cto.as_ptr(), meta.uid(), meta.gid()) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EPERM) {
+            return Err(e);
+        }
+        // not root: at least the group, if we are a member.
+        let _ = unsafe { libc::lchown(cto.as_ptr(), u32::MAX, meta.gid()) };
+    }
+    if !is_link {
+        fs::set_permissions(to, fs::Permissions::from_mode(meta.mode() & 0o7777))?;
+    }
+
+    let times = [
+        libc::timespec {
+            tv_sec:  meta.atime() as libc::time_t,
+            tv_nsec: meta.atime_nsec() as _,
+        },
+        libc::timespec {
+            tv_sec:  meta.mtime() as libc::time_t,
+            tv_nsec: meta.mtime_nsec() as _,
+        },
+    ];
+    check(unsafe { libc::utimensat(libc::AT_FDCWD, cto.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) })
+}
+
+// Copy the extended attributes. Attributes that the destination does
+// not support, or that we may not set (trusted.*, user.* on a symlink)
+// are skipped.
+#[cfg(target_os = "linux")]
+fn copy_xattrs(from: &CString, to: &CString) -> io::Result<()> {
+    let skip = |e: &io::Error| {
+        matches!(e.raw_os_error(), Some(libc::ENOTSUP) | Some(libc::EPERM) | Some(libc::EACCES))
+    };
+
+    // first ask for the size, then get the names.
+    let size = unsafe { libc::llistxattr(from.as_ptr(), std::ptr::null_mut(), 0) };
+    if size <= 0 {
+        let e = io::Error::last_os_error();
+        return if size == 0 || skip(&e) { Ok(()) } else { Err(e) };
+    }
+    let mut names = vec![0u8; size as usize];
+    let ptr = names.as_mut_ptr() as *mut libc::c_char;
+    let size = unsafe { libc::llistxattr(from.as_ptr(), ptr, names.len()) };
+    check(size as libc::c_int)?;
+    names.truncate(size as usize);
+
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let cname = CString::new(name).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let size = unsafe { libc::lgetxattr(from.as_ptr(), cname.as_ptr(), std::ptr::null_mut(), 0) };
+        check(size as libc::c_int)?;
+        let mut value = vec![0u8; size as usize];
+        let ptr = value.as_mut_ptr() as *mut libc::c_void;
+        let size = unsafe { libc::lgetxattr(from.as_ptr(), cname.as_ptr(), ptr, value.len()) };
+        check(size as libc::c_int)?;
+        let ptr = value.as_ptr() as *const libc::c_void;
+        let ret = unsafe { libc::lsetxattr(to.as_ptr(), cname.as_ptr(), ptr, size as usize, 0) };
+        if let Err(e) = check(ret) {
+            if !skip(&e) {
+                return Err(e);
+            }
+            debug!("move_tree: {:?}: xattr {:?} not copied: {}", to, cname, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_xattrs(_from: &CString, _to: &CString) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A directory on another filesystem than the temp dir, if there is one.
+    pub fn other_fs() -> Option<PathBuf> {
+        let shm = Path::new("/dev/shm");
+        let tmp = fs::metadata(std::env::temp_dir()).ok()?;
+        match fs::metadata(shm) {
+            Ok(m) if m.dev() != tmp.dev() => {
+                let dir = shm.join(format!("webdav-test-{}", uuid::Uuid::new_v4().to_simple()));
+                fs::create_dir(&dir).ok()?;
+                Some(dir)
+            },
+            _ => None,
+        }
+    }
+
+    /// Set an xattr.
+    pub fn set_xattr(path: &Path, name: &str, value: &[u8]) {
+        let (path, name) = (cpath(path).unwrap(), CString::new(name).unwrap());
+        let ptr = value.as_ptr() as *const libc::c_void;
+        check(unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), ptr, value.len(), 0) }).unwrap();
+    }
+
+    /// Get an xattr.
+    pub fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+        let (path, name) = (cpath(path).unwrap(), CString::new(name).unwrap());
+        let mut value = vec![0u8; 256];
+        let ptr = value.as_mut_ptr() as *mut libc::c_void;
+        let size = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), ptr, value.len()) };
+        if size < 0 {
+            return None;
+        }
+        value.truncate(size as usize);
+        Some(value)
+    }
+
+    /// Set the modification time (secs since the epoch).
+    pub fn set_mtime(path: &Path, secs: i64) {
+        let t = libc::timespec {
+            tv_sec:  secs as libc::time_t,
+            tv_nsec: 0,
+        };
+        let times = [t, t];
+        let path = cpath(path).unwrap();
+        check(unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) }).unwrap();
+    }
+
+    #[test]
+    fn test_move_tree() {
+        let other = match other_fs() {
+            Some(dir) => dir,
+            None => return,
+        };
+        let src = std::env::temp_dir().join(format!("webdav-test-{}", uuid::Uuid::new_v4().to_simple()));
+        fs::create_dir_all(src.join("dir/sub")).unwrap();
+        fs::write(src.join("dir/sub/file"), "data").unwrap();
+        std::os::unix::fs::symlink("sub/file", src.join("dir/link")).unwrap();
+        fs::set_permissions(src.join("dir/sub/file"), fs::Permissions::from_mode(0o440)).unwrap();
+        set_xattr(&src.join("dir/sub/file"), "user.color", b"blue");
+        set_mtime(&src.join("dir/sub/file"), 1_000_000_000);
+        set_mtime(&src.join("dir/sub"), 1_100_000_000);
+
+        move_tree(&src.join("dir"), &other.join("dir")).unwrap();
+        assert!(!src.join("dir").exists());
+        let file = other.join("dir/sub/file");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "data");
+        let meta = fs::metadata(&file).unwrap();
+        assert_eq!(meta.mtime(), 1_000_000_000);
+        assert_eq!(meta.mode() & 0o7777, 0o440);
+        assert_eq!(get_xattr(&file, "user.color").as_deref(), Some(&b"blue"[..]));
+        assert_eq!(fs::metadata(other.join("dir/sub")).unwrap().mtime(), 1_100_000_000);
+        assert_eq!(fs::read_link(other.join("dir/link")).unwrap(), Path::new("sub/file"));
+        // no temporary files left.
+        assert_eq!(fs::read_dir(&other).unwrap().count(), 1);
+
+        // a failed move leaves the source alone.
+        assert!(move_tree(&src, &other.join("nonexistent/dir")).is_err());
+        assert!(src.exists());
+
+        fs::remove_dir_all(&src).unwrap();
+        fs::remove_dir_all(&other).unwrap();
+    }
+}
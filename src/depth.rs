@@ -0,0 +1,145 @@
+//! Strict checking of the Depth: header.
+//!
+//! The webdav handler treats a Depth: header it cannot parse as if there
+//! was none, so "Depth: 2" on a PROPFIND is a depth-infinity request,
+//! and on a COPY a deep copy. Here a value other than "0", "1" or
+//! "infinity" (or more than one Depth: header) is a "400 Bad Request",
+//! and so is a depth that the method does not take (RFC 4918):
+//!
+//! - PROPFIND, REPORT: 0, 1, infinity
+//! - COPY, LOCK: 0, infinity
+//! - MOVE: infinity
+//! - DELETE: 0, infinity. Depth 0 is not in the RFC, the handler allows it
+//!
+//! Other methods do not look at the header. `[server] max-depth` limits
+//! the depth of a PROPFIND and a REPORT further, a deeper request gets
+//! a "403 Forbidden" with `propfind-finite-depth`. A request without a
+//! Depth: header is depth infinity.
+//!
+use http::header::{HeaderMap, HeaderValue};
+use http::{Method, StatusCode};
+
+/// Value of the Depth: header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Depth {
+    Zero,
+    One,
+    Infinity,
+}
+
+const ALL: &[Depth] = &[Depth::Zero, Depth::One, Depth::Infinity];
+const NOT_ONE: &[Depth] = &[Depth::Zero, Depth::Infinity];
+const INFINITY: &[Depth] = &[Depth::Infinity];
+
+impl Depth {
+    /// Depth for `[server] max-depth`.
+    pub fn from_max(max: u32) -> Option<Depth> {
+        match max {
+            0 => Some(Depth::Zero),
+            1 => Some(Depth::One),
+            _ => None,
+        }
+    }
+
+    fn parse(value: &HeaderValue) -> Option<Depth> {
+        match value.as_bytes() {
+            b"0" => Some(Depth::Zero),
+            b"1" => Some(Depth::One),
+            v if v.eq_ignore_ascii_case(b"infinity") => Some(Depth::Infinity),
+            _ => None,
+        }
+    }
+}
+
+// The depths a method takes, `None` if it does not look at the header.
+fn allowed(method: &Method) -> Option<&'static [Depth]> {
+    match method.as_str() {
+        "PROPFIND" | "REPORT" => Some(ALL),
+        "COPY" | "LOCK" | "DELETE" => Some(NOT_ONE),
+        "MOVE" => Some(INFINITY),
+        _ => None,
+    }
+}
+
+/// Check the Depth: header of a request.
+pub fn check(method: &Method, headers: &HeaderMap, max: Option<Depth>) -> Result<(), StatusCode> {
+    let allowed = match allowed(method) {
+        Some(allowed) => allowed,
+        None => return Ok(()),
+    };
+    let mut values = headers.get_all("depth").iter();
+    let depth = match (values.next(), values.next()) {
+        (None, _) => Depth::Infinity,
+        (Some(value), None) => Depth::parse(value).ok_or(StatusCode::BAD_REQUEST)?,
+        (Some(_), Some(_)) => return Err(StatusCode::BAD_REQUEST),
+    };
+    if !allowed.contains(&depth) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    match max {
+        Some(max) if depth > max && allowed == ALL => Err(StatusCode::FORBIDDEN),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn depth(method: &str, value: Option<&str>, max: Option<Depth>) -> Result<(), StatusCode> {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = value {
+            headers.insert("depth", value.parse().unwrap());
+        }
+        check(&Method::from_bytes(method.as_bytes()).unwrap(), &headers, max)
+    }
+
+    #[test]
+    fn test_depth() {
+        let bad = Err(StatusCode::BAD_REQUEST);
+        for value in &["0", "1", "infinity", "Infinity"] {
+            assert_eq!(depth("PROPFIND", Some(value), None), Ok(()));
+            assert_eq!(depth("REPORT", Some(value), None), Ok(()));
+        }
+        for value in &["2", "", "one", "0, 1", " 0"] {
+            assert_eq!(depth("PROPFIND", Some(value), None), bad);
+        }
+
+        for method in &["COPY", "LOCK", "DELETE"] {
+            assert_eq!(depth(method, Some("0"), None), Ok(()));
+            assert_eq!(depth(method, Some("infinity"), None), Ok(()));
+            assert_eq!(depth(method, None, None), Ok(()));
+            assert_eq!(depth(method, Some("1"), None), bad);
+            assert_eq!(depth(method, Some("2"), None), bad);
+        }
+        assert_eq!(depth("MOVE", Some("infinity"), None), Ok(()));
+        assert_eq!(depth("MOVE", None, None), Ok(()));
+        assert_eq!(depth("MOVE", Some("0"), None), bad);
+        assert_eq!(depth("MOVE", Some("1"), None), bad);
+
+        // methods without a depth ignore the header.
+        assert_eq!(depth("GET", Some("2"), None), Ok(()));
+        assert_eq!(depth("PUT", Some("garbage"), None), Ok(()));
+
+        let mut headers = HeaderMap::new();
+        headers.append("depth", "0".parse().unwrap());
+        headers.append("depth", "0".parse().unwrap());
+        assert_eq!(check(&Method::from_bytes(b"PROPFIND").unwrap(), &headers, None), bad);
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let forbidden = Err(StatusCode::FORBIDDEN);
+        let max = Depth::from_max(0);
+        assert_eq!(depth("PROPFIND", Some("0"), max), Ok(()));
+        assert_eq!(depth("PROPFIND", Some("1"), max), forbidden);
+        assert_eq!(depth("PROPFIND", None, max), forbidden);
+        // the limit is only for PROPFIND and REPORT.
+        assert_eq!(depth("COPY", Some("infinity"), max), Ok(()));
+
+        let max = Depth::from_max(1);
+        assert_eq!(depth("REPORT", Some("1"), max), Ok(()));
+        assert_eq!(depth("REPORT", Some("infinity"), max), forbidden);
+        assert!(Depth::from_max(2).is_none());
+    }
+}
@@ -0,0 +1,73 @@
+//! Content-Disposition header.
+//!
+//! Filenames that are not plain ASCII need the RFC 5987 / RFC 6266
+//! `filename*=UTF-8''...` form. Older clients only know `filename="..."`,
+//! so we send both, with a ASCII-only fallback in the legacy form.
+//!
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::config::Disposition;
+
+// RFC 5987 attr-char is ALPHA / DIGIT / "!" / "#" / "$" / "&" / "+" /
+// "-" / "." / "^" / "_" / "`" / "|" / "~". Everything else is encoded.
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// Value of the Content-Disposition header for a file called `name`.
+pub fn header_value(disposition: Disposition, name: &str) -> String {
+    let kind = match disposition {
+        Disposition::Attachment => "attachment",
+        Disposition::Inline => "inline",
+    };
+    // Legacy form: printable ASCII only, no quotes or backslashes.
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    if fallback == name {
+        return format!("{}; filename=\"{}\"", kind, name);
+    }
+    let encoded = utf8_percent_encode(name, ATTR_CHAR);
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}", kind, fallback, encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disposition() {
+        let hdr = |name| header_value(Disposition::Attachment, name);
+        assert_eq!(hdr("report.pdf"), "attachment; filename=\"report.pdf\"");
+        assert_eq!(
+            header_value(Disposition::Inline, "my report.pdf"),
+            "inline; filename=\"my report.pdf\""
+        );
+        assert_eq!(
+            hdr("say \"hi\".txt"),
+            "attachment; filename=\"say _hi_.txt\"; filename*=UTF-8''say%20%22hi%22.txt"
+        );
+        assert_eq!(
+            hdr("报告 2021.doc"),
+            "attachment; filename=\"__ 2021.doc\"; filename*=UTF-8''%E6%8A%A5%E5%91%8A%202021.doc"
+        );
+        assert_eq!(
+            hdr("party 🎉.png"),
+            "attachment; filename=\"party _.png\"; filename*=UTF-8''party%20%F0%9F%8E%89.png"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Error pages for browsers.
+//!
+//! A GET or HEAD that fails gets an HTML page instead of the usual
+//! body if the client asks for HTML (browsers send `Accept: text/html`).
+//! Other methods, and clients that do not ask for HTML (WebDAV clients),
+//! get the normal response. The status code and the other headers of
+//! the response stay the same, except for the ones that describe the
+//! old body. The `Content-Range: bytes */len` of a 416 is kept, it
+//! tells the client the size of the file.
+//!
+//! The pages come from `[error-pages]`, by status code ("404") or by
+//! class ("4xx", "5xx"). The exact code wins. Without a page, a minimal
+//! built-in page is sent. The files are read at startup.
+//!
+use std::collections::HashMap;
+use std::io;
+
+use bytes::Bytes;
+use http::{HeaderMap, StatusCode};
+
+/// The error pages.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    pages: HashMap<String, Bytes>,
+}
+
+/// Is this a valid key for `[error-pages]`.
+pub fn valid_key(key: &str) -> bool {
+    match key {
+        "4xx" | "5xx" => true,
+        _ => key.parse::<u16>().map(|c| (400..600).contains(&c)).unwrap_or(false),
+    }
+}
+
+impl ErrorPages {
+    /// Read the pages from `[error-pages]`.
+    pub fn load(config: &HashMap<String, String>) -> io::Result<ErrorPages> {
+        let mut pages = HashMap::new();
+        for (key, file) in config {
+            let page = std::fs::read(file).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file, e)))?;
+            pages.insert(key.to_string(), Bytes::from(page));
+        }
+        Ok(ErrorPages { pages })
+    }
+
+    /// The page for this status.
+    pub fn page(&self, status: StatusCode) -> Bytes {
+        let class = format!("{}xx", status.as_u16() / 100);
+        match self.pages.get(status.as_str()).or_else(|| self.pages.get(&class)) {
+            Some(page) => page.clone(),
+            None => Bytes::from(builtin(status)),
+        }
+    }
+
+    /// Replace the body of a failed response with the error page.
+    pub fn apply(&self, resp: hyper::Response<hyper::Body>, head: bool) -> hyper::Response<hyper::Body> {
+        let status = resp.status();
+        if !status.is_client_error() && !status.is_server_error() {
+            return resp;
+        }
+        let page = self.page(status);
+        let (mut parts, _) = resp.into_parts();
+        for name in &["content-encoding", "etag", "last-modified"] {
+            parts.headers.remove(*name);
+        }
+        if status != StatusCode::RANGE_NOT_SATISFIABLE {
+            parts.headers.remove("content-range");
+        }
+        parts.headers.insert("content-type", "text/html; charset=utf-8".parse().unwrap());
+        parts.headers.insert("content-length", page.len().into());
+        let body = if head { hyper::Body::empty() } else { hyper::Body::from(page) };
+        hyper::Response::from_parts(parts, body)
+    }
+}
+
+/// Does the client ask for HTML.
+pub fn wants_html(headers: &HeaderMap) -> bool {
+    let accept = headers.get_all("accept").iter().filter_map(|v| v.to_str().ok());
+    accept.flat_map(|v| v.split(',')).any(|item| {
+        let mut params = item.split(';');
+        let mime = params.next().unwrap_or("").trim();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .next()
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        mime.eq_ignore_ascii_case("text/html") && q > 0.0
+    })
+}
+
+// The page when none is configured.
+fn builtin(status: StatusCode) -> String {
+    let title = format!("{} {}", status.as_u16(), status.canonical_reason().unwrap_or(""));
+    format!(
+        concat!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head>\n",
+            "<body><h1>{0}</h1></body></html>\n"
+        ),
+        title.trim_end()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_pages() {
+        let accept = |v: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("accept", v.parse().unwrap());
+            wants_html(&headers)
+        };
+        assert!(accept("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"));
+        assert!(!accept("*/*"));
+        assert!(!accept("text/html;q=0, application/xml"));
+        assert!(!wants_html(&HeaderMap::new()));
+
+        let dir = std::env::temp_dir().join(format!("webdav-test-{}", uuid::Uuid::new_v4().to_simple()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("404.html"), "not here").unwrap();
+        std::fs::write(dir.join("5xx.html"), "oops").unwrap();
+        let mut config = HashMap::new();
+        config.insert("404".to_string(), dir.join("404.html").display().to_string());
+        config.insert("5xx".to_string(), dir.join("5xx.html").display().to_string());
+        let pages = ErrorPages::load(&config).unwrap();
+        assert_eq!(pages.page(StatusCode::NOT_FOUND), "not here");
+        assert_eq!(pages.page(StatusCode::BAD_GATEWAY), "oops");
+        let builtin = pages.page(StatusCode::FORBIDDEN);
+        assert!(String::from_utf8_lossy(&builtin).contains("<h1>403 Forbidden</h1>"));
+
+        config.insert("403".to_string(), dir.join("nonexistent").display().to_string());
+        assert!(ErrorPages::load(&config).is_err());
+        assert!(valid_key("404") && valid_key("5xx"));
+        assert!(!valid_key("200") && !valid_key("3xx") && !valid_key("x"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
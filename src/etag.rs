@@ -0,0 +1,450 @@
+//! Stable ETags.
+//!
+//! LocalFs builds the ETag of a file from its inode number, size and
+//! modification time. The inode number changes when files are restored
+//! from a backup, copied to another server, or when a directory is
+//! served from a different filesystem, and then every client thinks
+//! that every file has changed.
+//!
+//! `EtagFs` wraps LocalFs, and by default leaves the inode number out.
+//! The ETag is then a function of the size and the modification time
+//! only: `SIZE-MTIME` for files and `MTIME` for directories, in hex,
+//! with MTIME in microseconds since the epoch. It survives restarts of
+//! the server and moving the data elsewhere, as long as the modification
+//! times are preserved.
+//!
+//! Since everything goes through it, it also syncs writes to disk for
+//! the `fsync` setting, see `crate::fsync`, and it decides on the
+//! creation time. DAV:creationdate is the birth time of the file where
+//! the filesystem has one (statx on Linux), else the modification time.
+//! The webdav handler would use the ctime, which changes with every
+//! chmod or rename. The handler writes DAV:creationdate as RFC 3339
+//! ("2021-05-17T12:00:00Z") and DAV:getlastmodified as an HTTP-date
+//! ("Mon, 17 May 2021 12:00:00 GMT"), as RFC 4918 says.
+//!
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::FutureExt;
+use futures::StreamExt;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+use webdav_handler::localfs::LocalFs;
+
+use crate::fsync::Fsync;
+
+/// LocalFs, with stable ETags.
+#[derive(Clone)]
+pub struct EtagFs {
+    fs:    LocalFs,
+    inode: bool,
+    fsync: Option<Fsync>,
+}
+
+impl EtagFs {
+    pub fn new(fs: LocalFs) -> EtagFs {
+        EtagFs {
+            fs,
+            inode: false,
+            fsync: None,
+        }
+    }
+
+    /// Include the inode number in the ETag (the LocalFs format).
+    pub fn set_inode(&mut self, inode: bool) {
+        self.inode = inode;
+    }
+
+    /// Sync files and directories to disk after they are changed.
+    pub fn set_fsync(&mut self, fsync: Option<Fsync>) {
+        self.fsync = fsync;
+    }
+
+    /// Run a blocking function, with the uid/gid of the LocalFs.
+    pub async fn blocking<F, R>(&self, func: F) -> R
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.fs.blocking(func).await
+    }
+
+    fn wrap_meta<'a>(&self, fut: FsFuture<'a, Box<dyn DavMetaData>>) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        let inode = self.inode;
+        fut.map(move |res| res.map(|meta| stable_meta(meta, inode))).boxed()
+    }
+
+    // Run `fut`, then sync the `paths` if it succeeded.
+    fn synced<'a, P>(&'a self, fut: FsFuture<'a, ()>, paths: P) -> FsFuture<'a, ()>
+    where P: FnOnce(&Fsync) -> Vec<PathBuf> {
+        let fsync = match self.fsync {
+            Some(ref fsync) => fsync.clone(),
+            None => return fut,
+        };
+        let paths = paths(&fsync);
+        async move {
+            fut.await?;
+            self.fs.blocking(move || fsync.sync(paths)).await.map_err(FsError::from)
+        }
+        .boxed()
+    }
+}
+
+fn stable_meta(meta: Box<dyn DavMetaData>, inode: bool) -> Box<dyn DavMetaData> {
+    Box::new(StableMeta { meta, inode })
+}
+
+impl DavFileSystem for EtagFs {
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.wrap_meta(self.fs.metadata(path))
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.wrap_meta(self.fs.symlink_metadata(path))
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        let inode = self.inode;
+        async move {
+            let entries = self.fs.read_dir(path, meta).await?;
+            let entries = entries.map(move |e| Box::new(StableDirEntry(e, inode)) as Box<dyn DavDirEntry>);
+            Ok(Box::pin(entries) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        let o = &options;
+        let writing = o.write || o.append || o.truncate || o.create || o.create_new;
+        let fsync = self.fsync.clone().filter(|_| writing);
+        async move {
+            let file = self.fs.open(path, options).await?;
+            let mut file: Box<dyn DavFile> = Box::new(StableFile(file, self.inode));
+            if let Some(fsync) = fsync {
+                // a new file is only there after a sync of its directory.
+                let mut paths = vec![fsync.path(path)];
+                if options.create || options.create_new {
+                    paths.push(fsync.parent(path));
+                }
+                file = Box::new(SyncFile {
+                    file,
+                    fs: self.fs.clone(),
+                    fsync,
+                    paths,
+                });
+            }
+            Ok(file)
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.synced(self.fs.create_dir(path), |f| vec![f.parent(path)])
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.synced(self.fs.remove_dir(path), |f| vec![f.parent(path)])
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.synced(self.fs.remove_file(path), |f| vec![f.parent(path)])
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.synced(self.fs.rename(from, to), |f| vec![f.parent(from), f.parent(to)])
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.synced(self.fs.copy(from, to), |f| vec![f.path(to), f.parent(to)])
+    }
+}
+
+// Metadata with an ETag without the inode number (unless `inode`),
+// and a creation time.
+#[derive(Debug, Clone)]
+struct StableMeta {
+    meta:  Box<dyn DavMetaData>,
+    inode: bool,
+}
+
+impl DavMetaData for StableMeta {
+    fn len(&self) -> u64 {
+        self.meta.len()
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        self.meta.modified()
+    }
+
+    fn is_dir(&self) -> bool {
+        self.meta.is_dir()
+    }
+
+    fn is_file(&self) -> bool {
+        self.meta.is_file()
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.meta.is_symlink()
+    }
+
+    fn accessed(&self) -> FsResult<SystemTime> {
+        self.meta.accessed()
+    }
+
+    fn created(&self) -> FsResult<SystemTime> {
+        self.meta.created().or_else(|_| self.meta.modified())
+    }
+
+    fn status_changed(&self) -> FsResult<SystemTime> {
+        self.meta.status_changed()
+    }
+
+    fn executable(&self) -> FsResult<bool> {
+        self.meta.executable()
+    }
+
+    fn etag(&self) -> Option<String> {
+        if self.inode {
+            return self.meta.etag();
+        }
+        let t = self.meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        let t = t.as_secs() * 1000000 + t.subsec_micros() as u64;
+        if self.meta.is_file() {
+            Some(format!("{:x}-{:x}", self.meta.len(), t))
+        } else {
+            Some(format!("{:x}", t))
+        }
+    }
+}
+
+struct StableDirEntry(Box<dyn DavDirEntry>, bool);
+
+impl DavDirEntry for StableDirEntry {
+    fn name(&self) -> Vec<u8> {
+        self.0.name()
+    }
+
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        let inode = self.1;
+        self.0.metadata().map(move |res| res.map(|meta| stable_meta(meta, inode))).boxed()
+    }
+
+    fn is_dir(&self) -> FsFuture<'_, bool> {
+        self.0.is_dir()
+    }
+
+    fn is_file(&self) -> FsFuture<'_, bool> {
+        self.0.is_file()
+    }
+
+    fn is_symlink(&self) -> FsFuture<'_, bool> {
+        self.0.is_symlink()
+    }
+}
+
+#[derive(Debug)]
+struct StableFile(Box<dyn DavFile>, bool);
+
+impl DavFile for StableFile {
+    fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        let inode = self.1;
+        self.0.metadata().map(move |res| res.map(|meta| stable_meta(meta, inode))).boxed()
+    }
+
+    fn write_buf(&mut self, buf: Box<dyn bytes::Buf + Send>) -> FsFuture<'_, ()> {
+        self.0.write_buf(buf)
+    }
+
+    fn write_bytes(&mut self, buf: bytes::Bytes) -> FsFuture<'_, ()> {
+        self.0.write_bytes(buf)
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, bytes::Bytes> {
+        self.0.read_bytes(count)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        self.0.seek(pos)
+    }
+
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        self.0.flush()
+    }
+}
+
+// A file opened for writing, synced when it is closed.
+struct SyncFile {
+    file:  Box<dyn DavFile>,
+    fs:    LocalFs,
+    fsync: Fsync,
+    paths: Vec<PathBuf>,
+}
+
+impl std::fmt::Debug for SyncFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SyncFile").field("paths", &self.paths).finish()
+    }
+}
+
+impl DavFile for SyncFile {
+    fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        self.file.metadata()
+    }
+
+    fn write_buf(&mut self, buf: Box<dyn bytes::Buf + Send>) -> FsFuture<'_, ()> {
+        self.file.write_buf(buf)
+    }
+
+    fn write_bytes(&mut self, buf: bytes::Bytes) -> FsFuture<'_, ()> {
+        self.file.write_bytes(buf)
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, bytes::Bytes> {
+        self.file.read_bytes(count)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        self.file.seek(pos)
+    }
+
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        async move {
+            self.file.flush().await?;
+            let (fsync, paths) = (self.fsync.clone(), self.paths.clone());
+            self.fs.blocking(move || fsync.sync(paths)).await.map_err(FsError::from)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::stat::utimes;
+    use nix::sys::time::{TimeVal, TimeValLike};
+
+    async fn etag(fs: &EtagFs, path: &str) -> String {
+        let path = DavPath::new(path).unwrap();
+        fs.metadata(&path).await.unwrap().etag().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_stable_etag() {
+        let tmp = std::env::temp_dir().join(format!("webdav-test-{}", uuid::Uuid::new_v4().to_simple()));
+        let (dir1, dir2) = (tmp.join("1"), tmp.join("2"));
+        std::fs::create_dir_all(&dir1).unwrap();
+        std::fs::create_dir_all(&dir2).unwrap();
+        std::fs::write(dir1.join("file"), "hello").unwrap();
+
+        // A copy of the file, with the same mtime, but a different inode.
+        std::fs::copy(dir1.join("file"), dir2.join("file")).unwrap();
+        let mtime = TimeVal::microseconds(1_600_000_000_123_456);
+        utimes(&dir1.join("file"), &mtime, &mtime).unwrap();
+        utimes(&dir2.join("file"), &mtime, &mtime).unwrap();
+
+        // "restart": a new instance, serving the copy.
+        let fs1 = EtagFs::new(*LocalFs::new(&dir1, false, false, false));
+        let fs2 = EtagFs::new(*LocalFs::new(&dir2, false, false, false));
+        assert_eq!(etag(&fs1, "/file").await, "5-5af3107a5e240");
+        assert_eq!(etag(&fs1, "/file").await, etag(&fs2, "/file").await);
+
+        // the LocalFs format includes the inode.
+        let (mut fs1, mut fs2) = (fs1, fs2);
+        fs1.set_inode(true);
+        fs2.set_inode(true);
+        assert_ne!(etag(&fs1, "/file").await, etag(&fs2, "/file").await);
+
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fsync() {
+        use crate::fsync::tests::Recorder;
+        use std::sync::Arc;
+
+        let tmp = std::env::temp_dir().join(format!("webdav-test-{}", uuid::Uuid::new_v4().to_simple()));
+        std::fs::create_dir_all(&tmp).unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let mut fs = EtagFs::new(*LocalFs::new(&tmp, false, false, false));
+        fs.set_fsync(Some(Fsync::with_syncer(&tmp, recorder.clone())));
+        let synced = || std::mem::take(&mut *recorder.0.lock().unwrap());
+        let path = |p: &str| DavPath::new(p).unwrap();
+
+        fs.create_dir(&path("/dir")).await.unwrap();
+        assert_eq!(synced(), vec![tmp.clone()]);
+
+        // the file, then its directory, when it is closed.
+        let options = OpenOptions {
+            write: true,
+            create: true,
+            ..OpenOptions::default()
+        };
+        let mut file = fs.open(&path("/dir/file"), options).await.unwrap();
+        file.write_bytes(bytes::Bytes::from("hello")).await.unwrap();
+        assert!(synced().is_empty());
+        file.flush().await.unwrap();
+        assert_eq!(synced(), vec![tmp.join("dir/file"), tmp.join("dir")]);
+
+        // reading does not sync.
+        let read = OpenOptions {
+            read: true,
+            ..OpenOptions::default()
+        };
+        let mut file = fs.open(&path("/dir/file"), read).await.unwrap();
+        file.flush().await.unwrap();
+        assert!(synced().is_empty());
+
+        fs.rename(&path("/dir/file"), &path("/file")).await.unwrap();
+        assert_eq!(synced(), vec![tmp.join("dir"), tmp.clone()]);
+        fs.copy(&path("/file"), &path("/dir/copy")).await.unwrap();
+        assert_eq!(synced(), vec![tmp.join("dir/copy"), tmp.join("dir")]);
+        fs.remove_file(&path("/dir/copy")).await.unwrap();
+        fs.remove_dir(&path("/dir")).await.unwrap();
+        assert_eq!(synced(), vec![tmp.join("dir"), tmp.clone()]);
+
+        // failures are not synced, and without fsync nothing is.
+        assert!(fs.remove_dir(&path("/dir")).await.is_err());
+        fs.set_fsync(None);
+        fs.remove_file(&path("/file")).await.unwrap();
+        assert!(synced().is_empty());
+
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn test_created() {
+        // a filesystem without a birth time.
+        #[derive(Debug, Clone)]
+        struct NoBirthTime;
+
+        impl DavMetaData for NoBirthTime {
+            fn len(&self) -> u64 {
+                0
+            }
+
+            fn modified(&self) -> FsResult<SystemTime> {
+                Ok(UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000))
+            }
+
+            fn is_dir(&self) -> bool {
+                false
+            }
+
+            fn status_changed(&self) -> FsResult<SystemTime> {
+                Ok(UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000))
+            }
+        }
+
+        for inode in &[false, true] {
+            let meta = stable_meta(Box::new(NoBirthTime), *inode);
+            assert_eq!(meta.created().unwrap(), meta.modified().unwrap());
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Filesystem errors to HTTP status codes.
+//!
+//! For the methods that it implements, the webdav handler turns
+//! filesystem errors into a status code itself (through `FsError`).
+//! The responses that we generate ourselves (like the localized autoindex)
+//! use the mapping here, which follows the same rules:
+//!
+//! - ENOENT: 404 Not Found
+//! - EACCES, EPERM, EROFS, EISDIR: 403 Forbidden
+//! - ENOSPC, EDQUOT, EMLINK: 507 Insufficient Storage
+//! - ENAMETOOLONG: 414 URI Too Long
+//! - EEXIST, ENOTEMPTY: 405 for MKCOL, 412 for PUT, COPY and MOVE, 409 otherwise
+//! - ENOTDIR: 409 Conflict when creating something, 404 otherwise
+//!
+use std::io;
+
+use http::{Method, StatusCode};
+use webdav_handler::fs::FsError;
+
+/// Status code for an I/O error during a request with this method.
+pub fn io_status(e: &io::Error, method: &Method) -> StatusCode {
+    let errno = match e.raw_os_error() {
+        Some(errno) => errno,
+        None => {
+            return match e.kind() {
+                io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                io::ErrorKind::AlreadyExists => exists_status(method),
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+        },
+    };
+    match errno {
+        libc::ENOENT => StatusCode::NOT_FOUND,
+        libc::EACCES | libc::EPERM | libc::EROFS | libc::EISDIR => StatusCode::FORBIDDEN,
+        libc::ENOSPC | libc::EDQUOT | libc::EMLINK => StatusCode::INSUFFICIENT_STORAGE,
+        libc::ENAMETOOLONG => StatusCode::URI_TOO_LONG,
+        libc::EEXIST | libc::ENOTEMPTY => exists_status(method),
+        libc::ENOTDIR if creates(method) => StatusCode::CONFLICT,
+        libc::ENOTDIR => StatusCode::NOT_FOUND,
+        libc::EFBIG => StatusCode::PAYLOAD_TOO_LARGE,
+        libc::ELOOP => StatusCode::LOOP_DETECTED,
+        libc::EXDEV => StatusCode::BAD_GATEWAY,
+        libc::ENOSYS | libc::EOPNOTSUPP => StatusCode::NOT_IMPLEMENTED,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Status code for an error from a `DavFileSystem`.
+pub fn fs_status(e: &FsError, method: &Method) -> StatusCode {
+    match e {
+        FsError::NotImplemented => StatusCode::NOT_IMPLEMENTED,
+        FsError::GeneralFailure => StatusCode::INTERNAL_SERVER_ERROR,
+        FsError::Exists => exists_status(method),
+        FsError::NotFound => StatusCode::NOT_FOUND,
+        FsError::Forbidden => StatusCode::FORBIDDEN,
+        FsError::InsufficientStorage => StatusCode::INSUFFICIENT_STORAGE,
+        FsError::LoopDetected => StatusCode::LOOP_DETECTED,
+        FsError::PathTooLong => StatusCode::URI_TOO_LONG,
+        FsError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        FsError::IsRemote => StatusCode::BAD_GATEWAY,
+    }
+}
+
+// Something is in the way.
+fn exists_status(method: &Method) -> StatusCode {
+    match method.as_str() {
+        "MKCOL" => StatusCode::METHOD_NOT_ALLOWED,
+        "PUT" | "COPY" | "MOVE" => StatusCode::PRECONDITION_FAILED,
+        _ => StatusCode::CONFLICT,
+    }
+}
+
+// Methods that create a resource.
+fn creates(method: &Method) -> bool {
+    matches!(method.as_str(), "MKCOL" | "PUT" | "COPY" | "MOVE")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_status() {
+        let status = |errno, method: &str| {
+            let method = Method::from_bytes(method.as_bytes()).unwrap();
+            io_status(&io::Error::from_raw_os_error(errno), &method)
+        };
+        assert_eq!(status(libc::ENOENT, "GET"), StatusCode::NOT_FOUND);
+        assert_eq!(status(libc::EACCES, "GET"), StatusCode::FORBIDDEN);
+        assert_eq!(status(libc::EPERM, "DELETE"), StatusCode::FORBIDDEN);
+        assert_eq!(status(libc::EROFS, "PUT"), StatusCode::FORBIDDEN);
+        assert_eq!(status(libc::EISDIR, "PUT"), StatusCode::FORBIDDEN);
+        assert_eq!(status(libc::ENOSPC, "PUT"), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(status(libc::EDQUOT, "PUT"), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(status(libc::EMLINK, "MKCOL"), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(status(libc::ENAMETOOLONG, "GET"), StatusCode::URI_TOO_LONG);
+        assert_eq!(status(libc::EEXIST, "MKCOL"), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(status(libc::EEXIST, "PUT"), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(status(libc::EEXIST, "MOVE"), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(status(libc::ENOTEMPTY, "DELETE"), StatusCode::CONFLICT);
+        assert_eq!(status(libc::ENOTDIR, "MKCOL"), StatusCode::CONFLICT);
+        assert_eq!(status(libc::ENOTDIR, "GET"), StatusCode::NOT_FOUND);
+        assert_eq!(status(libc::EFBIG, "PUT"), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(status(libc::ELOOP, "GET"), StatusCode::LOOP_DETECTED);
+        assert_eq!(status(libc::EXDEV, "MOVE"), StatusCode::BAD_GATEWAY);
+        assert_eq!(status(libc::ENOSYS, "GET"), StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(status(libc::EIO, "GET"), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // not an OS error.
+        let e = io::Error::new(io::ErrorKind::NotFound, "gone");
+        assert_eq!(io_status(&e, &Method::GET), StatusCode::NOT_FOUND);
+        let e = io::Error::new(io::ErrorKind::InvalidData, "?");
+        assert_eq!(io_status(&e, &Method::GET), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // FsError follows the same rules.
+        assert_eq!(fs_status(&FsError::Exists, &Method::PUT), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(fs_status(&FsError::PathTooLong, &Method::GET), StatusCode::URI_TOO_LONG);
+    }
+}
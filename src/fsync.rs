@@ -0,0 +1,126 @@
+//! Durable writes.
+//!
+//! Normally a PUT is done when the data is in the page cache of the
+//! kernel. If the power fails right after the "201 Created", the file
+//! can be empty or missing after the reboot. With `fsync = true` in a
+//! location, the data and the directory entries are synced to disk
+//! before the client gets a response:
+//!
+//! - a file that was written is synced when it is closed, so before
+//!   an upload filter or a chunked upload renames it to the target,
+//!   and if it was opened with "create", its directory is synced too,
+//! - after a rename (MOVE, and the renames above), a copy, a delete
+//!   or a MKCOL, the directories that changed are synced.
+//!
+//! The file is opened again to sync it. On Linux fsync() flushes the
+//! file, not only the data written through that descriptor.
+//!
+//! This is slow: every PUT waits for the disk at least twice, and a
+//! DELETE of a directory syncs it once for every entry.
+//!
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use webdav_handler::davpath::DavPath;
+
+/// What actually syncs a file or directory.
+pub trait Syncer: Send + Sync {
+    fn sync(&self, path: &Path) -> io::Result<()>;
+}
+
+struct FileSync;
+
+impl Syncer for FileSync {
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+}
+
+/// The files and directories under `basedir` to sync.
+#[derive(Clone)]
+pub struct Fsync {
+    basedir: PathBuf,
+    syncer:  Arc<dyn Syncer>,
+}
+
+impl std::fmt::Debug for Fsync {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Fsync").field("basedir", &self.basedir).finish()
+    }
+}
+
+impl Fsync {
+    pub fn new(basedir: impl AsRef<Path>) -> Fsync {
+        Fsync::with_syncer(basedir, Arc::new(FileSync))
+    }
+
+    /// Sync with something else than fsync().
+    pub fn with_syncer(basedir: impl AsRef<Path>, syncer: Arc<dyn Syncer>) -> Fsync {
+        Fsync {
+            basedir: basedir.as_ref().to_path_buf(),
+            syncer,
+        }
+    }
+
+    /// The path of `path` on the filesystem.
+    pub fn path(&self, path: &DavPath) -> PathBuf {
+        self.basedir.join(path.as_rel_ospath())
+    }
+
+    /// The directory that `path` is in, `basedir` for the root itself.
+    pub fn parent(&self, path: &DavPath) -> PathBuf {
+        match path.as_rel_ospath().parent() {
+            Some(dir) => self.basedir.join(dir),
+            None => self.basedir.clone(),
+        }
+    }
+
+    /// Sync `paths`, in order. A directory that is in the list twice in
+    /// a row (a rename within a directory) is synced once.
+    pub fn sync(&self, mut paths: Vec<PathBuf>) -> io::Result<()> {
+        paths.dedup();
+        for path in &paths {
+            if let Err(e) = self.syncer.sync(path) {
+                error!("fsync {:?}: {}", path, e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A syncer that only remembers what it synced.
+    #[derive(Default)]
+    pub struct Recorder(pub Mutex<Vec<PathBuf>>);
+
+    impl Syncer for Recorder {
+        fn sync(&self, path: &Path) -> io::Result<()> {
+            self.0.lock().unwrap().push(path.to_path_buf());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_fsync() {
+        let recorder = Arc::new(Recorder::default());
+        let fsync = Fsync::with_syncer("/srv", recorder.clone());
+        let path = DavPath::new("/dir/file").unwrap();
+        assert_eq!(fsync.path(&path), Path::new("/srv/dir/file"));
+        assert_eq!(fsync.parent(&path), Path::new("/srv/dir"));
+        assert_eq!(fsync.parent(&DavPath::new("/").unwrap()), Path::new("/srv"));
+        let paths = vec![fsync.parent(&path), fsync.parent(&path), "/srv".into()];
+        fsync.sync(paths).unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), vec![PathBuf::from("/srv/dir"), "/srv".into()]);
+
+        let dir = std::env::temp_dir();
+        assert!(Fsync::new(&dir).sync(vec![dir.clone()]).is_ok());
+        assert!(Fsync::new(&dir).sync(vec![dir.join("nonexistent")]).is_err());
+    }
+}
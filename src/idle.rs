@@ -0,0 +1,487 @@
+//! Idle connection reaper.
+//!
+//! Every accepted connection is registered with the reaper, and remembers
+//! when it last sent or received data. A background task closes connections
+//! that have been idle (no bytes in either direction) for longer than
+//! `idle-timeout`. That is about abandoned keep-alive connections, it is
+//! not a request timeout: a request that keeps the connection idle for a
+//! long time while we are working on it (a huge PROPFIND) is closed too.
+//!
+//! A connection leaves the registry when it is dropped, whether it was
+//! reaped or closed normally.
+//!
+//! On shutdown, `drain` closes the connections that are between requests
+//! (idle keep-alive connections) right away. Connections with a request in
+//! flight get to finish it, unless no data goes back and forth for
+//! `shutdown-conn-timeout`, or `shutdown-timeout` has passed. A request is
+//! in flight from the moment it comes in until its response body has been
+//! sent, see `Requests`.
+//!
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use hyper::body::HttpBody;
+use hyper::server::accept::Accept;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::context::CancelToken;
+
+/// The reaper. Clones share the same registry.
+#[derive(Clone)]
+pub struct IdleReaper {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    timeout: Option<Duration>,
+    start:   Instant,
+    next_id: AtomicU64,
+    reaped:  AtomicU64,
+    conns:   Mutex<HashMap<u64, Arc<ConnState>>>,
+}
+
+struct ConnState {
+    // milliseconds since `Inner::start`.
+    last_active: AtomicU64,
+    reaped:      AtomicBool,
+    requests:    AtomicUsize,
+    waker:       Mutex<Option<Waker>>,
+}
+
+impl ConnState {
+    // Close the connection: the next read or write fails. Returns
+    // false if it was closed already.
+    fn close(&self) -> bool {
+        if self.reaped.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+        true
+    }
+}
+
+/// Counters, for the admin API.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IdleStats {
+    pub open:   usize,
+    pub reaped: u64,
+}
+
+/// What `drain` did with the connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DrainStats {
+    /// Closed right away, there was no request in flight.
+    pub idle:    usize,
+    /// Finished their requests.
+    pub drained: usize,
+    /// Closed after `conn_timeout` without any data.
+    pub stalled: usize,
+    /// Still busy when the total timeout passed.
+    pub cut_off: usize,
+}
+
+impl IdleReaper {
+    /// New reaper. Without a timeout, connections are only counted.
+    pub fn new(timeout: Option<Duration>) -> IdleReaper {
+        IdleReaper {
+            inner: Arc::new(Inner {
+                timeout,
+                start: Instant::now(),
+                next_id: AtomicU64::new(0),
+                reaped: AtomicU64::new(0),
+                conns: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Register a connection.
+    pub fn wrap<S>(&self, stream: S) -> IdleStream<S> {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let state = Arc::new(ConnState {
+            last_active: AtomicU64::new(self.inner.now()),
+            reaped:      AtomicBool::new(false),
+            requests:    AtomicUsize::new(0),
+            waker:       Mutex::new(None),
+        });
+        self.inner.conns.lock().unwrap().insert(id, state.clone());
+        IdleStream {
+            stream,
+            id,
+            state,
+            reaper: self.inner.clone(),
+        }
+    }
+
+    /// Wrap an acceptor, so that all its connections are registered.
+    pub fn incoming<A>(&self, incoming: A) -> IdleIncoming<A> {
+        IdleIncoming {
+            incoming,
+            reaper: self.clone(),
+        }
+    }
+
+    pub fn stats(&self) -> IdleStats {
+        IdleStats {
+            open:   self.inner.conns.lock().unwrap().len(),
+            reaped: self.inner.reaped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Close connections that have been idle for too long. Returns the
+    /// number of connections that were closed.
+    fn reap(&self, now: u64) -> usize {
+        let timeout = match self.inner.timeout {
+            Some(t) => t.as_millis() as u64,
+            None => return 0,
+        };
+        let conns = self.inner.conns.lock().unwrap();
+        let mut count = 0;
+        for state in conns.values() {
+            let idle = now.saturating_sub(state.last_active.load(Ordering::Relaxed));
+            if idle >= timeout && state.close() {
+                count += 1;
+            }
+        }
+        self.inner.reaped.fetch_add(count as u64, Ordering::Relaxed);
+        count
+    }
+
+    /// The background task.
+    pub async fn run(self) {
+        let timeout = match self.inner.timeout {
+            Some(t) => t,
+            None => return,
+        };
+        let mut interval = tokio::time::interval((timeout / 4).max(Duration::from_secs(1)));
+        loop {
+            interval.tick().await;
+            let count = self.reap(self.inner.now());
+            if count > 0 {
+                debug!("idle: closed {} idle connections", count);
+            }
+        }
+    }
+
+    /// Close all connections, for a shutdown. Connections without a
+    /// request in flight are closed right away, the others when they are
+    /// done, or when they have not sent or received anything for
+    /// `conn_timeout`. Whatever is left after `timeout` is closed as well.
+    ///
+    /// `shutdown` (which stops the listeners) is cancelled when all
+    /// connections have been looked at once, so that the ones that hyper
+    /// closes by itself are counted too.
+    pub async fn drain(
+        &self,
+        conn_timeout: Duration,
+        timeout: Duration,
+        shutdown: &CancelToken,
+    ) -> DrainStats
+    {
+        let deadline = Instant::now() + timeout;
+        let conn_timeout = conn_timeout.as_millis() as u64;
+        let mut stats = DrainStats::default();
+        // the connections that we are waiting for.
+        let mut active = HashSet::new();
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            if shutdown.is_cancelled() {
+                interval.tick().await;
+            }
+            let now = self.inner.now();
+            let conns = self.inner.conns.lock().unwrap();
+            active.retain(|id| {
+                let open = conns.contains_key(id);
+                if !open {
+                    stats.drained += 1;
+                }
+                open
+            });
+            for (id, state) in conns.iter() {
+                if state.reaped.load(Ordering::SeqCst) {
+                    continue;
+                }
+                if state.requests.load(Ordering::SeqCst) == 0 {
+                    if state.close() {
+                        if active.remove(id) {
+                            stats.drained += 1;
+                        } else {
+                            stats.idle += 1;
+                        }
+                    }
+                } else if now.saturating_sub(state.last_active.load(Ordering::Relaxed)) >= conn_timeout {
+                    if state.close() {
+                        active.remove(id);
+                        stats.stalled += 1;
+                    }
+                } else {
+                    active.insert(*id);
+                }
+            }
+            shutdown.cancel();
+            if active.is_empty() {
+                return stats;
+            }
+            if Instant::now() >= deadline {
+                for id in active.drain() {
+                    if conns.get(&id).map(|state| state.close()).unwrap_or(false) {
+                        stats.cut_off += 1;
+                    }
+                }
+                return stats;
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+}
+
+/// A connection, registered with the reaper.
+pub struct IdleStream<S> {
+    stream: S,
+    id:     u64,
+    state:  Arc<ConnState>,
+    reaper: Arc<Inner>,
+}
+
+impl<S> IdleStream<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// The requests on this connection.
+    pub fn requests(&self) -> Requests {
+        Requests(self.state.clone())
+    }
+
+    fn touch(&self) {
+        self.state.last_active.store(self.reaper.now(), Ordering::Relaxed);
+    }
+
+    fn check(&self, cx: &mut Context<'_>) -> io::Result<()> {
+        if self.state.reaped.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "idle connection closed"));
+        }
+        *self.state.waker.lock().unwrap() = Some(cx.waker().clone());
+        Ok(())
+    }
+}
+
+impl<S> Drop for IdleStream<S> {
+    fn drop(&mut self) {
+        self.reaper.conns.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.check(cx)?;
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            this.touch();
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.check(cx)?;
+        let res = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if n > 0 {
+                this.touch();
+            }
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Counts the requests in flight on a connection.
+#[derive(Clone)]
+pub struct Requests(Arc<ConnState>);
+
+impl Requests {
+    /// A request came in. It is in flight until the `InFlight` is dropped.
+    pub fn start(&self) -> InFlight {
+        self.0.requests.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.0.clone())
+    }
+}
+
+/// A request in flight.
+pub struct InFlight(Arc<ConnState>);
+
+impl InFlight {
+    /// Keep the request in flight until this body has been sent.
+    pub fn body(self, body: hyper::Body) -> InFlightBody {
+        InFlightBody { body, _flight: self }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.requests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The body of a response, while the request is in flight.
+pub struct InFlightBody {
+    body:    hyper::Body,
+    _flight: InFlight,
+}
+
+impl HttpBody for InFlightBody {
+    type Data = bytes::Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>>
+    {
+        Pin::new(&mut self.get_mut().body).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>>
+    {
+        Pin::new(&mut self.get_mut().body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// An acceptor that registers its connections with the reaper.
+pub struct IdleIncoming<A> {
+    incoming: A,
+    reaper:   IdleReaper,
+}
+
+impl<A: Accept + Unpin> Accept for IdleIncoming<A> {
+    type Conn = IdleStream<A::Conn>;
+    type Error = A::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>>
+    {
+        let this = self.get_mut();
+        match Pin::new(&mut this.incoming).poll_accept(cx) {
+            Poll::Ready(Some(Ok(conn))) => Poll::Ready(Some(Ok(this.reaper.wrap(conn)))),
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<A: Accept + Unpin> tls_listener::AsyncAccept for IdleIncoming<A> {
+    type Connection = IdleStream<A::Conn>;
+    type Error = A::Error;
+
+    fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Self::Connection, Self::Error>> {
+        match Accept::poll_accept(self, cx) {
+            Poll::Ready(Some(res)) => Poll::Ready(res),
+            // AddrIncoming never ends.
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_idle_reaper() {
+        let reaper = IdleReaper::new(Some(Duration::from_secs(10)));
+        let (a, mut b) = tokio::io::duplex(64);
+        let mut a = reaper.wrap(a);
+        assert_eq!(reaper.stats(), IdleStats { open: 1, reaped: 0 });
+
+        // active: not reaped.
+        b.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(reaper.reap(reaper.inner.now() + 5000), 0);
+
+        // idle for 10 seconds: a read that is waiting gets an error.
+        let reader = tokio::spawn(async move { a.read(&mut buf).await });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(reaper.reap(reaper.inner.now() + 10000), 1);
+        let err = reader.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // the connection is gone from the registry.
+        assert_eq!(reaper.stats(), IdleStats { open: 0, reaped: 1 });
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let reaper = IdleReaper::new(None);
+        let mut conns = Vec::new();
+        for _ in 0..4 {
+            let (a, b) = tokio::io::duplex(64);
+            conns.push((reaper.wrap(a), b));
+        }
+        let (idle, done, stalled, busy) = (&conns[0].0, &conns[1].0, &conns[2].0, &conns[3].0);
+        let done_req = done.requests().start();
+        let _stalled_req = stalled.requests().start();
+        let busy_req = busy.requests().start();
+        let (idle_state, stalled_state) = (idle.state.clone(), stalled.state.clone());
+
+        // one connection finishes its request, one keeps sending data.
+        let busy_state = busy.state.clone();
+        let inner = reaper.inner.clone();
+        let keepalive = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            drop(done_req);
+            loop {
+                busy_state.last_active.store(inner.now(), Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+        let shutdown = CancelToken::default();
+        let stats = reaper.drain(Duration::from_millis(400), Duration::from_millis(1000), &shutdown).await;
+        keepalive.abort();
+        drop(busy_req);
+
+        assert_eq!(stats, DrainStats { idle: 1, drained: 1, stalled: 1, cut_off: 1 });
+        assert!(shutdown.is_cancelled());
+        assert!(idle_state.reaped.load(Ordering::SeqCst));
+        assert!(stalled_state.reaped.load(Ordering::SeqCst));
+        assert!(conns.iter().all(|(c, _)| c.state.reaped.load(Ordering::SeqCst)));
+    }
+}
@@ -0,0 +1,119 @@
+//! Source address allow and deny lists.
+//!
+//! A coarse network ACL for the whole server, checked before anything
+//! else (authentication, the admin API, the locations). `allow-from` and
+//! `deny-from` in `[server]` are lists of addresses and CIDR prefixes
+//! ("192.0.2.0/24", "2001:db8::/32", "127.0.0.1").
+//!
+//! - only `deny-from`: everyone is allowed, except those in it.
+//! - `allow-from`: nobody is allowed, except those in it, and then not
+//!   those in `deny-from` (to make a hole in an allowed network).
+//!
+//! A refused client gets "403 Forbidden", and the connection is closed.
+//!
+//! The address is that of the client, or for a request through a reverse
+//! proxy on the same host (a loopback address), the first address in
+//! X-Forwarded-For, as for PAM's rhost.
+//!
+use std::net::IpAddr;
+
+use crate::auth::canonical_ip;
+
+/// An address prefix, like 192.0.2.0/24.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    len:  u8,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Result<Cidr, String> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr = canonical_ip(addr.trim().parse::<IpAddr>().map_err(|_| "not an address".to_string())?);
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(len) => len.trim().parse::<u8>().ok().filter(|l| *l <= max).ok_or("invalid prefix length")?,
+            None => max,
+        };
+        Ok(Cidr { addr, len })
+    }
+
+    /// Is `ip` in this prefix.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical_ip(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix(u32::from(net).into(), u32::from(ip).into(), self.len + 96)
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix(net.into(), ip.into(), self.len),
+            _ => false,
+        }
+    }
+}
+
+// Are the first `len` of the 128 bits the same.
+fn prefix(net: u128, ip: u128, len: u8) -> bool {
+    len == 0 || (net ^ ip) >> (128 - len as u32) == 0
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IpAcl {
+    allow: Option<Vec<Cidr>>,
+    deny:  Vec<Cidr>,
+}
+
+impl IpAcl {
+    /// The lists of `allow-from` and `deny-from`.
+    pub fn new(allow: &[String], deny: &[String]) -> Result<IpAcl, String> {
+        let parse = |list: &[String]| {
+            list.iter()
+                .map(|s| Cidr::parse(s).map_err(|e| format!("{:?}: {}", s, e)))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let allow = Some(parse(allow)?).filter(|a| !a.is_empty());
+        Ok(IpAcl {
+            allow,
+            deny: parse(deny)?,
+        })
+    }
+
+    /// May `ip` use the server.
+    pub fn allowed(&self, ip: IpAddr) -> bool {
+        let allowed = match self.allow {
+            Some(ref allow) => allow.iter().any(|c| c.contains(ip)),
+            None => true,
+        };
+        allowed && !self.deny.iter().any(|c| c.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ipacl() {
+        let net = Cidr::parse("192.0.2.0/24").unwrap();
+        assert!(net.contains(ip("192.0.2.77")) && net.contains(ip("::ffff:192.0.2.1")));
+        assert!(!net.contains(ip("192.0.3.1")) && !net.contains(ip("2001:db8::1")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("203.0.113.9")));
+        assert!(Cidr::parse("2001:db8::/32").unwrap().contains(ip("2001:db8:ffff::1")));
+        assert!(!Cidr::parse("2001:db8::1").unwrap().contains(ip("2001:db8::2")));
+        assert!(Cidr::parse("192.0.2.0/33").is_err() && Cidr::parse("example.com").is_err());
+
+        let strings = |l: &[&str]| l.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let deny = IpAcl::new(&[], &strings(&["10.0.0.0/8"])).unwrap();
+        assert!(!deny.allowed(ip("10.1.2.3")) && deny.allowed(ip("192.0.2.1")));
+        let allow = IpAcl::new(&strings(&["10.0.0.0/8"]), &strings(&["10.9.0.0/16"])).unwrap();
+        assert!(allow.allowed(ip("10.1.2.3")) && !allow.allowed(ip("10.9.1.1")));
+        assert!(!allow.allowed(ip("192.0.2.1")));
+        assert!(IpAcl::default().allowed(ip("192.0.2.1")));
+        assert!(IpAcl::new(&strings(&["nope"]), &[]).is_err());
+    }
+}
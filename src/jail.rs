@@ -0,0 +1,263 @@
+//! Chroot jail.
+//!
+//! With `chroot` in `[server]`, the server does a chroot() to a directory
+//! after it has set up the listeners, and then drops root. Anything that
+//! is opened after that is looked up inside the jail, and two things are
+//! opened at request time by libraries, not by the server itself:
+//!
+//! - NSS (acct-type = "unix"). The NSS modules and nsswitch.conf are
+//!   loaded at the first lookup and stay loaded, so we do a lookup before
+//!   the chroot. What they then read (/etc/passwd, /etc/group) has to be
+//!   in the jail.
+//! - PAM. Every authentication reads /etc/pam.d/SERVICE and loads the
+//!   modules in it again, so all of those have to be in the jail.
+//!
+//! `check` looks for them before the chroot, so that a jail that misses
+//! any fails at startup instead of at the first login. After it, the
+//! capability bounding set is emptied and, once the uid is switched,
+//! checked that no capability is left.
+//!
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Where libpam looks for modules that are not given with a full path.
+const PAM_MODULE_DIRS: &[&str] = &[
+    "/lib/security",
+    "/lib64/security",
+    "/usr/lib/security",
+    "/usr/lib64/security",
+    "/lib/x86_64-linux-gnu/security",
+    "/usr/lib/x86_64-linux-gnu/security",
+    "/lib/aarch64-linux-gnu/security",
+    "/usr/lib/aarch64-linux-gnu/security",
+    "/lib/i386-linux-gnu/security",
+    "/usr/lib/i386-linux-gnu/security",
+    "/lib/arm-linux-gnueabihf/security",
+    "/usr/lib/arm-linux-gnueabihf/security",
+];
+
+/// Check that `jail` has what NSS (`unix`) and PAM (`pam_service`) need.
+/// Returns what is missing, as paths inside the jail.
+pub fn check(jail: &Path, unix: bool, pam_service: Option<&str>) -> Result<(), String> {
+    let mut missing = Vec::new();
+    if unix {
+        for file in &["/etc/passwd", "/etc/group"] {
+            if !exists(jail, Path::new(file)) {
+                missing.push(file.to_string());
+            }
+        }
+    }
+    if let Some(service) = pam_service {
+        let mut seen = HashSet::new();
+        // without a file of its own, the service gets "other".
+        let own = exists(jail, &pam_file(service)) || !exists(jail, &pam_file("other"));
+        let name = if own { service } else { "other" };
+        pam_config(jail, name, &mut seen, &mut missing);
+    }
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("missing in the chroot: {}", missing.join(", ")))
+    }
+}
+
+// Does `path` exist below `jail`.
+fn exists(jail: &Path, path: &Path) -> bool {
+    jail.join(path.strip_prefix("/").unwrap_or(path)).exists()
+}
+
+fn pam_file(name: &str) -> PathBuf {
+    Path::new("/etc/pam.d").join(name)
+}
+
+// Check /etc/pam.d/`name` in the jail, the modules in it, and the files
+// that it includes.
+fn pam_config(jail: &Path, name: &str, seen: &mut HashSet<String>, missing: &mut Vec<String>) {
+    if !seen.insert(name.to_string()) {
+        return;
+    }
+    let file = pam_file(name);
+    let data = match fs::read_to_string(jail.join(file.strip_prefix("/").unwrap())) {
+        Ok(data) => data,
+        Err(_) => {
+            missing.push(file.display().to_string());
+            return;
+        },
+    };
+    for line in data.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let words = pam_words(line);
+        match words.as_slice() {
+            [] => {},
+            ["@include", name, ..] => pam_config(jail, name, seen, missing),
+            [_, "include", name, ..] | [_, "substack", name, ..] => pam_config(jail, name, seen, missing),
+            // a type with a '-' in front: the module may be missing.
+            [kind, _, module, ..] if !kind.starts_with('-') => {
+                if let Err(path) = pam_module(jail, module) {
+                    missing.push(path);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+// The words of a line. A control like [success=1 default=ignore] is one.
+fn pam_words(line: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let end = if rest.starts_with('[') {
+            rest.find(']').map(|i| i + 1).unwrap_or(rest.len())
+        } else {
+            rest.find(char::is_whitespace).unwrap_or(rest.len())
+        };
+        words.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    words
+}
+
+// A module with a full path has to be in the jail at that path. For a
+// bare name, the directory it is in outside the jail must have it in
+// the jail too.
+fn pam_module(jail: &Path, module: &str) -> Result<(), String> {
+    if module.starts_with('/') {
+        return if exists(jail, Path::new(module)) { Ok(()) } else { Err(module.to_string()) };
+    }
+    let mut found = None;
+    for dir in PAM_MODULE_DIRS.iter().map(Path::new) {
+        let path = dir.join(module);
+        if exists(jail, &path) {
+            return Ok(());
+        }
+        if found.is_none() && path.exists() {
+            found = Some(path);
+        }
+    }
+    Err(found.map(|p| p.display().to_string()).unwrap_or_else(|| module.to_string()))
+}
+
+/// Look up `uid` and `gid`, so that the NSS modules are loaded before the
+/// chroot. The result does not matter.
+pub fn preload_nss(uid: u32, gid: u32) {
+    unsafe {
+        libc::getpwuid(uid);
+        libc::getgrgid(gid);
+    }
+}
+
+/// Empty the capability bounding set. After this, no capability can be
+/// gained again, not even by running a setuid-root program.
+#[cfg(target_os = "linux")]
+pub fn drop_bounding_set() -> io::Result<()> {
+    for cap in 0..64 {
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) } != 0 {
+            let err = io::Error::last_os_error();
+            // EINVAL: past the last capability that the kernel knows.
+            if err.raw_os_error() == Some(libc::EINVAL) && cap > 0 {
+                return Ok(());
+            }
+            return Err(io::Error::new(err.kind(), format!("PR_CAPBSET_DROP {}: {}", cap, err)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_bounding_set() -> io::Result<()> {
+    Ok(())
+}
+
+/// Check that the process has no capabilities left.
+#[cfg(target_os = "linux")]
+pub fn check_no_capabilities() -> io::Result<()> {
+    #[repr(C)]
+    struct CapHeader {
+        version: u32,
+        pid:     libc::c_int,
+    }
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct CapData {
+        effective:   u32,
+        permitted:   u32,
+        inheritable: u32,
+    }
+    const VERSION_3: u32 = 0x20080522;
+
+    let mut header = CapHeader { version: VERSION_3, pid: 0 };
+    let mut data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        let err = io::Error::last_os_error();
+        return Err(io::Error::new(err.kind(), format!("capget: {}", err)));
+    }
+    if data.iter().any(|d| d.effective != 0 || d.permitted != 0) {
+        return Err(io::Error::other("capabilities left after dropping privileges"));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn check_no_capabilities() -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jail(files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("webdav-test-{}", uuid::Uuid::new_v4().to_simple()));
+        fs::create_dir(&dir).unwrap();
+        for (name, data) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = jail(&[]);
+        assert!(check(&dir, false, None).is_ok());
+        let err = check(&dir, true, Some("webdav")).unwrap_err();
+        assert_eq!(err, "missing in the chroot: /etc/passwd, /etc/group, /etc/pam.d/webdav");
+        fs::remove_dir_all(&dir).unwrap();
+
+        let webdav = "# comment\n\
+                      auth [success=1 default=ignore] /lib/security/pam_a.so nullok\n\
+                      -account required /lib/security/pam_b.so\n\
+                      @include common\n\
+                      password include other\n";
+        let dir = jail(&[
+            ("etc/passwd", ""),
+            ("etc/group", ""),
+            ("etc/pam.d/webdav", webdav),
+            ("etc/pam.d/common", "session optional /lib/security/pam_c.so\n"),
+            ("etc/pam.d/other", "auth required /lib/security/pam_a.so\n"),
+            ("lib/security/pam_a.so", ""),
+        ]);
+        let err = check(&dir, true, Some("webdav")).unwrap_err();
+        assert_eq!(err, "missing in the chroot: /lib/security/pam_c.so");
+        fs::write(dir.join("lib/security/pam_c.so"), "").unwrap();
+        assert!(check(&dir, true, Some("webdav")).is_ok());
+
+        // a bare module name is looked for in the module directories.
+        fs::write(dir.join("etc/pam.d/webdav"), "auth required pam_a.so\n").unwrap();
+        assert!(check(&dir, true, Some("webdav")).is_ok());
+        fs::write(dir.join("etc/pam.d/webdav"), "auth required pam_none.so\n").unwrap();
+        let err = check(&dir, true, Some("webdav")).unwrap_err();
+        assert_eq!(err, "missing in the chroot: pam_none.so");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pam_words() {
+        let words = pam_words("-auth  [success=1 default=ignore]\tpam_unix.so nullok");
+        assert_eq!(words, vec!["-auth", "[success=1 default=ignore]", "pam_unix.so", "nullok"]);
+    }
+}
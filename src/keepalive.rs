@@ -0,0 +1,146 @@
+//! Keep-alive for HTTP/1.0 and HTTP/1.1 clients.
+//!
+//! An HTTP/1.1 connection stays open after a response, unless the
+//! request has `Connection: close`. An HTTP/1.0 connection is closed,
+//! unless the request has `Connection: keep-alive`, and then the response
+//! has to say `Connection: keep-alive` too, or the client waits for the
+//! server to close. The tokens in the header are a comma separated list,
+//! and not case sensitive (`Connection: TE, Keep-Alive`).
+//!
+//! HTTP/1.0 has no chunked encoding: a body of unknown length can only
+//! end with the connection. So an HTTP/1.0 response that is streamed (a
+//! PROPFIND) gets `Connection: close`, even if the client asked for
+//! keep-alive, instead of a promise that the connection stays open.
+//!
+//! hyper does most of this already, but it decides before it knows the
+//! response. `apply` makes the decision explicit in the response, which
+//! hyper follows.
+//!
+//! A Content-Length that does not match the body leaves the client
+//! waiting for bytes that never come, or reading the rest as the next
+//! response. Bodies are replaced after the webdav handler is done (error
+//! pages, the "<error>" bodies, rewritten multistatus), so `set_length`
+//! sets it again from the body, when its size is known. A body of unknown
+//! size (a file, a streamed PROPFIND) goes out as it is: with the length
+//! that the handler knew, or chunked.
+//!
+use http::header::{HeaderMap, CONNECTION, CONTENT_LENGTH};
+use http::{StatusCode, Version};
+use hyper::body::HttpBody;
+
+// Is `token` in the Connection: header.
+fn has_token(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+/// Does the client want the connection to stay open after this request.
+pub fn requested(version: Version, headers: &HeaderMap) -> bool {
+    match version {
+        Version::HTTP_09 | Version::HTTP_10 => {
+            has_token(headers, "keep-alive") && !has_token(headers, "close")
+        },
+        _ => !has_token(headers, "close"),
+    }
+}
+
+/// Say in the response whether the connection stays open. `keep_alive` is
+/// whether the client asked for it and the server allows it.
+pub fn apply(resp: &mut hyper::Response<hyper::Body>, version: Version, keep_alive: bool) {
+    let http10 = match version {
+        Version::HTTP_09 | Version::HTTP_10 => true,
+        Version::HTTP_11 => false,
+        // no Connection: header in HTTP/2.
+        _ => return,
+    };
+    let length = resp.headers().contains_key(CONTENT_LENGTH) || resp.body().size_hint().exact().is_some();
+    let keep_alive = keep_alive && !has_token(resp.headers(), "close") && (length || !http10);
+    // hyper looks at the version of the response: an HTTP/1.1 response
+    // gets "Connection: keep-alive" if the request wanted it, whatever
+    // the response said.
+    if http10 {
+        *resp.version_mut() = Version::HTTP_10;
+    }
+    if !keep_alive {
+        resp.headers_mut().insert(CONNECTION, "close".parse().unwrap());
+    } else if http10 {
+        resp.headers_mut().insert(CONNECTION, "keep-alive".parse().unwrap());
+    }
+}
+
+/// Set Content-Length to the size of the body, if that is known. Not for
+/// a response to HEAD, a 204 or a 304: their body is empty, and the
+/// header (if they have one) is about what a GET would return.
+pub fn set_length(resp: &mut hyper::Response<hyper::Body>, head: bool) {
+    let status = resp.status();
+    let no_body = matches!(status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED);
+    if head || no_body || status.is_informational() {
+        return;
+    }
+    if let Some(length) = resp.body().size_hint().exact() {
+        resp.headers_mut().insert(CONTENT_LENGTH, length.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keep_alive() {
+        let headers = |value: &str| {
+            let mut h = HeaderMap::new();
+            h.insert(CONNECTION, value.parse().unwrap());
+            h
+        };
+        assert!(!requested(Version::HTTP_10, &HeaderMap::new()));
+        assert!(requested(Version::HTTP_10, &headers("keep-alive")));
+        assert!(requested(Version::HTTP_10, &headers("TE, Keep-Alive")));
+        assert!(!requested(Version::HTTP_10, &headers("keep-alive, close")));
+        assert!(!requested(Version::HTTP_10, &headers("keep-alive-please")));
+        assert!(requested(Version::HTTP_11, &HeaderMap::new()));
+        assert!(requested(Version::HTTP_11, &headers("keep-alive")));
+        assert!(!requested(Version::HTTP_11, &headers("TE, CLOSE")));
+
+        let connection = |version, keep_alive, body: hyper::Body| {
+            let mut resp = hyper::Response::new(body);
+            apply(&mut resp, version, keep_alive);
+            resp.headers().get(CONNECTION).map(|v| v.to_str().unwrap().to_string())
+        };
+        let streamed = || {
+            let chunks: Vec<Result<_, std::io::Error>> = vec![Ok("data")];
+            hyper::Body::wrap_stream(futures::stream::iter(chunks))
+        };
+        let close = Some("close".to_string());
+        assert_eq!(connection(Version::HTTP_10, true, "data".into()), Some("keep-alive".to_string()));
+        assert_eq!(connection(Version::HTTP_10, true, streamed()), close);
+        assert_eq!(connection(Version::HTTP_10, false, "data".into()), close);
+        assert_eq!(connection(Version::HTTP_11, true, streamed()), None);
+        assert_eq!(connection(Version::HTTP_11, false, "data".into()), close);
+        assert_eq!(connection(Version::HTTP_2, false, "data".into()), None);
+    }
+
+    #[test]
+    fn test_set_length() {
+        let length = |status, body: hyper::Body, head| {
+            let mut resp = hyper::Response::new(body);
+            *resp.status_mut() = status;
+            resp.headers_mut().insert(CONTENT_LENGTH, "100".parse().unwrap());
+            set_length(&mut resp, head);
+            resp.headers()[CONTENT_LENGTH].to_str().unwrap().to_string()
+        };
+        let streamed = || {
+            let chunks: Vec<Result<_, std::io::Error>> = vec![Ok("data")];
+            hyper::Body::wrap_stream(futures::stream::iter(chunks))
+        };
+        assert_eq!(length(StatusCode::NOT_FOUND, "<error>404</error>".into(), false), "18");
+        assert_eq!(length(StatusCode::OK, hyper::Body::empty(), false), "0");
+        assert_eq!(length(StatusCode::OK, streamed(), false), "100");
+        assert_eq!(length(StatusCode::OK, hyper::Body::empty(), true), "100");
+        assert_eq!(length(StatusCode::NOT_MODIFIED, hyper::Body::empty(), false), "100");
+    }
+}
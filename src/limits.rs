@@ -0,0 +1,787 @@
+//! Request limits.
+//!
+//! `UserLimiter` caps the number of requests that one authenticated
+//! user can have in progress at the same time. A request counts from
+//! the moment the user is authenticated until the response body has
+//! been sent (or the connection is closed), because that is when the
+//! `UserGuard` that is attached to the response body is dropped.
+//!
+//! `UploadLimiter` caps the memory used by PUT bodies. The webdav handler
+//! streams a PUT body to disk: it reads a chunk, writes it, and only then
+//! reads the next one, so every upload has one chunk in memory. A chunk
+//! counts against the limit from the moment it is read from the client
+//! until the handler asks for the next one. If the limit has been reached,
+//! uploads wait before they hand over the next chunk, and since nobody reads
+//! from their connections, TCP flow control slows down the clients.
+//!
+//! `WriteLimiter` caps the number of writes (PUT, PATCH, COPY, MOVE,
+//! DELETE) in progress, separate from the other limits. Reads are cheap,
+//! but writes compete for the disk, and fewer of them at the same time
+//! also make the quota checks less racy. A write counts until the
+//! response has been made, which for a PUT is after the body has been
+//! written. Writes over the limit wait for a slot, and get
+//! "503 Service Unavailable" if none comes free in time.
+//!
+//! `MemoryBudget` is a coarse cap on the memory of the large buffers,
+//! over all requests: XML request bodies, PUT body chunks, and the
+//! PROPFIND response buffer. It is not an allocator, the buffers are
+//! counted where they are made. A request body that does not fit gets
+//! "503 Service Unavailable"; an upload or a PROPFIND waits until other
+//! buffers are freed (backpressure), since its response has already
+//! started or its client is already sending. What is in use is counted
+//! without a budget as well, for the metrics.
+//!
+//! `RateLimiter` is a token bucket for the rate of requests to the whole
+//! server: it holds up to `burst` tokens, a request takes one, and they
+//! come back at `rate` per second. It is a safety valve against floods
+//! (that would also hit the PAM backend), not a fair share per client.
+//!
+//! `FailureDelay` slows down the responses to failed logins of one
+//! username: every failure in a row doubles the delay, up to a maximum,
+//! and a successful login resets it. Unlike the PAM failure-backoff, it
+//! does not depend on the client address, so it also slows down guessing
+//! that comes from many addresses. The account is never locked.
+//!
+//! `check_headers` caps the size and the number of the request headers.
+//! hyper reads the headers into a buffer of at most `http1_buf_size`
+//! bytes, and it parses at most `MAX_HEADER_COUNT` of them; a request
+//! that does not fit gets "431 Request Header Fields Too Large" before
+//! it is handed to us. The exact limits are checked after parsing.
+//! The buffer also has room for a request target of `max-uri-length`
+//! bytes, which `check_uri` checks before anything else.
+//!
+//! GET, HEAD, DELETE, COPY, MOVE and UNLOCK have no request body. If a
+//! client sends one anyway, it is read and thrown away (`drain`), up to
+//! `MAX_DRAIN` bytes, so that the next request on the connection starts
+//! where it should; a larger body gets "413 Payload Too Large" and the
+//! connection is closed. With `unexpected-body = "reject"` the request
+//! also gets "400 Bad Request", after the body has been read.
+//!
+//! `check_framing` refuses requests whose headers can be read in more than
+//! one way, the usual request smuggling tricks when there is a proxy in
+//! front: Content-Length together with Transfer-Encoding, Content-Length
+//! headers that differ, and more than one Host header. hyper itself
+//! refuses differing Content-Length headers, and drops a Content-Length
+//! that comes after a Transfer-Encoding (the body is chunked); the rest
+//! is left to us. Such a request gets "400 Bad Request", and the
+//! connection is closed. `duplicate-headers = "allow"` turns it off.
+//!
+//! `check_path` caps the length (decoded bytes) and the number of
+//! segments of the normalized request path and Destination, before
+//! anything is looked up on disk. Filesystems have limits of their own
+//! (PATH_MAX, name lengths), but they differ, and a path that is over
+//! them fails in odd ways. Too long gets "414 URI Too Long", too deep
+//! "403 Forbidden".
+//!
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use http::{HeaderMap, StatusCode};
+use hyper::body::HttpBody;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+#[derive(Debug, Clone, Default)]
+pub struct UserLimiter {
+    max:    Option<usize>,
+    counts: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl UserLimiter {
+    /// New limiter. `None` means "no limit", but we still keep count.
+    pub fn new(max: Option<usize>) -> UserLimiter {
+        UserLimiter {
+            max,
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Count a new request for `user`. Returns `None` if the user
+    /// is already at the maximum.
+    pub fn acquire(&self, user: &str) -> Option<UserGuard> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(user.to_string()).or_insert(0);
+        if self.max.map(|max| *count >= max).unwrap_or(false) {
+            return None;
+        }
+        *count += 1;
+        Some(UserGuard {
+            user:    user.to_string(),
+            limiter: self.clone(),
+        })
+    }
+
+    /// Requests in progress, per user.
+    pub fn counts(&self) -> HashMap<String, usize> {
+        let counts = self.counts.lock().unwrap();
+        counts.iter().filter(|(_, &n)| n > 0).map(|(u, &n)| (u.clone(), n)).collect()
+    }
+}
+
+/// Decrements the request count of the user when dropped.
+#[derive(Debug)]
+pub struct UserGuard {
+    user:    String,
+    limiter: UserLimiter,
+}
+
+impl Drop for UserGuard {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.user) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.user);
+            }
+        }
+    }
+}
+
+/// Limit on the total size of PUT body chunks in memory.
+#[derive(Debug, Clone, Default)]
+pub struct UploadLimiter {
+    max:    usize,
+    bytes:  Option<Arc<Semaphore>>,
+    memory: MemoryBudget,
+}
+
+impl UploadLimiter {
+    /// New limiter. `None` means "no limit". The chunks count against
+    /// `memory` as well.
+    pub fn new(max: Option<usize>, memory: MemoryBudget) -> UploadLimiter {
+        UploadLimiter {
+            max: max.unwrap_or(0),
+            bytes: max.map(|max| Arc::new(Semaphore::new(max))),
+            memory,
+        }
+    }
+
+    /// Bytes in memory right now.
+    #[allow(dead_code)]
+    pub fn in_use(&self) -> usize {
+        self.bytes.as_ref().map(|b| self.max - b.available_permits()).unwrap_or(0)
+    }
+
+    /// Wrap the body of a PUT request.
+    pub fn wrap(&self, body: hyper::Body) -> hyper::Body {
+        let (bytes, memory, max) = (self.bytes.clone(), self.memory.clone(), self.max);
+        let held: Option<(Option<OwnedSemaphorePermit>, Reservation)> = None;
+        let stream = futures::stream::unfold((body, held), move |(mut body, held)| {
+            let (bytes, memory) = (bytes.clone(), memory.clone());
+            async move {
+                // the handler wants the next chunk, so the previous one was written.
+                drop(held);
+                let chunk = match body.data().await? {
+                    Ok(chunk) => chunk,
+                    Err(e) => return Some((Err(e), (body, None))),
+                };
+                // a chunk larger than the limit takes all of it.
+                let permit = match bytes {
+                    Some(bytes) => {
+                        let n = chunk.len().min(max).max(1);
+                        Some(bytes.acquire_many_owned(n as u32).await.ok()?)
+                    },
+                    None => None,
+                };
+                let reservation = memory.reserve(chunk.len()).await;
+                Some((Ok(chunk), (body, Some((permit, reservation)))))
+            }
+        });
+        hyper::Body::wrap_stream(stream)
+    }
+}
+
+/// Default for `max-concurrent-writes`.
+pub const DEFAULT_MAX_WRITES: usize = 64;
+
+/// Default for `write-queue-timeout` (secs).
+pub const DEFAULT_WRITE_QUEUE_TIMEOUT: u64 = 30;
+
+/// Limit on the number of writes in progress.
+#[derive(Debug, Clone, Default)]
+pub struct WriteLimiter {
+    max:     usize,
+    permits: Option<Arc<Semaphore>>,
+    waiting: Arc<AtomicUsize>,
+}
+
+impl WriteLimiter {
+    /// New limiter. `None` is the default, 0 means "no limit".
+    pub fn new(max: Option<usize>) -> WriteLimiter {
+        let max = max.unwrap_or(DEFAULT_MAX_WRITES);
+        WriteLimiter {
+            max,
+            permits: Some(Arc::new(Semaphore::new(max))).filter(|_| max > 0),
+            waiting: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Wait at most `timeout` for a free slot. "503 Service Unavailable"
+    /// if there was none, `Ok(None)` if there is no limit.
+    pub async fn acquire(&self, timeout: Duration) -> Result<Option<OwnedSemaphorePermit>, StatusCode> {
+        let permits = match self.permits.as_ref() {
+            Some(permits) => permits.clone(),
+            None => return Ok(None),
+        };
+        if let Ok(permit) = permits.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        let permit = tokio::time::timeout(timeout, permits.acquire_owned()).await;
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        match permit {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            _ => Err(StatusCode::SERVICE_UNAVAILABLE),
+        }
+    }
+
+    /// Writes in progress.
+    pub fn active(&self) -> usize {
+        self.permits.as_ref().map(|p| self.max - p.available_permits()).unwrap_or(0)
+    }
+
+    /// Writes waiting for a slot.
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+}
+
+/// Memory of the large buffers, over all requests.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBudget {
+    max:   Option<usize>,
+    used:  Arc<AtomicUsize>,
+    freed: Arc<Notify>,
+}
+
+impl MemoryBudget {
+    /// New budget. `None` means "no limit", but we still keep count.
+    pub fn new(max: Option<usize>) -> MemoryBudget {
+        MemoryBudget {
+            max,
+            used: Arc::new(AtomicUsize::new(0)),
+            freed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Bytes in use right now.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    // Count `n` more bytes, if they fit.
+    fn take(&self, n: usize) -> bool {
+        let fits = |used: usize| match self.max {
+            Some(max) if used + n > max => None,
+            _ => Some(used + n),
+        };
+        self.used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, fits).is_ok()
+    }
+
+    fn give(&self, n: usize) {
+        if n > 0 {
+            self.used.fetch_sub(n, Ordering::SeqCst);
+            self.freed.notify_waiters();
+        }
+    }
+
+    /// An empty reservation, that can `grow`.
+    pub fn reservation(&self) -> Reservation {
+        Reservation {
+            budget: self.clone(),
+            bytes:  0,
+        }
+    }
+
+    /// Reserve `n` bytes, waiting until they are free. More than the
+    /// whole budget takes all of it.
+    pub async fn reserve(&self, n: usize) -> Reservation {
+        let n = self.max.map(|max| n.min(max)).unwrap_or(n);
+        loop {
+            // created before the check, so that no free is missed.
+            let freed = self.freed.notified();
+            if self.take(n) {
+                return Reservation {
+                    budget: self.clone(),
+                    bytes:  n,
+                };
+            }
+            freed.await;
+        }
+    }
+}
+
+/// Bytes taken from a `MemoryBudget`, given back when dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes:  usize,
+}
+
+impl Reservation {
+    /// Reserve `n` more bytes. `false` if that goes over the budget.
+    pub fn grow(&mut self, n: usize) -> bool {
+        if !self.budget.take(n) {
+            return false;
+        }
+        self.bytes += n;
+        true
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.give(self.bytes);
+    }
+}
+
+/// Token bucket for the overall request rate.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    rate:   f64,
+    burst:  f64,
+    bucket: Option<Arc<Mutex<(f64, Instant)>>>,
+}
+
+impl RateLimiter {
+    /// New limiter, `rate` requests per second with bursts of `burst`.
+    /// `None` means "no limit".
+    pub fn new(rate: Option<f64>, burst: Option<u32>) -> RateLimiter {
+        let rate = match rate {
+            Some(rate) => rate,
+            None => return RateLimiter::default(),
+        };
+        let burst = burst.map(|b| b as f64).unwrap_or_else(|| rate.ceil()).max(1.0);
+        RateLimiter {
+            rate,
+            burst,
+            bucket: Some(Arc::new(Mutex::new((burst, Instant::now())))),
+        }
+    }
+
+    // Add the tokens that came in since the last time.
+    fn refill(&self, bucket: &mut (f64, Instant), now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.1).as_secs_f64();
+        bucket.0 = (bucket.0 + elapsed * self.rate).min(self.burst);
+        bucket.1 = now;
+    }
+
+    /// Take a token for a request. If there is none, returns how long
+    /// it will take until there is one.
+    pub fn acquire(&self) -> Result<(), Duration> {
+        self.acquire_at(Instant::now())
+    }
+
+    fn acquire_at(&self, now: Instant) -> Result<(), Duration> {
+        let mut bucket = match self.bucket.as_ref() {
+            Some(bucket) => bucket.lock().unwrap(),
+            None => return Ok(()),
+        };
+        self.refill(&mut bucket, now);
+        if bucket.0 < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.0) / self.rate));
+        }
+        bucket.0 -= 1.0;
+        Ok(())
+    }
+
+    /// Number of tokens in the bucket now, `None` if there is no limit.
+    pub fn tokens(&self) -> Option<f64> {
+        let mut bucket = self.bucket.as_ref()?.lock().unwrap();
+        self.refill(&mut bucket, Instant::now());
+        Some(bucket.0)
+    }
+}
+
+/// Default for `auth-failure-delay-max` (secs).
+pub const DEFAULT_MAX_FAILURE_DELAY: f64 = 30.0;
+
+// A username that has not failed for this long starts over.
+const FAILURE_FORGET: Duration = Duration::from_secs(900);
+
+// Above this many usernames, the ones that have been forgotten are removed.
+const FAILURE_PRUNE: usize = 1024;
+
+// Failures in a row and the time of the last one, per username.
+type Failures = HashMap<String, (u32, Instant)>;
+
+/// Escalating delay of failed logins, per username.
+#[derive(Debug, Clone, Default)]
+pub struct FailureDelay {
+    first:    Duration,
+    max:      Duration,
+    failures: Option<Arc<Mutex<Failures>>>,
+}
+
+impl FailureDelay {
+    /// New delay, `first` secs after the first failure, doubling with
+    /// every failure after that, up to `max` secs. `None` means "no delay".
+    pub fn new(first: Option<f64>, max: Option<f64>) -> FailureDelay {
+        let first = match first {
+            Some(first) if first > 0.0 => first,
+            _ => return FailureDelay::default(),
+        };
+        let max = max.unwrap_or(DEFAULT_MAX_FAILURE_DELAY).max(first);
+        FailureDelay {
+            first:    Duration::from_secs_f64(first),
+            max:      Duration::from_secs_f64(max),
+            failures: Some(Arc::new(Mutex::new(HashMap::new()))),
+        }
+    }
+
+    /// Count a failed login of `user`. Returns how long to wait before
+    /// the response is sent.
+    pub fn failed(&self, user: &str) -> Duration {
+        self.failed_at(user, Instant::now())
+    }
+
+    fn failed_at(&self, user: &str, now: Instant) -> Duration {
+        let mut failures = match self.failures.as_ref() {
+            Some(failures) => failures.lock().unwrap(),
+            None => return Duration::ZERO,
+        };
+        if failures.len() >= FAILURE_PRUNE {
+            failures.retain(|_, (_, last)| now.saturating_duration_since(*last) < FAILURE_FORGET);
+        }
+        let entry = failures.entry(user.to_string()).or_insert((0, now));
+        if now.saturating_duration_since(entry.1) >= FAILURE_FORGET {
+            entry.0 = 0;
+        }
+        *entry = (entry.0.saturating_add(1), now);
+        let factor = 2u32.saturating_pow(entry.0 - 1);
+        self.first.saturating_mul(factor).min(self.max)
+    }
+
+    /// A successful login of `user` resets the delay.
+    pub fn succeeded(&self, user: &str) {
+        if let Some(failures) = self.failures.as_ref() {
+            failures.lock().unwrap().remove(user);
+        }
+    }
+}
+
+/// Default for `max-header-size`. Large enough for an `If:` header
+/// with a few hundred lock tokens.
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 65536;
+
+/// Most headers that hyper parses, and the default for `max-headers`.
+pub const MAX_HEADER_COUNT: usize = 100;
+
+// The smallest buffer hyper takes.
+const MIN_BUF_SIZE: usize = 8192;
+
+// Room for the method and the version on the request line.
+const REQUEST_LINE_EXTRA: usize = 64;
+
+/// Default for `max-uri-length`.
+pub const DEFAULT_MAX_URI_LENGTH: usize = 8192;
+
+/// Size of hyper's read buffer for headers of at most `max_size` bytes
+/// and a request target of at most `max_uri` bytes.
+pub fn http1_buf_size(max_size: usize, max_uri: usize) -> usize {
+    let line = max_uri.saturating_add(REQUEST_LINE_EXTRA).max(MIN_BUF_SIZE);
+    max_size.saturating_add(line)
+}
+
+/// Check the length of the request target as it was sent: path and
+/// query, still percent-encoded, and not normalized.
+pub fn check_uri(uri: &http::Uri, max_len: usize) -> Result<(), StatusCode> {
+    let len = uri.path_and_query().map(|p| p.as_str().len()).unwrap_or(0);
+    let authority = uri.authority().map(|a| a.as_str().len()).unwrap_or(0);
+    if len + authority > max_len {
+        return Err(StatusCode::URI_TOO_LONG);
+    }
+    Ok(())
+}
+
+/// Check the size (names, values, ": " and CRLF) and the number of the
+/// request headers.
+pub fn check_headers(headers: &HeaderMap, max_size: usize, max_count: usize) -> Result<(), StatusCode> {
+    if headers.len() > max_count {
+        return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+    let size: usize = headers.iter().map(|(k, v)| k.as_str().len() + v.len() + 4).sum();
+    if size > max_size {
+        return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+    Ok(())
+}
+
+/// Headers that frame the request ambiguously, see the module doc.
+pub fn check_framing(headers: &HeaderMap) -> Result<(), &'static str> {
+    if headers.get_all("host").iter().count() > 1 {
+        return Err("more than one Host header");
+    }
+    let mut lengths = headers.get_all("content-length").iter();
+    if let Some(first) = lengths.next() {
+        if headers.contains_key("transfer-encoding") {
+            return Err("both Content-Length and Transfer-Encoding");
+        }
+        if lengths.any(|l| l != first) || first.as_bytes().contains(&b',') {
+            return Err("conflicting Content-Length headers");
+        }
+    }
+    Ok(())
+}
+
+/// Most bytes of an unexpected request body that are read to keep the
+/// connection usable.
+pub const MAX_DRAIN: usize = 65536;
+
+/// Is this a method without a request body, and does the request have one.
+pub fn unexpected_body(method: &http::Method, headers: &HeaderMap) -> bool {
+    use http::Method;
+    let no_body = [Method::GET, Method::HEAD, Method::DELETE];
+    let no_dav_body = ["COPY", "MOVE", "UNLOCK"];
+    if !no_body.contains(method) && !no_dav_body.contains(&method.as_str()) {
+        return false;
+    }
+    let length = headers.get("content-length").and_then(|v| v.to_str().ok());
+    headers.contains_key("transfer-encoding") || length.map(|l| l.trim() != "0").unwrap_or(false)
+}
+
+/// Read `body` and throw it away, if it is no more than `max` bytes.
+pub async fn drain(mut body: hyper::Body, max: usize) -> Result<(), StatusCode> {
+    let mut len = 0;
+    while let Some(chunk) = body.data().await {
+        len += chunk.map_err(|_| StatusCode::BAD_REQUEST)?.len();
+        if len > max {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    }
+    Ok(())
+}
+
+/// Default for `max-path-length`.
+pub const DEFAULT_MAX_PATH_LENGTH: usize = 4096;
+
+/// Default for `max-path-segments`.
+pub const DEFAULT_MAX_PATH_SEGMENTS: usize = 256;
+
+/// Check the length and the depth of a normalized, percent-encoded path.
+pub fn check_path(path: &str, max_len: usize, max_segments: usize) -> Result<(), StatusCode> {
+    if percent_encoding::percent_decode_str(path).count() > max_len {
+        return Err(StatusCode::URI_TOO_LONG);
+    }
+    if path.split('/').filter(|s| !s.is_empty()).count() > max_segments {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_check_framing() {
+        let headers = |list: &[(&'static str, &'static str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in list {
+                map.append(*name, value.parse().unwrap());
+            }
+            check_framing(&map)
+        };
+        assert!(headers(&[("host", "a"), ("content-length", "5")]).is_ok());
+        assert!(headers(&[("content-length", "5"), ("content-length", "5")]).is_ok());
+        assert!(headers(&[("transfer-encoding", "chunked")]).is_ok());
+        assert!(headers(&[("host", "a"), ("host", "b")]).is_err());
+        assert!(headers(&[("host", "a"), ("host", "a")]).is_err());
+        assert!(headers(&[("content-length", "5"), ("content-length", "6")]).is_err());
+        assert!(headers(&[("content-length", "5, 6")]).is_err());
+        assert!(headers(&[("content-length", "5"), ("transfer-encoding", "chunked")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_body() {
+        let mut headers = HeaderMap::new();
+        assert!(!unexpected_body(&http::Method::GET, &headers));
+        headers.insert("content-length", "0".parse().unwrap());
+        assert!(!unexpected_body(&http::Method::GET, &headers));
+        headers.insert("content-length", "4".parse().unwrap());
+        assert!(unexpected_body(&http::Method::GET, &headers));
+        assert!(unexpected_body(&"MOVE".parse().unwrap(), &headers));
+        assert!(!unexpected_body(&http::Method::PUT, &headers));
+        assert!(!unexpected_body(&"PROPFIND".parse().unwrap(), &headers));
+        let mut headers = HeaderMap::new();
+        headers.insert("transfer-encoding", "chunked".parse().unwrap());
+        assert!(unexpected_body(&http::Method::DELETE, &headers));
+
+        assert_eq!(drain(hyper::Body::from("data"), 4).await, Ok(()));
+        assert_eq!(drain(hyper::Body::from("data"), 3).await, Err(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    #[test]
+    fn test_check_path() {
+        assert!(check_path("/a/b/c", 6, 3).is_ok());
+        assert_eq!(check_path("/a/b/cd", 6, 3), Err(StatusCode::URI_TOO_LONG));
+        assert_eq!(check_path("/a/b/c/", 7, 3), Ok(()));
+        assert_eq!(check_path("/a/b/c/d", 100, 3), Err(StatusCode::FORBIDDEN));
+        // decoded length: "%C3%A9" is 2 bytes.
+        assert!(check_path("/%C3%A9", 3, 1).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        assert!(RateLimiter::new(None, None).acquire().is_ok());
+        assert_eq!(RateLimiter::new(None, None).tokens(), None);
+
+        // 2 per second, bursts of 3.
+        let limiter = RateLimiter::new(Some(2.0), Some(3));
+        let start = limiter.bucket.as_ref().unwrap().lock().unwrap().1;
+        for _ in 0..3 {
+            assert!(limiter.acquire_at(start).is_ok());
+        }
+        assert_eq!(limiter.acquire_at(start), Err(Duration::from_millis(500)));
+        let later = start + Duration::from_millis(750);
+        assert!(limiter.acquire_at(later).is_ok());
+        assert_eq!(limiter.acquire_at(later), Err(Duration::from_millis(250)));
+        // it does not fill up beyond the burst.
+        assert!(limiter.acquire_at(later + Duration::from_secs(60)).is_ok());
+        assert!((limiter.tokens().unwrap() - 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_failure_delay() {
+        assert_eq!(FailureDelay::new(None, None).failed("alice"), Duration::ZERO);
+
+        let delay = FailureDelay::new(Some(0.5), Some(3.0));
+        let now = Instant::now();
+        let ms = |user, now| delay.failed_at(user, now).as_millis();
+        assert_eq!(ms("alice", now), 500);
+        assert_eq!(ms("alice", now), 1000);
+        assert_eq!(ms("alice", now), 2000);
+        assert_eq!(ms("alice", now), 3000);
+        assert_eq!(ms("alice", now), 3000);
+        // per username.
+        assert_eq!(ms("bob", now), 500);
+        // a success resets it.
+        delay.succeeded("alice");
+        assert_eq!(ms("alice", now), 500);
+        // and so does a long time without failures.
+        assert_eq!(ms("bob", now + FAILURE_FORGET), 500);
+        for _ in 0..40 {
+            ms("bob", now);
+        }
+        assert_eq!(ms("bob", now), 3000);
+    }
+
+    #[tokio::test]
+    async fn test_write_limiter() {
+        let unlimited = WriteLimiter::new(Some(0));
+        assert!(unlimited.acquire(Duration::ZERO).await.unwrap().is_none());
+        assert_eq!(WriteLimiter::new(None).max, DEFAULT_MAX_WRITES);
+
+        let limiter = WriteLimiter::new(Some(2));
+        let first = limiter.acquire(Duration::ZERO).await.unwrap();
+        let _second = limiter.acquire(Duration::ZERO).await.unwrap();
+        assert_eq!(limiter.active(), 2);
+        assert!(limiter.acquire(Duration::from_millis(10)).await.is_err());
+        assert_eq!(limiter.waiting(), 0);
+
+        // a waiting write gets the slot that comes free.
+        let waiter = limiter.clone();
+        let third = tokio::spawn(async move { waiter.acquire(Duration::from_secs(10)).await.is_ok() });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(limiter.waiting(), 1);
+        drop(first);
+        assert!(third.await.unwrap());
+        assert_eq!(limiter.waiting(), 0);
+        assert_eq!(limiter.active(), 1);
+    }
+
+    #[test]
+    fn test_user_limiter() {
+        let limiter = UserLimiter::new(Some(2));
+        let g1 = limiter.acquire("a").unwrap();
+        let _g2 = limiter.acquire("a").unwrap();
+        assert!(limiter.acquire("a").is_none());
+        let _g3 = limiter.acquire("b").unwrap();
+        assert_eq!(limiter.counts().get("a"), Some(&2));
+
+        drop(g1);
+        assert_eq!(limiter.counts().get("a"), Some(&1));
+        assert!(limiter.acquire("a").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_upload_limiter() {
+        let memory = MemoryBudget::new(None);
+        let limiter = UploadLimiter::new(Some(1000), memory.clone());
+        let chunks = || {
+            let chunks = (0..3).map(|_| Ok::<_, std::io::Error>(bytes::Bytes::from(vec![0u8; 600])));
+            hyper::Body::wrap_stream(futures::stream::iter(chunks))
+        };
+        let mut b1 = limiter.wrap(chunks());
+        let mut b2 = limiter.wrap(chunks());
+
+        // b1 has a chunk out, b2 has to wait for it.
+        assert_eq!(b1.next().await.unwrap().unwrap().len(), 600);
+        assert_eq!(limiter.in_use(), 600);
+        assert_eq!(memory.used(), 600);
+        let waiting = tokio::time::timeout(std::time::Duration::from_millis(50), b2.next()).await;
+        assert!(waiting.is_err());
+
+        // b1 gone: b2 can go on.
+        drop(b1);
+        assert_eq!(b2.next().await.unwrap().unwrap().len(), 600);
+        assert_eq!(limiter.in_use(), 600);
+        drop(b2);
+        assert_eq!(limiter.in_use(), 0);
+        assert_eq!(memory.used(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget() {
+        let memory = MemoryBudget::new(Some(1000));
+        let mut r1 = memory.reservation();
+        assert!(r1.grow(600));
+        assert!(!r1.grow(600));
+        assert_eq!(memory.used(), 600);
+
+        // waits until there is room, and more than the budget takes all.
+        let r2 = tokio::time::timeout(std::time::Duration::from_millis(50), memory.reserve(600)).await;
+        assert!(r2.is_err());
+        let waiting = tokio::spawn({
+            let memory = memory.clone();
+            async move { memory.reserve(5000).await }
+        });
+        drop(r1);
+        let r3 = waiting.await.unwrap();
+        assert_eq!(memory.used(), 1000);
+        drop(r3);
+        assert_eq!(memory.used(), 0);
+
+        // without a budget, everything fits.
+        let unlimited = MemoryBudget::new(None);
+        let mut r = unlimited.reservation();
+        assert!(r.grow(usize::MAX / 2));
+        assert_eq!(unlimited.used(), usize::MAX / 2);
+    }
+
+    #[test]
+    fn test_check_headers() {
+        let too_large = Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "localhost".parse().unwrap());
+        assert_eq!(check_headers(&headers, 64, 10), Ok(()));
+        assert_eq!(check_headers(&headers, 16, 10), too_large);
+
+        for _ in 0..10 {
+            headers.append("x-many", "1".parse().unwrap());
+        }
+        assert_eq!(check_headers(&headers, 1000, 11), Ok(()));
+        assert_eq!(check_headers(&headers, 1000, 10), too_large);
+
+        // an If: header with 200 lock tokens fits in the default.
+        let token = "(<opaquelocktoken:f81d4fae-7dec-11d0-a765-00a0c91e6bf6>)";
+        let tagged = format!("<https://example.com/alice/some/file.txt> {}", token);
+        let mut headers = HeaderMap::new();
+        headers.insert("if", vec![tagged; 200].join(" ").parse().unwrap());
+        assert_eq!(check_headers(&headers, DEFAULT_MAX_HEADER_SIZE, MAX_HEADER_COUNT), Ok(()));
+        let buf_size = http1_buf_size(DEFAULT_MAX_HEADER_SIZE, DEFAULT_MAX_URI_LENGTH);
+        assert!(buf_size > DEFAULT_MAX_HEADER_SIZE + DEFAULT_MAX_URI_LENGTH);
+    }
+}
@@ -0,0 +1,1012 @@
+//! In-memory lock manager.
+//!
+//! The webdav handler sees paths with the location prefix already
+//! stripped off, so the same `DavPath` can refer to different files
+//! depending on the location and user. That is why locks are kept
+//! per served directory (after "~" expansion). Locations that serve
+//! the same directory share their locks.
+//!
+//! Unlike `MemLs`, the lock manager can enumerate all locks, so that
+//! they can be listed and released through the admin API.
+//!
+//! The lock manager also decides on the timeout of a lock. The webdav
+//! handler passes `None` if the client did not send a Timeout: header
+//! (or, when refreshing, asked for `Infinite`), and caps `Infinite` on
+//! new locks at its own maximum. We apply the configured default and
+//! maximum timeouts on top of that. The granted timeout is what is
+//! returned to the client. When we grant less than the client asked for
+//! (or `Infinite` on a refresh), that is logged to the audit log.
+//!
+//! Expired locks are removed whenever the locks of a directory are looked
+//! at, and by a sweeper task that runs every `sweep-interval` seconds, so
+//! that the admin API and the metrics do not count locks that are gone.
+//! Every expiry is logged.
+//!
+//! DAV:lockdiscovery (a PROPFIND) shows the time that a lock has left,
+//! and the lock token only to the principal that holds the lock:
+//! with the token, anyone can UNLOCK. Locks without a principal show
+//! their token to everyone. The webdav handler gets DAV:supportedlock
+//! wrong, `fix_supportedlock` corrects the PROPFIND response.
+//!
+//! The DAV:owner of a LOCK request (text, or often a DAV:href) is kept
+//! with the lock as the parsed element, and is returned as it was sent
+//! in DAV:lockdiscovery, to everyone, and in the admin API. It is written
+//! out again by the XML writer, not copied as bytes, so what is in it is
+//! escaped, and it cannot close the element or add any of its own.
+//!
+//! A client that keeps taking locks (and never unlocks) can fill up the
+//! store. `max-per-user` caps the active locks of one principal, over all
+//! directories, `max-per-path` the (shared) locks on one resource. A LOCK
+//! over either gets "507 Insufficient Storage", see `over_limit`; the
+//! locks that are there stay as they are, and can be refreshed.
+//!
+//! A lock with Depth: infinity on a collection covers everything below
+//! it (`covers`): a write anywhere in the tree needs its token, in the
+//! webdav handler and in our own checks (auto-mkcol, chunked uploads),
+//! and a new lock below it conflicts with it. A Depth: 0 lock covers the
+//! collection itself only.
+//!
+//! The locks themselves are kept in a `LockStore`. `MemStore` keeps them
+//! in memory, so they are gone after a restart. `FileStore` (`[locks]
+//! store = "file"`) writes them to a file as well, and loads them again
+//! at startup, with their expiry times: a client that holds a long lock
+//! can go on with it after a restart.
+//!
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::admin::AUDIT;
+
+use serde_json::{json, Value};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::ls::{DavLock, DavLockSystem};
+use xmltree::Element;
+
+/// A lock, and the directory it lives in.
+#[derive(Debug, Clone)]
+pub struct LockEntry {
+    pub root: String,
+    pub lock: DavLock,
+}
+
+impl LockEntry {
+    /// Filesystem path of the locked resource.
+    pub fn fs_path(&self) -> String {
+        let path = String::from_utf8_lossy(self.lock.path.as_bytes());
+        format!("{}{}", self.root.trim_end_matches('/'), path)
+    }
+
+    fn expired(&self, now: SystemTime) -> bool {
+        self.lock.timeout_at.map(|t| t <= now).unwrap_or(false)
+    }
+}
+
+/// Where the locks are kept. The lock manager does the WebDAV logic,
+/// the store only has to find, add, change and remove entries, each of
+/// them atomically.
+pub trait LockStore: std::fmt::Debug + Send + Sync {
+    /// Add `entry`, unless there is a lock that `conflicts`; that one is
+    /// returned instead.
+    fn create(&self, entry: LockEntry, conflicts: &dyn Fn(&LockEntry) -> bool) -> Result<(), Box<LockEntry>>;
+
+    /// Change the first lock that `matches` with `update`, and return it.
+    fn refresh(
+        &self,
+        matches: &dyn Fn(&LockEntry) -> bool,
+        update: &mut dyn FnMut(&mut LockEntry),
+    ) -> Option<LockEntry>;
+
+    /// Remove the locks that match, and return them.
+    fn remove(&self, matches: &dyn Fn(&LockEntry) -> bool) -> Vec<LockEntry>;
+
+    /// The locks that match.
+    fn query(&self, matches: &dyn Fn(&LockEntry) -> bool) -> Vec<LockEntry>;
+
+    /// Remove the locks that expired at `now`, and return them.
+    fn sweep(&self, now: SystemTime) -> Vec<LockEntry> {
+        self.remove(&|e| e.expired(now))
+    }
+}
+
+/// Lock store in memory.
+#[derive(Debug, Default)]
+pub struct MemStore {
+    entries: Mutex<Vec<LockEntry>>,
+}
+
+impl LockStore for MemStore {
+    fn create(&self, entry: LockEntry, conflicts: &dyn Fn(&LockEntry) -> bool) -> Result<(), Box<LockEntry>> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(e) = entries.iter().find(|e| conflicts(e)) {
+            return Err(Box::new(e.clone()));
+        }
+        entries.push(entry);
+        Ok(())
+    }
+
+    fn refresh(
+        &self,
+        matches: &dyn Fn(&LockEntry) -> bool,
+        update: &mut dyn FnMut(&mut LockEntry),
+    ) -> Option<LockEntry>
+    {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.iter_mut().find(|e| matches(e))?;
+        update(entry);
+        Some(entry.clone())
+    }
+
+    fn remove(&self, matches: &dyn Fn(&LockEntry) -> bool) -> Vec<LockEntry> {
+        let mut entries = self.entries.lock().unwrap();
+        let (removed, keep) = entries.drain(..).partition(|e| matches(e));
+        *entries = keep;
+        removed
+    }
+
+    fn query(&self, matches: &dyn Fn(&LockEntry) -> bool) -> Vec<LockEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().filter(|e| matches(e)).cloned().collect()
+    }
+}
+
+/// Lock store in a file, so that locks survive a restart.
+///
+/// The locks are in memory, as in `MemStore`, and after every change the
+/// file is written again, one lock per line (JSON). The file is opened
+/// once, at startup, so it can be outside a chroot. Locks that expired
+/// while the server was down are removed when it is opened, lines that
+/// cannot be read (a write cut off by a crash) are skipped.
+#[derive(Debug)]
+pub struct FileStore {
+    mem:  MemStore,
+    file: Mutex<File>,
+    path: String,
+}
+
+impl FileStore {
+    /// Open or create the file `path`, and load the locks in it.
+    pub fn open(path: &str) -> io::Result<FileStore> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false).mode(0o600);
+        let mut file = options.open(path)?;
+        let mut data = String::new();
+        file.read_to_string(&mut data)?;
+        let mut entries = Vec::new();
+        for (n, line) in data.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+            match serde_json::from_str(line).ok().and_then(|v| from_json(&v)) {
+                Some(entry) => entries.push(entry),
+                None => warn!("locks: {}: line {}: cannot be read, skipped", path, n + 1),
+            }
+        }
+        let store = FileStore {
+            mem: MemStore {
+                entries: Mutex::new(entries),
+            },
+            file: Mutex::new(file),
+            path: path.to_string(),
+        };
+        for e in store.mem.sweep(SystemTime::now()) {
+            info!("lock: {} on {} (principal {:?}) expired", e.lock.token, e.fs_path(), e.lock.principal);
+        }
+        store.save();
+        Ok(store)
+    }
+
+    // Write all locks to the file.
+    fn save(&self) {
+        let mut file = self.file.lock().unwrap();
+        let mut data = String::new();
+        for e in self.mem.entries.lock().unwrap().iter() {
+            data.push_str(&to_json(e).to_string());
+            data.push('\n');
+        }
+        let res = file
+            .set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(data.as_bytes()))
+            .and_then(|_| file.sync_data());
+        if let Err(e) = res {
+            error!("locks: {}: cannot save: {}", self.path, e);
+        }
+    }
+}
+
+impl LockStore for FileStore {
+    fn create(&self, entry: LockEntry, conflicts: &dyn Fn(&LockEntry) -> bool) -> Result<(), Box<LockEntry>> {
+        self.mem.create(entry, conflicts)?;
+        self.save();
+        Ok(())
+    }
+
+    fn refresh(
+        &self,
+        matches: &dyn Fn(&LockEntry) -> bool,
+        update: &mut dyn FnMut(&mut LockEntry),
+    ) -> Option<LockEntry>
+    {
+        let entry = self.mem.refresh(matches, update)?;
+        self.save();
+        Some(entry)
+    }
+
+    fn remove(&self, matches: &dyn Fn(&LockEntry) -> bool) -> Vec<LockEntry> {
+        let removed = self.mem.remove(matches);
+        if !removed.is_empty() {
+            self.save();
+        }
+        removed
+    }
+
+    fn query(&self, matches: &dyn Fn(&LockEntry) -> bool) -> Vec<LockEntry> {
+        self.mem.query(matches)
+    }
+}
+
+// Times in the file are milliseconds since the epoch.
+fn to_json(e: &LockEntry) -> Value {
+    let lock = &e.lock;
+    let owner = lock.owner.as_ref().and_then(|o| {
+        let mut xml = Vec::new();
+        o.write(&mut xml).ok()?;
+        String::from_utf8(xml).ok()
+    });
+    let at = |t: SystemTime| t.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+    json!({
+        "root": e.root,
+        "token": lock.token,
+        "path": lock.path.as_url_string(),
+        "principal": lock.principal,
+        "owner": owner,
+        "timeout": lock.timeout.map(|t| t.as_millis() as u64),
+        "timeout_at": lock.timeout_at.map(at),
+        "shared": lock.shared,
+        "deep": lock.deep,
+    })
+}
+
+fn from_json(v: &Value) -> Option<LockEntry> {
+    let string = |name: &str| v.get(name).and_then(|s| s.as_str()).map(|s| s.to_string());
+    let millis = |name: &str| v.get(name).and_then(|t| t.as_u64()).map(Duration::from_millis);
+    let owner = match string("owner") {
+        Some(xml) => Some(Element::parse(xml.as_bytes()).ok()?),
+        None => None,
+    };
+    Some(LockEntry {
+        root: string("root")?,
+        lock: DavLock {
+            token: string("token")?,
+            path: DavPath::new(&string("path")?).ok()?,
+            principal: string("principal"),
+            owner,
+            timeout_at: millis("timeout_at").map(|t| UNIX_EPOCH + t),
+            timeout: millis("timeout"),
+            shared: v.get("shared")?.as_bool()?,
+            deep: v.get("deep")?.as_bool()?,
+        },
+    })
+}
+
+/// Default interval of the sweeper.
+pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps track of all locks.
+#[derive(Debug, Clone)]
+pub struct LockManager {
+    store:           Arc<dyn LockStore>,
+    default_timeout: Option<Duration>,
+    max_timeout:     Option<Duration>,
+    sweep_interval:  Duration,
+    max_per_user:    Option<usize>,
+    max_per_path:    Option<usize>,
+    principals:      Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl Default for LockManager {
+    fn default() -> LockManager {
+        LockManager {
+            store:           Arc::new(MemStore::default()),
+            default_timeout: None,
+            max_timeout:     None,
+            sweep_interval:  DEFAULT_SWEEP_INTERVAL,
+            max_per_user:    None,
+            max_per_path:    None,
+            principals:      Arc::default(),
+        }
+    }
+}
+
+impl LockManager {
+    /// Create a new, empty, lock manager.
+    pub fn new() -> LockManager {
+        LockManager::default()
+    }
+
+    /// Timeout for locks where the client did not ask for one.
+    pub fn default_timeout(mut self, timeout: Option<Duration>) -> LockManager {
+        self.default_timeout = timeout;
+        self
+    }
+
+    /// Maximum timeout we grant.
+    pub fn max_timeout(mut self, timeout: Option<Duration>) -> LockManager {
+        self.max_timeout = timeout;
+        self
+    }
+
+    /// How often the sweeper removes expired locks (0: never).
+    pub fn sweep_interval(mut self, interval: Duration) -> LockManager {
+        self.sweep_interval = interval;
+        self
+    }
+
+    /// Maximum number of active locks of one principal.
+    pub fn max_per_user(mut self, max: Option<usize>) -> LockManager {
+        self.max_per_user = max;
+        self
+    }
+
+    /// Maximum number of active locks on one path.
+    pub fn max_per_path(mut self, max: Option<usize>) -> LockManager {
+        self.max_per_path = max;
+        self
+    }
+
+    /// Keep the locks in `store`.
+    pub fn store(mut self, store: Arc<dyn LockStore>) -> LockManager {
+        self.store = store;
+        self
+    }
+
+    // The timeout we grant, given the timeout the client asked for,
+    // and whether that is less than what it asked for.
+    fn granted_timeout(&self, requested: Option<Duration>) -> (Option<Duration>, bool) {
+        let granted = match (requested.or(self.default_timeout), self.max_timeout) {
+            (Some(t), Some(max)) => Some(std::cmp::min(t, max)),
+            (None, max) => max,
+            (t, None) => t,
+        };
+        let clamped = match (requested, granted) {
+            (Some(r), Some(g)) => g < r,
+            (None, Some(_)) => self.default_timeout.is_none(),
+            (_, None) => false,
+        };
+        (granted, clamped)
+    }
+
+    // Grant a timeout for a lock, and log it if it was clamped.
+    fn grant(&self, requested: Option<Duration>, lock: &str, principal: Option<&str>) -> Option<Duration> {
+        let (granted, clamped) = self.granted_timeout(requested);
+        if clamped {
+            log_clamped(requested, granted, lock, principal);
+        }
+        granted
+    }
+
+    /// Return a locksystem for the directory `root`.
+    #[cfg(test)]
+    pub fn locksystem(&self, root: &str) -> Box<dyn DavLockSystem> {
+        self.locksystem_for(root, None)
+    }
+
+    /// Return a locksystem for the directory `root`, for requests of
+    /// `principal`. Lock discovery only shows the tokens of its locks.
+    pub fn locksystem_for(&self, root: &str, principal: Option<&str>) -> Box<dyn DavLockSystem> {
+        Box::new(DirLs {
+            root:      root.to_string(),
+            principal: principal.map(|p| p.to_string()),
+            manager:   self.clone(),
+        })
+    }
+
+    /// List all active locks.
+    pub fn list(&self) -> Vec<LockEntry> {
+        let now = SystemTime::now();
+        self.store.query(&|e| !e.expired(now))
+    }
+
+    /// Would a new lock on `path` in the directory `root`, by `principal`,
+    /// go over `max-per-user` or `max-per-path`. Checked before the webdav
+    /// handler gets the LOCK, so two at the same time can both get in.
+    pub fn over_limit(&self, root: &str, path: &DavPath, principal: Option<&str>) -> bool {
+        if self.max_per_user.is_none() && self.max_per_path.is_none() {
+            return false;
+        }
+        let now = SystemTime::now();
+        let over = |max: Option<usize>, matches: &dyn Fn(&LockEntry) -> bool| match max {
+            Some(max) => self.store.query(&|e| !e.expired(now) && matches(e)).len() >= max,
+            None => false,
+        };
+        let mine = |e: &LockEntry| e.lock.principal.as_deref() == principal;
+        (principal.is_some() && over(self.max_per_user, &mine)) ||
+            over(self.max_per_path, &|e| e.root == root && e.lock.path == *path)
+    }
+
+    /// Number of active locks per principal. Principals that had locks
+    /// before are listed with 0.
+    pub fn per_user(&self) -> BTreeMap<String, usize> {
+        let principals = self.principals.lock().unwrap();
+        let mut counts: BTreeMap<_, _> = principals.iter().map(|p| (p.clone(), 0)).collect();
+        for e in self.list() {
+            if let Some(p) = e.lock.principal {
+                *counts.entry(p).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    /// A LOCK without a body refreshes the locks in its If: header. The
+    /// webdav handler lets anyone do that, so check that the locks in the
+    /// directory `root` that the header mentions belong to `principal`.
+    /// Unknown tokens are left to the handler.
+    pub fn may_refresh(&self, root: &str, if_header: &str, principal: Option<&str>) -> bool {
+        let tokens = if_tokens(if_header);
+        let entries = self.store.query(&|e| e.root == root && tokens.contains(&e.lock.token.as_str()));
+        entries
+            .iter()
+            .all(|e| e.lock.principal.is_none() || e.lock.principal.as_deref() == principal)
+    }
+
+    /// Remove the locks that expired at `now`. Returns how many.
+    pub fn expire(&self, now: SystemTime) -> usize {
+        let expired = self.store.sweep(now);
+        for e in &expired {
+            info!("lock: {} on {} (principal {:?}) expired", e.lock.token, e.fs_path(), e.lock.principal);
+        }
+        expired.len()
+    }
+
+    /// The sweeper task.
+    pub async fn run(self) {
+        if self.sweep_interval == Duration::from_secs(0) {
+            return;
+        }
+        let mut interval = tokio::time::interval(self.sweep_interval);
+        loop {
+            interval.tick().await;
+            self.expire(SystemTime::now());
+        }
+    }
+
+    /// Forcibly release the lock with token `token`.
+    pub fn release_token(&self, token: &str) -> Vec<LockEntry> {
+        self.release(|e| e.lock.token == token)
+    }
+
+    /// Forcibly release all locks on or below the filesystem path `path`.
+    pub fn release_path(&self, path: &str) -> Vec<LockEntry> {
+        let path = path.trim_end_matches('/');
+        self.release(|e| {
+            let fs_path = e.fs_path();
+            fs_path == path || (fs_path.starts_with(path) && fs_path[path.len()..].starts_with('/'))
+        })
+    }
+
+    fn release(&self, matches: impl Fn(&LockEntry) -> bool) -> Vec<LockEntry> {
+        self.store.remove(&matches)
+    }
+}
+
+// Log a timeout that was less than the client asked for.
+fn log_clamped(requested: Option<Duration>, granted: Option<Duration>, lock: &str, principal: Option<&str>) {
+    let secs = |t: Option<Duration>| t.map(|t| t.as_secs().to_string());
+    info!(
+        target: AUDIT,
+        "lock: timeout for {} (principal {:?}) clamped from {} to {} seconds",
+        lock,
+        principal,
+        secs(requested).as_deref().unwrap_or("Infinite"),
+        secs(granted).as_deref().unwrap_or("Infinite"),
+    );
+}
+
+// The locksystem for one directory.
+#[derive(Debug, Clone)]
+struct DirLs {
+    root:      String,
+    principal: Option<String>,
+    manager:   LockManager,
+}
+
+impl DirLs {
+    // The store, after the expired locks are gone.
+    fn store(&self) -> &dyn LockStore {
+        self.manager.expire(SystemTime::now());
+        &*self.manager.store
+    }
+}
+
+/// The state tokens in an If: header, like <urn:uuid:...>; not the
+/// resource tags (<http://host/path>) or etags (["..."]).
+pub fn if_tokens(value: &str) -> Vec<&str> {
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|s| s.split('>').next())
+        .filter(|t| t.starts_with("urn:") || t.starts_with("opaquelocktoken:"))
+        .collect()
+}
+
+// The Result<_, DavLock> return types are dictated by the trait.
+#[allow(clippy::result_large_err)]
+impl DavLockSystem for DirLs {
+    fn lock(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        owner: Option<&Element>,
+        timeout: Option<Duration>,
+        shared: bool,
+        deep: bool,
+    ) -> Result<DavLock, DavLock>
+    {
+        let root = self.root.as_str();
+        let target = String::from_utf8_lossy(path.as_bytes());
+        let target = format!("{}{}", root.trim_end_matches('/'), target);
+        let requested = timeout;
+        let (timeout, clamped) = self.manager.granted_timeout(requested);
+        let lock = DavLock {
+            token: uuid::Uuid::new_v4().to_urn().to_string(),
+            path: path.clone(),
+            principal: principal.map(|s| s.to_string()),
+            owner: owner.cloned(),
+            timeout_at: timeout.map(|d| SystemTime::now() + d),
+            timeout,
+            shared,
+            deep,
+        };
+        let entry = LockEntry {
+            root: root.to_string(),
+            lock: lock.clone(),
+        };
+        // any conflicting locks on, above, or (if deep) below the path?
+        let conflicts = |e: &LockEntry| {
+            e.root == root &&
+                (covers(&e.lock, path) || (deep && is_below(&e.lock.path, path))) &&
+                !(shared && e.lock.shared)
+        };
+        self.store().create(entry, &conflicts).map_err(|e| e.lock)?;
+        if let Some(principal) = principal {
+            self.manager.principals.lock().unwrap().insert(principal.to_string());
+        }
+        if clamped {
+            log_clamped(requested, timeout, &target, principal);
+        }
+        Ok(lock)
+    }
+
+    fn unlock(&self, path: &DavPath, token: &str) -> Result<(), ()> {
+        let root = self.root.as_str();
+        let removed = self
+            .store()
+            .remove(&|e| e.root == root && e.lock.token == token && is_below(path, &e.lock.path));
+        if removed.is_empty() {
+            return Err(());
+        }
+        Ok(())
+    }
+
+    fn refresh(&self, path: &DavPath, token: &str, timeout: Option<Duration>) -> Result<DavLock, ()> {
+        let root = self.root.as_str();
+        let matches = |e: &LockEntry| e.root == root && e.lock.token == token && is_below(path, &e.lock.path);
+        let mut update = |entry: &mut LockEntry| {
+            let timeout = self.manager.grant(timeout, &entry.fs_path(), entry.lock.principal.as_deref());
+            entry.lock.timeout = timeout;
+            entry.lock.timeout_at = timeout.map(|d| SystemTime::now() + d);
+        };
+        let entry = self.store().refresh(&matches, &mut update).ok_or(())?;
+        Ok(entry.lock)
+    }
+
+    fn check(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        ignore_principal: bool,
+        deep: bool,
+        submitted_tokens: Vec<&str>,
+    ) -> Result<(), DavLock>
+    {
+        let root = self.root.as_str();
+        let entries = self.store().query(&|e| e.root == root);
+        let holds = |lock: &DavLock| {
+            submitted_tokens.iter().any(|t| *t == lock.token) &&
+                (ignore_principal || principal == lock.principal.as_deref())
+        };
+
+        // locks on the path itself, or deep locks above it.
+        let mut holds_lock = false;
+        let mut shared_lock = None;
+        for e in entries.iter().filter(|e| covers(&e.lock, path)) {
+            if holds(&e.lock) {
+                holds_lock = true;
+            } else if !e.lock.shared {
+                return Err(e.lock.clone());
+            } else {
+                shared_lock.get_or_insert(&e.lock);
+            }
+        }
+        if let (false, Some(lock)) = (holds_lock, shared_lock) {
+            return Err(lock.clone());
+        }
+
+        // if it's a deep check, we must hold all locks below the path as well.
+        if deep {
+            for e in entries.iter().filter(|e| is_below(&e.lock.path, path)) {
+                if !holds(&e.lock) {
+                    return Err(e.lock.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn discover(&self, path: &DavPath) -> Vec<DavLock> {
+        let root = self.root.as_str();
+        let entries = self.store().query(&|e| e.root == root && covers(&e.lock, path));
+        let now = SystemTime::now();
+        entries
+            .into_iter()
+            .map(|e| {
+                let mut lock = e.lock;
+                if lock.principal.is_some() && lock.principal != self.principal {
+                    lock.token = String::new();
+                }
+                // the time left, rounded up.
+                if let Some(at) = lock.timeout_at {
+                    let left = at.duration_since(now).unwrap_or_default();
+                    let secs = left.as_secs() + (left.subsec_nanos() > 0) as u64;
+                    lock.timeout = Some(Duration::from_secs(secs));
+                }
+                lock
+            })
+            .collect()
+    }
+
+    fn delete(&self, path: &DavPath) -> Result<(), ()> {
+        let root = self.root.as_str();
+        self.store().remove(&|e| e.root == root && is_below(&e.lock.path, path));
+        Ok(())
+    }
+}
+
+// In DAV:supportedlock the webdav handler puts the locktype into the
+// lockscope, like <lockscope><exclusive/><write/></lockscope>.
+const BAD_LOCKENTRY: &[u8] = b"<D:write></D:write></D:lockscope>";
+const GOOD_LOCKENTRY: &[u8] = b"</D:lockscope><D:locktype><D:write></D:write></D:locktype>";
+
+// Replace every BAD_LOCKENTRY.
+fn replace_lockentry(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut rest = data;
+    while let Some(pos) = rest.windows(BAD_LOCKENTRY.len()).position(|w| w == BAD_LOCKENTRY) {
+        out.extend_from_slice(&rest[..pos]);
+        out.extend_from_slice(GOOD_LOCKENTRY);
+        rest = &rest[pos + BAD_LOCKENTRY.len()..];
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+/// Correct the DAV:supportedlock property in the body of a PROPFIND
+/// response, while it is streamed.
+pub fn fix_supportedlock(body: hyper::Body) -> hyper::Body {
+    use hyper::body::HttpBody;
+
+    // the end of a chunk that might be the start of a BAD_LOCKENTRY is
+    // held back until the next chunk is there.
+    let stream = futures::stream::unfold(Some((body, Vec::new())), |state| {
+        async move {
+            let (mut body, carry) = state?;
+            let data = match body.data().await {
+                Some(Ok(chunk)) => [carry.as_slice(), &chunk[..]].concat(),
+                Some(Err(e)) => return Some((Err(e), None)),
+                None if carry.is_empty() => return None,
+                None => return Some((Ok(bytes::Bytes::from(carry)), None)),
+            };
+            let mut out = replace_lockentry(&data);
+            let keep = out.len().saturating_sub(BAD_LOCKENTRY.len() - 1);
+            let carry = out.split_off(keep);
+            Some((Ok(bytes::Bytes::from(out)), Some((body, carry))))
+        }
+    });
+    hyper::Body::wrap_stream(stream)
+}
+
+// path segments.
+fn segs(path: &DavPath) -> Vec<&[u8]> {
+    path.as_bytes().split(|&c| c == b'/').filter(|s| !s.is_empty()).collect()
+}
+
+// is `path` equal to, or below, `top`.
+fn is_below(path: &DavPath, top: &DavPath) -> bool {
+    let path = segs(path);
+    let top = segs(top);
+    path.len() >= top.len() && path[..top.len()] == top[..]
+}
+
+// does the lock apply to this path.
+fn covers(lock: &DavLock, path: &DavPath) -> bool {
+    if lock.deep {
+        is_below(path, &lock.path)
+    } else {
+        segs(&lock.path) == segs(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p(s: &str) -> DavPath {
+        DavPath::new(s).unwrap()
+    }
+
+    #[test]
+    fn test_locks() {
+        let mgr = LockManager::new();
+        let ls = mgr.locksystem("/home/a");
+
+        // exclusive depth-infinity lock on a directory.
+        let lock = ls.lock(&p("/dir/"), Some("a"), None, None, false, true).unwrap();
+        assert!(ls.lock(&p("/dir/file"), Some("a"), None, None, false, false).is_err());
+        assert!(ls.check(&p("/dir/file"), Some("a"), false, false, vec![]).is_err());
+        assert!(ls
+            .check(&p("/dir/file"), Some("a"), false, false, vec![lock.token.as_str()])
+            .is_ok());
+        assert!(ls.check(&p("/other"), None, false, false, vec![]).is_ok());
+
+        // a different directory does not see the lock.
+        let ls2 = mgr.locksystem("/home/b");
+        assert!(ls2.lock(&p("/dir/file"), Some("b"), None, None, false, false).is_ok());
+        assert_eq!(mgr.list().len(), 2);
+
+        // release everything below /home/a.
+        let released = mgr.release_path("/home/a/");
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].fs_path(), "/home/a/dir/");
+        assert!(ls.check(&p("/dir/file"), Some("a"), false, false, vec![]).is_ok());
+        assert_eq!(mgr.list().len(), 1);
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let secs = |n| Some(Duration::from_secs(n));
+        let mgr = LockManager::new().default_timeout(secs(60)).max_timeout(secs(300));
+        let ls = mgr.locksystem("/srv");
+
+        let lock = ls.lock(&p("/a"), None, None, None, false, false).unwrap();
+        assert_eq!(lock.timeout, secs(60));
+        let lock = ls.refresh(&p("/a"), &lock.token, secs(100_000)).unwrap();
+        assert_eq!(lock.timeout, secs(300));
+        let lock = ls.lock(&p("/b"), None, None, secs(120), false, false).unwrap();
+        assert_eq!(lock.timeout, secs(120));
+
+        let mgr = LockManager::new().max_timeout(secs(300));
+        let lock = mgr.locksystem("/srv").lock(&p("/a"), None, None, None, false, false).unwrap();
+        assert_eq!(lock.timeout, secs(300));
+
+        // what is clamped, and logged.
+        assert_eq!(mgr.granted_timeout(None), (secs(300), true));
+        assert_eq!(mgr.granted_timeout(secs(100_000)), (secs(300), true));
+        assert_eq!(mgr.granted_timeout(secs(300)), (secs(300), false));
+        let mgr = mgr.default_timeout(secs(60));
+        assert_eq!(mgr.granted_timeout(None), (secs(60), false));
+    }
+
+    // LOCK through the webdav handler, returns the timeout in the response.
+    async fn lock_timeout(mgr: &LockManager, timeout: &str) -> String {
+        use webdav_handler::{memfs::MemFs, DavHandler};
+
+        let dh = DavHandler::builder()
+            .filesystem(MemFs::new())
+            .locksystem(mgr.locksystem("/"))
+            .build_handler();
+        let body = concat!(
+            r#"<?xml version="1.0"?><D:lockinfo xmlns:D="DAV:">"#,
+            "<D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype>",
+            "</D:lockinfo>"
+        );
+        let req = http::Request::builder()
+            .method("LOCK")
+            .uri("/file")
+            .header("Timeout", timeout)
+            .body(hyper::Body::from(body))
+            .unwrap();
+        let resp = dh.handle(req).await;
+        let body = hyper::body::to_bytes(hyper::Body::wrap_stream(resp.into_body())).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        let start = body.find("<D:timeout>").unwrap() + 11;
+        let end = body.find("</D:timeout>").unwrap();
+        body[start..end].to_string()
+    }
+
+    #[tokio::test]
+    async fn test_timeout_clamp() {
+        let mgr = LockManager::new().max_timeout(Some(Duration::from_secs(300)));
+        assert_eq!(lock_timeout(&mgr, "Infinite").await, "Second-300");
+        mgr.release_path("/");
+        assert_eq!(lock_timeout(&mgr, "Second-4100000000").await, "Second-300");
+        mgr.release_path("/");
+        assert_eq!(lock_timeout(&mgr, "Second-30").await, "Second-30");
+    }
+
+    #[test]
+    fn test_over_limit() {
+        let mgr = LockManager::new().max_per_user(Some(2)).max_per_path(Some(1));
+        let ls = mgr.locksystem("/srv");
+        assert!(!mgr.over_limit("/srv", &p("/a"), Some("alice")));
+        ls.lock(&p("/a"), Some("alice"), None, None, true, false).unwrap();
+        assert!(mgr.over_limit("/srv", &p("/a"), Some("bob")));
+        assert!(!mgr.over_limit("/other", &p("/a"), Some("bob")));
+        assert!(!mgr.over_limit("/srv", &p("/b"), Some("alice")));
+        let lock = ls.lock(&p("/b"), Some("alice"), None, None, false, false).unwrap();
+        assert!(mgr.over_limit("/other", &p("/c"), Some("alice")));
+        assert!(!mgr.over_limit("/srv", &p("/c"), None));
+        assert_eq!(mgr.per_user().get("alice"), Some(&2));
+
+        mgr.release_token(&lock.token);
+        assert!(!mgr.over_limit("/other", &p("/c"), Some("alice")));
+        mgr.release_path("/srv");
+        assert_eq!(mgr.per_user().get("alice"), Some(&0));
+        assert!(!LockManager::new().over_limit("/srv", &p("/a"), None));
+    }
+
+    #[test]
+    fn test_may_refresh() {
+        let mgr = LockManager::new();
+        let ls = mgr.locksystem("/srv");
+        let lock = ls.lock(&p("/a"), Some("alice"), None, None, false, false).unwrap();
+        let anon = ls.lock(&p("/b"), None, None, None, false, false).unwrap();
+
+        let hdr = format!("</dav/a> (<{}> [\"etag\"])", lock.token);
+        assert_eq!(if_tokens(&hdr), vec![lock.token.as_str()]);
+        assert!(mgr.may_refresh("/srv", &hdr, Some("alice")));
+        assert!(!mgr.may_refresh("/srv", &hdr, Some("bob")));
+        assert!(!mgr.may_refresh("/srv", &hdr, None));
+        // another directory, anonymous locks, unknown tokens.
+        assert!(mgr.may_refresh("/other", &hdr, Some("bob")));
+        assert!(mgr.may_refresh("/srv", &format!("(<{}>)", anon.token), Some("bob")));
+        assert!(mgr.may_refresh("/srv", "(<urn:uuid:nope>)", Some("bob")));
+    }
+
+    #[test]
+    fn test_expire() {
+        let mgr = LockManager::new();
+        let ls = mgr.locksystem("/srv");
+        ls.lock(&p("/a"), None, None, Some(Duration::from_secs(60)), false, false).unwrap();
+        ls.lock(&p("/b"), None, None, None, false, false).unwrap();
+
+        let now = SystemTime::now();
+        assert_eq!(mgr.expire(now), 0);
+        assert_eq!(mgr.expire(now + Duration::from_secs(61)), 1);
+        assert_eq!(mgr.store.query(&|_| true).len(), 1);
+        assert_eq!(mgr.list()[0].lock.path, p("/b"));
+    }
+
+    #[test]
+    fn test_release_token() {
+        let mgr = LockManager::new();
+        let ls = mgr.locksystem("/srv");
+        let l1 = ls.lock(&p("/a"), None, None, None, true, false).unwrap();
+        let _l2 = ls.lock(&p("/a"), None, None, None, true, false).unwrap();
+        assert_eq!(mgr.release_token(&l1.token).len(), 1);
+        assert_eq!(mgr.list().len(), 1);
+        assert!(mgr.release_path("/sr").is_empty());
+    }
+
+    #[test]
+    fn test_mem_store() {
+        let store = MemStore::default();
+        let mgr = LockManager::new();
+        let lock = mgr.locksystem("/srv").lock(&p("/a"), None, None, None, false, false).unwrap();
+        let entry = mgr.list().remove(0);
+        assert!(store.create(entry.clone(), &|_| false).is_ok());
+        let conflict = store.create(entry.clone(), &|e| e.lock.token == lock.token).unwrap_err();
+        assert_eq!(conflict.lock.token, lock.token);
+
+        let timeout = Some(Duration::from_secs(10));
+        let refreshed = store.refresh(&|e| e.lock.token == lock.token, &mut |e| e.lock.timeout = timeout);
+        assert_eq!(refreshed.unwrap().lock.timeout, timeout);
+        assert!(store.refresh(&|_| false, &mut |_| ()).is_none());
+        assert_eq!(store.query(&|_| true).len(), 1);
+        assert!(store.sweep(SystemTime::now()).is_empty());
+        assert_eq!(store.remove(&|e| e.root == "/srv").len(), 1);
+        assert!(store.query(&|_| true).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fix_supportedlock() {
+        let body = concat!(
+            "<D:supportedlock><D:lockentry><D:lockscope><D:exclusive></D:exclusive>",
+            "<D:write></D:write></D:lockscope></D:lockentry></D:supportedlock>"
+        );
+        let expected = concat!(
+            "<D:supportedlock><D:lockentry><D:lockscope><D:exclusive></D:exclusive></D:lockscope>",
+            "<D:locktype><D:write></D:write></D:locktype></D:lockentry></D:supportedlock>"
+        );
+        // split into chunks everywhere.
+        for n in 1..body.len() {
+            let chunks: Vec<Result<_, std::io::Error>> = vec![Ok(&body[..n]), Ok(&body[n..])];
+            let fixed = fix_supportedlock(hyper::Body::wrap_stream(futures::stream::iter(chunks)));
+            assert_eq!(hyper::body::to_bytes(fixed).await.unwrap(), expected);
+        }
+        let fixed = fix_supportedlock(hyper::Body::from("<D:prop/>"));
+        assert_eq!(hyper::body::to_bytes(fixed).await.unwrap(), "<D:prop/>");
+    }
+
+    #[test]
+    fn test_discover() {
+        let mgr = LockManager::new();
+        let ls = mgr.locksystem_for("/srv", Some("alice"));
+        let timeout = Some(Duration::from_secs(60));
+        let lock = ls.lock(&p("/a"), Some("alice"), None, timeout, false, false).unwrap();
+        let anon = ls.lock(&p("/b"), None, None, None, false, false).unwrap();
+
+        let found = ls.discover(&p("/a"));
+        assert_eq!(found[0].token, lock.token);
+        assert!(found[0].timeout <= timeout && found[0].timeout >= Some(Duration::from_secs(59)));
+        let other = mgr.locksystem_for("/srv", Some("bob"));
+        assert_eq!(other.discover(&p("/a"))[0].token, "");
+        assert_eq!(mgr.locksystem("/srv").discover(&p("/a"))[0].token, "");
+        assert_eq!(other.discover(&p("/b"))[0].token, anon.token);
+        assert_eq!(other.discover(&p("/b"))[0].timeout, None);
+    }
+
+    #[test]
+    fn test_file_store() {
+        let file = std::env::temp_dir().join(format!("webdav-test-{}", uuid::Uuid::new_v4().to_simple()));
+        let file = file.to_str().unwrap();
+        let secs = |n| Some(Duration::from_secs(n));
+        let open = || LockManager::new().store(Arc::new(FileStore::open(file).unwrap()));
+
+        let mgr = open();
+        let ls = mgr.locksystem_for("/srv", Some("alice"));
+        let owner = Element::parse(&br#"<D:owner xmlns:D="DAV:"><D:href>me</D:href></D:owner>"#[..]).unwrap();
+        let lock = ls.lock(&p("/dir/"), Some("alice"), Some(&owner), secs(600), false, true).unwrap();
+        let short = ls.lock(&p("/short"), Some("alice"), None, secs(600), true, false).unwrap();
+        ls.lock(&p("/gone"), None, None, None, false, false).unwrap();
+        mgr.release(|e| e.lock.path == p("/gone"));
+        // the short lock expires while the server is down.
+        mgr.store.refresh(&|e| e.lock.token == short.token, &mut |e| {
+            e.lock.timeout_at = Some(SystemTime::now() - Duration::from_secs(1));
+        });
+        drop((ls, mgr));
+
+        // restart: the lock is back, with its expiry time.
+        let mgr = open();
+        let locks = mgr.list();
+        assert_eq!(locks.len(), 1);
+        let again = &locks[0].lock;
+        assert_eq!((again.token.as_str(), again.principal.as_deref()), (lock.token.as_str(), Some("alice")));
+        assert_eq!(again.path, p("/dir/"));
+        assert!(again.path.is_collection() && again.deep && !again.shared);
+        assert_eq!(again.timeout, secs(600));
+        let at = |t: Option<SystemTime>| t.unwrap().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        assert_eq!(at(again.timeout_at), at(lock.timeout_at));
+        assert_eq!(again.owner.as_ref().unwrap().get_child("href").unwrap().get_text().unwrap(), "me");
+        let ls = mgr.locksystem_for("/srv", Some("alice"));
+        assert!(ls.check(&p("/dir/file"), Some("alice"), false, false, vec![]).is_err());
+        assert!(ls
+            .check(&p("/dir/file"), Some("alice"), false, false, vec![lock.token.as_str()])
+            .is_ok());
+
+        // the expired lock was pruned from the file, a broken line is skipped.
+        let data = std::fs::read_to_string(file).unwrap();
+        assert_eq!(data.lines().count(), 1);
+        assert!(!data.contains(&short.token));
+        std::fs::write(file, format!("{}{{\"root\": \"/srv\", \"tok", data)).unwrap();
+        drop((ls, mgr));
+        assert_eq!(open().list().len(), 1);
+
+        // the sweeper removes expired locks from the file too.
+        let mgr = open();
+        assert_eq!(mgr.expire(SystemTime::now() + Duration::from_secs(601)), 1);
+        assert_eq!(std::fs::read_to_string(file).unwrap(), "");
+        assert!(open().list().is_empty());
+        std::fs::remove_file(file).unwrap();
+    }
+}
@@ -14,26 +14,83 @@
 #[macro_use]
 extern crate log;
 
+mod admin;
+mod allprop;
+mod applequota;
 mod auth;
+mod autoindex;
+mod baseurl;
+mod bodylog;
+mod byteranges;
 mod cache;
+mod capabilities;
+mod charset;
+mod checksum;
+mod chunked;
+mod clientcert;
+mod coalesce;
 mod config;
+mod context;
+mod control;
+mod crossfs;
+mod depth;
+mod disposition;
+mod errorpage;
+mod etag;
+mod fserror;
+mod fsync;
+mod idle;
+mod ipacl;
+mod jail;
+mod keepalive;
+mod limits;
+mod locks;
+mod metrics;
+mod mkcol;
+mod normalize;
+mod nsprefix;
+mod onefs;
+#[cfg(feature = "pam")]
+mod pamauth;
+mod pathhook;
+mod policy;
+mod precondition;
+mod probecache;
+mod readahead;
+mod report;
+mod requestid;
 mod rootfs;
+mod rootprobe;
+mod shared;
+mod shed;
+mod slowlog;
 #[doc(hidden)]
 pub mod router;
+mod statcache;
 mod suid;
 mod tls;
+mod timewindow;
+mod transcode;
+mod truncate;
+mod transfers;
+mod trash;
 mod unixuser;
+mod uploadfilter;
 mod userfs;
+mod vary;
+mod xmlbody;
 
 use std::convert::TryFrom;
 use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
 use std::os::unix::io::{FromRawFd, AsRawFd};
 use std::process::exit;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use clap::clap_app;
-use headers::{authorization::Basic, Authorization, HeaderMapExt};
+use futures::StreamExt;
 use http::status::StatusCode;
 use hyper::{
     self,
@@ -41,51 +98,249 @@ use hyper::{
     service::{make_service_fn, service_fn},
 };
 use tls_listener::TlsListener;
+use tokio_rustls::rustls::Session;
 use tokio_rustls::server::TlsStream;
 use webdav_handler::{davpath::DavPath, DavConfig, DavHandler, DavMethod, DavMethodSet};
-use webdav_handler::{fakels::FakeLs, fs::DavFileSystem, ls::DavLockSystem};
-
-use crate::config::{AcctType, Auth, CaseInsensitive, Handler, Location, OnNotfound};
+use webdav_handler::fs::DavFileSystem;
+use webdav_handler::ls::DavLockSystem;
+
+use crate::auth::{ConnAuth, Identity};
+use crate::baseurl::BaseUrl;
+use crate::bodylog::BodyLog;
+use crate::checksum::Checksum;
+use crate::clientcert::CertMap;
+use crate::config::Capabilities;
+use crate::errorpage::ErrorPages;
+use crate::config::{AccessErrors, Accounts, AcctType, Auth, CaseInsensitive, ClientAuth, Handler, Location};
+use crate::config::{AllProp, DuplicateHeaders, EtagStrength, OnNotfound, PutRange, UnexpectedBody};
+use crate::context::{CancelToken, RequestContext};
+use crate::idle::{IdleReaper, IdleStream};
+use crate::ipacl::IpAcl;
+use crate::limits::{MemoryBudget, RateLimiter, UploadLimiter, UserGuard, UserLimiter, WriteLimiter};
+use crate::metrics::Metrics;
+use crate::locks::LockManager;
+use crate::onefs::OneFs;
+use crate::pathhook::PathHooks;
+use crate::policy::Policy;
 use crate::rootfs::RootFs;
+use crate::rootprobe::RootProbe;
 use crate::router::MatchedRoute;
+use crate::probecache::ProbeCache;
+use crate::shed::Shedder;
+use crate::statcache::StatCache;
 use crate::suid::proc_switch_ugid;
+use crate::timewindow::WriteWindows;
 use crate::tls::tls_config;
-use crate::userfs::UserFs;
-
-static PROGNAME: &'static str = "webdav-server";
+use crate::transcode::{Passthrough, TranscodeHook};
+use crate::transfers::Transfers;
+use crate::trash::{Sweeper, Trash};
+use crate::uploadfilter::{Chain, UploadFilter};
+use crate::userfs::{ConnEncoding, Precompressed, UserFs};
+
+static PROGNAME: &str = "webdav-server";
+
+// RFC 5842 methods. We do not support bindings (we do not advertise
+// "bind" in the DAV: header either), but these are known methods, so
+// they get a 501 Not Implemented instead of 405 Method Not Allowed.
+const BIND_METHODS: &[&str] = &["BIND", "REBIND", "UNBIND"];
+
+// RFC 3253 (DeltaV) methods. Versioning is not implemented either, and
+// not advertised ("version-control" is not in the DAV: header), so these
+// are a 501 as well. A versioning client that asks for DAV:checked-in,
+// DAV:version-name or DAV:supported-method-set in a PROPFIND gets them in
+// a "404 Not Found" propstat, and a REPORT DAV:version-tree is refused
+// with DAV:supported-report (see `report`): the resources are simply not
+// under version control.
+const DELTAV_METHODS: &[&str] = &[
+    "VERSION-CONTROL",
+    "CHECKOUT",
+    "CHECKIN",
+    "UNCHECKOUT",
+    "MKWORKSPACE",
+    "UPDATE",
+    "LABEL",
+    "MERGE",
+    "BASELINE-CONTROL",
+    "MKACTIVITY",
+];
+
+// Methods that echo the request (cross-site tracing). Always refused,
+// whatever the configuration and path, and never in an Allow header.
+const TRACE_METHODS: &[&str] = &["TRACE", "TRACK"];
+
+// Default retention (in seconds) of deleted files in the trash.
+const DEFAULT_TRASH_RETENTION: u64 = 30 * 86400;
+
+// Default maximum number of directories that auto-mkcol creates for a request.
+const DEFAULT_AUTO_MKCOL_DEPTH: usize = 8;
+
+// Default time (in seconds) after which unfinished chunked uploads are removed.
+const DEFAULT_UPLOAD_EXPIRY: u64 = 86400;
+
+// Default time (in seconds) that a shutdown waits for requests to finish.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+
+// Default time (in seconds) that a shutdown waits for a connection
+// that does not send or receive anything.
+const DEFAULT_SHUTDOWN_CONN_TIMEOUT: u64 = 10;
+
+// Default listen(2) backlog.
+const DEFAULT_LISTEN_BACKLOG: i32 = 128;
+
+// Default value (in seconds) of the Retry-After header on a 503.
+const DEFAULT_RETRY_AFTER: u64 = 5;
 
 // Contains "state" and a handle to the config.
 #[derive(Clone)]
 struct Server {
-    dh:     DavHandler,
-    auth:   auth::Auth,
-    config: Arc<config::Config>,
+    dh:          DavHandler,
+    auth:        auth::Auth,
+    ipacl:       IpAcl,
+    bodylog:     Option<BodyLog>,
+    locks:       LockManager,
+    reports:     Arc<report::Reports>,
+    pathhooks:   Arc<Vec<PathHooks>>,
+    windows:     Arc<Vec<Option<WriteWindows>>>,
+    userlimiter: UserLimiter,
+    uploads:     UploadLimiter,
+    writes:      WriteLimiter,
+    memory:      MemoryBudget,
+    ratelimiter: RateLimiter,
+    shedder:     Shedder,
+    statcaches:  Arc<Vec<Option<StatCache>>>,
+    probecaches: Arc<Vec<Option<ProbeCache>>>,
+    rootprobes:  Arc<Vec<Option<RootProbe>>>,
+    autoindex:   Arc<Vec<Option<autoindex::Templates>>>,
+    filters:     Arc<Vec<Option<Arc<dyn UploadFilter>>>>,
+    transcoders: Arc<Vec<Arc<dyn TranscodeHook>>>,
+    certmap:     Option<Arc<CertMap>>,
+    errorpages:  Arc<ErrorPages>,
+    sweeper:     Sweeper,
+    idle:        IdleReaper,
+    metrics:     Metrics,
+    transfers:   Transfers,
+    public_url:  Option<BaseUrl>,
+    config:      Arc<config::Config>,
 }
 
-type HttpResult = Result<hyper::Response<webdav_handler::body::Body>, io::Error>;
+type HttpResult = Result<hyper::Response<hyper::Body>, io::Error>;
 type HttpRequest = http::Request<hyper::Body>;
 
 // Server implementation.
 impl Server {
     // Constructor.
     pub fn new(config: Arc<config::Config>, auth: auth::Auth) -> Self {
-        // mostly empty handler.
-        let ls = FakeLs::new() as Box<dyn DavLockSystem>;
-        let dh = DavHandler::builder().locksystem(ls).build_handler();
+        // empty handler, filesystem and locksystem are set per request.
+        let dh = DavHandler::new();
+        let secs = |s: Option<u64>| s.map(Duration::from_secs);
+        let locks = LockManager::new()
+            .default_timeout(secs(config.locks.default_timeout))
+            .max_timeout(secs(config.locks.max_timeout))
+            .sweep_interval(secs(config.locks.sweep_interval).unwrap_or(locks::DEFAULT_SWEEP_INTERVAL))
+            .max_per_user(config.locks.max_per_user)
+            .max_per_path(config.locks.max_per_path);
+        let reports = Arc::new(report::Reports::new());
+        let pathhooks = config.location.iter().map(PathHooks::for_location).collect();
+        // checked by config::check.
+        let windows = config
+            .location
+            .iter()
+            .map(|l| WriteWindows::new(&l.write_windows, l.write_timezone.as_deref()).ok().flatten())
+            .collect();
+        let userlimiter = UserLimiter::new(config.server.max_requests_per_user);
+        let statcaches = config
+            .location
+            .iter()
+            .map(|l| l.stat_cache.map(|s| StatCache::new(Duration::from_secs(s))))
+            .collect();
+        let probecaches = config
+            .location
+            .iter()
+            .map(|l| l.probe_cache.map(|s| ProbeCache::new(Duration::from_secs(s))))
+            .collect();
+        let rootprobes = config
+            .location
+            .iter()
+            .map(|l| l.root_probe.map(|s| RootProbe::new(Duration::from_secs(s))))
+            .collect();
+        // checked by config::check.
+        let autoindex = config
+            .location
+            .iter()
+            .map(|l| autoindex::Templates::load(l).ok().flatten())
+            .collect();
+        let filters = config
+            .location
+            .iter()
+            .map(|l| l.upload_filter.as_deref().and_then(|f| uploadfilter::from_config(f).ok()))
+            .collect();
+        let transcoders = config
+            .location
+            .iter()
+            .map(|_| Arc::new(Passthrough) as Arc<dyn TranscodeHook>)
+            .collect();
+        let public_url = config.server.public_url.as_ref().and_then(|u| BaseUrl::parse(u).ok());
+        // checked by config::check.
+        let certmap = CertMap::new(&config.client_cert).ok().map(Arc::new);
+        // checked by config::check.
+        let errorpages = ErrorPages::load(&config.error_pages).unwrap_or_default();
+
+        let memory = MemoryBudget::new(config.server.memory_budget);
+
+        let metrics = match config.metrics.sink {
+            Some(config::MetricsSink::Prometheus) => Metrics::with_prometheus(),
+            _ => Metrics::new(),
+        };
 
-        Server { dh, auth, config }
+        Server {
+            dh,
+            auth: auth.with_metrics(metrics.clone()),
+            // checked by config::check.
+            ipacl: IpAcl::new(&config.server.allow_from, &config.server.deny_from).unwrap_or_default(),
+            // checked by config::check.
+            bodylog: BodyLog::new(&config.server.log_bodies, config.server.log_body_max).ok().flatten(),
+            locks,
+            reports,
+            pathhooks: Arc::new(pathhooks),
+            windows: Arc::new(windows),
+            userlimiter,
+            uploads: UploadLimiter::new(config.server.max_upload_buffer, memory.clone()),
+            writes: WriteLimiter::new(config.server.max_writes),
+            memory,
+            ratelimiter: RateLimiter::new(config.server.max_request_rate, config.server.request_burst),
+            shedder: Shedder::new(
+                config.server.shed_connections,
+                config.server.shed_memory,
+                config.server.shed_writes,
+                config.server.shed_retry_after,
+            ),
+            statcaches: Arc::new(statcaches),
+            probecaches: Arc::new(probecaches),
+            rootprobes: Arc::new(rootprobes),
+            autoindex: Arc::new(autoindex),
+            filters: Arc::new(filters),
+            transcoders: Arc::new(transcoders),
+            certmap,
+            errorpages: Arc::new(errorpages),
+            sweeper: Sweeper::new(),
+            idle: IdleReaper::new(config.server.idle_timeout.map(Duration::from_secs)),
+            metrics,
+            transfers: Transfers::new(),
+            public_url,
+            config,
+        }
     }
 
     // check user account.
     async fn acct<'a>(
         &'a self,
         location: &Location,
-        auth_user: Option<&'a String>,
+        auth_user: Option<&'a Identity>,
         user_param: Option<&'a str>,
-    ) -> Result<Option<Arc<unixuser::User>>, StatusCode>
+    ) -> Result<Option<Arc<Identity>>, StatusCode>
     {
         // Get username - if any.
-        let user = match auth_user.map(|u| u.as_str()).or(user_param) {
+        let user = match auth_user.map(|u| u.username.as_str()).or(user_param) {
             Some(u) => u,
             None => return Ok(None),
         };
@@ -111,47 +366,213 @@ impl Server {
         };
 
         // check minimum uid
-        if let Some(min_uid) = self.config.unix.min_uid {
-            if pwd.uid < min_uid {
-                debug!("acct: {}: uid {} too low (<{})", pwd.name, pwd.uid, min_uid);
+        if let (Some(min_uid), Some(uid)) = (self.config.unix.min_uid, pwd.uid) {
+            if uid < min_uid {
+                debug!("acct: {}: uid {} too low (<{})", pwd.username, uid, min_uid);
                 return Err(StatusCode::FORBIDDEN);
             }
         }
         Ok(Some(pwd))
     }
 
-    // return a new response::Builder with the Server: header set.
-    fn response_builder(&self) -> http::response::Builder {
-        let mut builder = hyper::Response::builder();
-        let id = self
-            .config
-            .server
-            .identification
-            .as_ref()
-            .map(|s| s.as_str())
-            .unwrap_or("webdav-server-rs");
-        if id != "" {
-            builder = builder.header("Server", id);
+    // The last things to do for every response: the Server headers,
+    // the request ID, the DAV: prefix, Content-Length, keep-alive,
+    // and the metrics.
+    fn finish_response(
+        &self,
+        res: &mut hyper::Response<hyper::Body>,
+        method: &http::Method,
+        start: Instant,
+        ctx: &RequestContext,
+    )
+    {
+        self.set_server_headers(res.headers_mut());
+        // checked by requestid::for_request.
+        res.headers_mut().insert(requestid::HEADER, ctx.request_id.parse().unwrap());
+        // before keep-alive, the body may lose its length.
+        if let Some(prefix) = self.config.server.dav_prefix.as_deref() {
+            nsprefix::apply(res, method, prefix);
+        }
+        keepalive::set_length(res, *method == http::Method::HEAD);
+        // hyper closes the connection after a response with "Connection: close".
+        let keep_alive = ctx.keep_alive && self.config.server.keep_alive != Some(false);
+        keepalive::apply(res, ctx.version, keep_alive);
+
+        // only methods that we know, any method name could be sent.
+        let known = method.as_str() == "REPORT" || DavMethod::try_from(method).is_ok();
+        let method = if known { method.as_str() } else { "other" };
+        let sink = self.metrics.sink();
+        let status = res.status();
+        sink.incr_counter("webdav_requests_total", &[("method", method), ("status", status.as_str())], 1);
+        let elapsed = start.elapsed().as_secs_f64();
+        sink.observe_histogram("webdav_request_duration_seconds", &[("method", method)], elapsed);
+        sink.set_gauge("webdav_open_connections", &[], self.idle.stats().open as f64);
+        sink.set_gauge("webdav_active_writes", &[], self.writes.active() as f64);
+        sink.set_gauge("webdav_memory_used_bytes", &[], self.memory.used() as f64);
+        for (user, count) in self.locks.per_user() {
+            sink.set_gauge("webdav_locks", &[("user", &user)], count as f64);
         }
-        builder
     }
 
-    // Set Server: webdav-server-rs header.
-    fn set_server_header(&self, headers: &mut http::HeaderMap<http::header::HeaderValue>) {
-        let id = self
-            .config
-            .server
-            .identification
-            .as_ref()
-            .map(|s| s.as_str())
-            .unwrap_or("webdav-server-rs");
-        if id != "" {
+    // Set the Server: and X-Powered-By: headers, on every response.
+    fn set_server_headers(&self, headers: &mut http::HeaderMap<http::header::HeaderValue>) {
+        // both checked by config::check.
+        let server = &self.config.server;
+        let id = server.identification.as_deref().unwrap_or("webdav-server-rs");
+        if !id.is_empty() {
             headers.insert("server", id.parse().unwrap());
         }
+        if let Some(powered_by) = server.powered_by.as_deref().filter(|p| !p.is_empty()) {
+            headers.insert("x-powered-by", powered_by.parse().unwrap());
+        }
     }
 
-    // handle a request.
+    // Authentication state of a new TLS connection. When client
+    // certificates are required, a certificate that does not map to
+    // a user ends the connection.
+    async fn tls_conn_auth(&self, cert: Option<Vec<u8>>, remote_addr: SocketAddr) -> io::Result<ConnAuth> {
+        let user = match (cert, self.certmap.as_ref()) {
+            (Some(cert), Some(certmap)) => certmap.username(&cert).await,
+            _ => None,
+        };
+        if user.is_none() && self.config.server.tls_client_auth == Some(ClientAuth::Required) {
+            info!("tls: {}: client certificate does not map to a user", remote_addr);
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "client certificate not accepted"));
+        }
+        Ok(ConnAuth::with_client_cert(user))
+    }
+
+    // handle a request, and log it if it was slow.
     async fn route(&self, req: HttpRequest, remote_ip: SocketAddr) -> HttpResult {
+        // On a dual-stack listener IPv4 clients show up as ::ffff:a.b.c.d.
+        let remote_ip = SocketAddr::new(auth::canonical_ip(remote_ip.ip()), remote_ip.port());
+        let timeout = self.config.server.request_timeout.map(Duration::from_secs);
+        let mut ctx = RequestContext::new(remote_ip, timeout);
+        ctx.request_id = requestid::for_request(req.headers(), remote_ip.ip().is_loopback());
+        ctx.version = req.version();
+        ctx.keep_alive = keepalive::requested(req.version(), req.headers());
+        let request_id = ctx.request_id.clone();
+
+        let start = Instant::now();
+        let method = req.method().clone();
+        // browsers get an HTML page when a GET fails.
+        let get = method == http::Method::GET || method == http::Method::HEAD;
+        let html = get && errorpage::wants_html(req.headers());
+        let threshold = self.config.server.slow_request.unwrap_or(slowlog::DEFAULT_THRESHOLD);
+        let path = req.uri().path().to_string();
+        // the bodies of these methods are logged, see `bodylog`.
+        let bodylog = self.bodylog.as_ref().filter(|b| b.wants(&method));
+        let req = match bodylog {
+            Some(bodylog) => bodylog.request(req),
+            None => req,
+        };
+        // the slow request log counts the bytes of the body.
+        let req = match threshold {
+            0 => req,
+            _ => {
+                let timing = ctx.timing.clone();
+                req.map(|body| timing.count_request(body))
+            },
+        };
+        let mut res = requestid::scope(request_id, self.dispatch(req, &mut ctx)).await?;
+        audit_shared(&method, &path, &ctx, res.status());
+        if html {
+            res = self.errorpages.apply(res, method == http::Method::HEAD);
+        }
+        self.finish_response(&mut res, &method, start, &ctx);
+        if let Some(bodylog) = bodylog {
+            res = res.map(|body| bodylog.response(body, &ctx.request_id, &method, &path));
+        }
+        if threshold == 0 {
+            return Ok(res);
+        }
+        let request = slowlog::Request {
+            method: method.to_string(),
+            path,
+            user: ctx.identity.as_ref().map(|id| id.username.clone()),
+            id: ctx.request_id.clone(),
+            metrics: self.metrics.clone(),
+        };
+        let threshold = Duration::from_secs(threshold);
+        Ok(res.map(|body| ctx.timing.clone().finish(body, request, threshold)))
+    }
+
+    // route a request to the admin API or a location.
+    async fn dispatch(&self, req: HttpRequest, ctx: &mut RequestContext) -> HttpResult {
+        let remote_ip = ctx.remote_ip;
+
+        // allow-from and deny-from, before anything else.
+        let client_ip = auth::forwarded_ip(req.headers(), remote_ip.ip()).unwrap_or_else(|| remote_ip.ip());
+        if !self.ipacl.allowed(client_ip) {
+            debug!("route: {}: refused by allow-from / deny-from", client_ip);
+            let mut resp = self.error(StatusCode::FORBIDDEN).await?;
+            resp.headers_mut().insert("connection", "close".parse().unwrap());
+            return Ok(resp);
+        }
+
+        if TRACE_METHODS.iter().any(|m| req.method().as_str().eq_ignore_ascii_case(m)) {
+            debug!("route: {} refused", req.method());
+            return self.error(StatusCode::METHOD_NOT_ALLOWED).await;
+        }
+
+        // Before the target is parsed any further.
+        let max_uri = self.config.server.max_uri_length.unwrap_or(limits::DEFAULT_MAX_URI_LENGTH);
+        if let Err(status) = limits::check_uri(req.uri(), max_uri) {
+            debug!("route: request target longer than {} bytes", max_uri);
+            return self.error(status).await;
+        }
+
+        // hyper has already refused headers that are far too large.
+        let max_size = self.config.server.max_header_size.unwrap_or(limits::DEFAULT_MAX_HEADER_SIZE);
+        let max_count = self.config.server.max_headers.unwrap_or(limits::MAX_HEADER_COUNT);
+        if let Err(status) = limits::check_headers(req.headers(), max_size, max_count) {
+            debug!("route: {}: request headers too large", req.uri().path());
+            return self.error(status).await;
+        }
+
+        // Headers that a proxy in front may read differently than we do.
+        if self.config.server.duplicate_headers != Some(DuplicateHeaders::Allow) {
+            if let Err(e) = limits::check_framing(req.headers()) {
+                debug!("route: {}: {}", req.uri().path(), e);
+                let mut resp = self.error(StatusCode::BAD_REQUEST).await?;
+                resp.headers_mut().insert("connection", "close".parse().unwrap());
+                return Ok(resp);
+            }
+        }
+
+        // A body where there should be none: read it, so that the next
+        // request on the connection is not read from the middle of it.
+        let mut req = req;
+        if limits::unexpected_body(req.method(), req.headers()) {
+            debug!("route: {} {}: unexpected request body", req.method(), req.uri().path());
+            let body = std::mem::replace(req.body_mut(), hyper::Body::empty());
+            if let Err(status) = limits::drain(body, limits::MAX_DRAIN).await {
+                let mut resp = self.error(status).await?;
+                resp.headers_mut().insert("connection", "close".parse().unwrap());
+                return Ok(resp);
+            }
+            if self.config.server.unexpected_body == Some(UnexpectedBody::Reject) {
+                return self.error(StatusCode::BAD_REQUEST).await;
+            }
+            req.headers_mut().remove("transfer-encoding");
+            req.headers_mut().insert("content-length", 0.into());
+        }
+
+        // Normalize the paths before anything looks at them.
+        if let Err(e) = normalize_paths(&mut req, self.config.server.strict_paths) {
+            debug!("route: {}: {:?}", req.uri().path(), e);
+            return self.error(StatusCode::BAD_REQUEST).await;
+        }
+        let max_len = self.config.server.max_path_length.unwrap_or(limits::DEFAULT_MAX_PATH_LENGTH);
+        let max_segments = self.config.server.max_path_segments.unwrap_or(limits::DEFAULT_MAX_PATH_SEGMENTS);
+        let dest = req.headers().get("destination").and_then(|d| d.to_str().ok());
+        for path in std::iter::once(req.uri().path()).chain(dest) {
+            if let Err(status) = limits::check_path(path, max_len, max_segments) {
+                debug!("route: {}: path too long or too deep", req.uri().path());
+                return self.error(status).await;
+            }
+        }
+
         // Get the URI path.
         let davpath = match DavPath::from_uri(req.uri()) {
             Ok(p) => p,
@@ -159,12 +580,80 @@ impl Server {
         };
         let path = davpath.as_bytes();
 
-        // Get the method.
+        // The public capabilities document?
+        let public = self.config.server.capabilities == Some(Capabilities::Public);
+        if public && path == capabilities::PATH.as_bytes() {
+            if req.method() != http::Method::GET && req.method() != http::Method::HEAD {
+                return self.error(StatusCode::METHOD_NOT_ALLOWED).await;
+            }
+            return Ok(json_response(&capabilities::capabilities(&self.config)));
+        }
+
+        // Admin API?
+        if let Some(admin_path) = self.admin_path(path) {
+            return self.admin(req, admin_path, ctx).await;
+        }
+
+        // Overloaded: refuse new work, so that the rest can finish. See `shed`.
+        if self.shedder.enabled() {
+            let load = shed::Load {
+                connections: self.idle.stats().open,
+                memory:      self.memory.used(),
+                writes:      self.writes.active() + self.writes.waiting(),
+            };
+            if let Some(reason) = self.shedder.check(req.method(), &load) {
+                debug!("route: {} {}: shed, over shed-{}", req.method(), req.uri().path(), reason);
+                self.metrics.sink().incr_counter("webdav_shed_requests_total", &[("reason", reason)], 1);
+                let mut resp = self.error(StatusCode::SERVICE_UNAVAILABLE).await?;
+                resp.headers_mut().insert("Retry-After", self.shedder.retry_after().as_secs().into());
+                return Ok(resp);
+            }
+        }
+
+        // Too many requests to the whole server?
+        if let Err(wait) = self.ratelimiter.acquire() {
+            debug!("route: over max-request-rate");
+            let mut resp = self.error(StatusCode::TOO_MANY_REQUESTS).await?;
+            let secs = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+            resp.headers_mut().insert("Retry-After", secs.into());
+            return Ok(resp);
+        }
+
+        // Get the method. REPORT is not known to the webdav handler, it
+        // is routed as PROPFIND (both are read-only) and handled by us.
         let method = match DavMethod::try_from(req.method()) {
             Ok(m) => m,
+            Err(_) if req.method().as_str() == "REPORT" => DavMethod::PropFind,
+            Err(_) if BIND_METHODS.contains(&req.method().as_str()) ||
+                DELTAV_METHODS.contains(&req.method().as_str()) =>
+            {
+                debug!("route: {} not implemented", req.method());
+                return self.error(StatusCode::NOT_IMPLEMENTED).await;
+            },
             Err(_) => return self.error(http::StatusCode::METHOD_NOT_ALLOWED).await,
         };
 
+        // A HTTP/1.x request body without Content-Length or chunked
+        // encoding is empty. For a PUT that is almost certainly a broken
+        // client (HTTP/1.0 clients that expect us to read until the
+        // connection is closed), so do not truncate the file.
+        if method == DavMethod::Put && !has_body_length(&req) {
+            debug!("route: PUT without Content-Length");
+            return self.error(StatusCode::LENGTH_REQUIRED).await;
+        }
+
+        // Clients send the Destination with the public path prefix, that
+        // the proxy stripped from the request URI. Strip it here too.
+        let trusted = remote_ip.ip().is_loopback();
+        ctx.base_url = BaseUrl::for_request(self.public_url.as_ref(), req.headers(), trusted);
+        if !ctx.base_url.prefix().is_empty() {
+            let dest = req.headers().get("destination").and_then(|d| d.to_str().ok());
+            let dest = dest.and_then(|d| d.parse::<http::Uri>().ok());
+            if let Some(path) = dest.as_ref().and_then(|d| ctx.base_url.strip(d.path())) {
+                req.headers_mut().insert("destination", path.parse().unwrap());
+            }
+        }
+
         // Request is stored here.
         let mut reqdata = Some(req);
         let mut got_match = false;
@@ -187,10 +676,31 @@ impl Server {
                 reqdata.get_or_insert(clone_httpreq(&req));
             }
 
-            // handle request.
-            let res = self
-                .handle(req, method, path, route, location, remote_ip.clone())
-                .await?;
+            // handle request. An I/O error becomes a status code,
+            // instead of a dropped connection.
+            let http_method = req.method().clone();
+            let res = match self.handle(req, method, path, route, location, ctx).await {
+                Ok(res) => res,
+                Err(e) => {
+                    debug!("route: {:?}: {}", davpath, e);
+                    return self.error(fserror::io_status(&e, &http_method)).await;
+                },
+            };
+            // Do not tell what exists, if wanted.
+            let mut res = res;
+            if location.access_errors == Some(AccessErrors::Opaque) && res.status() == StatusCode::FORBIDDEN {
+                debug!("route: {:?}: 403 Forbidden, sending 404 Not Found", davpath);
+                res = self.error(StatusCode::NOT_FOUND).await?;
+            }
+            // Cache-Control for a GET, see `vary`.
+            let get = http_method == http::Method::GET || http_method == http::Method::HEAD;
+            if get && (res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED) {
+                let public = matches!(location.auth, Some(Auth::False)).then(|| "public");
+                if let Some(cc) = location.cache_control.as_deref().or(public) {
+                    ctx.vary.cache_control(cc);
+                }
+            }
+            ctx.vary.apply(res.headers_mut());
 
             // no on_notfound? then this is final.
             if reqdata.is_none() || res.status() != StatusCode::NOT_FOUND {
@@ -205,6 +715,100 @@ impl Server {
         self.error(StatusCode::NOT_FOUND).await
     }
 
+    // If this is a request for the admin API, return the path below the admin route.
+    fn admin_path<'a>(&self, path: &'a [u8]) -> Option<&'a str> {
+        let route = self.config.admin.route.as_ref()?.trim_end_matches('/').as_bytes();
+        let rest = std::str::from_utf8(path.strip_prefix(route)?).ok()?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+
+    // handle an admin API request.
+    async fn admin(&self, req: HttpRequest, path: &str, ctx: &mut RequestContext) -> HttpResult {
+        let accounts = &self.config.admin.accounts;
+        let start = Instant::now();
+        let user = self.auth.auth(&req, accounts, ctx).await;
+        ctx.timing.auth += start.elapsed();
+        let user = match user {
+            Ok(user) => user.username,
+            Err(status) => return self.auth_error(status, accounts).await,
+        };
+        ctx.identity = Some(Identity::new(user.as_str()));
+        if !self.config.admin.users.contains(&user) {
+            debug!("admin: user {} not allowed", user);
+            return self.error(StatusCode::FORBIDDEN).await;
+        }
+        // the metrics for a Prometheus scrape, in its own format.
+        if path.trim_end_matches('/') == "/prometheus" && req.method() == http::Method::GET {
+            return match self.metrics.prometheus() {
+                Some(text) => {
+                    let response = hyper::Response::builder()
+                        .header("Content-Type", "text/plain; version=0.0.4")
+                        .body(text.into())
+                        .unwrap();
+                    Ok(response)
+                },
+                None => self.error(StatusCode::NOT_FOUND).await,
+            };
+        }
+        let query = req.uri().query();
+        match admin::handle(req.method(), path, query, &self.admin_state(), &user) {
+            Ok(value) => Ok(json_response(&value)),
+            Err(status) => self.error(status).await,
+        }
+    }
+
+    fn admin_state(&self) -> admin::AdminState<'_> {
+        admin::AdminState {
+            locks:        &self.locks,
+            userlimiter:  &self.userlimiter,
+            statcaches:   &self.statcaches[..],
+            idle:         &self.idle,
+            metrics:      &self.metrics,
+            ratelimiter:  &self.ratelimiter,
+            writes:       &self.writes,
+            memory:       &self.memory,
+            shedder:      &self.shedder,
+            transfers:    &self.transfers,
+            capabilities: self.config.server.capabilities.map(|_| capabilities::capabilities(&self.config)),
+        }
+    }
+
+    // a command on the control socket. The ones that the admin API has
+    // as well go through admin::handle.
+    fn control(&self, command: control::Command, uid: u32, drain: &CancelToken) -> serde_json::Value {
+        use control::Command;
+        let user = format!("uid {}", uid);
+        let query = |k: &str, v: &str| {
+            url::form_urlencoded::Serializer::new(String::new()).append_pair(k, v).finish()
+        };
+        let (method, path, query) = match command {
+            Command::ListLocks(path) => (http::Method::GET, "/locks", path.map(|p| query("path", &p))),
+            Command::ReleaseLock(token) => (http::Method::DELETE, "/locks", Some(query("token", &token))),
+            Command::Metrics => (http::Method::GET, "/metrics", None),
+            Command::FlushCache => {
+                let stat: usize = self.statcaches.iter().flatten().map(|sc| sc.clear()).sum();
+                let probe: usize = self.probecaches.iter().flatten().map(|pc| pc.clear()).sum();
+                let accounts = cache::cached::flush();
+                info!(target: admin::AUDIT, "control: {} flushed the caches", user);
+                return serde_json::json!({ "statcache": stat, "probecache": probe, "accounts": accounts });
+            },
+            Command::Drain => {
+                info!(target: admin::AUDIT, "control: {} started a drain", user);
+                drain.cancel();
+                return serde_json::json!({ "open": self.idle.stats().open });
+            },
+            Command::Help => return serde_json::json!(control::COMMANDS),
+        };
+        match admin::handle(&method, path, query.as_deref(), &self.admin_state(), &user) {
+            Ok(value) => value,
+            Err(status) => serde_json::json!({ "error": status.to_string() }),
+        }
+    }
+
     // handle a request.
     async fn handle<'a, 't: 'a, 'p: 'a>(
         &'a self,
@@ -213,7 +817,7 @@ impl Server {
         path: &'a [u8],
         route: MatchedRoute<'t, 'p, usize>,
         location: &'a Location,
-        remote_ip: SocketAddr,
+        ctx: &mut RequestContext,
     ) -> HttpResult
     {
         // See if we matched a :user parameter
@@ -231,49 +835,109 @@ impl Server {
             None => None,
         };
 
-        // Do authentication if needed.
-        let auth_hdr = req.headers().typed_get::<Authorization<Basic>>();
-        let do_auth = match location.auth {
+        // Do authentication if needed. Shared folders always need it.
+        let creds = self.auth.has_credentials(&req, &location.accounts);
+        let shared = shared::is_shared(location);
+        let do_auth = shared || match location.auth {
             Some(Auth::True) => true,
-            Some(Auth::Write) => !DavMethodSet::WEBDAV_RO.contains(method) || auth_hdr.is_some(),
+            Some(Auth::Write) => !DavMethodSet::WEBDAV_RO.contains(method) || creds,
             Some(Auth::False) => false,
-            Some(Auth::Opportunistic) | None => auth_hdr.is_some(),
+            Some(Auth::Opportunistic) | None => creds,
         };
+        let optional = !shared && match location.auth {
+            Some(Auth::Write) => DavMethodSet::WEBDAV_RO.contains(method),
+            Some(Auth::Opportunistic) | None => true,
+            _ => false,
+        };
+        if optional {
+            ctx.vary.add("Authorization");
+        }
+        // No credentials, or credentials that were rejected, get a 401 with
+        // a Basic challenge, so that the client asks the user for them.
+        // Valid credentials that do not give access get a 403.
         let auth_user = if do_auth {
-            let user = match self.auth.auth(&req, location, remote_ip).await {
+            let start = Instant::now();
+            let user = self.auth.auth(&req, &location.accounts, ctx).await;
+            ctx.timing.auth += start.elapsed();
+            let user = match user {
                 Ok(user) => user,
-                Err(status) => return self.auth_error(status, location).await,
+                Err(status) => return self.auth_error(status, &location.accounts).await,
             };
             // if there was a :user in the route, return error if it does not match.
-            if user_param.map(|u| u != &user).unwrap_or(false) {
-                debug!("handle: auth user and :user mismatch");
-                return self.auth_error(StatusCode::UNAUTHORIZED, location).await;
+            if user_param.map(|u| u != user.username).unwrap_or(false) {
+                debug!("handle: auth user {} and :user mismatch", user.username);
+                return self.error(StatusCode::FORBIDDEN).await;
             }
+            ctx.vary.private();
             Some(user)
         } else {
             None
         };
 
+        // Count the request against the per-user limit.
+        let guard = match auth_user.as_ref() {
+            Some(user) => {
+                match self.userlimiter.acquire(&user.username) {
+                    Some(guard) => Some(guard),
+                    None => {
+                        debug!("handle: too many requests for user {}", user.username);
+                        return self.error(StatusCode::TOO_MANY_REQUESTS).await;
+                    },
+                }
+            },
+            None => None,
+        };
+
         // Now see if we want to do a account lookup, for uid/gid/homedir.
         let pwd = match self.acct(location, auth_user.as_ref(), user_param).await {
             Ok(pwd) => pwd,
-            Err(status) => return self.auth_error(status, location).await,
+            Err(status) => return self.auth_error(status, &location.accounts).await,
         };
+        ctx.identity = pwd.as_deref().or(auth_user.as_ref()).cloned();
+        let mut req = req;
+        if let (Some(_), Some(id)) = (auth_user.as_ref(), ctx.identity.as_ref()) {
+            req.extensions_mut().insert(auth::Authenticated(id.clone()));
+        }
+
+        // Shared folders are for their members only.
+        if let (true, Some(id)) = (shared, ctx.identity.as_ref()) {
+            match shared::is_member(location, id, &self.config.unix.retry()).await {
+                Ok(true) => {},
+                Ok(false) => {
+                    debug!("handle: {} is not a member of shared folder {:?}", id.username, location.route);
+                    return self.error(StatusCode::FORBIDDEN).await;
+                },
+                Err(_) => return self.busy().await,
+            }
+            ctx.shared = true;
+        }
 
         // Expand "~" in the directory.
         let dir = match expand_directory(location.directory.as_str(), pwd.as_ref()) {
             Ok(d) => d,
             Err(_) => return self.error(StatusCode::NOT_FOUND).await,
         };
+        if location.directory.starts_with('~') {
+            if let Err(status) = home_directory(&dir, pwd.as_ref(), location.create_home) {
+                return self.error(status).await;
+            }
+        }
+
+        // The directory is gone (NFS down?), see `rootprobe`.
+        if let Some(probe) = self.rootprobes[*route.data].as_ref() {
+            if !probe.available(Path::new(&dir)).await {
+                let mut resp = self.error(StatusCode::SERVICE_UNAVAILABLE).await?;
+                resp.headers_mut().insert("Retry-After", probe.interval().as_secs().into());
+                return Ok(resp);
+            }
+        }
 
         // If :path matched, we can calculate the prefix.
         // If it didn't, the entire path _is_ the prefix.
         let prefix = match route.params[1].as_ref() {
             Some(p) => {
                 let mut start = p.start();
-                if start > 0 {
-                    start -= 1;
-                }
+                start = start.saturating_sub(1);
                 &path[..start]
             },
             None => path,
@@ -286,6 +950,65 @@ impl Server {
             },
         };
 
+        // Run the path hooks, they might rewrite the request.
+        let mut req = req;
+        let hooks = &self.pathhooks[*route.data];
+        if !hooks.is_empty() {
+            let anonymous = Identity::default();
+            let id = ctx.identity.as_ref().unwrap_or(&anonymous);
+            if let Err(status) = run_pathhooks(hooks, &mut req, &prefix, id) {
+                return self.error(status).await;
+            }
+        }
+
+        // The MOVE that puts a chunked upload together is an upload, too.
+        let chunked_move = match location.chunked_uploads.as_deref() {
+            Some(name) if method == DavMethod::Move => path_below(req.uri().path(), &prefix)
+                .and_then(|p| DavPath::new(&p).ok())
+                .and_then(|target| chunked::upload_dir(name, &target))
+                .is_some(),
+            _ => false,
+        };
+        let is_upload = method == DavMethod::Put || chunked_move;
+
+        // Refuse uploads of these types before anything is written.
+        if is_upload && !location.deny_types.is_empty() {
+            let content_type = req.headers().get("content-type").and_then(|v| v.to_str().ok());
+            if pathhook::denied_type(&location.deny_types, content_type.unwrap_or("")) {
+                let path = req.uri().path();
+                debug!("handle: {} {}: content type {:?} not allowed", req.method(), path, content_type);
+                return self.error(StatusCode::UNSUPPORTED_MEDIA_TYPE).await;
+            }
+        }
+
+        // A Depth: header that the method does not take.
+        let max_depth = self.config.server.max_depth.and_then(depth::Depth::from_max);
+        match depth::check(req.method(), req.headers(), max_depth) {
+            Ok(()) => {},
+            Err(StatusCode::FORBIDDEN) => {
+                debug!("handle: {} {}: deeper than max-depth", req.method(), req.uri().path());
+                let body = report::error_body(Some("propfind-finite-depth"));
+                return self.xml_response(StatusCode::FORBIDDEN, body);
+            },
+            Err(status) => {
+                debug!("handle: {} {}: bad Depth: header", req.method(), req.uri().path());
+                return self.error(status).await;
+            },
+        }
+
+        // The checksum that an upload has to match.
+        let checksum = if location.verify_checksum && is_upload {
+            match Checksum::from_headers(req.headers()) {
+                Ok(checksum) => checksum.map(Arc::new),
+                Err(status) => {
+                    debug!("handle: {} {}: bad checksum header", req.method(), req.uri().path());
+                    return self.error(status).await;
+                },
+            }
+        } else {
+            None
+        };
+
         // Get User-Agent for user-agent specific modes.
         let user_agent = req
             .headers()
@@ -303,57 +1026,769 @@ impl Server {
         // macOS optimizations?
         let macos = user_agent.contains("WebDAVFS/") && user_agent.contains("Darwin");
 
-        // Get the filesystem.
+        // Get the filesystem. In a shared folder that can be as the
+        // shared owner instead of the user.
+        let owner = match location.shared_owner.as_deref().filter(|_| location.setuid) {
+            Some(owner) => {
+                match cache::cached::unixuser(owner, self.config.unix.aux_groups).await {
+                    Ok(owner) => Some(owner),
+                    Err(e) => {
+                        error!("handle: shared-owner {}: {}", owner, e);
+                        return self.error(StatusCode::INTERNAL_SERVER_ERROR).await;
+                    },
+                }
+            },
+            None => None,
+        };
         let auth_ugid = if location.setuid {
-            pwd.as_ref().map(|p| (p.uid, p.gid, p.groups.as_slice()))
+            owner.as_ref().or(pwd.as_ref()).and_then(|p| p.ugid())
         } else {
             None
         };
+        let ls = self.locks.locksystem_for(&dir, auth_user.as_ref().map(|u| u.username.as_str()));
+        let lock_root = dir.clone();
+        let precompressed = match (location.handler, method) {
+            (Handler::Filesystem, DavMethod::Get) | (Handler::Filesystem, DavMethod::Head)
+                if location.precompressed =>
+            {
+                let accept = req.headers().get("accept-encoding").and_then(|s| s.to_str().ok());
+                Some(Precompressed::new(accept, req.extensions().get::<ConnEncoding>()))
+            },
+            (Handler::Filesystem, _) if location.precompressed => Some(Precompressed::new(None, None)),
+            _ => None,
+        };
+        // Methods that change things do not get to see cached data.
+        let statcache = self.statcaches[*route.data].clone().map(|sc| {
+            if DavMethodSet::WEBDAV_RO.contains(method) {
+                sc
+            } else {
+                sc.invalidate_only()
+            }
+        });
+        let probecache = self.probecaches[*route.data].as_ref();
+        if let Some(probecache) = probecache.filter(|_| !DavMethodSet::WEBDAV_RO.contains(method)) {
+            probecache.clear();
+        }
         let fs = match location.handler {
             Handler::Virtroot => {
-                let auth_user = auth_user.as_ref().map(String::to_owned);
-                RootFs::new(dir, auth_user, auth_ugid) as Box<dyn DavFileSystem>
+                let auth_user = auth_user.as_ref().map(|u| u.username.clone());
+                RootFs::new(dir.clone(), auth_user, auth_ugid) as Box<dyn DavFileSystem>
             },
             Handler::Filesystem => {
-                UserFs::new(dir, auth_ugid, true, case_insensitive, macos) as Box<dyn DavFileSystem>
+                let trash = location.trash.as_ref().map(|name| {
+                    let retention = location.trash_retention.unwrap_or(DEFAULT_TRASH_RETENTION);
+                    let trash_dir = Path::new(&dir).join(name);
+                    self.sweeper.register(trash_dir, Duration::from_secs(retention), auth_ugid);
+                    Trash::new(name)
+                });
+                if let Some(name) = location.chunked_uploads.as_ref() {
+                    let expiry = location.upload_expiry.unwrap_or(DEFAULT_UPLOAD_EXPIRY);
+                    let uploads_dir = Path::new(&dir).join(name);
+                    let expiry = Duration::from_secs(expiry);
+                    self.sweeper.register_with(uploads_dir, expiry, auth_ugid, chunked::purge);
+                }
+                let mut fs = UserFs::new(&dir, auth_ugid, true, case_insensitive, macos);
+                // quota properties show the quota of the authenticated user.
+                let quota_uid = auth_user.as_ref().and(pwd.as_ref()).and_then(|p| p.uid);
+                fs.set_quota_uid(quota_uid);
+                fs.set_precompressed(precompressed.clone());
+                fs.set_statcache(statcache);
+                fs.set_trash(trash);
+                fs.set_etag_inode(location.etag_inode);
+                fs.set_fsync(location.fsync);
+                let filter = match (checksum.clone(), self.filters[*route.data].clone()) {
+                    (Some(checksum), Some(filter)) => Some(Arc::new(Chain(vec![checksum, filter])) as _),
+                    (Some(checksum), None) => Some(checksum as Arc<dyn UploadFilter>),
+                    (None, filter) => filter,
+                };
+                fs.set_upload_filter(filter);
+                match location.one_filesystem {
+                    true => OneFs::new(fs, &dir) as Box<dyn DavFileSystem>,
+                    false => fs as Box<dyn DavFileSystem>,
+                }
             },
         };
 
-        // Build a handler.
+        // Read and check XML bodies, before anyone parses them.
+        let mut empty_body = false;
+        let mut mkcol_body = None;
+        let mut apple_quota = false;
+        let mut probe = None;
+        let mut _xml_memory = None;
+        let req = if xmlbody::is_xml_method(req.method(), method) {
+            let max_size = self.config.server.max_xml_body.unwrap_or(xmlbody::MAX_BODY);
+            let (parts, body) = req.into_parts();
+            let body = match xmlbody::read_body(body, max_size, &self.memory).await {
+                Ok((body, reservation)) => {
+                    _xml_memory = Some(reservation);
+                    body
+                },
+                Err(StatusCode::SERVICE_UNAVAILABLE) => return self.busy().await,
+                Err(status) => return self.error(status).await,
+            };
+            if let Err(status) = xmlbody::check(&body) {
+                return self.error(status).await;
+            }
+            if method == DavMethod::PropPatch {
+                let max = self.config.server.max_proppatch_props.unwrap_or(xmlbody::DEFAULT_MAX_PROPPATCH);
+                if let Some(n) = xmlbody::proppatch_props(&body).filter(|n| *n > max) {
+                    debug!("handle: PROPPATCH with {} properties (max {})", n, max);
+                    return self.error(StatusCode::PAYLOAD_TOO_LARGE).await;
+                }
+            }
+            empty_body = body.is_empty();
+            // allprop may be refused or limited, see `allprop`.
+            let mut body = body;
+            if method == DavMethod::PropFind && allprop::is_allprop(&body) {
+                match location.allprop {
+                    Some(AllProp::Forbid) => {
+                        debug!("handle: allprop PROPFIND refused");
+                        return self.error(StatusCode::FORBIDDEN).await;
+                    },
+                    Some(AllProp::Live) => body = allprop::live(&body),
+                    Some(AllProp::Allow) | None => {},
+                }
+            }
+            if method == DavMethod::MkCol && !empty_body && location.extended_mkcol {
+                mkcol_body = Some(body.clone());
+            }
+            if method == DavMethod::PropFind && location.macos_compat {
+                apple_quota = applequota::wants(&body);
+            }
+            // a probe of the root, see `probecache`.
+            let depth0 = parts.headers.get("depth").map(|d| d == "0").unwrap_or(false);
+            let root = path_below(parts.uri.path(), &prefix).filter(|p| p == "/").is_some();
+            if let Some(probecache) = probecache.filter(|_| method == DavMethod::PropFind && depth0 && root) {
+                if probecache::is_probe(&body) {
+                    let path = format!("{}{}", ctx.base_url.prefix(), parts.uri.path());
+                    let user = auth_user.as_ref().map(|u| u.username.as_str());
+                    probe = Some((probecache, probecache::Key::new(&path, user, &body)));
+                }
+            }
+            http::Request::from_parts(parts, hyper::Body::from(body))
+        } else {
+            req
+        };
+
+        if req.method().as_str() == "REPORT" {
+            return self.report(req).await;
+        }
+
+        // A LOCK refresh must be done by the owner of the lock.
+        if method == DavMethod::Lock && empty_body {
+            let if_hdr = req.headers().get("if").and_then(|v| v.to_str().ok()).unwrap_or("");
+            let principal = auth_user.as_ref().map(|u| u.username.as_str());
+            if !self.locks.may_refresh(&lock_root, if_hdr, principal) {
+                debug!("handle: lock refresh by {:?}, not the owner", principal);
+                return self.error(StatusCode::FORBIDDEN).await;
+            }
+        }
+
+        // A new lock, over max-per-user or max-per-path.
+        if method == DavMethod::Lock && !empty_body {
+            let principal = auth_user.as_ref().map(|u| u.username.as_str());
+            let path = path_below(req.uri().path(), &prefix).and_then(|p| DavPath::new(&p).ok());
+            if path.map(|p| self.locks.over_limit(&lock_root, &p, principal)).unwrap_or(false) {
+                debug!("handle: LOCK {} by {:?}: too many locks", req.uri().path(), principal);
+                return self.error(StatusCode::INSUFFICIENT_STORAGE).await;
+            }
+        }
+
+        // Range on a PUT is not a partial PUT (that is Content-Range),
+        // see `put-range`.
+        let mut req = req;
+        if method == DavMethod::Put && req.headers().contains_key(http::header::RANGE) {
+            match location.put_range {
+                Some(PutRange::Reject) => {
+                    debug!("handle: PUT {} with a Range header", req.uri().path());
+                    return self.error(StatusCode::BAD_REQUEST).await;
+                },
+                Some(PutRange::Ignore) | None => {
+                    req.headers_mut().remove(http::header::RANGE);
+                },
+            }
+        }
+
+        // Name of the file, for Content-Disposition.
+        let filename = match (location.disposition, method) {
+            (Some(_), DavMethod::Get) | (Some(_), DavMethod::Head) => {
+                DavPath::new(req.uri().path())
+                    .ok()
+                    .filter(|p| !p.is_collection())
+                    .and_then(|p| p.as_pathbuf().file_name()?.to_str().map(|n| n.to_string()))
+            },
+            _ => None,
+        };
+
+        // What the transcode hook gets to see of a GET.
+        let transcoder = &*self.transcoders[*route.data];
+        let transcode = match method {
+            DavMethod::Get if !req.uri().path().ends_with('/') => {
+                for header in transcoder.vary() {
+                    ctx.vary.add(header);
+                }
+                let accept = req.headers().get("accept").and_then(|v| v.to_str().ok());
+                let query = req.uri().query().map(|q| q.to_string());
+                let user = auth::authenticated(&req).map(|id| id.username.clone());
+                Some((req.uri().path().to_string(), query, accept.map(|a| a.to_string()), user))
+            },
+            _ => None,
+        };
+
+        // Weak ETags: If-None-Match uses the weak comparison, so compare
+        // the tag without the W/ (the webdav handler never matches weak tags).
+        let etag_strength = location.etag_strength();
+        let mut req = req;
+        if etag_strength != EtagStrength::Strong {
+            if let Some(value) = req.headers().get("if-none-match").and_then(|v| v.to_str().ok()) {
+                let value = value.replace("W/", "");
+                req.headers_mut().insert("if-none-match", value.parse().unwrap());
+            }
+        }
+
         let methods = location
             .methods
             .unwrap_or(DavMethodSet::from_vec(vec!["GET", "HEAD"]).unwrap());
-        let hide_symlinks = location.hide_symlinks.clone().unwrap_or(true);
 
-        let mut config = DavConfig::new()
-            .filesystem(fs)
-            .strip_prefix(prefix)
-            .methods(methods)
-            .hide_symlinks(hide_symlinks)
-            .autoindex(location.autoindex);
-        if let Some(auth_user) = auth_user {
-            config = config.principal(auth_user);
+        // If-Match and If-None-Match on PROPFIND.
+        let propfind = method == DavMethod::PropFind && methods.contains(method);
+        if propfind && precondition::has_conditions(req.headers()) {
+            let target = path_below(req.uri().path(), &prefix).and_then(|p| DavPath::new(&p).ok());
+            let meta = match target {
+                Some(target) => fs.metadata(&target).await.ok(),
+                None => None,
+            };
+            let etag = meta.and_then(|m| m.etag());
+            let depth0 = req.headers().get("depth").map(|d| d == "0").unwrap_or(false);
+            match precondition::propfind(req.headers(), etag.as_deref(), depth0) {
+                Some(StatusCode::NOT_MODIFIED) => {
+                    let weak = if etag_strength != EtagStrength::Strong { "W/" } else { "" };
+                    let etag = format!("{}\"{}\"", weak, etag.unwrap_or_default());
+                    let resp = hyper::Response::builder()
+                        .status(StatusCode::NOT_MODIFIED)
+                        .header("ETag", etag)
+                        .body(hyper::Body::empty())
+                        .unwrap();
+                    return Ok(resp);
+                },
+                Some(status) => return self.error(status).await,
+                None => {},
+            }
+        }
+
+        // A probe of the root that was answered before.
+        let conditional = precondition::has_conditions(req.headers()) || req.headers().contains_key("if");
+        let probe = probe.filter(|_| propfind && !conditional);
+        if let Some(resp) = probe.as_ref().and_then(|(cache, key)| cache.get(key)) {
+            debug!("handle: PROPFIND {}: cached probe", req.uri().path());
+            return Ok(resp);
+        }
+
+        // No ranges here: the whole file, with a 200.
+        let get = method == DavMethod::Get || method == DavMethod::Head;
+        let byte_ranges = location.ranges.unwrap_or(true);
+        if get && !byte_ranges && req.headers_mut().remove("range").is_some() {
+            debug!("handle: ranges are off here, sending the whole file");
+            req.headers_mut().remove("if-range");
+        }
+
+        // If-Range: a range of the version the client has, or all of it.
+        if get && req.headers().contains_key("if-range") && req.headers().contains_key("range") {
+            let target = path_below(req.uri().path(), &prefix).and_then(|p| DavPath::new(&p).ok());
+            let meta = match target {
+                Some(target) => fs.metadata(&target).await.ok(),
+                None => None,
+            };
+            let etag = meta.as_ref().and_then(|m| m.etag());
+            let modified = meta.and_then(|m| m.modified().ok());
+            if !precondition::if_range(req.headers(), etag.as_deref(), modified, SystemTime::now()) {
+                debug!("handle: If-Range does not match, sending the whole file");
+                req.headers_mut().remove("range");
+            }
+            req.headers_mut().remove("if-range");
+        }
+
+        // If-Match on the directory index, see `precondition`.
+        if get && req.headers().contains_key("if-match") && methods.contains(method) {
+            let target = path_below(req.uri().path(), &prefix).and_then(|p| DavPath::new(&p).ok());
+            let meta = match target {
+                Some(target) => fs.metadata(&target).await.ok(),
+                None => None,
+            };
+            if let Some(meta) = meta.filter(|m| m.is_dir()) {
+                if !precondition::if_match(req.headers(), meta.etag().as_deref()) {
+                    debug!("handle: {} {}: If-Match does not match", req.method(), req.uri().path());
+                    return self.error(StatusCode::PRECONDITION_FAILED).await;
+                }
+            }
+        }
+
+        // one-filesystem: the webdav handler turns a refused parent of a
+        // new resource into a 409, so check the paths here first.
+        if location.one_filesystem && methods.contains(method) {
+            let source = path_below(req.uri().path(), &prefix).and_then(|p| DavPath::new(&p).ok());
+            let paths: Vec<_> = source
+                .into_iter()
+                .chain(target_path(&req, method, &prefix))
+                .map(|p| p.as_rel_ospath().to_path_buf())
+                .collect();
+            if tokio::task::block_in_place(|| onefs::same_device(Path::new(&dir), &paths)).is_err() {
+                debug!("handle: {} {}: on another filesystem", req.method(), req.uri().path());
+                return self.error(StatusCode::FORBIDDEN).await;
+            }
+        }
+
+        // Writes only at the times of write-windows, see `timewindow`.
+        let changes = !DavMethodSet::WEBDAV_RO.contains(method) && methods.contains(method);
+        if let Some(windows) = self.windows[*route.data].as_ref().filter(|_| changes) {
+            if !windows.allowed_now() {
+                debug!("handle: {} {}: outside write-windows", req.method(), req.uri().path());
+                return self.error(StatusCode::FORBIDDEN).await;
+            }
+        }
+
+        // What this location lets users create, overwrite and delete.
+        let policy = Policy::new(location);
+        if !policy.allows_all() && methods.contains(method) {
+            let exists = match target_path(&req, method, &prefix) {
+                Some(target) if policy.needs_target(method) => fs.metadata(&target).await.is_ok(),
+                _ => false,
+            };
+            if let Err(status) = policy.check(method, exists) {
+                debug!("handle: {} {}: not allowed by the allow-* settings", req.method(), req.uri().path());
+                return self.error(status).await;
+            }
+        }
+
+        // If-Match and If-None-Match: * of a write, see `precondition`.
+        let write = matches!(method, DavMethod::Put | DavMethod::Delete | DavMethod::Copy | DavMethod::Move);
+        if write && methods.contains(method) && precondition::has_conditions(req.headers()) {
+            let target = path_below(req.uri().path(), &prefix).and_then(|p| DavPath::new(&p).ok());
+            let exists = match target {
+                Some(target) => fs.metadata(&target).await.is_ok(),
+                None => false,
+            };
+            if let Some(status) = precondition::write(req.headers(), exists) {
+                debug!("handle: {} {}: precondition failed", req.method(), req.uri().path());
+                return self.error(status).await;
+            }
+        }
+
+        // Wait for a slot under max-concurrent-writes. It is held until
+        // the response has been made.
+        let is_write = matches!(
+            method,
+            DavMethod::Put | DavMethod::Patch | DavMethod::Copy | DavMethod::Move | DavMethod::Delete
+        );
+        let _write = if is_write && methods.contains(method) {
+            let timeout = self.config.server.write_queue_timeout;
+            let timeout = Duration::from_secs(timeout.unwrap_or(limits::DEFAULT_WRITE_QUEUE_TIMEOUT));
+            match self.writes.acquire(timeout).await {
+                Ok(permit) => permit,
+                Err(_) => {
+                    debug!("handle: {} {}: over max-concurrent-writes", req.method(), req.uri().path());
+                    return self.busy().await;
+                },
+            }
+        } else {
+            None
+        };
+
+        // Create missing parent directories of the target of PUT, COPY
+        // and MOVE, if wanted. Not if the target is locked, the webdav
+        // handler has not checked lock tokens yet.
+        let auto_mkcol = matches!(method, DavMethod::Put | DavMethod::Copy | DavMethod::Move);
+        if location.auto_mkcol && auto_mkcol && methods.contains(method) {
+            let target = target_path(&req, method, &prefix);
+            if let Some(target) = target.filter(|t| ls.discover(t).is_empty()) {
+                let max = location.auto_mkcol_depth.unwrap_or(DEFAULT_AUTO_MKCOL_DEPTH);
+                match ctx.run(userfs::create_parents(&*fs, &target, Some(max))).await {
+                    Ok(Ok(())) => {},
+                    Ok(Err(e)) => debug!("handle: auto-mkcol {:?}: {:?}", target, e),
+                    Err(e) => {
+                        debug!("handle: auto-mkcol {:?}: {:?}", target, e);
+                        return self.error(e.status()).await;
+                    },
+                }
+            }
+        }
+
+        // Chunked uploads: create the upload directory for a new upload,
+        // and put the chunks together on the MOVE of NAME/ID/.file. As
+        // with auto-mkcol, not below a lock, and not too many of them.
+        if let Some(name) = location.chunked_uploads.as_deref().filter(|_| methods.contains(method)) {
+            let target = path_below(req.uri().path(), &prefix).and_then(|p| DavPath::new(&p).ok());
+            match (method, target) {
+                (DavMethod::MkCol, Some(target))
+                    if chunked::in_uploads(name, &target) && ls.discover(&target).is_empty() =>
+                {
+                    let max = location.auto_mkcol_depth.unwrap_or(DEFAULT_AUTO_MKCOL_DEPTH);
+                    match ctx.run(userfs::create_parents(&*fs, &target, Some(max))).await {
+                        Ok(Ok(())) => {},
+                        Ok(Err(e)) => debug!("handle: chunked upload {:?}: {:?}", target, e),
+                        Err(e) => {
+                            debug!("handle: chunked upload {:?}: {:?}", target, e);
+                            return self.error(e.status()).await;
+                        },
+                    }
+                },
+                (DavMethod::Move, Some(target)) => {
+                    if let Some(upload) = chunked::upload_dir(name, &target) {
+                        let start = Instant::now();
+                        let principal = auth_user.as_ref().map(|u| u.username.as_str());
+                        let moved = self.chunked_move(fs.clone(), &req, &prefix, &upload, &*ls, principal);
+                        let resp = ctx.run(moved).await;
+                        ctx.timing.fs += start.elapsed();
+                        if checksum.map(|c| c.mismatch()).unwrap_or(false) {
+                            debug!("handle: chunked upload {:?}: checksum mismatch", upload);
+                            return self.error(StatusCode::BAD_REQUEST).await;
+                        }
+                        return match resp {
+                            Ok(resp) => resp,
+                            Err(e) => self.error(e.status()).await,
+                        };
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        // GET of a directory: the first index file that is there is served.
+        let get = method == DavMethod::Get || method == DavMethod::Head;
+        let dir = path_below(req.uri().path(), &prefix).and_then(|p| DavPath::new(&p).ok());
+        let indexfile = match dir {
+            Some(dir) if get && dir.is_collection() && !location.indexfile.is_empty() => {
+                autoindex::find_index(&*fs, &dir, &location.indexfile).await
+            },
+            _ => None,
+        };
+
+        // Localized directory index.
+        if let Some(templates) = self.autoindex[*route.data].as_ref() {
+            if get && location.autoindex && methods.contains(method) && indexfile.is_none() {
+                ctx.vary.add("Accept-Language");
+                let start = Instant::now();
+                let index = self.autoindex(&*fs, templates, &req, &prefix, ctx).await;
+                ctx.timing.fs += start.elapsed();
+                if let Some(resp) = index {
+                    return resp;
+                }
+            }
+        }
+
+        // Extended MKCOL. Without a property store, only a body that sets
+        // no properties can be done, as a plain MKCOL.
+        if let Some(body) = mkcol_body.filter(|_| methods.contains(method)) {
+            let props = match mkcol::parse(&body) {
+                Ok(props) => props,
+                Err((status, msg)) => return self.xml_response(status, msg),
+            };
+            if !props.is_empty() {
+                debug!("handle: extended mkcol: cannot set {} properties", props.len());
+                return self.xml_response(StatusCode::FORBIDDEN, mkcol::refused(&props));
+            }
+            req = req.map(|_| hyper::Body::empty());
         }
-        if let Some(indexfile) = location.indexfile.clone() {
-            config = config.indexfile(indexfile);
+
+        // The webdav handler builds hrefs from the prefix and the request
+        // path, so give it the paths as the client sees them.
+        let public_prefix = ctx.base_url.prefix();
+        if !public_prefix.is_empty() {
+            add_path_prefix(&mut req, public_prefix);
         }
+        let prefix = format!("{}{}", public_prefix, prefix);
+
+        // The Apple quota properties, see `applequota`.
+        let apple_quota = if apple_quota { fs.get_quota().await.ok() } else { None };
+
+        // Build a handler.
+        let hide_symlinks = location.hide_symlinks.unwrap_or(true);
+        let principal = auth_user.map(|u| u.username);
+        let dav_config = |fs: Box<dyn DavFileSystem>, ls: Box<dyn DavLockSystem>| {
+            let mut config = DavConfig::new()
+                .filesystem(fs)
+                .locksystem(ls)
+                .strip_prefix(prefix.clone())
+                .methods(methods)
+                .hide_symlinks(hide_symlinks)
+                .autoindex(location.autoindex);
+            if let Some(principal) = principal.clone() {
+                config = config.principal(principal);
+            }
+            if let Some(indexfile) = indexfile.clone() {
+                config = config.indexfile(indexfile);
+            }
+            config
+        };
+        let config = dav_config(fs, ls);
+
+        // PUT bodies count against max-upload-buffer while in memory.
+        if method == DavMethod::Put {
+            let uploads = &self.uploads;
+            req = req.map(|body| uploads.wrap(body));
+        }
+
+        // More than one range: ask for one range that covers them all.
+        let mut ranges = None;
+        if method == DavMethod::Get || method == DavMethod::Head {
+            let range = req.headers().get("range").and_then(|r| r.to_str().ok());
+            if let Some(specs) = range.and_then(byteranges::parse) {
+                if specs.len() > byteranges::MAX_RANGES {
+                    debug!("handle: {} ranges", specs.len());
+                    return self.error(StatusCode::RANGE_NOT_SATISFIABLE).await;
+                }
+                if specs.len() > 1 {
+                    let hull = byteranges::hull(&specs);
+                    req.headers_mut().insert("range", hull.parse().unwrap());
+                    ranges = Some(specs);
+                }
+            }
+        }
+
+        // The number of responses to a deep PROPFIND, see `truncate`.
+        let depth0 = req.headers().get("depth").map(|d| d == "0").unwrap_or(false);
+        let max_responses = self.config.server.propfind_responses.filter(|_| !depth0);
 
         // All set.
-        self.run_davhandler(config, req).await
+        let start = Instant::now();
+        let resp = self.run_davhandler(config, req, guard).await;
+        ctx.timing.fs += start.elapsed();
+        let mut resp = resp?;
+        if let Some(probecache) = probecache.filter(|_| !DavMethodSet::WEBDAV_RO.contains(method)) {
+            probecache.clear();
+        }
+        if checksum.map(|c| c.mismatch()).unwrap_or(false) {
+            // the upload filter logged the path already.
+            debug!("handle: PUT: checksum mismatch");
+            return self.error(StatusCode::BAD_REQUEST).await;
+        }
+        if let Some(specs) = ranges {
+            resp = byteranges::split(resp, &specs);
+        }
+        if !byte_ranges && resp.headers().contains_key("accept-ranges") {
+            resp.headers_mut().insert("accept-ranges", "none".parse().unwrap());
+        }
+        if method == DavMethod::Get && location.read_ahead && resp.status().is_success() {
+            let window = location.read_window.unwrap_or(readahead::DEFAULT_WINDOW);
+            let (parts, body) = resp.into_parts();
+            let body = readahead::read_ahead(body, window, &self.memory);
+            resp = hyper::Response::from_parts(parts, body);
+        }
+
+        // Make redirects absolute, if we know the public URL.
+        let location_hdr = resp.headers().get("Location").and_then(|v| v.to_str().ok());
+        let location_hdr = location_hdr.filter(|l| l.starts_with('/'));
+        if let Some(url) = location_hdr.and_then(|l| ctx.base_url.absolute(l)) {
+            resp.headers_mut().insert("Location", url.parse().unwrap());
+        }
+
+        // Make the ETag weak, if wanted. With "mixed" only for caching: a
+        // 206 and the response to a write keep the strong one, for If-Range
+        // and If-Match.
+        let weak = match etag_strength {
+            EtagStrength::Strong => false,
+            EtagStrength::Weak => true,
+            EtagStrength::Mixed => get && resp.status() != StatusCode::PARTIAL_CONTENT,
+        };
+        if weak {
+            let headers = resp.headers_mut();
+            let etag = headers.get("ETag").and_then(|v| v.to_str().ok());
+            if let Some(etag) = etag.filter(|e| !e.starts_with("W/")) {
+                let value = format!("W/{}", etag);
+                headers.insert("ETag", value.parse().unwrap());
+            }
+        }
+
+        // Add Content-Disposition to files that were served.
+        if let (Some(disposition), Some(name)) = (location.disposition, filename) {
+            let status = resp.status();
+            let headers = resp.headers_mut();
+            if (status == StatusCode::OK || status == StatusCode::PARTIAL_CONTENT) &&
+                !headers.contains_key("Content-Disposition")
+            {
+                let value = disposition::header_value(disposition, &name);
+                headers.insert("Content-Disposition", value.parse().unwrap());
+            }
+        }
+
+        // Add the default charset to text files.
+        if let (Some(charset), DavMethod::Get | DavMethod::Head) = (location.charset.as_deref(), method) {
+            resp = charset::apply(resp, charset, location.charset_sniff).await;
+        }
+
+        // If we served a precompressed sidecar file, say so.
+        if let Some(pc) = precompressed {
+            if method == DavMethod::Get || method == DavMethod::Head {
+                ctx.vary.add("Accept-Encoding");
+                if let Some(encoding) = pc.encoding() {
+                    resp.headers_mut().insert("Content-Encoding", encoding.parse().unwrap());
+                }
+            }
+        }
+
+        // Correct DAV:supportedlock, add the Apple quota properties, and
+        // truncate.
+        if method == DavMethod::PropFind && resp.status() == StatusCode::MULTI_STATUS {
+            let (mut parts, body) = resp.into_parts();
+            parts.headers.remove("content-length");
+            let server = &self.config.server;
+            let size = server.propfind_buffer.unwrap_or(coalesce::DEFAULT_BUFFER);
+            let interval = server.propfind_flush.unwrap_or(coalesce::DEFAULT_INTERVAL);
+            let interval = Duration::from_secs_f64(interval);
+            let mut body = locks::fix_supportedlock(body);
+            if let Some((used, total)) = apple_quota {
+                body = applequota::add_props(body, used, total);
+            }
+            if let Some(max) = max_responses {
+                body = truncate::truncate(body, max);
+            }
+            let body = coalesce::coalesce(body, size, interval, &self.memory);
+            resp = hyper::Response::from_parts(parts, body);
+        }
+
+        // Keep the response to a probe.
+        if let Some((cache, key)) = probe.filter(|_| resp.status() == StatusCode::MULTI_STATUS) {
+            let (parts, body) = resp.into_parts();
+            let body = hyper::body::to_bytes(body).await.map_err(io::Error::other)?;
+            cache.insert(key, &parts.headers, body.clone());
+            resp = hyper::Response::from_parts(parts, hyper::Body::from(body));
+        }
+
+        // The transcode hook may send something else than the file.
+        if let Some((path, query, accept, user)) = transcode {
+            let (query, accept, user) = (query.as_deref(), accept.as_deref(), user.as_deref());
+            let res = transcode::apply(transcoder, &path, query, accept, user, resp).await;
+            resp = match res {
+                Ok(resp) => resp,
+                Err(e) => {
+                    debug!("handle: transcode {}: {}", path, e);
+                    return self.error(StatusCode::INTERNAL_SERVER_ERROR).await;
+                },
+            };
+        }
+        Ok(resp)
+    }
+
+    // Directory index from a template. Returns `None` if the request is not
+    // for a directory index, the webdav handler deals with it then.
+    async fn autoindex(
+        &self,
+        fs: &dyn DavFileSystem,
+        templates: &autoindex::Templates,
+        req: &HttpRequest,
+        prefix: &str,
+        ctx: &RequestContext,
+    ) -> Option<HttpResult>
+    {
+        let path = DavPath::new(&path_below(req.uri().path(), prefix)?).ok()?;
+        let href_prefix = format!("{}{}", ctx.base_url.prefix(), prefix);
+        // no slash: the webdav handler redirects.
+        if !path.is_collection() || !fs.metadata(&path).await.ok()?.is_dir() {
+            return None;
+        }
+        let accept = req.headers().get("accept-language").and_then(|v| v.to_str().ok());
+        let index = match templates.render(fs, &path, &href_prefix, accept).await {
+            Ok(index) => index,
+            Err(e) => {
+                debug!("autoindex: {}: {:?}", req.uri().path(), e);
+                return Some(self.error(fserror::fs_status(&e, req.method())).await);
+            },
+        };
+        let body = if req.method() == http::Method::HEAD {
+            hyper::Body::empty()
+        } else {
+            hyper::Body::from(index.html)
+        };
+        let response = hyper::Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Content-Language", index.language)
+            .body(body)
+            .unwrap();
+        Some(Ok(response))
+    }
+
+    // The MOVE of a chunked upload to its destination.
+    async fn chunked_move(
+        &self,
+        fs: Box<dyn DavFileSystem>,
+        req: &HttpRequest,
+        prefix: &str,
+        upload: &DavPath,
+        ls: &dyn DavLockSystem,
+        principal: Option<&str>,
+    ) -> HttpResult
+    {
+        let dest = match req.headers().get("destination").and_then(|d| d.to_str().ok()) {
+            Some(dest) => dest.parse::<http::Uri>().ok().map(|d| d.path().to_string()),
+            None => return self.error(StatusCode::BAD_REQUEST).await,
+        };
+        let dest = dest.and_then(|d| path_below(&d, prefix)).and_then(|d| DavPath::new(&d).ok());
+        let dest = match dest {
+            Some(dest) => dest,
+            None => return self.error(StatusCode::BAD_GATEWAY).await,
+        };
+        // the locks on the destination, as for other MOVEs.
+        let if_hdr = req.headers().get("if").and_then(|v| v.to_str().ok()).unwrap_or("");
+        if ls.check(&dest, principal, false, true, locks::if_tokens(if_hdr)).is_err() {
+            debug!("handle: chunked upload to {:?}: locked", dest);
+            return self.error(StatusCode::LOCKED).await;
+        }
+        let exists = fs.metadata(&dest).await.is_ok();
+        let overwrite = req.headers().get("overwrite").map(|o| o != "F").unwrap_or(true);
+        if exists && !overwrite {
+            return self.error(StatusCode::PRECONDITION_FAILED).await;
+        }
+        let total = req
+            .headers()
+            .get("oc-total-length")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+
+        if let Err(status) = chunked::assemble(fs.clone(), upload, &dest, total).await {
+            return self.error(status).await;
+        }
+        let status = if exists { StatusCode::NO_CONTENT } else { StatusCode::CREATED };
+        let mut resp = hyper::Response::builder().status(status);
+        if let Some(etag) = fs.metadata(&dest).await.ok().and_then(|m| m.etag()) {
+            let etag = format!("\"{}\"", etag);
+            resp = resp.header("ETag", &etag).header("OC-ETag", etag);
+        }
+        Ok(resp.body(hyper::Body::empty()).unwrap())
+    }
+
+    fn xml_response(&self, status: StatusCode, msg: String) -> HttpResult {
+        let response = hyper::Response::builder()
+            .status(status)
+            .header("Content-Type", "text/xml; charset=utf-8")
+            .body(msg.into())
+            .unwrap();
+        Ok(response)
+    }
+
+    // handle a REPORT request.
+    async fn report(&self, req: HttpRequest) -> HttpResult {
+        let (parts, body) = req.into_parts();
+        let (body, _reservation) = match xmlbody::read_body(body, xmlbody::MAX_BODY, &self.memory).await {
+            Ok(body) => body,
+            Err(StatusCode::SERVICE_UNAVAILABLE) => return self.busy().await,
+            Err(status) => return self.error(status).await,
+        };
+        let depth = parts.headers.get("depth").and_then(|d| d.to_str().ok());
+        let (status, msg) = self.reports.handle(parts.uri.path(), depth, &body);
+        self.xml_response(status, msg)
     }
 
-    async fn build_error(&self, code: StatusCode, location: Option<&Location>) -> HttpResult {
+    async fn build_error(&self, code: StatusCode, accounts: Option<&Accounts>) -> HttpResult {
         let msg = format!(
             "<error>{} {}</error>\n",
             code.as_u16(),
             code.canonical_reason().unwrap_or("")
         );
-        let mut response = self
-            .response_builder()
+        let mut response = hyper::Response::builder()
             .status(code)
             .header("Content-Type", "text/xml");
         if code == StatusCode::UNAUTHORIZED {
-            let realm = location.and_then(|location| location.accounts.realm.as_ref());
+            let realm = accounts.and_then(|accounts| accounts.realm.as_ref());
             let realm = realm.or(self.config.accounts.realm.as_ref());
             let realm = realm.map(|s| s.as_str()).unwrap_or("Webdav Server");
             response = response.header("WWW-Authenticate", format!("Basic realm=\"{}\"", realm).as_str());
@@ -361,8 +1796,22 @@ impl Server {
         Ok(response.body(msg.into()).unwrap())
     }
 
-    async fn auth_error(&self, code: StatusCode, location: &Location) -> HttpResult {
-        self.build_error(code, Some(location)).await
+    async fn auth_error(&self, code: StatusCode, accounts: &Accounts) -> HttpResult {
+        let mut resp = self.build_error(code, Some(accounts)).await?;
+        // The authentication backend is temporarily unavailable, tell
+        // the client when to come back instead of having it retry right away.
+        if code == StatusCode::SERVICE_UNAVAILABLE {
+            let secs = self.config.pam.retry_after.unwrap_or(DEFAULT_RETRY_AFTER);
+            resp.headers_mut().insert("Retry-After", secs.into());
+        }
+        Ok(resp)
+    }
+
+    // "503 Service Unavailable": too busy right now, or NSS did not answer.
+    async fn busy(&self) -> HttpResult {
+        let mut resp = self.error(StatusCode::SERVICE_UNAVAILABLE).await?;
+        resp.headers_mut().insert("Retry-After", DEFAULT_RETRY_AFTER.into());
+        Ok(resp)
     }
 
     async fn error(&self, code: StatusCode) -> HttpResult {
@@ -370,10 +1819,40 @@ impl Server {
     }
 
     // Call the davhandler, then add headers to the response.
-    async fn run_davhandler(&self, config: DavConfig, req: HttpRequest) -> HttpResult {
+    async fn run_davhandler(
+        &self,
+        config: DavConfig,
+        req: HttpRequest,
+        guard: Option<UserGuard>,
+    ) -> HttpResult
+    {
+        let download = match *req.method() {
+            http::Method::GET => Some(req.uri().path().to_string()),
+            _ => None,
+        };
+        let user = auth::authenticated(&req).map(|i| i.username.clone());
+        let mut req = req;
+        if *req.method() == http::Method::PUT {
+            let total = content_length(req.headers());
+            let path = req.uri().path().to_string();
+            let transfers = &self.transfers;
+            req = req.map(|body| transfers.wrap(body, &http::Method::PUT, &path, user.as_deref(), total));
+        }
         let resp = self.dh.handle_with(config, req).await;
-        let (mut parts, body) = resp.into_parts();
-        self.set_server_header(&mut parts.headers);
+        let (parts, body) = resp.into_parts();
+
+        // The guard lives as long as the body is being sent.
+        let body = hyper::Body::wrap_stream(body.inspect(move |_| {
+            let _ = &guard;
+        }));
+        let body = match download {
+            Some(path) => {
+                let total = content_length(&parts.headers);
+                let body = self.transfers.wrap(body, &http::Method::GET, &path, user.as_deref(), total);
+                self.metrics.download(body, &path)
+            },
+            None => body,
+        };
         Ok(http::Response::from_parts(parts, body))
     }
 }
@@ -388,29 +1867,32 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     )
     .get_matches();
 
-    if matches.is_present("DBG") {
+    // the request ID goes in front of the messages of a request.
+    let logger = if matches.is_present("DBG") {
         use env_logger::Env;
         let level = "webdav_server=debug,webdav_handler=debug";
-        env_logger::Builder::from_env(Env::default().default_filter_or(level)).init();
+        env_logger::Builder::from_env(Env::default().default_filter_or(level)).build()
     } else {
-        env_logger::init();
-    }
+        env_logger::Builder::from_default_env().build()
+    };
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(requestid::Logger(logger)))?;
 
     let port = matches.value_of("PORT");
     let cfg = matches.value_of("CFG").unwrap_or("/etc/webdav-server.toml");
 
     // read config.
-    let mut config = match config::read(cfg.clone()) {
+    let mut config = match config::read(cfg) {
         Err(e) => {
             eprintln!("{}: {}: {}", PROGNAME, cfg, e);
             exit(1);
         },
         Ok(c) => c,
     };
-    config::check(cfg.clone(), &config);
+    config::check(cfg, &config);
 
     // build routes.
-    if let Err(e) = config::build_routes(cfg.clone(), &mut config) {
+    if let Err(e) = config::build_routes(cfg, &mut config) {
         eprintln!("{}: {}: {}", PROGNAME, cfg, e);
         exit(1);
     }
@@ -424,6 +1906,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     let config = Arc::new(config);
 
+    // see if PAM works before we start serving.
+    #[cfg(feature = "pam")]
+    if config.pam.self_test.unwrap_or(true) && uses_pam(&config) {
+        if let Err(e) = pamauth::self_test(&config.pam.service) {
+            eprintln!("{}: PAM self-test failed: {}", PROGNAME, e);
+            exit(1);
+        }
+    }
+
     // set cache timeouts.
     if let Some(timeout) = config.unix.cache_timeout {
         cache::cached::set_pwcache_timeout(timeout);
@@ -442,21 +1933,49 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // initialize auth early.
     let auth = auth::Auth::new(config.clone())?;
 
+    // open the lock store, before a chroot.
+    let lock_store = match (config.locks.store, config.locks.file.as_ref()) {
+        (Some(config::LockStoreType::File), Some(file)) => {
+            let store = locks::FileStore::open(file).unwrap_or_else(|e| {
+                eprintln!("{}: {}: [locks] file: {}: {}", PROGNAME, cfg, file, e);
+                exit(1);
+            });
+            Some(Arc::new(store) as Arc<dyn locks::LockStore>)
+        },
+        _ => None,
+    };
+
     // start tokio runtime and initialize the rest from within the runtime.
-    let rt = tokio::runtime::Builder::new_multi_thread()
-        .enable_io()
-        .enable_time()
-        .build()?;
+    // Filesystem calls run in block_in_place(): the worker hands its other
+    // tasks to a thread from the blocking pool while it waits for the disk.
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(n) = config.server.worker_threads {
+        builder.worker_threads(n);
+    }
+    if let Some(n) = config.server.blocking_threads {
+        builder.max_blocking_threads(n);
+    }
+    let rt = builder.enable_io().enable_time().build()?;
 
     rt.block_on(async move {
         // build servers (one for each listen address).
-        let dav_server = Server::new(config.clone(), auth);
+        let mut dav_server = Server::new(config.clone(), auth);
+        if let Some(store) = lock_store {
+            dav_server.locks = dav_server.locks.store(store);
+        }
         let mut servers = Vec::new();
         let mut tls_servers = Vec::new();
+        // cancelled on SIGTERM and SIGINT, the servers stop accepting.
+        let shutdown = CancelToken::default();
+        // hyper refuses requests with headers that do not fit.
+        let max_header_size = config.server.max_header_size.unwrap_or(limits::DEFAULT_MAX_HEADER_SIZE);
+        let max_uri_length = config.server.max_uri_length.unwrap_or(limits::DEFAULT_MAX_URI_LENGTH);
+        let buf_size = limits::http1_buf_size(max_header_size, max_uri_length);
+        let keep_alive = config.server.keep_alive.unwrap_or(true);
 
         // Plaintext servers.
         for sockaddr in addrs {
-            let listener = match make_listener(sockaddr) {
+            let listener = match make_listener(sockaddr, &config.server) {
                 Ok(l) => l,
                 Err(e) => {
                     eprintln!("{}: listener on {:?}: {}", PROGNAME, &sockaddr, e);
@@ -464,23 +1983,40 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 },
             };
             let dav_server = dav_server.clone();
-            let make_service = make_service_fn(move |socket: &AddrStream| {
+            let idle = dav_server.idle.clone();
+            let make_service = make_service_fn(move |socket: &IdleStream<AddrStream>| {
                 let dav_server = dav_server.clone();
-                let remote_addr = socket.remote_addr();
+                let remote_addr = socket.get_ref().remote_addr();
+                if let Err(e) = set_sockopts(socket.get_ref(), &dav_server.config.server) {
+                    debug!("{}: socket options: {}", remote_addr, e);
+                }
+                let conn_auth = ConnAuth::new();
+                let conn_encoding = ConnEncoding::new();
+                let requests = socket.requests();
                 async move {
-                    let func = move |req| {
+                    let func = move |mut req: HttpRequest| {
                         let dav_server = dav_server.clone();
-                        async move { dav_server.route(req, remote_addr).await }
+                        let flight = requests.start();
+                        req.extensions_mut().insert(conn_auth.clone());
+                        req.extensions_mut().insert(conn_encoding.clone());
+                        async move {
+                            let res = dav_server.route(req, remote_addr).await?;
+                            Ok::<_, io::Error>(res.map(|body| flight.body(body)))
+                        }
                     };
                     Ok::<_, hyper::Error>(service_fn(func))
                 }
             });
-            let incoming = AddrIncoming::from_listener(listener)?;
-            let server = hyper::Server::builder(incoming);
+            let incoming = idle.incoming(AddrIncoming::from_listener(listener)?);
+            let server = hyper::Server::builder(incoming)
+                .http1_max_buf_size(buf_size)
+                .http1_keepalive(keep_alive);
             println!("Listening on http://{:?}", sockaddr);
 
+            let shutdown = shutdown.clone();
             servers.push(async move {
-                if let Err(e) = server.serve(make_service).await {
+                let server = server.serve(make_service).with_graceful_shutdown(shutdown.cancelled());
+                if let Err(e) = server.await {
                     eprintln!("{}: server error: {}", PROGNAME, e);
                     exit(1);
                 }
@@ -489,21 +2025,36 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
         // TLS servers.
         for sockaddr in tls_addrs {
-            let listener = make_listener(sockaddr).unwrap_or_else(|e| {
+            let listener = make_listener(sockaddr, &config.server).unwrap_or_else(|e| {
                 eprintln!("{}: listener on {:?}: {}", PROGNAME, &sockaddr, e);
                 exit(1);
             });
             let dav_server = dav_server.clone();
+            let idle = dav_server.idle.clone();
             let tls_config = tls_config(&config.server)?;
-            let make_service = make_service_fn(move |stream: &TlsStream<AddrStream>| {
+            let make_service = make_service_fn(move |stream: &TlsStream<IdleStream<AddrStream>>| {
                 let dav_server = dav_server.clone();
-                let remote_addr = stream.get_ref().0.remote_addr();
+                let remote_addr = stream.get_ref().0.get_ref().remote_addr();
+                if let Err(e) = set_sockopts(stream.get_ref().0.get_ref(), &dav_server.config.server) {
+                    debug!("{}: socket options: {}", remote_addr, e);
+                }
+                let cert = stream.get_ref().1.get_peer_certificates().and_then(|c| c.into_iter().next());
+                let requests = stream.get_ref().0.requests();
                 async move {
-                    let func = move |req| {
+                    // an error closes the connection.
+                    let conn_auth = dav_server.tls_conn_auth(cert.map(|c| c.0), remote_addr).await?;
+                    let conn_encoding = ConnEncoding::new();
+                    let func = move |mut req: HttpRequest| {
                         let dav_server = dav_server.clone();
-                        async move { dav_server.route(req, remote_addr).await }
+                        let flight = requests.start();
+                        req.extensions_mut().insert(conn_auth.clone());
+                        req.extensions_mut().insert(conn_encoding.clone());
+                        async move {
+                            let res = dav_server.route(req, remote_addr).await?;
+                            Ok::<_, io::Error>(res.map(|body| flight.body(body)))
+                        }
                     };
-                    Ok::<_, hyper::Error>(service_fn(func))
+                    Ok::<_, io::Error>(service_fn(func))
                 }
             });
 
@@ -515,8 +2066,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             std::mem::forget(listener);
 
             println!("Listening on http://{:?}", sockaddr);
+            let shutdown = shutdown.clone();
             tls_servers.push(async move {
-                loop {
+                while !shutdown.is_cancelled() {
                     // reuse the incoming socket after the server exits.
                     let listen_fd = match nix::unistd::dup(master_listen_fd) {
                         Ok(fd) => fd,
@@ -541,29 +2093,84 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             break;
                         }
                     };
-                    let incoming = TlsListener::new(tls_config.clone(), a_incoming);
-                    let server = hyper::Server::builder(incoming);
-                    if let Err(e) = server.serve(make_service.clone()).await {
+                    let incoming = TlsListener::new(tls_config.clone(), idle.incoming(a_incoming));
+                    let server = hyper::Server::builder(incoming)
+                        .http1_max_buf_size(buf_size)
+                        .http1_keepalive(keep_alive)
+                        .serve(make_service.clone())
+                        .with_graceful_shutdown(shutdown.cancelled());
+                    if let Err(e) = server.await {
                         eprintln!("{}: server error: {} (retrying)", PROGNAME, e);
                     }
                 }
             });
         }
 
+        // the control socket, created before chroot and dropping privileges.
+        let drain = CancelToken::default();
+        if let Some(path) = config.admin.socket.as_ref() {
+            let socket = control::ControlSocket::bind(path).unwrap_or_else(|e| {
+                eprintln!("{}: [admin] socket {}: {}", PROGNAME, path, e);
+                exit(1);
+            });
+            let dav_server = dav_server.clone();
+            let drain = drain.clone();
+            tokio::spawn(socket.serve(move |command, uid| dav_server.control(command, uid, &drain)));
+        }
+
+        // chroot, if configured. This has to happen after the listeners
+        // have been set up and the TLS keys have been read, and before
+        // dropping privileges. What NSS and PAM need has to be in it.
+        if let Some(dir) = config.server.chroot.as_ref() {
+            #[cfg(feature = "pam")]
+            let pam_service = Some(config.pam.service.as_str()).filter(|_| uses_pam(&config));
+            #[cfg(not(feature = "pam"))]
+            let pam_service = None;
+            let unix = uses_unix(&config);
+            if let Err(e) = jail::check(Path::new(dir), unix, pam_service) {
+                eprintln!("{}: chroot {}: {}", PROGNAME, dir, e);
+                exit(1);
+            }
+            if unix {
+                // uid and gid are set, config::check makes sure.
+                jail::preload_nss(config.server.uid.unwrap(), config.server.gid.unwrap());
+            }
+            if let Err(e) = nix::unistd::chroot(dir.as_str()).and_then(|_| nix::unistd::chdir("/")) {
+                eprintln!("{}: chroot {}: {}", PROGNAME, dir, e);
+                exit(1);
+            }
+            if let Err(e) = jail::drop_bounding_set() {
+                eprintln!("{}: chroot {}: {}", PROGNAME, dir, e);
+                exit(1);
+            }
+        }
+
         // drop privs.
-        match (&config.server.uid, &config.server.gid) {
-            (&Some(uid), &Some(gid)) => {
-                if !suid::have_suid_privs() {
-                    eprintln!(
-                        "{}: insufficent priviliges to switch uid/gid (not root).",
-                        PROGNAME
-                    );
-                    exit(1);
-                }
-                let keep_privs = config.location.iter().any(|l| l.setuid);
-                proc_switch_ugid(uid, gid, keep_privs);
-            },
-            _ => {},
+        if let (&Some(uid), &Some(gid)) = (&config.server.uid, &config.server.gid) {
+            if !suid::have_suid_privs() {
+                eprintln!(
+                    "{}: insufficent priviliges to switch uid/gid (not root).",
+                    PROGNAME
+                );
+                exit(1);
+            }
+            let keep_privs = config.location.iter().any(|l| l.setuid);
+            proc_switch_ugid(uid, gid, keep_privs);
+        }
+        if config.server.chroot.is_some() {
+            if let Err(e) = jail::check_no_capabilities() {
+                eprintln!("{}: chroot: {}", PROGNAME, e);
+                exit(1);
+            }
+        }
+
+        // look up the heavy users before the first requests come in.
+        if !config.unix.warm_cache.is_empty() {
+            if config.unix.cache_timeout == Some(0) {
+                warn!("unix: warm-cache: the cache is disabled (cache-timeout = 0)");
+            } else {
+                cache::cached::warm(&config.unix.warm_cache, config.unix.aux_groups).await;
+            }
         }
 
         // spawn all servers, and wait for them to finish.
@@ -574,27 +2181,260 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         for server in tls_servers.drain(..) {
             tasks.push(tokio::spawn(server));
         }
-        for task in tasks.drain(..) {
-            let _ = task.await;
+        tokio::spawn(dav_server.sweeper.clone().run());
+        tokio::spawn(dav_server.locks.clone().run());
+        tokio::spawn(dav_server.idle.clone().run());
+
+        // on SIGTERM or SIGINT, stop accepting, and drain the connections.
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        let servers = futures::future::join_all(tasks);
+        tokio::pin!(servers);
+        tokio::select! {
+            _ = &mut servers => return Ok(()),
+            _ = sigterm.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+            _ = drain.cancelled() => {},
         }
+        let secs = |s: Option<u64>, default: u64| Duration::from_secs(s.unwrap_or(default));
+        let timeout = secs(config.server.shutdown_timeout, DEFAULT_SHUTDOWN_TIMEOUT);
+        let conn_timeout = secs(config.server.shutdown_conn_timeout, DEFAULT_SHUTDOWN_CONN_TIMEOUT);
+        info!("shutting down, waiting up to {:?} for {} connections", timeout, dav_server.idle.stats().open);
+        let stats = dav_server.idle.drain(conn_timeout, timeout, &shutdown).await;
+        info!(
+            "shutdown: {} idle connections closed, {} finished, {} stalled, {} cut off",
+            stats.idle, stats.drained, stats.stalled, stats.cut_off
+        );
+        // closed connections go away at their next read or write.
+        let _ = tokio::time::timeout(Duration::from_secs(1), servers).await;
 
         Ok::<_, Box<dyn std::error::Error>>(())
     })
 }
 
+// Is PAM used for authentication anywhere.
+#[cfg(feature = "pam")]
+fn uses_pam(config: &config::Config) -> bool {
+    use crate::config::AuthType;
+    let accounts = config
+        .location
+        .iter()
+        .map(|l| &l.accounts)
+        .chain(Some(&config.accounts))
+        .chain(Some(&config.admin.accounts));
+    accounts
+        .filter_map(|a| a.auth_type.as_ref())
+        .any(|t| matches!(t, AuthType::Pam))
+}
+
+// Is a unix account looked up anywhere.
+fn uses_unix(config: &config::Config) -> bool {
+    let accounts = config
+        .location
+        .iter()
+        .map(|l| &l.accounts)
+        .chain(Some(&config.accounts))
+        .chain(Some(&config.admin.accounts));
+    accounts.filter_map(|a| a.acct_type.as_ref()).any(|t| matches!(t, AcctType::Unix))
+}
+
 // Clones a http request with an empty body.
 fn clone_httpreq(req: &HttpRequest) -> HttpRequest {
     let mut builder = http::Request::builder()
         .method(req.method().clone())
         .uri(req.uri().clone())
-        .version(req.version().clone());
+        .version(req.version());
     for (name, value) in req.headers().iter() {
         builder = builder.header(name, value);
     }
+    if let Some(conn_auth) = req.extensions().get::<ConnAuth>() {
+        builder = builder.extension(conn_auth.clone());
+    }
     builder.body(hyper::Body::empty()).unwrap()
 }
 
-fn expand_directory(dir: &str, pwd: Option<&Arc<unixuser::User>>) -> Result<String, StatusCode> {
+// Does the request say how long its body is. HTTP/2 has framing.
+fn has_body_length(req: &HttpRequest) -> bool {
+    if req.version() >= http::Version::HTTP_2 {
+        return true;
+    }
+    let headers = req.headers();
+    headers.contains_key("content-length") ||
+        headers
+            .get_all("transfer-encoding")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.to_ascii_lowercase().contains("chunked"))
+}
+
+// The Content-Length header, if there is a valid one.
+fn content_length(headers: &http::HeaderMap) -> Option<u64> {
+    headers.get("content-length")?.to_str().ok()?.trim().parse().ok()
+}
+
+// A response with a JSON body.
+fn json_response(value: &serde_json::Value) -> hyper::Response<hyper::Body> {
+    let body = serde_json::to_string_pretty(value).unwrap() + "\n";
+    hyper::Response::builder()
+        .header("Content-Type", "application/json")
+        .body(body.into())
+        .unwrap()
+}
+
+// The part of a (URL) path below the prefix of a location.
+fn path_below(path: &str, prefix: &str) -> Option<String> {
+    match path.strip_prefix(prefix)? {
+        "" => Some("/".to_string()),
+        r if r.starts_with('/') => Some(r.to_string()),
+        _ => None,
+    }
+}
+
+// The path that `method` works on, below `prefix`: the destination for
+// COPY and MOVE, the request path otherwise.
+fn target_path(req: &HttpRequest, method: DavMethod, prefix: &str) -> Option<DavPath> {
+    let target = match method {
+        DavMethod::Copy | DavMethod::Move => {
+            req.headers()
+                .get("destination")
+                .and_then(|d| d.to_str().ok())
+                .and_then(|d| d.parse::<http::Uri>().ok())
+                .map(|d| d.path().to_string())
+        },
+        _ => Some(req.uri().path().to_string()),
+    };
+    target
+        .and_then(|t| path_below(&t, prefix))
+        .and_then(|t| DavPath::new(&t).ok())
+}
+
+// Log a request in a shared folder that changes something.
+fn audit_shared(method: &http::Method, path: &str, ctx: &RequestContext, status: StatusCode) {
+    let read_only = DavMethod::try_from(method).map(|m| DavMethodSet::WEBDAV_RO.contains(m));
+    if !ctx.shared || read_only.unwrap_or(true) {
+        return;
+    }
+    let user = ctx.identity.as_ref().map(|id| id.username.as_str()).unwrap_or("-");
+    info!(
+        target: admin::AUDIT,
+        "[{}] shared: {} {} {} from {}: {}",
+        ctx.request_id,
+        user,
+        method,
+        path,
+        ctx.remote_ip.ip(),
+        status.as_u16()
+    );
+}
+
+// Run the path hooks of a location on the request path and, for
+// MOVE and COPY, on the Destination path. Rewrites them if needed.
+fn run_pathhooks(
+    hooks: &PathHooks,
+    req: &mut HttpRequest,
+    prefix: &str,
+    id: &Identity,
+) -> Result<(), StatusCode>
+{
+    let below = |path: &str| path_below(path, prefix);
+    let method = req.method().clone();
+
+    let uri = req.uri().clone();
+    let path = below(uri.path()).ok_or(StatusCode::NOT_FOUND)?;
+    if let Some(newpath) = hooks.run(&method, &path, id)? {
+        let mut pq = format!("{}{}", prefix, newpath);
+        if let Some(query) = uri.query() {
+            pq = format!("{}?{}", pq, query);
+        }
+        let mut parts = uri.into_parts();
+        parts.path_and_query = Some(pq.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?);
+        *req.uri_mut() = http::Uri::from_parts(parts).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    if !matches!(method.as_str(), "MOVE" | "COPY") {
+        return Ok(());
+    }
+    let dest = match req.headers().get("destination").and_then(|d| d.to_str().ok()) {
+        Some(dest) => dest.parse::<http::Uri>().map_err(|_| StatusCode::BAD_REQUEST)?,
+        None => return Ok(()),
+    };
+    // A destination outside of this location is refused by the webdav handler.
+    let path = match below(dest.path()) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    if let Some(newpath) = hooks.run(&method, &path, id)? {
+        let newdest = match (dest.scheme_str(), dest.authority()) {
+            (Some(scheme), Some(authority)) => format!("{}://{}{}{}", scheme, authority, prefix, newpath),
+            _ => format!("{}{}", prefix, newpath),
+        };
+        let value = newdest.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        req.headers_mut().insert("destination", value);
+    }
+    Ok(())
+}
+
+// Put the public path prefix in front of the request path and the Destination.
+fn add_path_prefix(req: &mut HttpRequest, public_prefix: &str) {
+    let uri = req.uri().clone();
+    let pq = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let pq = format!("{}{}", public_prefix, pq);
+    let mut parts = uri.into_parts();
+    parts.path_and_query = pq.parse().ok();
+    if let Ok(uri) = http::Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+    let dest = req.headers().get("destination").and_then(|d| d.to_str().ok());
+    if let Some(dest) = dest.and_then(|d| d.parse::<http::Uri>().ok()) {
+        let value = format!("{}{}", public_prefix, dest.path());
+        req.headers_mut().insert("destination", value.parse().unwrap());
+    }
+}
+
+// Normalize the request path and the Destination header, see `normalize`.
+fn normalize_paths(req: &mut HttpRequest, strict: bool) -> Result<(), normalize::PathError> {
+    let check = |path: &str| {
+        let normalized = normalize::normalize(path)?;
+        if strict && normalized != path {
+            return Err(normalize::PathError::Invalid);
+        }
+        Ok(normalized)
+    };
+
+    // "*" is for OPTIONS.
+    let uri = req.uri().clone();
+    if uri.path() != "*" {
+        let path = check(uri.path())?;
+        if path != uri.path() {
+            let pq = match uri.query() {
+                Some(q) => format!("{}?{}", path, q),
+                None => path,
+            };
+            let mut parts = uri.into_parts();
+            parts.path_and_query = Some(pq.parse().map_err(|_| normalize::PathError::Invalid)?);
+            *req.uri_mut() = http::Uri::from_parts(parts).map_err(|_| normalize::PathError::Invalid)?;
+        }
+    }
+
+    if let Some(value) = req.headers().get("destination") {
+        let encoded = normalize::encode_uri(value.as_bytes()).ok_or(normalize::PathError::Invalid)?;
+        let dest = encoded.parse::<http::Uri>().map_err(|_| normalize::PathError::Invalid)?;
+        let path = check(dest.path())?;
+        if path != dest.path() || encoded.as_bytes() != value.as_bytes() {
+            req.headers_mut().insert("destination", path.parse().unwrap());
+        }
+    }
+
+    // The webdav handler matches the resource tags of the If: header
+    // against the path with the public prefix, like the Destination
+    // that it gets (see `add_path_prefix`). So they are only encoded.
+    let value = req.headers().get("if").and_then(|v| normalize::if_header(v.as_bytes()));
+    if let Some(value) = value.and_then(|v| http::HeaderValue::from_str(&v).ok()) {
+        req.headers_mut().insert("if", value);
+    }
+    Ok(())
+}
+
+fn expand_directory(dir: &str, pwd: Option<&Arc<Identity>>) -> Result<String, StatusCode> {
     // If it doesn't start with "~", skip.
     if !dir.starts_with("~") {
         return Ok(dir.to_string());
@@ -612,24 +2452,92 @@ fn expand_directory(dir: &str, pwd: Option<&Arc<unixuser::User>>) -> Result<Stri
             return Err(StatusCode::NOT_FOUND);
         },
     };
-    let homedir = pwd.dir.to_str().ok_or(StatusCode::NOT_FOUND)?;
+    let homedir = pwd.home.as_ref().and_then(|h| h.to_str()).ok_or(StatusCode::NOT_FOUND)?;
     Ok(format!("{}/{}", homedir, &dir[1..]))
 }
 
+// The directory that "~" expanded to must be there. With create-home it is
+// created (and the home directory itself, if that is missing too), mode
+// 0700, owned by the user. Otherwise, or if that fails, 403.
+fn home_directory(dir: &str, pwd: Option<&Arc<Identity>>, create: bool) -> Result<(), StatusCode> {
+    let dir = Path::new(dir);
+    if tokio::task::block_in_place(|| dir.is_dir()) {
+        return Ok(());
+    }
+    let pwd = match pwd {
+        Some(pwd) if create => pwd,
+        _ => {
+            debug!("home_directory: {:?} does not exist", dir);
+            return Err(StatusCode::FORBIDDEN);
+        },
+    };
+    tokio::task::block_in_place(|| create_home(dir, pwd)).map_err(|e| {
+        warn!("create-home: {}: {:?}: {}", pwd.username, dir, e);
+        StatusCode::FORBIDDEN
+    })
+}
+
+fn create_home(dir: &Path, pwd: &Identity) -> io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    let home = pwd.home.as_deref().ok_or(io::ErrorKind::NotFound)?;
+    let missing: Vec<&Path> = dir.ancestors().take_while(|p| !p.exists()).collect();
+    // never create the parent of a home directory (/home).
+    if missing.iter().any(|p| !p.starts_with(home)) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no parent directory"));
+    }
+    for path in missing.into_iter().rev() {
+        std::fs::DirBuilder::new().mode(0o700).create(path)?;
+        if nix::unistd::geteuid().is_root() {
+            std::os::unix::fs::chown(path, pwd.uid, pwd.gid)?;
+        }
+        info!("create-home: {}: created {:?}", pwd.username, path);
+    }
+    Ok(())
+}
+
 // Make a new TcpListener, and if it's a V6 listener, set the
-// V6_V6ONLY socket option on it.
-fn make_listener(addr: SocketAddr) -> io::Result<tokio::net::TcpListener> {
+// V6_V6ONLY socket option on it (unless we want a dual-stack socket).
+// Optionally set SO_REUSEPORT, so that several server processes
+// can listen on the same port.
+fn make_listener(addr: SocketAddr, cfg: &config::Server) -> io::Result<tokio::net::TcpListener> {
     use socket2::{Domain, SockAddr, Socket, Type, Protocol};
     let s = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
     if addr.is_ipv6() {
-        s.set_only_v6(true)?;
+        s.set_only_v6(cfg.ipv6_only.unwrap_or(true))?;
     }
     s.set_nonblocking(true)?;
-    s.set_nodelay(true)?;
     s.set_reuse_address(true)?;
+    if cfg.reuse_port.unwrap_or(false) {
+        s.set_reuse_port(true)?;
+    }
     let addr: SockAddr = addr.into();
     s.bind(&addr)?;
-    s.listen(128)?;
+    s.listen(cfg.listen_backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG))?;
     let listener: std::net::TcpListener = s.into();
     tokio::net::TcpListener::from_std(listener)
 }
+
+// Set TCP_NODELAY and the TCP keepalive options on an accepted connection.
+fn set_sockopts<S: AsRawFd>(sock: &S, cfg: &config::Server) -> io::Result<()> {
+    let sock = socket2::SockRef::from(sock);
+    sock.set_nodelay(cfg.tcp_nodelay.unwrap_or(true))?;
+    if let Some(time) = cfg.tcp_keepalive {
+        #[allow(unused_mut)]
+        let mut keepalive = socket2::TcpKeepalive::new().with_time(Duration::from_secs(time));
+        // not every system lets us set these.
+        #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "netbsd", target_vendor = "apple"))]
+        {
+            if let Some(intvl) = cfg.tcp_keepalive_intvl {
+                keepalive = keepalive.with_interval(Duration::from_secs(intvl));
+            }
+            if let Some(retries) = cfg.tcp_keepalive_retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+        }
+        sock.set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,356 @@
+//! Counters, for the admin API, and a pluggable metrics sink.
+//!
+//! A client that goes away in the middle of a download is normal (a user
+//! who clicks "cancel", a media player that seeks). hyper then gets
+//! EPIPE or ECONNRESET when it writes to the socket, closes the connection
+//! and drops the response body, which closes the file. Here we only notice
+//! that the body was dropped before the end, log that at debug level,
+//! and count it.
+//!
+//! Slow requests are counted per method, see `slowlog`.
+//!
+//! Everything that is counted or timed also goes to a `MetricsSink`.
+//! By default that is `NoopSink`, with `[metrics] sink = "prometheus"`
+//! it is `Prometheus`, which keeps the metrics in memory and renders
+//! them in the Prometheus text format, at `/prometheus` below the admin
+//! route. Other exporters (StatsD, OpenTelemetry) only have to implement
+//! the trait. The metrics are:
+//!
+//! - `webdav_requests_total{method,status}`: counter
+//! - `webdav_request_duration_seconds{method}`: histogram, until the
+//!   response headers are sent
+//! - `webdav_open_connections`: gauge
+//! - `webdav_active_writes`: gauge, see `max-concurrent-writes`
+//! - `webdav_memory_used_bytes`: gauge, see `memory-budget`
+//! - `webdav_locks{user}`: gauge, active locks per principal
+//! - `webdav_aborted_downloads_total`: counter
+//! - `webdav_slow_requests_total{method}`: counter
+//! - `webdav_shed_requests_total{reason}`: counter, see `shed`
+//! - `webdav_pam_auth_total{result}`: counter, result is ok, failed or
+//!   unavailable
+//! - `webdav_pam_auth_duration_seconds`: histogram
+//!
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::Stream;
+
+/// Where metrics go. Names follow the Prometheus conventions, labels
+/// are (name, value) pairs. Called from request handling, so it should
+/// not block.
+pub trait MetricsSink: Send + Sync {
+    /// Add `value` to a counter.
+    fn incr_counter(&self, name: &str, labels: &[(&str, &str)], value: u64);
+
+    /// Record one observation (a duration in seconds, a size).
+    fn observe_histogram(&self, name: &str, labels: &[(&str, &str)], value: f64);
+
+    /// Set a gauge.
+    fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64);
+}
+
+/// Throws everything away.
+pub struct NoopSink;
+
+impl MetricsSink for NoopSink {
+    fn incr_counter(&self, _name: &str, _labels: &[(&str, &str)], _value: u64) {}
+
+    fn observe_histogram(&self, _name: &str, _labels: &[(&str, &str)], _value: f64) {}
+
+    fn set_gauge(&self, _name: &str, _labels: &[(&str, &str)], _value: f64) {}
+}
+
+// Histogram buckets (upper bounds), in seconds.
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count:  u64,
+    sum:    f64,
+}
+
+// Metrics by (name, rendered labels).
+#[derive(Default)]
+struct Families {
+    counters:   BTreeMap<(String, String), u64>,
+    gauges:     BTreeMap<(String, String), f64>,
+    histograms: BTreeMap<(String, String), Histogram>,
+}
+
+/// Keeps the metrics in memory, for a Prometheus scrape.
+#[derive(Default)]
+pub struct Prometheus {
+    families: Mutex<Families>,
+}
+
+// `a="1",b="2"`
+fn labels_string(labels: &[(&str, &str)]) -> String {
+    let labels = labels.iter().map(|(name, value)| {
+        let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+        format!("{}=\"{}\"", name, value)
+    });
+    labels.collect::<Vec<_>>().join(",")
+}
+
+// name{labels}, or name.
+fn series(name: &str, labels: &str) -> String {
+    if labels.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}{{{}}}", name, labels)
+    }
+}
+
+impl Prometheus {
+    pub fn new() -> Prometheus {
+        Prometheus::default()
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+        // a TYPE line before the first series of every metric.
+        let mut last = String::new();
+        let mut type_line = |out: &mut String, name: &str, kind: &str| {
+            if last != name {
+                let _ = writeln!(out, "# TYPE {} {}", name, kind);
+                last = name.to_string();
+            }
+        };
+        for ((name, labels), value) in &families.counters {
+            type_line(&mut out, name, "counter");
+            let _ = writeln!(out, "{} {}", series(name, labels), value);
+        }
+        for ((name, labels), value) in &families.gauges {
+            type_line(&mut out, name, "gauge");
+            let _ = writeln!(out, "{} {}", series(name, labels), value);
+        }
+        for ((name, labels), h) in &families.histograms {
+            type_line(&mut out, name, "histogram");
+            let sep = if labels.is_empty() { "" } else { "," };
+            let bucket = format!("{}_bucket", name);
+            let mut cumulative = 0;
+            for (le, count) in BUCKETS.iter().zip(h.counts.iter()) {
+                cumulative += count;
+                let labels = format!("{}{}le=\"{}\"", labels, sep, le);
+                let _ = writeln!(out, "{} {}", series(&bucket, &labels), cumulative);
+            }
+            let inf = format!("{}{}le=\"+Inf\"", labels, sep);
+            let _ = writeln!(out, "{} {}", series(&bucket, &inf), h.count);
+            let _ = writeln!(out, "{} {}", series(&format!("{}_sum", name), labels), h.sum);
+            let _ = writeln!(out, "{} {}", series(&format!("{}_count", name), labels), h.count);
+        }
+        out
+    }
+}
+
+impl MetricsSink for Prometheus {
+    fn incr_counter(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        let key = (name.to_string(), labels_string(labels));
+        *self.families.lock().unwrap().counters.entry(key).or_insert(0) += value;
+    }
+
+    fn observe_histogram(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let key = (name.to_string(), labels_string(labels));
+        let mut families = self.families.lock().unwrap();
+        let h = families.histograms.entry(key).or_default();
+        if let Some(idx) = BUCKETS.iter().position(|le| value <= *le) {
+            h.counts[idx] += 1;
+        }
+        h.count += 1;
+        h.sum += value;
+    }
+
+    fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let key = (name.to_string(), labels_string(labels));
+        self.families.lock().unwrap().gauges.insert(key, value);
+    }
+}
+
+/// The counters. Clones share them.
+#[derive(Clone)]
+pub struct Metrics {
+    inner:      Arc<Inner>,
+    sink:       Arc<dyn MetricsSink>,
+    prometheus: Option<Arc<Prometheus>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            inner:      Arc::default(),
+            sink:       Arc::new(NoopSink),
+            prometheus: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    aborted_downloads: AtomicU64,
+    slow_requests:     Mutex<HashMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Send the metrics to `sink`.
+    #[allow(dead_code)]
+    pub fn with_sink(sink: Arc<dyn MetricsSink>) -> Metrics {
+        Metrics {
+            sink,
+            ..Metrics::default()
+        }
+    }
+
+    /// Keep the metrics for Prometheus.
+    pub fn with_prometheus() -> Metrics {
+        let prometheus = Arc::new(Prometheus::new());
+        Metrics {
+            sink: prometheus.clone(),
+            prometheus: Some(prometheus),
+            ..Metrics::default()
+        }
+    }
+
+    /// The sink.
+    pub fn sink(&self) -> &dyn MetricsSink {
+        &*self.sink
+    }
+
+    /// The metrics in the Prometheus format, if they are kept.
+    pub fn prometheus(&self) -> Option<String> {
+        self.prometheus.as_ref().map(|p| p.render())
+    }
+
+    /// Number of downloads that the client did not read to the end.
+    pub fn aborted_downloads(&self) -> u64 {
+        self.inner.aborted_downloads.load(Ordering::Relaxed)
+    }
+
+    /// Count a slow request.
+    pub fn slow_request(&self, method: &str) {
+        *self.inner.slow_requests.lock().unwrap().entry(method.to_string()).or_insert(0) += 1;
+        self.sink.incr_counter("webdav_slow_requests_total", &[("method", method)], 1);
+    }
+
+    /// Number of slow requests, per method.
+    pub fn slow_requests(&self) -> HashMap<String, u64> {
+        self.inner.slow_requests.lock().unwrap().clone()
+    }
+
+    /// Wrap the body of a GET response.
+    pub fn download(&self, body: hyper::Body, path: &str) -> hyper::Body {
+        hyper::Body::wrap_stream(Download {
+            body,
+            path: path.to_string(),
+            done: false,
+            metrics: self.clone(),
+        })
+    }
+}
+
+struct Download {
+    body:    hyper::Body,
+    path:    String,
+    done:    bool,
+    metrics: Metrics,
+}
+
+impl Stream for Download {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.body).poll_next(cx);
+        if let Poll::Ready(None) = res {
+            this.done = true;
+        }
+        res
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        if !self.done {
+            debug!("download of {} aborted by the client", self.path);
+            self.metrics.inner.aborted_downloads.fetch_add(1, Ordering::Relaxed);
+            self.metrics.sink.incr_counter("webdav_aborted_downloads_total", &[], 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::service_fn;
+    use std::io;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_aborted_download() {
+        let metrics = Metrics::new();
+
+        // read to the end: not counted.
+        let body = metrics.download(hyper::Body::from("data"), "/small");
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "data");
+        assert_eq!(metrics.aborted_downloads(), 0);
+
+        // a client that disconnects halfway through a large body.
+        let (client, server) = tokio::io::duplex(4096);
+        let m = metrics.clone();
+        let service = service_fn(move |_req| {
+            let chunks = (0..10000).map(|_| Ok::<_, io::Error>(Bytes::from_static(&[0u8; 1024])));
+            let body = m.download(hyper::Body::wrap_stream(futures::stream::iter(chunks)), "/large");
+            async { Ok::<_, hyper::Error>(hyper::Response::new(body)) }
+        });
+        let conn = tokio::spawn(hyper::server::conn::Http::new().serve_connection(server, service));
+
+        let mut client = client;
+        client.write_all(b"GET /large HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let mut buf = [0u8; 8192];
+        client.read_exact(&mut buf).await.unwrap();
+        drop(client);
+
+        // hyper ends the connection and drops the body.
+        assert!(conn.await.unwrap().is_err());
+        assert_eq!(metrics.aborted_downloads(), 1);
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let metrics = Metrics::with_prometheus();
+        let sink = metrics.sink();
+        sink.incr_counter("webdav_requests_total", &[("method", "GET"), ("status", "200")], 1);
+        sink.incr_counter("webdav_requests_total", &[("method", "GET"), ("status", "200")], 2);
+        sink.incr_counter("webdav_requests_total", &[("method", "PUT"), ("status", "201")], 1);
+        sink.set_gauge("webdav_open_connections", &[], 4.0);
+        sink.observe_histogram("webdav_pam_auth_duration_seconds", &[], 0.02);
+        sink.observe_histogram("webdav_pam_auth_duration_seconds", &[], 30.0);
+        metrics.slow_request("a\"b");
+
+        let text = metrics.prometheus().unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.iter().filter(|l| **l == "# TYPE webdav_requests_total counter").count(), 1);
+        assert!(lines.contains(&r#"webdav_requests_total{method="GET",status="200"} 3"#));
+        assert!(lines.contains(&r#"webdav_requests_total{method="PUT",status="201"} 1"#));
+        assert!(lines.contains(&r#"webdav_slow_requests_total{method="a\"b"} 1"#));
+        assert!(lines.contains(&"webdav_open_connections 4"));
+        assert!(lines.contains(&r#"webdav_pam_auth_duration_seconds_bucket{le="0.01"} 0"#));
+        assert!(lines.contains(&r#"webdav_pam_auth_duration_seconds_bucket{le="0.025"} 1"#));
+        assert!(lines.contains(&r#"webdav_pam_auth_duration_seconds_bucket{le="10"} 1"#));
+        assert!(lines.contains(&r#"webdav_pam_auth_duration_seconds_bucket{le="+Inf"} 2"#));
+        assert!(lines.contains(&"webdav_pam_auth_duration_seconds_count 2"));
+
+        assert!(Metrics::new().prometheus().is_none());
+    }
+}
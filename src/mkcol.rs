@@ -0,0 +1,121 @@
+//! Extended MKCOL (RFC 5689).
+//!
+//! A MKCOL can have a `DAV:mkcol` body, with properties to set on the new
+//! collection. The webdav handler refuses a MKCOL with a body (415). With
+//! `extended-mkcol`, the body is checked first. There is no store for dead
+//! properties, so a body that sets any property is refused with a 403 and
+//! a `DAV:mkcol-response` that has a 403 for every property, before the
+//! collection is made. A body that only sets `DAV:resourcetype` is a plain
+//! MKCOL.
+//!
+//! `DAV:resourcetype` in the body can only be `DAV:collection`. Other
+//! resource types (calendars, address books) get a 403 with the
+//! `DAV:valid-resourcetype` precondition.
+//!
+use http::StatusCode;
+use xmltree::Element;
+
+use crate::report::error_body;
+
+// Is this element DAV:name.
+fn is_dav(e: &Element, name: &str) -> bool {
+    e.name == name && e.namespace.as_deref() == Some("DAV:")
+}
+
+fn elements(e: &Element) -> impl Iterator<Item = &Element> {
+    e.children.iter().filter_map(|n| n.as_element())
+}
+
+/// Parse the body. Returns the properties to set, or the status and
+/// body of the error response.
+pub fn parse(body: &[u8]) -> Result<Vec<Element>, (StatusCode, String)> {
+    let root = match Element::parse(body) {
+        Ok(root) if is_dav(&root, "mkcol") => root,
+        _ => return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, error_body(None))),
+    };
+    let mut props = Vec::new();
+    let sets = elements(&root).filter(|e| is_dav(e, "set"));
+    for prop in sets.flat_map(elements).filter(|e| is_dav(e, "prop")).flat_map(elements) {
+        if is_dav(prop, "resourcetype") {
+            if elements(prop).any(|t| !is_dav(t, "collection")) {
+                debug!("mkcol: unsupported resourcetype");
+                return Err((StatusCode::FORBIDDEN, error_body(Some("valid-resourcetype"))));
+            }
+            continue;
+        }
+        props.push(prop.clone());
+    }
+    Ok(props)
+}
+
+// (namespace, name) of a property.
+fn key(e: &Element) -> (String, String) {
+    (e.namespace.clone().unwrap_or_default(), e.name.clone())
+}
+
+/// The body of the 403 for a MKCOL that sets `props`: a mkcol-response
+/// with a 403 for every property.
+pub fn refused(props: &[Element]) -> String {
+    let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    body.push_str("<D:mkcol-response xmlns:D=\"DAV:\">");
+    for prop in props {
+        let (ns, name) = key(prop);
+        body.push_str(&format!(
+            concat!(
+                "<D:propstat><D:prop><P:{} xmlns:P=\"{}\"/></D:prop>",
+                "<D:status>HTTP/1.1 403 Forbidden</D:status></D:propstat>"
+            ),
+            name,
+            escape(&ns),
+        ));
+    }
+    body.push_str("</D:mkcol-response>\n");
+    body
+}
+
+// For an attribute value.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = br#"<?xml version="1.0" encoding="utf-8"?>
+<D:mkcol xmlns:D="DAV:" xmlns:E="urn:example">
+  <D:set>
+    <D:prop>
+      <D:resourcetype><D:collection/></D:resourcetype>
+      <D:displayname>Photos</D:displayname>
+      <E:color>blue</E:color>
+    </D:prop>
+  </D:set>
+</D:mkcol>"#;
+
+    #[test]
+    fn test_parse() {
+        let props = parse(BODY).unwrap();
+        let keys: Vec<_> = props.iter().map(key).collect();
+        assert_eq!(keys, [
+            ("DAV:".to_string(), "displayname".to_string()),
+            ("urn:example".to_string(), "color".to_string())
+        ]);
+
+        let calendar = br#"<D:mkcol xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav"><D:set><D:prop>
+            <D:resourcetype><D:collection/><C:calendar/></D:resourcetype></D:prop></D:set></D:mkcol>"#;
+        assert_eq!(parse(calendar).unwrap_err().0, StatusCode::FORBIDDEN);
+        let other = br#"<D:propertyupdate xmlns:D="DAV:"/>"#;
+        assert_eq!(parse(other).unwrap_err().0, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn test_refused() {
+        let props = parse(BODY).unwrap();
+        let body = refused(&props);
+        assert!(body.starts_with("<?xml") && body.contains("<D:mkcol-response"));
+        let color = r#"<P:color xmlns:P="urn:example"/></D:prop><D:status>HTTP/1.1 403 Forbidden"#;
+        assert!(body.contains(color));
+        assert!(body.contains(r#"<P:displayname xmlns:P="DAV:"/></D:prop><D:status>HTTP/1.1 403 Forbidden"#));
+    }
+}
@@ -0,0 +1,221 @@
+//! Request path normalization.
+//!
+//! The router, the path hooks and the ACLs look at the request path as
+//! it is sent by the client, and the webdav handler normalizes it again
+//! before it goes to the filesystem. If those two do not agree on what a
+//! path means, a request can pass the checks for one directory and then
+//! touch another (`/alice/../bob/file`, or `/alice/%2e%2e/bob/file`, which
+//! the handler takes to be a file called "..").
+//!
+//! So the path (and the Destination header) is normalized once, before
+//! anything else looks at it, and the request is rewritten to use the
+//! normalized path:
+//!
+//! - `//` is collapsed into `/`
+//! - `.` and `..` segments are resolved, also when they are percent-encoded
+//! - a path that climbs above `/` is refused
+//! - an encoded `/` or NUL in a segment (`%2f`, `%00`) is refused
+//!
+//! With `strict-paths` in the `[server]` section, a path that is not in
+//! normalized form is refused instead of rewritten.
+//!
+//! Clients do not all encode the URLs in headers the same way: some send
+//! a Destination or an If: resource tag as raw UTF-8, some percent-encode
+//! the lock tokens. Before the Destination is normalized, `encode_uri`
+//! percent-encodes what cannot be in a URI, the way our hrefs are
+//! encoded. `if_header` does the same for the resource tags of the If:
+//! header, and decodes the state tokens, so that they match the tokens
+//! that LOCK handed out.
+//!
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+// Bytes that cannot be in a URI. `%` is not here, so encoded bytes stay
+// as they are; non-ASCII bytes are always encoded.
+const NOT_URI: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'<')
+    .add(b'>')
+    .add(b'\\')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// Why a path was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathError {
+    /// Not an absolute path.
+    Invalid,
+    /// `..` above the root.
+    AboveRoot,
+    /// An encoded `/` or NUL.
+    EncodedSlash,
+}
+
+/// Normalize a path, as sent by the client (percent-encoded, without a query).
+pub fn normalize(path: &str) -> Result<String, PathError> {
+    if !path.starts_with('/') {
+        return Err(PathError::Invalid);
+    }
+    let mut segments: Vec<&str> = Vec::new();
+    // a path that ends in "." or ".." is a directory.
+    let mut dot = false;
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        let decoded: Vec<u8> = percent_decode_str(segment).collect();
+        if decoded.iter().any(|&c| c == b'/' || c == 0) {
+            return Err(PathError::EncodedSlash);
+        }
+        dot = true;
+        match decoded.as_slice() {
+            b"." => {},
+            b".." => {
+                segments.pop().ok_or(PathError::AboveRoot)?;
+            },
+            _ => {
+                segments.push(segment);
+                dot = false;
+            },
+        }
+    }
+    let isdir = dot || path.ends_with('/');
+    let mut normalized = format!("/{}", segments.join("/"));
+    if isdir && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Percent-encode the bytes of a header value that cannot be in a URI.
+/// `None` if the value is not UTF-8.
+pub fn encode_uri(value: &[u8]) -> Option<String> {
+    let value = std::str::from_utf8(value).ok()?;
+    Some(utf8_percent_encode(value, NOT_URI).to_string())
+}
+
+// A percent-encoded state token, decoded. Tokens that would not be a
+// plain token after decoding are left alone.
+fn decode_token(token: &str) -> String {
+    let plain = |t: &str| t.bytes().all(|c| c.is_ascii_graphic() && c != b'<' && c != b'>');
+    match percent_decode_str(token).decode_utf8() {
+        Ok(decoded) if plain(&decoded) => decoded.to_string(),
+        _ => token.to_string(),
+    }
+}
+
+// A resource tag, encoded. A tag that is just a path (RFC 4918 allows
+// that) gets a scheme and host, the webdav handler wants an absolute URL
+// and only looks at the path.
+fn resource_tag(tag: &str) -> Option<String> {
+    let tag = encode_uri(tag.as_bytes())?;
+    let uri = tag.parse::<http::Uri>().ok()?;
+    match uri.scheme_str() {
+        Some(_) => Some(tag),
+        None if tag.starts_with('/') => Some(format!("http://localhost{}", tag)),
+        None => None,
+    }
+}
+
+// Index of `close` in `s`, outside of double quotes.
+fn find_close(s: &str, close: u8) -> Option<usize> {
+    let mut quoted = false;
+    s.bytes().position(|c| {
+        if c == b'"' {
+            quoted = !quoted;
+        }
+        c == close && !quoted
+    })
+}
+
+/// Rewrite an If: header: the resource tags are encoded like `encode_uri`,
+/// and the state tokens are decoded. `None` if the header cannot be
+/// parsed, it is left alone then.
+pub fn if_header(value: &[u8]) -> Option<String> {
+    let value = std::str::from_utf8(value).ok()?;
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    let mut in_list = false;
+    while let Some(pos) = rest.find(&['<', '[', '(', ')'][..]) {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        let close = match rest.as_bytes()[0] {
+            b'<' => b'>',
+            b'[' => b']',
+            c => {
+                in_list = c == b'(';
+                out.push(c as char);
+                rest = &rest[1..];
+                continue;
+            },
+        };
+        let end = find_close(rest, close)?;
+        let inner = &rest[1..end];
+        match close {
+            b'>' if in_list => out.push_str(&format!("<{}>", decode_token(inner))),
+            b'>' => out.push_str(&format!("<{}>", resource_tag(inner)?)),
+            _ => out.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("/"), Ok("/".to_string()));
+        assert_eq!(normalize("/a/b/"), Ok("/a/b/".to_string()));
+        assert_eq!(normalize("/a//b/./c/../d"), Ok("/a/b/d".to_string()));
+        assert_eq!(normalize("//a///b//"), Ok("/a/b/".to_string()));
+        assert_eq!(normalize("/a/b/.."), Ok("/a/".to_string()));
+        assert_eq!(normalize("/a/."), Ok("/a/".to_string()));
+        assert_eq!(normalize("/a/.."), Ok("/".to_string()));
+        assert_eq!(normalize("/a/%2e%2E/b"), Ok("/b".to_string()));
+        assert_eq!(normalize("/a/%2e/b"), Ok("/a/b".to_string()));
+        assert_eq!(normalize("/a/...b/.x"), Ok("/a/...b/.x".to_string()));
+        assert_eq!(normalize("/a%20b/%41"), Ok("/a%20b/%41".to_string()));
+        assert_eq!(normalize("/.."), Err(PathError::AboveRoot));
+        assert_eq!(normalize("/a/../../etc/passwd"), Err(PathError::AboveRoot));
+        assert_eq!(normalize("/a/%2e%2e/%2E%2E/etc"), Err(PathError::AboveRoot));
+        assert_eq!(normalize("/a%2fb"), Err(PathError::EncodedSlash));
+        assert_eq!(normalize("/a/%2F../b"), Err(PathError::EncodedSlash));
+        assert_eq!(normalize("/a%00"), Err(PathError::EncodedSlash));
+        assert_eq!(normalize("a/b"), Err(PathError::Invalid));
+    }
+
+    #[test]
+    fn test_encode_uri() {
+        let encode = |s: &str| encode_uri(s.as_bytes()).unwrap();
+        assert_eq!(encode("/a/b"), "/a/b");
+        assert_eq!(encode("http://host/d\u{e9}j\u{e0} vu"), "http://host/d%C3%A9j%C3%A0%20vu");
+        assert_eq!(encode("/d%C3%A9j%c3%a0"), "/d%C3%A9j%c3%a0");
+        assert_eq!(encode("/a{b}|\"c\""), "/a%7Bb%7D%7C%22c%22");
+        assert!(encode_uri(b"/\xff").is_none());
+    }
+
+    #[test]
+    fn test_if_header() {
+        let rewrite = |v: &str| if_header(v.as_bytes());
+        assert_eq!(rewrite("(<urn:uuid:1234>)").unwrap(), "(<urn:uuid:1234>)");
+        assert_eq!(rewrite("(<urn%3Auuid%3A1234>)").unwrap(), "(<urn:uuid:1234>)");
+        // a token that is not plain after decoding stays encoded.
+        assert_eq!(rewrite("(<urn:x%3E>)").unwrap(), "(<urn:x%3E>)");
+        assert_eq!(
+            rewrite("<http://host/\u{e9}t\u{e9}> (<urn:uuid:1> [\"e]tag\"]) (Not <DAV:no-lock>)").unwrap(),
+            "<http://host/%C3%A9t%C3%A9> (<urn:uuid:1> [\"e]tag\"]) (Not <DAV:no-lock>)"
+        );
+        assert_eq!(rewrite("</a/b> (<urn:uuid:1>)").unwrap(), "<http://localhost/a/b> (<urn:uuid:1>)");
+        let value = "<https://host/a%20b> (<urn:uuid:1>) <https://host/\u{20ac}> (<urn:uuid:2>)";
+        assert_eq!(
+            rewrite(value).unwrap(),
+            "<https://host/a%20b> (<urn:uuid:1>) <https://host/%E2%82%AC> (<urn:uuid:2>)"
+        );
+        assert!(rewrite("<a/b> (<urn:uuid:1>)").is_none());
+        assert!(rewrite("(<urn:uuid:1234)").is_none());
+    }
+}
@@ -0,0 +1,307 @@
+//! The prefix of the DAV: namespace in XML responses.
+//!
+//! The webdav handler writes the elements of the DAV: namespace with the
+//! prefix "D", declared on the root element. That is valid XML, but some
+//! clients only understand the prefix they are used to, or only the
+//! default namespace. With `dav-prefix` in `[server]`, the XML bodies of
+//! the WebDAV responses (the multistatus of PROPFIND, PROPPATCH, REPORT,
+//! COPY, MOVE and DELETE, the body of a LOCK, the errors) are rewritten
+//! while they are streamed, to use that prefix, or with "" the default
+//! namespace (`xmlns="DAV:"`).
+//!
+//! Only the elements that are in DAV: are renamed. Properties in other
+//! namespaces keep their prefixes and their declarations, and with the
+//! default namespace, elements without a namespace get `xmlns=""`, so
+//! that they are not moved into DAV:. GET and HEAD responses, which are
+//! the files themselves, are never rewritten.
+//!
+use bytes::Bytes;
+use hyper::body::HttpBody;
+
+// The prefix of the webdav handler.
+const HANDLER_PREFIX: &str = "D";
+const DAV: &str = "DAV:";
+
+/// Is `prefix` a valid value for `dav-prefix`.
+pub fn valid(prefix: &str) -> bool {
+    let mut chars = prefix.chars();
+    match chars.next() {
+        None => true,
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c)) &&
+                !prefix.to_ascii_lowercase().starts_with("xml")
+        },
+        Some(_) => false,
+    }
+}
+
+// What is in scope at an element, in the input and in the output.
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    // the handler prefix is bound to DAV:.
+    dav:         bool,
+    // the default namespace in the input and in the output.
+    default_in:  Option<String>,
+    default_out: Option<String>,
+}
+
+// The rewriter: the elements that are open, and what is left of the last chunk.
+struct Rewriter {
+    prefix: String,
+    stack:  Vec<Scope>,
+    carry:  Vec<u8>,
+}
+
+// The attributes of a start tag, as (name, value, the attribute as it was).
+fn attributes(tag: &str) -> Vec<(&str, &str, &str)> {
+    let mut attrs = Vec::new();
+    let mut rest = tag;
+    loop {
+        let start = rest.len() - rest.trim_start().len();
+        rest = &rest[start..];
+        let eq = match rest.find('=') {
+            Some(eq) => eq,
+            None => return attrs,
+        };
+        let name = rest[..eq].trim();
+        let value = rest[eq + 1..].trim_start();
+        let quote = match value.chars().next() {
+            Some(q) if q == '"' || q == '\'' => q,
+            _ => return attrs,
+        };
+        let end = match value[1..].find(quote) {
+            Some(end) => end + 1,
+            None => return attrs,
+        };
+        let consumed = rest.len() - value.len() + end + 1;
+        attrs.push((name, &value[1..end], &rest[..consumed]));
+        rest = &rest[consumed..];
+    }
+}
+
+impl Rewriter {
+    fn new(prefix: &str) -> Rewriter {
+        Rewriter {
+            prefix: prefix.to_string(),
+            stack:  Vec::new(),
+            carry:  Vec::new(),
+        }
+    }
+
+    // The name of a DAV: element in the output.
+    fn name(&self, local: &str) -> String {
+        match self.prefix.as_str() {
+            "" => local.to_string(),
+            prefix => format!("{}:{}", prefix, local),
+        }
+    }
+
+    // Rewrite a tag, without the "<" and ">".
+    fn tag(&mut self, tag: &str) -> String {
+        if tag.starts_with('?') || tag.starts_with('!') {
+            return tag.to_string();
+        }
+        if let Some(name) = tag.strip_prefix('/') {
+            let scope = self.stack.pop().unwrap_or_default();
+            return match name.trim().split_once(':') {
+                Some((HANDLER_PREFIX, local)) if scope.dav => format!("/{}", self.name(local)),
+                _ => tag.to_string(),
+            };
+        }
+
+        let empty = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let name_end = tag.find(|c: char| c.is_ascii_whitespace()).unwrap_or(tag.len());
+        let (name, attrs) = tag.split_at(name_end);
+        let attrs = attributes(attrs);
+
+        // what is in scope here, in the input.
+        let parent = self.stack.last().cloned().unwrap_or_default();
+        let mut scope = parent.clone();
+        let xmlns_d = format!("xmlns:{}", HANDLER_PREFIX);
+        for &(attr, value, _) in &attrs {
+            if attr == xmlns_d {
+                scope.dav = value == DAV;
+            } else if attr == "xmlns" {
+                scope.default_in = Some(value.to_string()).filter(|v| !v.is_empty());
+                scope.default_out = scope.default_in.clone();
+            }
+        }
+
+        let mut out = String::new();
+        let declares_default = attrs.iter().any(|(attr, _, _)| *attr == "xmlns");
+        match name.split_once(':') {
+            Some((HANDLER_PREFIX, local)) if scope.dav => {
+                out.push_str(&self.name(local));
+                if self.prefix.is_empty() && !declares_default && scope.default_out.as_deref() != Some(DAV) {
+                    out.push_str(" xmlns=\"DAV:\"");
+                    scope.default_out = Some(DAV.to_string());
+                }
+            },
+            Some(_) => out.push_str(name),
+            None => {
+                out.push_str(name);
+                if !declares_default && scope.default_out != scope.default_in {
+                    let ns = scope.default_in.clone().unwrap_or_default();
+                    out.push_str(&format!(" xmlns=\"{}\"", ns));
+                    scope.default_out = scope.default_in.clone();
+                }
+            },
+        }
+        for &(attr, value, text) in &attrs {
+            // with the default namespace, "D" stays declared, for the
+            // elements that cannot be in it.
+            if attr == xmlns_d && value == DAV && !self.prefix.is_empty() {
+                out.push_str(&format!(" xmlns:{}=\"{}\"", self.prefix, DAV));
+            } else {
+                out.push(' ');
+                out.push_str(text);
+            }
+        }
+        if empty {
+            out.push('/');
+        } else {
+            self.stack.push(scope);
+        }
+        out
+    }
+
+    // Rewrite what there is of the body, and keep a tag that is not complete.
+    fn chunk(&mut self, chunk: &[u8]) -> Bytes {
+        let mut data = std::mem::take(&mut self.carry);
+        data.extend_from_slice(chunk);
+        if let Err(e) = std::str::from_utf8(&data) {
+            if e.error_len().is_some() {
+                return Bytes::from(data);
+            }
+            // a character that is split over two chunks.
+            self.carry = data.split_off(e.valid_up_to());
+        }
+        let text = std::str::from_utf8(&data).unwrap();
+        let mut out = String::with_capacity(text.len() + 64);
+        let mut rest = text;
+        while let Some(start) = rest.find('<') {
+            out.push_str(&rest[..start]);
+            let tag = &rest[start + 1..];
+            match tag_end(tag) {
+                Some(end) => {
+                    out.push('<');
+                    out.push_str(&self.tag(&tag[..end]));
+                    out.push('>');
+                    rest = &tag[end + 1..];
+                },
+                None => {
+                    let mut carry = rest.as_bytes()[start..].to_vec();
+                    carry.extend_from_slice(&self.carry);
+                    self.carry = carry;
+                    rest = "";
+                },
+            }
+        }
+        out.push_str(rest);
+        Bytes::from(out)
+    }
+}
+
+// Where the tag ends, the ">" that is not in an attribute value.
+fn tag_end(tag: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in tag.char_indices() {
+        match (quote, c) {
+            (None, '>') => return Some(i),
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            _ => {},
+        }
+    }
+    None
+}
+
+/// Rewrite an XML response body to use `prefix` for DAV:, while it is streamed.
+pub fn rewrite(body: hyper::Body, prefix: &str) -> hyper::Body {
+    let stream = futures::stream::unfold(Some((body, Rewriter::new(prefix))), |state| {
+        async move {
+            let (mut body, mut rewriter) = state?;
+            match body.data().await {
+                Some(Ok(chunk)) => {
+                    let out = rewriter.chunk(&chunk);
+                    Some((Ok(out), Some((body, rewriter))))
+                },
+                Some(Err(e)) => Some((Err(e), None)),
+                None if rewriter.carry.is_empty() => None,
+                None => Some((Ok(Bytes::from(std::mem::take(&mut rewriter.carry))), None)),
+            }
+        }
+    });
+    hyper::Body::wrap_stream(stream)
+}
+
+/// Rewrite the body of `resp` to use `prefix` for DAV:, if it is the XML
+/// body of a WebDAV response.
+pub fn apply(resp: &mut hyper::Response<hyper::Body>, method: &http::Method, prefix: &str) {
+    if prefix == HANDLER_PREFIX || method == http::Method::GET || method == http::Method::HEAD {
+        return;
+    }
+    let ctype = resp.headers().get("content-type").and_then(|v| v.to_str().ok()).unwrap_or("");
+    if !ctype.starts_with("application/xml") && !ctype.starts_with("text/xml") {
+        return;
+    }
+    resp.headers_mut().remove("content-length");
+    let body = std::mem::replace(resp.body_mut(), hyper::Body::empty());
+    *resp.body_mut() = rewrite(body, prefix);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewritten(xml: &str, prefix: &str) -> String {
+        let mut rewriter = Rewriter::new(prefix);
+        // one byte at a time, every tag and character is split.
+        let mut out = Vec::new();
+        for b in xml.as_bytes() {
+            out.extend_from_slice(&rewriter.chunk(&[*b]));
+        }
+        assert!(rewriter.carry.is_empty());
+        String::from_utf8(out).unwrap()
+    }
+
+    const MS: &str = concat!(
+        r#"<?xml version="1.0" encoding="utf-8"?><D:multistatus xmlns:D="DAV:"><D:response>"#,
+        r#"<D:href>/a/caf&#233;é</D:href><D:propstat><D:prop><X:color xmlns:X="urn:x">red</X:color>"#,
+        r#"<nons xmlns:X="urn:x"></nons><D:getetag>"1-2"</D:getetag></D:prop>"#,
+        r#"<D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response></D:multistatus>"#
+    );
+
+    #[test]
+    fn test_prefix() {
+        assert!(valid("") && valid("D") && valid("dav") && valid("_a.b-1"));
+        assert!(!valid("1d") && !valid("d:") && !valid("xmlns") && !valid("d a"));
+
+        let out = rewritten(MS, "d");
+        assert!(out.starts_with(r#"<?xml version="1.0" encoding="utf-8"?><d:multistatus xmlns:d="DAV:">"#));
+        assert!(out.contains(r#"<d:href>/a/caf&#233;é</d:href>"#));
+        assert!(out.contains(r#"<X:color xmlns:X="urn:x">red</X:color><nons xmlns:X="urn:x"></nons>"#));
+        assert!(out.ends_with("</d:propstat></d:response></d:multistatus>"));
+        assert_eq!(rewritten(MS, "D"), MS);
+
+        let out = rewritten(MS, "");
+        assert!(out.contains(r#"<multistatus xmlns="DAV:" xmlns:D="DAV:"><response>"#));
+        assert!(out.contains(r#"<X:color xmlns:X="urn:x">red</X:color>"#));
+        assert!(out.contains(r#"<nons xmlns="" xmlns:X="urn:x"></nons>"#));
+        assert!(out.contains(r#"<getetag>"1-2"</getetag>"#));
+
+        // "D" for another namespace is left alone.
+        let xml = r#"<D:prop xmlns:D="DAV:"><D:x xmlns:D="urn:d"><D:y/></D:x><D:z/></D:prop>"#;
+        let out = rewritten(xml, "d");
+        assert_eq!(out, r#"<d:prop xmlns:d="DAV:"><D:x xmlns:D="urn:d"><D:y/></D:x><d:z/></d:prop>"#);
+        // and an element in a default namespace keeps it.
+        let xml = r#"<D:prop xmlns:D="DAV:"><a xmlns="urn:a"><b/><D:c/></a></D:prop>"#;
+        let out = rewritten(xml, "");
+        let expected = concat!(
+            r#"<prop xmlns="DAV:" xmlns:D="DAV:">"#,
+            r#"<a xmlns="urn:a"><b/><c xmlns="DAV:"/></a></prop>"#
+        );
+        assert_eq!(out, expected);
+    }
+}
@@ -0,0 +1,178 @@
+//! Stay on one filesystem.
+//!
+//! The directory of a location can have other filesystems mounted below
+//! it (bind mounts, NFS), or symlinks that point to them. A MOVE from one
+//! to the other is then a copy and a delete (see `crossfs`), a COPY fills
+//! up another disk, and the quota that is reported is not the quota that
+//! applies.
+//!
+//! With `one-filesystem` in a location, `OneFs` wraps the filesystem,
+//! and every path that the webdav handler uses is resolved one component
+//! at a time first. If any part of it that exists is on another device
+//! (st_dev) than the directory of the location, the operation fails with
+//! "403 Forbidden". Symlinks are followed, as the operation itself would.
+//! A listing of a directory can still show a mount point, it just cannot
+//! be entered.
+//!
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use futures::future::{Future, FutureExt};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+#[derive(Clone)]
+pub struct OneFs {
+    fs:      Box<dyn DavFileSystem>,
+    basedir: PathBuf,
+}
+
+impl OneFs {
+    pub fn new(fs: Box<dyn DavFileSystem>, basedir: impl AsRef<Path>) -> Box<OneFs> {
+        Box::new(OneFs {
+            fs,
+            basedir: basedir.as_ref().to_path_buf(),
+        })
+    }
+
+    // Run `fut` if all `paths` are on the device of the base directory.
+    fn checked<'a, T: Send + 'a>(&'a self, paths: Vec<&'a DavPath>, fut: FsFuture<'a, T>) -> FsFuture<'a, T> {
+        async move {
+            let basedir = self.basedir.clone();
+            let paths: Vec<_> = paths.iter().map(|p| p.as_rel_ospath().to_path_buf()).collect();
+            tokio::task::block_in_place(move || same_device(&basedir, &paths))?;
+            fut.await
+        }
+        .boxed()
+    }
+}
+
+// Are the parts that exist of `paths` on the device of `basedir`.
+pub fn same_device(basedir: &Path, paths: &[PathBuf]) -> FsResult<()> {
+    let dev = match std::fs::metadata(basedir) {
+        Ok(meta) => meta.dev(),
+        // the operation itself will fail.
+        Err(_) => return Ok(()),
+    };
+    for path in paths {
+        let mut fspath = basedir.to_path_buf();
+        for component in path.components() {
+            fspath.push(component);
+            match std::fs::metadata(&fspath) {
+                Ok(meta) if meta.dev() != dev => {
+                    debug!("one-filesystem: {:?} is on another device", fspath);
+                    return Err(FsError::Forbidden);
+                },
+                Ok(_) => {},
+                Err(_) => break,
+            }
+        }
+    }
+    Ok(())
+}
+
+impl DavFileSystem for OneFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        self.checked(vec![path], self.fs.open(path, options))
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.checked(vec![path], self.fs.read_dir(path, meta))
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.checked(vec![path], self.fs.metadata(path))
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.checked(vec![path], self.fs.symlink_metadata(path))
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.checked(vec![path], self.fs.create_dir(path))
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.checked(vec![path], self.fs.remove_dir(path))
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.checked(vec![path], self.fs.remove_file(path))
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.checked(vec![from, to], self.fs.rename(from, to))
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.checked(vec![from, to], self.fs.copy(from, to))
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.checked(vec![path], self.fs.set_accessed(path, tm))
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.checked(vec![path], self.fs.set_modified(path, tm))
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        let fut = self.checked(vec![path], self.fs.have_props(path).map(Ok).boxed());
+        fut.map(|res| res.unwrap_or(false)).boxed()
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(http::StatusCode, DavProp)>>
+    {
+        self.checked(vec![path], self.fs.patch_props(path, patch))
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.checked(vec![path], self.fs.get_props(path, do_content))
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.checked(vec![path], self.fs.get_prop(path, prop))
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crossfs::tests::other_fs;
+
+    #[test]
+    fn test_same_device() {
+        let other = match other_fs() {
+            Some(other) => other,
+            None => return,
+        };
+        let dir = std::env::temp_dir().join(format!("webdav-test-{}", uuid::Uuid::new_v4().to_simple()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::os::unix::fs::symlink(&other, dir.join("other")).unwrap();
+        let path = |p: &str| vec![PathBuf::from(p)];
+
+        assert!(same_device(&dir, &path("sub/new/file")).is_ok());
+        assert!(same_device(&dir, &path("nope")).is_ok());
+        assert!(matches!(same_device(&dir, &path("other")), Err(FsError::Forbidden)));
+        assert!(matches!(same_device(&dir, &path("other/new")), Err(FsError::Forbidden)));
+        assert!(same_device(&dir, &[PathBuf::from("sub"), PathBuf::from("other")]).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&other).unwrap();
+    }
+}
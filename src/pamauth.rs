@@ -0,0 +1,614 @@
+//! PAM authentication.
+//!
+//! A small wrapper around `pam-sys`. We do not use the `pam` crate,
+//! because it does not let us set PAM items like PAM_RHOST.
+//!
+//! `authenticate` answers every prompt of the conversation from the
+//! credentials: prompts with echo on get the username, the first prompt
+//! with echo off the password. Our copies of the answers are zeroed.
+//! `authenticate_conv` hands every prompt to a callback instead, for
+//! stacks that ask more (an OTP after the password, a challenge), with
+//! the echo-off prompts (secrets) told apart from the echo-on ones. HTTP
+//! Basic auth has only the one password, so the server itself does not
+//! use that yet.
+//!
+//! PAM does not say which module made a request fail. What we can tell
+//! is the return code, the phase (pam_authenticate for a wrong password,
+//! pam_acct_mgmt for things like pam_time or an expired account), and
+//! the messages that modules sent through the conversation function
+//! (pam_tally2 and pam_faillock explain that an account is locked).
+//! All of that is in `PamError`, for the log. Clients never see it.
+//!
+//! A PAM module can hang (an LDAP or Kerberos server that does not
+//! answer). With `[pam] timeout`, the request gives up after that many
+//! secs with a `PamPhase::Timeout` error, which is handled like an
+//! unreachable backend (503). A PAM call cannot be interrupted, so the
+//! thread that runs it keeps waiting, and its answer is thrown away when
+//! it comes. Every call holds a permit of `[pam] max-pending` until it
+//! really returns, so hung calls cannot take more threads than that:
+//! when all permits are taken, logins get a `PamPhase::Pending` error
+//! (503) without calling PAM.
+//!
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::time::Duration;
+
+use libc::{c_char, c_int, c_void};
+use pam_sys::{PamConversation, PamFlag, PamHandle, PamItemType, PamMessage, PamMessageStyle};
+use pam_sys::{PamResponse, PamReturnCode};
+use tokio::sync::OwnedSemaphorePermit;
+
+use crate::requestid;
+
+/// The PAM call that failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PamPhase {
+    Start,
+    SetItem,
+    Authenticate,
+    AcctMgmt,
+    /// No answer within `[pam] timeout`.
+    Timeout,
+    /// Not called, `[pam] max-pending` calls are in progress.
+    Pending,
+}
+
+impl fmt::Display for PamPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PamPhase::Start => "pam_start",
+            PamPhase::SetItem => "pam_set_item",
+            PamPhase::Authenticate => "pam_authenticate",
+            PamPhase::AcctMgmt => "pam_acct_mgmt",
+            PamPhase::Timeout => "timeout",
+            PamPhase::Pending => "max-pending",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Error returned by a PAM call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PamError {
+    /// PAM return code.
+    pub code:     PamReturnCode,
+    /// Where it failed.
+    pub phase:    PamPhase,
+    /// Error and info messages from the PAM modules.
+    pub messages: Vec<String>,
+}
+
+impl PamError {
+    pub fn new(phase: PamPhase, code: PamReturnCode) -> PamError {
+        PamError {
+            code,
+            phase,
+            messages: Vec::new(),
+        }
+    }
+
+    /// Human readable reason, for the log.
+    pub fn reason(&self) -> &'static str {
+        use PamReturnCode::*;
+        match (self.code, self.phase) {
+            (_, PamPhase::Timeout) => "no answer from PAM in time",
+            (_, PamPhase::Pending) => "too many PAM calls in progress",
+            (AUTH_ERR, _) => "wrong password or unknown user",
+            (USER_UNKNOWN, _) => "unknown user",
+            (MAXTRIES, _) => "too many failed attempts, account locked (pam_tally2, pam_faillock)",
+            (PERM_DENIED, PamPhase::AcctMgmt) => "access denied by account policy (pam_time, pam_access)",
+            (PERM_DENIED, _) => "permission denied",
+            (ACCT_EXPIRED, _) => "account expired",
+            (NEW_AUTHTOK_REQD, _) => "password expired, must be changed",
+            (CRED_INSUFFICIENT, _) => "insufficient credentials",
+            (AUTHINFO_UNAVAIL, _) => "authentication backend unreachable",
+            (AUTHTOK_LOCK_BUSY, _) | (TRY_AGAIN, _) => "busy, try again",
+            (BAD_ITEM, _) => "invalid PAM item",
+            (BUF_ERR, _) => "invalid input (NUL byte?) or out of memory",
+            (SYSTEM_ERR, _) | (SERVICE_ERR, _) => "PAM configuration or module error",
+            (OPEN_ERR, _) | (SYMBOL_ERR, _) => "PAM module could not be loaded",
+            (ABORT, _) => "PAM aborted",
+            _ => "PAM error",
+        }
+    }
+}
+
+impl fmt::Display for PamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} ({:?})", self.phase, self.reason(), self.code)?;
+        if !self.messages.is_empty() {
+            write!(f, " [{}]", self.messages.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PamError {}
+
+// Items that can be set with `PamRequest::item`. Things like PAM_USER
+// or PAM_AUTHTOK are deliberately not in this list.
+const ITEMS: &[(&str, PamItemType)] = &[
+    ("PAM_TTY", PamItemType::TTY),
+    ("PAM_RUSER", PamItemType::RUSER),
+    ("PAM_XDISPLAY", PamItemType::XDISPLAY),
+];
+
+/// Look up a settable PAM item by name ("PAM_TTY", "PAM_RUSER", "PAM_XDISPLAY").
+pub fn item_type(name: &str) -> Option<PamItemType> {
+    ITEMS.iter().find(|(n, _)| *n == name).map(|(_, t)| *t)
+}
+
+/// A prompt from the PAM stack, for `authenticate_conv`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PamPrompt {
+    /// PAM_PROMPT_ECHO_ON: the answer may be shown (a username).
+    EchoOn(String),
+    /// PAM_PROMPT_ECHO_OFF: the answer is a secret (a password, an OTP).
+    EchoOff(String),
+}
+
+/// A PAM authentication request.
+#[derive(Debug, Clone)]
+pub struct PamRequest {
+    pub service: String,
+    pub user:    String,
+    pub pass:    String,
+    pub rhost:   Option<String>,
+    pub items:   Vec<(PamItemType, String)>,
+}
+
+impl PamRequest {
+    /// New request.
+    pub fn new(service: &str, user: &str, pass: &str) -> PamRequest {
+        PamRequest {
+            service: service.to_string(),
+            user:    user.to_string(),
+            pass:    pass.to_string(),
+            rhost:   None,
+            items:   Vec::new(),
+        }
+    }
+
+    /// Set PAM_RHOST.
+    pub fn rhost(mut self, rhost: Option<&str>) -> PamRequest {
+        self.rhost = rhost.map(|s| s.to_string());
+        self
+    }
+
+    /// Set an extra PAM item by name, see `item_type`.
+    pub fn item(mut self, name: &str, value: &str) -> Result<PamRequest, PamError> {
+        let item_type = item_type(name).ok_or(PamError::new(PamPhase::SetItem, PamReturnCode::BAD_ITEM))?;
+        self.items.push((item_type, value.to_string()));
+        Ok(self)
+    }
+
+    /// Run pam_authenticate and pam_acct_mgmt. This blocks.
+    pub fn authenticate(&self) -> Result<(), PamError> {
+        if self.user.contains('\0') || self.pass.contains('\0') {
+            return Err(PamError::new(PamPhase::Start, PamReturnCode::BUF_ERR));
+        }
+        self.authenticate_conv(credentials(&self.user, &self.pass))
+    }
+
+    /// Like `authenticate`, but every prompt goes to `answer`, and
+    /// `self.pass` is not used. `None`, or an answer with a NUL byte,
+    /// fails the conversation (PAM_CONV_ERR). This blocks.
+    pub fn authenticate_conv<F>(&self, mut answer: F) -> Result<(), PamError>
+    where
+        F: FnMut(PamPrompt) -> Option<String>,
+    {
+        let conv_data = Conv {
+            answer:   RefCell::new(&mut answer),
+            messages: RefCell::new(Vec::new()),
+        };
+        let conv = PamConversation {
+            conv:     Some(converse),
+            data_ptr: &conv_data as *const Conv as *mut c_void,
+        };
+
+        let mut handle: *mut PamHandle = ptr::null_mut();
+        let rc = pam_sys::start(&self.service, Some(&self.user), &conv, &mut handle);
+        if rc != PamReturnCode::SUCCESS {
+            return Err(PamError::new(PamPhase::Start, rc));
+        }
+        // SAFETY: pam_start succeeded, so the handle is valid until pam_end.
+        let handle = unsafe { &mut *handle };
+        let (phase, rc) = self.run(handle);
+        pam_sys::end(handle, rc);
+
+        match rc {
+            PamReturnCode::SUCCESS => Ok(()),
+            rc => {
+                let mut err = PamError::new(phase, rc);
+                err.messages = conv_data.messages.into_inner();
+                Err(err)
+            },
+        }
+    }
+
+    /// `authenticate` in a blocking thread, for at most `timeout`.
+    /// `permit` is released when the call returns, also after the
+    /// timeout.
+    pub async fn authenticate_timeout(
+        self,
+        timeout: Duration,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<(), PamError>
+    {
+        with_timeout(move || self.authenticate(), timeout, permit).await
+    }
+
+    fn run(&self, handle: &mut PamHandle) -> (PamPhase, PamReturnCode) {
+        let rhost = self.rhost.iter().map(|r| (PamItemType::RHOST, r));
+        let items = self.items.iter().map(|(t, v)| (*t, v));
+        for (item_type, value) in rhost.chain(items) {
+            let value = match CString::new(value.as_str()) {
+                Ok(v) => v,
+                Err(_) => return (PamPhase::SetItem, PamReturnCode::BUF_ERR),
+            };
+            // SAFETY: pam_set_item copies the string.
+            let item = unsafe { &*(value.as_ptr() as *const c_void) };
+            let rc = pam_sys::set_item(handle, item_type, item);
+            if rc != PamReturnCode::SUCCESS {
+                return (PamPhase::SetItem, rc);
+            }
+        }
+
+        let rc = pam_sys::authenticate(handle, PamFlag::NONE);
+        if rc != PamReturnCode::SUCCESS {
+            return (PamPhase::Authenticate, rc);
+        }
+        (PamPhase::AcctMgmt, pam_sys::acct_mgmt(handle, PamFlag::NONE))
+    }
+}
+
+// Run `f` in a blocking thread, with the request ID of the caller.
+// After `timeout`, stop waiting for it; what it returns after that is
+// dropped with its JoinHandle. `permit` goes when `f` returns.
+async fn with_timeout<F>(f: F, timeout: Duration, permit: OwnedSemaphorePermit) -> Result<(), PamError>
+where
+    F: FnOnce() -> Result<(), PamError> + Send + 'static,
+{
+    let id = requestid::current();
+    let f = move || {
+        let _permit = permit;
+        requestid::sync_scope(id, f)
+    };
+    match tokio::time::timeout(timeout, tokio::task::spawn_blocking(f)).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(_)) => Err(PamError::new(PamPhase::Start, PamReturnCode::ABORT)),
+        Err(_) => Err(PamError::new(PamPhase::Timeout, PamReturnCode::AUTHINFO_UNAVAIL)),
+    }
+}
+
+/// Startup self-test: try to authenticate a user that does not exist.
+///
+/// If PAM works, that fails with something like "user unknown" or
+/// "authentication failed". Anything else means that the PAM service
+/// is broken (missing config, module that cannot be loaded, backend
+/// that cannot be reached), or worse, lets anyone in.
+pub fn self_test(service: &str) -> Result<(), String> {
+    let user = format!("webdav-self-test-{}", &uuid::Uuid::new_v4().to_simple().to_string()[..8]);
+    let req = PamRequest::new(service, &user, "");
+    match req.authenticate() {
+        Ok(()) => Err(format!("service {}: authenticated non-existent user {}", service, user)),
+        Err(e) => {
+            use PamReturnCode::*;
+            match e.code {
+                USER_UNKNOWN | AUTH_ERR | PERM_DENIED | CRED_INSUFFICIENT | MAXTRIES | ACCT_EXPIRED => {
+                    debug!("pam self-test: service {}: ok ({:?})", service, e.code);
+                    Ok(())
+                },
+                _ => Err(format!("service {}: {}", service, e)),
+            }
+        },
+    }
+}
+
+// Answers from the credentials, for `authenticate`. The password is
+// handed out once, the conversation function zeroes it after use.
+fn credentials<'a>(user: &'a str, pass: &'a str) -> impl FnMut(PamPrompt) -> Option<String> + 'a {
+    let mut pass = Some(pass);
+    move |prompt| {
+        match prompt {
+            PamPrompt::EchoOn(_) => Some(user.to_string()),
+            PamPrompt::EchoOff(_) => pass.take().map(|p| p.to_string()),
+        }
+    }
+}
+
+// Overwrite a secret before its memory is freed.
+fn zero(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        // SAFETY: b is a valid reference. Volatile, so that it is not optimized out.
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+}
+
+// A copy of `answer` made with malloc, for PAM, which frees it. `answer`
+// itself is zeroed. None if it has a NUL byte, or out of memory.
+fn c_answer(mut answer: String) -> Option<*mut c_char> {
+    let len = answer.len();
+    let dup = match answer.contains('\0') {
+        true => ptr::null_mut(),
+        false => unsafe { libc::malloc(len + 1) as *mut c_char },
+    };
+    if !dup.is_null() {
+        // SAFETY: dup has room for len bytes and the NUL.
+        unsafe {
+            ptr::copy_nonoverlapping(answer.as_ptr(), dup as *mut u8, len);
+            *dup.add(len) = 0;
+        }
+    }
+    // SAFETY: zeroes are valid UTF-8.
+    zero(unsafe { answer.as_bytes_mut() });
+    Some(dup).filter(|d| !d.is_null())
+}
+
+// Free `n` responses, and the answers in them, zeroed. Those that are
+// not set are NULL (calloc).
+unsafe fn free_responses(resp: *mut PamResponse, n: isize) {
+    for j in 0..n {
+        let answer = (*resp.offset(j)).resp;
+        if !answer.is_null() {
+            zero(std::slice::from_raw_parts_mut(answer as *mut u8, libc::strlen(answer)));
+            libc::free(answer as *mut c_void);
+        }
+    }
+    libc::free(resp as *mut c_void);
+}
+
+// Data for the conversation function.
+struct Conv<'a> {
+    answer:   RefCell<&'a mut dyn FnMut(PamPrompt) -> Option<String>>,
+    messages: RefCell<Vec<String>>,
+}
+
+// The PAM conversation function. Prompts are answered by `Conv::answer`.
+// A panic in there must not unwind into PAM, it fails the conversation.
+extern "C" fn converse(
+    num_msg: c_int,
+    msg: *mut *mut PamMessage,
+    out_resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int
+{
+    if num_msg <= 0 {
+        return PamReturnCode::CONV_ERR as c_int;
+    }
+    // SAFETY: appdata_ptr is the Conv we passed to pam_start.
+    let conv = unsafe { &*(appdata_ptr as *const Conv) };
+
+    // PAM frees the responses, so they must be allocated with malloc.
+    let resp =
+        unsafe { libc::calloc(num_msg as usize, mem::size_of::<PamResponse>()) as *mut PamResponse };
+    if resp.is_null() {
+        return PamReturnCode::BUF_ERR as c_int;
+    }
+
+    for i in 0..num_msg as isize {
+        // SAFETY: PAM passes an array of num_msg message pointers.
+        let (m, r) = unsafe { (&**msg.offset(i), &mut *resp.offset(i)) };
+        let text = match m.msg.is_null() {
+            true => String::new(),
+            false => unsafe { CStr::from_ptr(m.msg) }.to_string_lossy().into_owned(),
+        };
+        let prompt = match PamMessageStyle::from(m.msg_style) {
+            PamMessageStyle::PROMPT_ECHO_ON => PamPrompt::EchoOn(text),
+            PamMessageStyle::PROMPT_ECHO_OFF => PamPrompt::EchoOff(text),
+            PamMessageStyle::ERROR_MSG | PamMessageStyle::TEXT_INFO => {
+                if !m.msg.is_null() {
+                    debug!("pam: {}", text);
+                    conv.messages.borrow_mut().push(text.trim().to_string());
+                }
+                continue;
+            },
+        };
+        let answer = panic::catch_unwind(AssertUnwindSafe(|| (*conv.answer.borrow_mut())(prompt)));
+        let answer = answer.unwrap_or_else(|_| {
+            error!("pam: conversation callback panicked");
+            None
+        });
+        match answer.and_then(c_answer) {
+            Some(answer) => r.resp = answer,
+            None => {
+                // SAFETY: the answers so far were made with malloc.
+                unsafe { free_responses(resp, num_msg as isize) };
+                return PamReturnCode::CONV_ERR as c_int;
+            },
+        }
+    }
+
+    // SAFETY: out_resp is a valid pointer passed in by PAM.
+    unsafe { *out_resp = resp };
+    PamReturnCode::SUCCESS as c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::Semaphore;
+
+    #[test]
+    fn test_items() {
+        let req = PamRequest::new("other", "user", "pass");
+        let req = req.item("PAM_TTY", "webdav").unwrap();
+        assert_eq!(req.items, vec![(PamItemType::TTY, "webdav".to_string())]);
+        assert_eq!(
+            req.clone().item("PAM_USER", "root").unwrap_err(),
+            PamError::new(PamPhase::SetItem, PamReturnCode::BAD_ITEM)
+        );
+        assert!(item_type("PAM_AUTHTOK").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let pending = Arc::new(Semaphore::new(1));
+        let permit = || pending.clone().try_acquire_owned().unwrap();
+        let second = Duration::from_secs(1);
+        assert_eq!(with_timeout(|| Ok(()), second, permit()).await, Ok(()));
+        let hung = || {
+            std::thread::sleep(Duration::from_millis(200));
+            Err(PamError::new(PamPhase::Authenticate, PamReturnCode::AUTH_ERR))
+        };
+        let err = with_timeout(hung, Duration::from_millis(20), permit()).await.unwrap_err();
+        assert_eq!(err.phase, PamPhase::Timeout);
+        assert_eq!(err.to_string(), "timeout: no answer from PAM in time (AUTHINFO_UNAVAIL)");
+        // the hung call still holds its permit.
+        assert!(pending.clone().try_acquire_owned().is_err());
+        // the late answer is dropped, and the permit is back.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(pending.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        crate::requestid::tests::capture_log();
+        let pending = Arc::new(Semaphore::new(1));
+        let permit = pending.try_acquire_owned().unwrap();
+        let call = || {
+            info!("pam thread test");
+            Ok(())
+        };
+        let call = with_timeout(call, Duration::from_secs(1), permit);
+        assert_eq!(requestid::scope("test-pam-id".to_string(), call).await, Ok(()));
+        let logged = crate::requestid::tests::logged("pam thread test");
+        assert_eq!(logged, vec!["webdav_server::pamauth::tests: [test-pam-id] pam thread test"]);
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // requests that go away while PAM is busy keep their permits
+        // until the PAM calls return, and then leave nothing behind.
+        let pending = Arc::new(Semaphore::new(10));
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let done = done.clone();
+            let busy = move || {
+                std::thread::sleep(Duration::from_millis(200));
+                done.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            };
+            let permit = pending.clone().try_acquire_owned().unwrap();
+            let call = with_timeout(busy, Duration::from_secs(1), permit);
+            assert!(tokio::time::timeout(Duration::from_millis(5), call).await.is_err());
+        }
+        assert_eq!(pending.available_permits(), 0);
+        assert!(pending.clone().try_acquire_owned().is_err());
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(done.load(Ordering::SeqCst), 10);
+        assert_eq!(pending.available_permits(), 10);
+    }
+
+    #[test]
+    fn test_converse() {
+        let texts = ["login: ", "Password: ", "Hello", "OTP: "];
+        let texts: Vec<CString> = texts.iter().map(|t| CString::new(*t).unwrap()).collect();
+        let styles = [
+            PamMessageStyle::PROMPT_ECHO_ON,
+            PamMessageStyle::PROMPT_ECHO_OFF,
+            PamMessageStyle::TEXT_INFO,
+            PamMessageStyle::PROMPT_ECHO_OFF,
+        ];
+        let mut msgs: Vec<PamMessage> = styles
+            .iter()
+            .zip(texts.iter())
+            .map(|(s, t)| PamMessage { msg_style: *s as c_int, msg: t.as_ptr() })
+            .collect();
+        let mut ptrs: Vec<*mut PamMessage> = msgs.iter_mut().map(|m| m as *mut PamMessage).collect();
+
+        let mut prompts = Vec::new();
+        let mut answer = |p: PamPrompt| {
+            prompts.push(p.clone());
+            match p {
+                PamPrompt::EchoOn(_) => Some("alice".to_string()),
+                PamPrompt::EchoOff(t) if t.starts_with("OTP") => Some("123456".to_string()),
+                PamPrompt::EchoOff(_) => Some("secret".to_string()),
+            }
+        };
+        let conv = Conv {
+            answer:   RefCell::new(&mut answer),
+            messages: RefCell::new(Vec::new()),
+        };
+        let data = &conv as *const Conv as *mut c_void;
+        let mut resp: *mut PamResponse = ptr::null_mut();
+        let rc = converse(ptrs.len() as c_int, ptrs.as_mut_ptr(), &mut resp, data);
+        assert_eq!(rc, PamReturnCode::SUCCESS as c_int);
+        let answers: Vec<Option<String>> = (0..4)
+            .map(|i| unsafe {
+                let r = (*resp.offset(i)).resp;
+                let a = (!r.is_null()).then(|| CStr::from_ptr(r).to_string_lossy().into_owned());
+                libc::free(r as *mut c_void);
+                a
+            })
+            .collect();
+        unsafe { libc::free(resp as *mut c_void) };
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(answers, vec![some("alice"), some("secret"), None, some("123456")]);
+        assert_eq!(conv.messages.borrow().as_slice(), ["Hello"]);
+        drop(conv);
+        assert_eq!(prompts[1], PamPrompt::EchoOff("Password: ".to_string()));
+        assert_eq!(prompts.len(), 3);
+
+        // no answer fails the conversation.
+        let mut none = |_: PamPrompt| None;
+        let conv = Conv {
+            answer:   RefCell::new(&mut none),
+            messages: RefCell::new(Vec::new()),
+        };
+        let data = &conv as *const Conv as *mut c_void;
+        let rc = converse(ptrs.len() as c_int, ptrs.as_mut_ptr(), &mut resp, data);
+        assert_eq!(rc, PamReturnCode::CONV_ERR as c_int);
+
+        // nor does a panic get into PAM.
+        let mut panics = |_: PamPrompt| -> Option<String> { panic!("answer") };
+        let conv = Conv {
+            answer:   RefCell::new(&mut panics),
+            messages: RefCell::new(Vec::new()),
+        };
+        let data = &conv as *const Conv as *mut c_void;
+        let rc = converse(ptrs.len() as c_int, ptrs.as_mut_ptr(), &mut resp, data);
+        assert_eq!(rc, PamReturnCode::CONV_ERR as c_int);
+    }
+
+    #[test]
+    fn test_credentials() {
+        let mut answer = credentials("alice", "secret");
+        let echo_off = || PamPrompt::EchoOff("Password: ".to_string());
+        assert_eq!(answer(PamPrompt::EchoOn("login: ".to_string())).as_deref(), Some("alice"));
+        assert_eq!(answer(echo_off()).as_deref(), Some("secret"));
+        // the password is handed out once.
+        assert_eq!(answer(echo_off()), None);
+
+        let ptr = c_answer("secret".to_string()).unwrap();
+        assert_eq!(unsafe { CStr::from_ptr(ptr) }.to_str().unwrap(), "secret");
+        unsafe { libc::free(ptr as *mut c_void) };
+        assert!(c_answer("se\0cret".to_string()).is_none());
+        let mut buf = *b"secret";
+        zero(&mut buf);
+        assert_eq!(buf, [0; 6]);
+    }
+
+    #[test]
+    fn test_error_reason() {
+        let mut err = PamError::new(PamPhase::AcctMgmt, PamReturnCode::PERM_DENIED);
+        assert_eq!(
+            err.to_string(),
+            "pam_acct_mgmt: access denied by account policy (pam_time, pam_access) (PERM_DENIED)"
+        );
+        err = PamError::new(PamPhase::Authenticate, PamReturnCode::MAXTRIES);
+        err.messages.push("Account locked due to 5 failed logins".to_string());
+        assert_eq!(
+            err.to_string(),
+            "pam_authenticate: too many failed attempts, account locked (pam_tally2, pam_faillock) \
+             (MAXTRIES) [Account locked due to 5 failed logins]"
+        );
+    }
+}
@@ -0,0 +1,332 @@
+//! Path hooks.
+//!
+//! A path hook is called for every request on a location, with the
+//! method, the path, and the identity of the user. It can rewrite the
+//! path, or refuse the request by returning a status code.
+//!
+//! Order of things:
+//!
+//! - hooks run after authentication and the account lookup. Requests
+//!   without a user get an identity with an empty username.
+//! - hooks run before the request is passed to the webdav handler. That
+//!   means before the `methods` check of the location, and before the
+//!   path is resolved on disk (case-insensitive lookup, `hide-symlinks`).
+//!   A hook only sees the path as the client sent it, it cannot know
+//!   if it is a symlink, or if it exists at all.
+//! - the path is the decoded path below the route of the location,
+//!   starting with "/". A rewritten path stays below that route.
+//! - for MOVE and COPY, the hooks run on the Destination path as well.
+//!
+//! Hooks are compiled in: register them in `PathHooks::for_location`.
+//!
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use http::{Method, StatusCode};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use webdav_handler::davpath::DavPath;
+
+use crate::auth::Identity;
+use crate::config::{Location, WindowsNames};
+
+// Characters that are encoded in a rewritten path.
+const PATH_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// A path hook.
+pub type PathHook = Box<dyn Fn(&Method, &mut PathBuf, &Identity) -> Result<(), StatusCode> + Send + Sync>;
+
+/// The path hooks of one location.
+#[derive(Default)]
+pub struct PathHooks {
+    hooks: Vec<PathHook>,
+}
+
+impl PathHooks {
+    /// The hooks for a location, from its configuration.
+    pub fn for_location(location: &Location) -> PathHooks {
+        let mut hooks = PathHooks::default();
+        if !location.deny_upload.is_empty() || !location.allow_upload.is_empty() {
+            let chunked = location.chunked_uploads.as_deref();
+            hooks.register(deny_upload(&location.deny_upload, &location.allow_upload, chunked));
+        }
+        match location.windows_names {
+            Some(WindowsNames::Reject) => hooks.register(reject_windows_names()),
+            Some(WindowsNames::Escape) => hooks.register(escape_windows_names()),
+            Some(WindowsNames::Allow) | None => {},
+        }
+        hooks
+    }
+
+    /// Add a hook. Hooks run in the order they were registered.
+    pub fn register(&mut self, hook: PathHook) {
+        self.hooks.push(hook);
+    }
+
+    /// No hooks at all?
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Run the hooks on a (percent-encoded) path. The hooks get to see
+    /// the decoded and normalized path. Returns the rewritten path, encoded,
+    /// if it changed.
+    pub fn run(&self, method: &Method, path: &str, id: &Identity) -> Result<Option<String>, StatusCode> {
+        let davpath = DavPath::new(path).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let orig = davpath.as_pathbuf();
+        let mut newpath = orig.clone();
+        for hook in &self.hooks {
+            hook(method, &mut newpath, id)?;
+        }
+        if newpath == orig {
+            return Ok(None);
+        }
+        if !newpath.has_root() || newpath.components().any(|c| c == Component::ParentDir) {
+            error!("pathhook: {:?}: invalid rewritten path {:?}", orig, newpath);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        // Re-encode. Non-UTF8 bytes are sent out as-is by percent-encoding them.
+        let mut newpath = newpath
+            .as_os_str()
+            .as_bytes()
+            .split(|&b| b == b'/')
+            .map(|seg| match std::str::from_utf8(seg) {
+                Ok(s) => utf8_percent_encode(s, PATH_SET).to_string(),
+                Err(_) => seg.iter().map(|b| format!("%{:02X}", b)).collect(),
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        if davpath.is_collection() && !newpath.ends_with('/') {
+            newpath.push('/');
+        }
+        debug!("pathhook: rewrote {:?} to {}", orig, newpath);
+        Ok(Some(newpath))
+    }
+}
+
+// The extensions of the file name, lowercased: "a.tar.GZ" has "tar"
+// and "gz". The dot of a dotfile (".profile") does not start one.
+fn extensions(path: &Path) -> Vec<String> {
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name.trim_start_matches('.'),
+        None => return Vec::new(),
+    };
+    name.split('.').skip(1).filter(|e| !e.is_empty()).map(|e| e.to_lowercase()).collect()
+}
+
+/// Built-in hook: refuse uploads (PUT, and MOVE or COPY to or from)
+/// with 403 Forbidden, if the file name has one of the `deny` extensions
+/// anywhere ("shell.php.jpg" has "php"), or if `allow` is not empty and
+/// the last extension is not in it. For MOVE and COPY, names without an
+/// extension (usually directories) are allowed. Chunks of a chunked
+/// upload (in the `chunked` directory) are not checked, the file that
+/// they are put together into is.
+pub fn deny_upload(deny: &[String], allow: &[String], chunked: Option<&str>) -> PathHook {
+    let normalize = |list: &[String]| -> Vec<String> {
+        list.iter().map(|e| e.trim_start_matches('.').to_lowercase()).collect()
+    };
+    let (deny, allow) = (normalize(deny), normalize(allow));
+    let chunked = chunked.map(|c| Path::new("/").join(c));
+    Box::new(move |method, path, id| {
+        if !matches!(method.as_str(), "PUT" | "MOVE" | "COPY") {
+            return Ok(());
+        }
+        if chunked.as_ref().map(|dir| path.starts_with(dir)).unwrap_or(false) {
+            return Ok(());
+        }
+        let exts = extensions(path);
+        if let Some(ext) = exts.iter().find(|e| deny.contains(e)) {
+            debug!("pathhook: {}: {} {:?}: extension {} not allowed", id.username, method, path, ext);
+            return Err(StatusCode::FORBIDDEN);
+        }
+        let allowed = match exts.last() {
+            _ if allow.is_empty() => true,
+            Some(ext) => allow.contains(ext),
+            None => method != Method::PUT,
+        };
+        if !allowed {
+            debug!("pathhook: {}: {} {:?}: extension not allowed", id.username, method, path);
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(())
+    })
+}
+
+// Names that Windows reserves for devices, also with an extension ("nul.txt").
+const RESERVED_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9",
+    "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+// Is this a reserved name.
+fn is_reserved(name: &str) -> bool {
+    let base = name.split('.').next().unwrap_or("").trim_end_matches(' ');
+    RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(base))
+}
+
+// A name that Windows cannot have: a reserved name, or one that ends
+// in a dot or a space.
+fn bad_windows_name(name: &str) -> bool {
+    is_reserved(name) || name.ends_with('.') || name.ends_with(' ')
+}
+
+/// Built-in hook: refuse to create names that Windows cannot have (PUT,
+/// MKCOL, LOCK, and MOVE or COPY to or from) with 403 Forbidden: "con",
+/// "aux.txt" and the other reserved device names, and names that end in
+/// a dot or a space. Only the last part of the path is checked, the
+/// directories above it exist already.
+pub fn reject_windows_names() -> PathHook {
+    Box::new(|method, path, id| {
+        if !matches!(method.as_str(), "PUT" | "MKCOL" | "LOCK" | "MOVE" | "COPY") {
+            return Ok(());
+        }
+        let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+        if bad_windows_name(&name) {
+            debug!("pathhook: {}: {} {:?}: not a Windows file name", id.username, method, path);
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(())
+    })
+}
+
+// The escaped form of a name: "_" in front of a reserved name, and
+// trailing dots and spaces replaced by "_".
+fn escape_name(name: &str) -> String {
+    let trimmed = name.trim_end_matches(&['.', ' '][..]);
+    let mut escaped = String::new();
+    if is_reserved(name) {
+        escaped.push('_');
+    }
+    escaped.push_str(trimmed);
+    escaped.push_str(&"_".repeat(name.len() - trimmed.len()));
+    escaped
+}
+
+/// Built-in hook: store names that Windows cannot have under another
+/// name, for all methods, so that the client can keep using the name
+/// it asked for: "con" is stored as "_con", "notes." as "notes_".
+/// Directory listings show the stored names.
+pub fn escape_windows_names() -> PathHook {
+    Box::new(|_, path, _| {
+        if !path.iter().any(|c| bad_windows_name(&c.to_string_lossy())) {
+            return Ok(());
+        }
+        let mut newpath = PathBuf::new();
+        for c in path.components() {
+            match c {
+                Component::Normal(name) => newpath.push(escape_name(&name.to_string_lossy())),
+                other => newpath.push(other),
+            }
+        }
+        *path = newpath;
+        Ok(())
+    })
+}
+
+/// Is an upload with this Content-Type refused, by `deny-upload-types`.
+/// Not a path hook, but it goes with `deny_upload`. The types are
+/// compared without parameters, case-insensitively; "text/*" is all
+/// text types.
+pub fn denied_type(types: &[String], content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    let kind = mime.split('/').next().unwrap_or("");
+    types.iter().map(|t| t.to_ascii_lowercase()).any(|t| match t.strip_suffix("/*") {
+        Some(prefix) => prefix == kind,
+        None => t == mime,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pathhooks() {
+        let id = Identity::new("user");
+        let mut hooks = PathHooks::default();
+        hooks.register(deny_upload(&["php".to_string(), ".exe".to_string()], &[], None));
+        hooks.register(Box::new(|_, path, _| {
+            if let Ok(rest) = path.strip_prefix("/old") {
+                *path = PathBuf::from("/new dir").join(rest);
+            }
+            Ok(())
+        }));
+
+        let put = Method::PUT;
+        assert_eq!(hooks.run(&put, "/a/index.PHP", &id), Err(StatusCode::FORBIDDEN));
+        assert_eq!(hooks.run(&put, "/a/setup%2eexe", &id), Err(StatusCode::FORBIDDEN));
+        assert_eq!(hooks.run(&put, "/a/index.php/x/..", &id), Err(StatusCode::FORBIDDEN));
+        assert_eq!(hooks.run(&Method::GET, "/a/index.php", &id), Ok(None));
+        assert_eq!(hooks.run(&put, "/a/index.html", &id), Ok(None));
+        assert_eq!(
+            hooks.run(&put, "/old/f%C3%BCr.txt", &id),
+            Ok(Some("/new%20dir/f%C3%BCr.txt".to_string()))
+        );
+        assert_eq!(hooks.run(&put, "/old/dir/", &id), Ok(Some("/new%20dir/dir/".to_string())));
+
+        hooks.register(Box::new(|_, path, _| {
+            *path = PathBuf::from("/../etc");
+            Ok(())
+        }));
+        assert_eq!(hooks.run(&put, "/x", &id), Err(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    #[test]
+    fn test_deny_upload() {
+        let id = Identity::new("user");
+        let list = |l: &[&str]| l.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let mut hooks = PathHooks::default();
+        hooks.register(deny_upload(&list(&["php"]), &list(&["jpg", "png"]), Some("uploads")));
+        let (put, mv) = (Method::PUT, Method::from_bytes(b"MOVE").unwrap());
+
+        assert_eq!(hooks.run(&put, "/photo.JPG", &id), Ok(None));
+        assert_eq!(hooks.run(&put, "/shell.php.jpg", &id), Err(StatusCode::FORBIDDEN));
+        assert_eq!(hooks.run(&put, "/notes.txt", &id), Err(StatusCode::FORBIDDEN));
+        assert_eq!(hooks.run(&put, "/README", &id), Err(StatusCode::FORBIDDEN));
+        assert_eq!(hooks.run(&put, "/.jpg", &id), Err(StatusCode::FORBIDDEN));
+        // directories, and chunks of a chunked upload.
+        assert_eq!(hooks.run(&mv, "/dir", &id), Ok(None));
+        assert_eq!(hooks.run(&put, "/uploads/1234/1", &id), Ok(None));
+        assert_eq!(hooks.run(&mv, "/uploads/1234/.file", &id), Ok(None));
+
+        let types = list(&["text/html", "Application/X-PHP", "video/*"]);
+        assert!(denied_type(&types, "text/html; charset=utf-8"));
+        assert!(denied_type(&types, "application/x-php"));
+        assert!(denied_type(&types, "video/mp4"));
+        assert!(!denied_type(&types, "text/plain"));
+        assert!(!denied_type(&types, ""));
+    }
+
+    #[test]
+    fn test_windows_names() {
+        let id = Identity::new("user");
+        let mut reject = PathHooks::default();
+        reject.register(reject_windows_names());
+        let (put, mkcol) = (Method::PUT, Method::from_bytes(b"MKCOL").unwrap());
+
+        assert_eq!(reject.run(&put, "/con", &id), Err(StatusCode::FORBIDDEN));
+        assert_eq!(reject.run(&put, "/dir/Aux.txt", &id), Err(StatusCode::FORBIDDEN));
+        assert_eq!(reject.run(&put, "/notes.", &id), Err(StatusCode::FORBIDDEN));
+        assert_eq!(reject.run(&mkcol, "/dir%20/", &id), Err(StatusCode::FORBIDDEN));
+        assert_eq!(reject.run(&put, "/console.txt", &id), Ok(None));
+        assert_eq!(reject.run(&put, "/com10", &id), Ok(None));
+        assert_eq!(reject.run(&Method::GET, "/con", &id), Ok(None));
+
+        let mut escape = PathHooks::default();
+        escape.register(escape_windows_names());
+        assert_eq!(escape.run(&put, "/con", &id), Ok(Some("/_con".to_string())));
+        assert_eq!(escape.run(&Method::GET, "/NUL.txt", &id), Ok(Some("/_NUL.txt".to_string())));
+        assert_eq!(escape.run(&put, "/a./b%20.", &id), Ok(Some("/a_/b__".to_string())));
+        assert_eq!(escape.run(&mkcol, "/lpt1/", &id), Ok(Some("/_lpt1/".to_string())));
+        assert_eq!(escape.run(&put, "/dir/file.txt", &id), Ok(None));
+    }
+}
@@ -0,0 +1,113 @@
+//! What a location lets users change.
+//!
+//! `allow-create`, `allow-overwrite`, `allow-delete` and `allow-mkcol`
+//! narrow down what a location allows, after `methods` and `auth` have
+//! let a request through. A request that would do something that is
+//! not allowed gets "403 Forbidden":
+//!
+//! - PUT creates a file, or overwrites it if it exists
+//! - COPY and MOVE create or overwrite the destination, MOVE also
+//!   deletes the source
+//! - LOCK on a path that does not exist creates an empty file
+//! - DELETE deletes, MKCOL creates a collection
+//!
+//! Everything is allowed by default.
+//!
+use http::StatusCode;
+use webdav_handler::DavMethod;
+
+use crate::config::Location;
+
+/// The allow-* settings of a location.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    create:    bool,
+    overwrite: bool,
+    delete:    bool,
+    mkcol:     bool,
+}
+
+impl Policy {
+    pub fn new(location: &Location) -> Policy {
+        Policy {
+            create:    location.allow_create.unwrap_or(true),
+            overwrite: location.allow_overwrite.unwrap_or(true),
+            delete:    location.allow_delete.unwrap_or(true),
+            mkcol:     location.allow_mkcol.unwrap_or(true),
+        }
+    }
+
+    /// Is everything allowed.
+    pub fn allows_all(&self) -> bool {
+        self.create && self.overwrite && self.delete && self.mkcol
+    }
+
+    /// Does `method` need to know if its target exists: the request
+    /// path, or the destination of a COPY or MOVE.
+    pub fn needs_target(&self, method: DavMethod) -> bool {
+        matches!(method, DavMethod::Put | DavMethod::Copy | DavMethod::Move | DavMethod::Lock)
+    }
+
+    /// May `method` go ahead. `exists` tells if the target exists.
+    pub fn check(&self, method: DavMethod, exists: bool) -> Result<(), StatusCode> {
+        let write = if exists { self.overwrite } else { self.create };
+        let allowed = match method {
+            DavMethod::Put | DavMethod::Copy => write,
+            DavMethod::Move => write && self.delete,
+            DavMethod::Lock => exists || self.create,
+            DavMethod::Delete => self.delete,
+            DavMethod::MkCol => self.mkcol,
+            _ => true,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(create: bool, overwrite: bool, delete: bool, mkcol: bool) -> Policy {
+        Policy {
+            create,
+            overwrite,
+            delete,
+            mkcol,
+        }
+    }
+
+    #[test]
+    fn test_policy() {
+        let forbidden = Err(StatusCode::FORBIDDEN);
+        let all = policy(true, true, true, true);
+        assert!(all.allows_all());
+        for method in &[DavMethod::Put, DavMethod::Move, DavMethod::Delete, DavMethod::MkCol] {
+            assert_eq!(all.check(*method, false), Ok(()));
+            assert_eq!(all.check(*method, true), Ok(()));
+        }
+
+        // an inbox: new files only.
+        let inbox = policy(true, false, false, false);
+        assert!(!inbox.allows_all());
+        assert_eq!(inbox.check(DavMethod::Put, false), Ok(()));
+        assert_eq!(inbox.check(DavMethod::Put, true), forbidden);
+        assert_eq!(inbox.check(DavMethod::Copy, false), Ok(()));
+        assert_eq!(inbox.check(DavMethod::Move, false), forbidden);
+        assert_eq!(inbox.check(DavMethod::Delete, true), forbidden);
+        assert_eq!(inbox.check(DavMethod::MkCol, false), forbidden);
+        assert_eq!(inbox.check(DavMethod::Lock, false), Ok(()));
+        assert_eq!(inbox.check(DavMethod::Get, true), Ok(()));
+
+        // edit existing files only.
+        let edit = policy(false, true, false, false);
+        assert_eq!(edit.check(DavMethod::Put, true), Ok(()));
+        assert_eq!(edit.check(DavMethod::Put, false), forbidden);
+        assert_eq!(edit.check(DavMethod::Lock, false), forbidden);
+        assert_eq!(edit.check(DavMethod::Lock, true), Ok(()));
+        assert_eq!(edit.check(DavMethod::PropPatch, true), Ok(()));
+    }
+}
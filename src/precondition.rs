@@ -0,0 +1,226 @@
+//! If-Match and If-None-Match for PROPFIND and writes, If-Match for the
+//! directory index, and If-Range.
+//!
+//! The webdav handler only looks at the If: header for PROPFIND, not at
+//! If-Match or If-None-Match. Clients that sync a tree can save a lot of
+//! work by asking "send the properties of this resource, unless its ETag
+//! is still the one I have". So:
+//!
+//! - If-Match that does not match the ETag of the resource: 412
+//! - If-None-Match that matches, with Depth: 0: 304 Not Modified
+//!
+//! If-None-Match with a deeper Depth is ignored. The ETag of a collection
+//! changes when members are added or removed, but not when a member
+//! changes, so it says nothing about the properties of the members.
+//!
+//! On PUT, DELETE, COPY and MOVE, the "*" forms are for safe creates
+//! and updates: `If-None-Match: *` is "only if it does not exist yet",
+//! `If-Match: *` is "only if it exists". The webdav handler checks them
+//! against a resource that exists, but DELETE, COPY and MOVE of a missing
+//! resource get a 404 before that. So whether it exists is checked here
+//! first, and a failed check is a 412.
+//!
+//! If-Range is checked here too, before the webdav handler sees the
+//! request, so that it also applies to requests with more than one range
+//! (see `byteranges`). A client that resumes a download must get the
+//! whole file if it changed, not a range of the new version:
+//!
+//! - an ETag only matches with the strong comparison; a weak tag never
+//! - a date only matches if it is the exact Last-Modified time, and the
+//!   file was not modified in the current second. Otherwise the date is
+//!   not a strong validator: the file may change again within the same
+//!   second, and still have the same Last-Modified.
+//!
+//! The date may be in any of the three HTTP-date formats (RFC 7231,
+//! section 7.1.1.1), and is compared to the second, like the
+//! Last-Modified it came from: a file that was modified after it, or
+//! before it, is sent whole.
+//!
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use headers::Header;
+use http::header::{HeaderMap, IF_MATCH, IF_NONE_MATCH, IF_RANGE};
+use http::StatusCode;
+
+/// Are there any headers to check.
+pub fn has_conditions(headers: &HeaderMap) -> bool {
+    headers.contains_key(IF_MATCH) || headers.contains_key(IF_NONE_MATCH)
+}
+
+// Does the header match the (unquoted) ETag. If-Match uses the strong
+// comparison, If-None-Match the weak one.
+fn matches(headers: &HeaderMap, name: http::header::HeaderName, etag: Option<&str>, weak: bool) -> bool {
+    let values = headers.get_all(name).into_iter().filter_map(|v| v.to_str().ok());
+    let mut tags = values.flat_map(|v| v.split(',')).map(|t| t.trim());
+    tags.any(|tag| {
+        if tag == "*" {
+            return etag.is_some();
+        }
+        let tag = match tag.strip_prefix("W/") {
+            Some(_) if !weak => return false,
+            Some(t) => t,
+            None => tag,
+        };
+        match (tag.strip_prefix('"').and_then(|t| t.strip_suffix('"')), etag) {
+            (Some(t), Some(etag)) => t == etag,
+            _ => false,
+        }
+    })
+}
+
+/// Does If-Match, if there is one, match the (unquoted) ETag `etag`.
+/// A GET or HEAD of a collection is checked with this: the webdav
+/// handler checks it for files, but not for the directory index.
+pub fn if_match(headers: &HeaderMap, etag: Option<&str>) -> bool {
+    !headers.contains_key(IF_MATCH) || matches(headers, IF_MATCH, etag, false)
+}
+
+/// Check the preconditions of a PROPFIND. `etag` is the ETag of the
+/// resource (`None` if it does not exist). Returns the status to send
+/// instead of the multistatus response, if any.
+pub fn propfind(headers: &HeaderMap, etag: Option<&str>, depth0: bool) -> Option<StatusCode> {
+    if !if_match(headers, etag) {
+        return Some(StatusCode::PRECONDITION_FAILED);
+    }
+    if depth0 && matches(headers, IF_NONE_MATCH, etag, true) {
+        return Some(StatusCode::NOT_MODIFIED);
+    }
+    None
+}
+
+/// Check the preconditions of a PUT, DELETE, COPY or MOVE that do not
+/// need the ETag. `exists` is whether the request target exists (for
+/// COPY and MOVE the source, not the destination).
+///
+/// - If-Match on a resource that does not exist: 412, whatever the tags
+///   (the webdav handler returns 404 for DELETE, COPY and MOVE)
+/// - If-None-Match: * on a resource that exists: 412
+///
+/// The rest is left to the webdav handler.
+pub fn write(headers: &HeaderMap, exists: bool) -> Option<StatusCode> {
+    if headers.contains_key(IF_MATCH) && !exists {
+        return Some(StatusCode::PRECONDITION_FAILED);
+    }
+    let values = headers.get_all(IF_NONE_MATCH).into_iter().filter_map(|v| v.to_str().ok());
+    let star = values.flat_map(|v| v.split(',')).any(|t| t.trim() == "*");
+    if star && exists {
+        return Some(StatusCode::PRECONDITION_FAILED);
+    }
+    None
+}
+
+/// Check If-Range. `etag` and `modified` are the (unquoted) ETag and
+/// the modification time of the file. Returns whether the range can be
+/// sent, or the whole file has to be sent instead.
+pub fn if_range(
+    headers: &HeaderMap,
+    etag: Option<&str>,
+    modified: Option<SystemTime>,
+    now: SystemTime,
+) -> bool
+{
+    let value = match headers.get(IF_RANGE) {
+        Some(value) => value,
+        None => return true,
+    };
+    let tag = value.to_str().unwrap_or("").trim();
+    if tag.starts_with('"') || tag.starts_with("W/") {
+        return match (tag.strip_prefix('"').and_then(|t| t.strip_suffix('"')), etag) {
+            (Some(t), Some(etag)) => t == etag,
+            _ => false,
+        };
+    }
+    let date = match headers::Date::decode(&mut std::iter::once(value)) {
+        Ok(date) => SystemTime::from(date),
+        Err(_) => return false,
+    };
+    let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    match modified {
+        Some(modified) => secs(date) == secs(modified) && secs(now) > secs(modified),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_propfind_preconditions() {
+        let headers = |name, value: &str| {
+            let mut h = HeaderMap::new();
+            h.insert(name, value.parse().unwrap());
+            h
+        };
+        let etag = Some("5-abc");
+
+        let h = headers(IF_NONE_MATCH, r#""1-aaa", W/"5-abc""#);
+        assert_eq!(propfind(&h, etag, true), Some(StatusCode::NOT_MODIFIED));
+        assert_eq!(propfind(&h, etag, false), None);
+        assert_eq!(propfind(&h, Some("6-abd"), true), None);
+        assert_eq!(propfind(&headers(IF_NONE_MATCH, "*"), None, true), None);
+
+        assert_eq!(propfind(&headers(IF_MATCH, r#""5-abc""#), etag, false), None);
+        assert_eq!(propfind(&headers(IF_MATCH, "*"), etag, false), None);
+        let h = headers(IF_MATCH, r#"W/"5-abc""#);
+        assert_eq!(propfind(&h, etag, false), Some(StatusCode::PRECONDITION_FAILED));
+        let h = headers(IF_MATCH, "*");
+        assert_eq!(propfind(&h, None, false), Some(StatusCode::PRECONDITION_FAILED));
+
+        assert!(if_match(&HeaderMap::new(), etag));
+        assert!(if_match(&headers(IF_MATCH, r#""1-aaa", "5-abc""#), etag));
+        assert!(!if_match(&headers(IF_MATCH, r#""1-aaa""#), etag));
+    }
+
+    #[test]
+    fn test_write_preconditions() {
+        let headers = |name, value: &str| {
+            let mut h = HeaderMap::new();
+            h.insert(name, value.parse().unwrap());
+            h
+        };
+        let failed = Some(StatusCode::PRECONDITION_FAILED);
+        assert_eq!(write(&HeaderMap::new(), false), None);
+
+        let h = headers(IF_NONE_MATCH, "*");
+        assert_eq!(write(&h, true), failed);
+        assert_eq!(write(&h, false), None);
+        assert_eq!(write(&headers(IF_NONE_MATCH, r#""5-abc""#), true), None);
+
+        let h = headers(IF_MATCH, "*");
+        assert_eq!(write(&h, false), failed);
+        assert_eq!(write(&h, true), None);
+        assert_eq!(write(&headers(IF_MATCH, r#""5-abc""#), false), failed);
+    }
+
+    #[test]
+    fn test_if_range() {
+        let headers = |value: &str| {
+            let mut h = HeaderMap::new();
+            h.insert(IF_RANGE, value.parse().unwrap());
+            h
+        };
+        let etag = Some("5-abc");
+        let modified = UNIX_EPOCH + std::time::Duration::from_millis(784_111_777_500);
+        let now = modified + std::time::Duration::from_secs(60);
+
+        assert!(if_range(&HeaderMap::new(), etag, Some(modified), now));
+        assert!(if_range(&headers(r#""5-abc""#), etag, Some(modified), now));
+        assert!(!if_range(&headers(r#""6-abd""#), etag, Some(modified), now));
+        assert!(!if_range(&headers(r#"W/"5-abc""#), etag, Some(modified), now));
+
+        let date = headers("Sun, 06 Nov 1994 08:49:37 GMT");
+        assert!(if_range(&date, etag, Some(modified), now));
+        assert!(!if_range(&headers("Sun, 06 Nov 1994 08:49:36 GMT"), etag, Some(modified), now));
+        // modified in the current second: the date is not a strong validator.
+        assert!(!if_range(&date, etag, Some(modified), modified));
+        // the obsolete formats.
+        assert!(if_range(&headers("Sunday, 06-Nov-94 08:49:37 GMT"), etag, Some(modified), now));
+        assert!(if_range(&headers("Sun Nov  6 08:49:37 1994"), etag, Some(modified), now));
+        // a newer file.
+        let newer = modified + std::time::Duration::from_secs(1);
+        assert!(!if_range(&date, etag, Some(newer), now));
+        assert!(!if_range(&date, etag, None, now));
+        assert!(!if_range(&headers("yesterday"), etag, Some(modified), now));
+    }
+}
@@ -0,0 +1,175 @@
+//! Cache for the PROPFIND probes of a location root.
+//!
+//! Many clients start every connection with a PROPFIND, Depth: 0, of the
+//! root of the mount, for a few standard properties, just to see that the
+//! server is there and what it is. On a busy server those add up, and
+//! they all get the same answer.
+//!
+//! With `probe-cache` in a location, the 207 response to such a probe is
+//! kept for that many seconds, and the same probe gets it again without
+//! going through the webdav handler. A probe is a PROPFIND with Depth: 0
+//! of the root of the location, that asks for (some of) the properties in
+//! `PROPS` by name, without conditional headers. Anything else, like
+//! allprop or other properties, goes the normal way.
+//!
+//! The key is the path, the user and the request body, so users never see
+//! each other's response. A method that changes things in the location
+//! empties the cache, before and after it runs. Changes made outside of
+//! the server, or through another location, are seen once the entry has
+//! expired.
+//!
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http::HeaderMap;
+use xmltree::Element;
+
+// Upper limit on the number of cached probes, per location.
+const MAX_ENTRIES: usize = 10_000;
+
+/// The properties that a probe may ask for.
+pub const PROPS: &[&str] = &[
+    "creationdate",
+    "displayname",
+    "getcontentlanguage",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "resourcetype",
+    "supportedlock",
+];
+
+/// What a probe is cached under.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    path: String,
+    user: Option<String>,
+    body: Bytes,
+}
+
+impl Key {
+    pub fn new(path: &str, user: Option<&str>, body: &[u8]) -> Key {
+        Key {
+            path: path.to_string(),
+            user: user.map(|u| u.to_string()),
+            body: Bytes::copy_from_slice(body),
+        }
+    }
+}
+
+// A cached response.
+struct Entry {
+    time:    Instant,
+    headers: HeaderMap,
+    body:    Bytes,
+}
+
+#[derive(Clone)]
+pub struct ProbeCache {
+    ttl:     Duration,
+    entries: Arc<Mutex<HashMap<Key, Entry>>>,
+}
+
+impl ProbeCache {
+    pub fn new(ttl: Duration) -> ProbeCache {
+        ProbeCache {
+            ttl,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The cached response to a probe, if it has not expired.
+    pub fn get(&self, key: &Key) -> Option<hyper::Response<hyper::Body>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(key).filter(|e| e.time.elapsed() < self.ttl)?;
+        let mut resp = hyper::Response::new(hyper::Body::from(entry.body.clone()));
+        *resp.status_mut() = http::StatusCode::MULTI_STATUS;
+        *resp.headers_mut() = entry.headers.clone();
+        resp.headers_mut().insert("content-length", entry.body.len().into());
+        Some(resp)
+    }
+
+    /// Keep the response to a probe.
+    pub fn insert(&self, key: Key, headers: &HeaderMap, body: Bytes) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            let ttl = self.ttl;
+            entries.retain(|_, e| e.time.elapsed() < ttl);
+            if entries.len() >= MAX_ENTRIES {
+                entries.clear();
+            }
+        }
+        let mut headers = headers.clone();
+        headers.remove("content-length");
+        let entry = Entry {
+            time: Instant::now(),
+            headers,
+            body,
+        };
+        entries.insert(key, entry);
+    }
+
+    /// Forget everything, something in the location changed. Returns the
+    /// number of entries there were.
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let n = entries.len();
+        entries.clear();
+        n
+    }
+}
+
+/// Is the body of a PROPFIND the body of a probe.
+pub fn is_probe(body: &[u8]) -> bool {
+    let tree = match Element::parse(body) {
+        Ok(tree) => tree,
+        Err(_) => return false,
+    };
+    let dav = |e: &Element| e.namespace.as_deref() == Some("DAV:");
+    let elements = |e: &Element| {
+        e.children.iter().filter_map(|n| n.as_element()).cloned().collect::<Vec<_>>()
+    };
+    match elements(&tree).as_slice() {
+        [prop] if dav(&tree) && tree.name == "propfind" && dav(prop) && prop.name == "prop" => {
+            let props = elements(prop);
+            !props.is_empty() && props.iter().all(|p| dav(p) && PROPS.contains(&p.name.as_str()))
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_cache() {
+        let probe = br#"<propfind xmlns="DAV:"><prop><resourcetype/><getetag/></prop></propfind>"#;
+        assert!(is_probe(probe));
+        assert!(is_probe(br#"<D:propfind xmlns:D="DAV:"><D:prop><D:displayname/></D:prop></D:propfind>"#));
+        assert!(!is_probe(br#"<propfind xmlns="DAV:"><allprop/></propfind>"#));
+        assert!(!is_probe(br#"<propfind xmlns="DAV:"><prop><lockdiscovery/></prop></propfind>"#));
+        assert!(!is_probe(br#"<propfind xmlns="DAV:"><prop xmlns:X="urn:x"><X:getetag/></prop></propfind>"#));
+        assert!(!is_probe(b""));
+
+        let cache = ProbeCache::new(Duration::from_millis(50));
+        let key = |user| Key::new("/", Some(user), probe);
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/xml".parse().unwrap());
+        headers.insert("content-length", "1".parse().unwrap());
+        cache.insert(key("alice"), &headers, Bytes::from("<multistatus/>"));
+        let resp = cache.get(&key("alice")).unwrap();
+        assert_eq!(resp.status(), http::StatusCode::MULTI_STATUS);
+        assert_eq!(resp.headers()["content-length"], "14");
+        assert_eq!(resp.headers()["content-type"], "application/xml");
+        assert!(cache.get(&key("bob")).is_none());
+        cache.clear();
+        assert!(cache.get(&key("alice")).is_none());
+        cache.insert(key("alice"), &headers, Bytes::from("<multistatus/>"));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.get(&key("alice")).is_none());
+    }
+}
@@ -0,0 +1,136 @@
+//! Read-ahead for GET.
+//!
+//! The webdav handler reads a file one chunk at a time, when hyper asks
+//! for the next chunk of the body, which it does after the previous one
+//! has been written to the socket. On a local disk that costs nothing,
+//! but on storage with a high latency (a network filesystem) every chunk
+//! waits for the storage and then for the network, never for both at
+//! the same time.
+//!
+//! With `read-ahead` in a location, `read_ahead` has a task of its own
+//! read the body, while the response sends what was read before. The task
+//! stops when `read-ahead-window` bytes have been read but not sent yet,
+//! and goes on when the response has sent some of them. The chunks that
+//! are read ahead count against the memory budget. If the client goes
+//! away, the task stops reading.
+//!
+use std::sync::Arc;
+
+use bytes::Bytes;
+use hyper::body::HttpBody;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+
+use crate::limits::{MemoryBudget, Reservation};
+
+/// Default for `read-ahead-window` (bytes).
+pub const DEFAULT_WINDOW: usize = 65536;
+
+// A chunk that was read ahead, and what it holds of the window and the budget.
+type Chunk = (Result<Bytes, hyper::Error>, Option<(OwnedSemaphorePermit, Reservation)>);
+
+/// Read `body` ahead in a task of its own, up to `window` bytes.
+pub fn read_ahead(mut body: hyper::Body, window: usize, memory: &MemoryBudget) -> hyper::Body {
+    let window = window.clamp(1, u32::MAX as usize) as u32;
+    let permits = Arc::new(Semaphore::new(window as usize));
+    let (tx, rx) = mpsc::unbounded_channel::<Chunk>();
+    let memory = memory.clone();
+    tokio::spawn(async move {
+        while let Some(chunk) = body.data().await {
+            let held = match chunk {
+                Ok(ref data) => {
+                    let n = (data.len() as u32).min(window);
+                    let permit = match permits.clone().acquire_many_owned(n).await {
+                        Ok(permit) => permit,
+                        Err(_) => break,
+                    };
+                    Some((permit, memory.reserve(data.len()).await))
+                },
+                Err(_) => None,
+            };
+            if tx.send((chunk, held)).is_err() {
+                // the response is gone.
+                break;
+            }
+        }
+    });
+
+    // what a chunk holds is given back when the next one is asked for,
+    // that is when hyper has written it.
+    let stream = futures::stream::unfold((rx, None), |(mut rx, held)| {
+        async move {
+            drop(held);
+            let (chunk, held) = rx.recv().await?;
+            Some((chunk, (rx, held)))
+        }
+    });
+    hyper::Body::wrap_stream(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    // A body of `n` chunks of `size` bytes, every chunk takes `latency` to read.
+    fn slow_body(n: usize, size: usize, latency: Duration) -> hyper::Body {
+        let stream = futures::stream::unfold(0, move |i| {
+            async move {
+                if i == n {
+                    return None;
+                }
+                tokio::time::sleep(latency).await;
+                Some((Ok::<_, std::io::Error>(vec![b'x'; size]), i + 1))
+            }
+        });
+        hyper::Body::wrap_stream(stream)
+    }
+
+    // Receive `body` like a client behind a link with `latency` per chunk.
+    async fn download(mut body: hyper::Body, latency: Duration) -> (usize, Duration) {
+        let start = Instant::now();
+        let mut len = 0;
+        while let Some(chunk) = body.data().await {
+            len += chunk.unwrap().len();
+            tokio::time::sleep(latency).await;
+        }
+        (len, start.elapsed())
+    }
+
+    #[tokio::test]
+    async fn test_read_ahead() {
+        let memory = MemoryBudget::new(None);
+        let body = read_ahead(hyper::Body::from("data"), 1, &memory);
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "data");
+        assert_eq!(memory.used(), 0);
+
+        // a window of 3 chunks: the one that is being sent, and two more.
+        let memory = MemoryBudget::new(None);
+        let mut body = read_ahead(slow_body(10, 1000, Duration::from_millis(0)), 3000, &memory);
+        assert_eq!(body.data().await.unwrap().unwrap().len(), 1000);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(memory.used(), 3000);
+        drop(body);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(memory.used(), 0);
+    }
+
+    // Download throughput from storage with 5 ms per chunk, to a client with
+    // 5 ms per chunk: without read-ahead they add up, with it they overlap.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bench_read_ahead() {
+        let (n, size, latency) = (40, 65536, Duration::from_millis(5));
+        let memory = MemoryBudget::new(None);
+        let (len, serial) = download(slow_body(n, size, latency), latency).await;
+        assert_eq!(len, n * size);
+        let body = read_ahead(slow_body(n, size, latency), 4 * size, &memory);
+        let (len, overlapped) = download(body, latency).await;
+        assert_eq!(len, n * size);
+        let mb_per_sec = |d: Duration| (n * size) as f64 / d.as_secs_f64() / 1e6;
+        println!(
+            "read-ahead: off {:.1} MB/s, on {:.1} MB/s",
+            mb_per_sec(serial),
+            mb_per_sec(overlapped)
+        );
+        assert!(overlapped.as_secs_f64() < serial.as_secs_f64() * 0.8);
+    }
+}
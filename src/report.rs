@@ -0,0 +1,109 @@
+//! REPORT method (RFC 3253).
+//!
+//! The webdav handler does not know about REPORT, so we handle it
+//! here. The body of the request is parsed, and the top-level element
+//! (the report type) is looked up in a table of report handlers.
+//! Reports that nobody registered get a `403 Forbidden` with a
+//! `DAV:supported-report` precondition error.
+//!
+use std::collections::HashMap;
+
+use http::StatusCode;
+use xmltree::Element;
+
+/// The request, as passed to a report handler.
+#[allow(dead_code)]
+pub struct ReportRequest<'a> {
+    /// Request path (not stripped of the location prefix).
+    pub path:  &'a str,
+    /// Value of the Depth: header, if any.
+    pub depth: Option<&'a str>,
+    /// The parsed body.
+    pub body:  &'a Element,
+}
+
+/// A report handler returns a status and a XML body.
+pub trait ReportHandler: Send + Sync {
+    fn report(&self, req: &ReportRequest) -> Result<(StatusCode, String), StatusCode>;
+}
+
+/// Table of report handlers, keyed by (namespace, name) of the report type.
+#[derive(Default)]
+pub struct Reports {
+    handlers: HashMap<(String, String), Box<dyn ReportHandler>>,
+}
+
+impl Reports {
+    /// Create an empty table.
+    pub fn new() -> Reports {
+        Reports::default()
+    }
+
+    /// Register a handler for a report type.
+    #[allow(dead_code)]
+    pub fn register(&mut self, ns: &str, name: &str, handler: Box<dyn ReportHandler>) {
+        self.handlers.insert((ns.to_string(), name.to_string()), handler);
+    }
+
+    /// Run the report. Returns status and XML body.
+    pub fn handle(&self, path: &str, depth: Option<&str>, body: &[u8]) -> (StatusCode, String) {
+        let body = match Element::parse(body) {
+            Ok(body) => body,
+            Err(_) => return (StatusCode::BAD_REQUEST, error_body(None)),
+        };
+        let key = (body.namespace.clone().unwrap_or_default(), body.name.clone());
+        let handler = match self.handlers.get(&key) {
+            Some(h) => h,
+            None => {
+                debug!("report: unsupported report {{{}}}{}", key.0, key.1);
+                return (StatusCode::FORBIDDEN, error_body(Some("supported-report")));
+            },
+        };
+        let req = ReportRequest { path, depth, body: &body };
+        match handler.report(&req) {
+            Ok(res) => res,
+            Err(status) => (status, error_body(None)),
+        }
+    }
+}
+
+/// DAV:error body, with an optional precondition element.
+pub fn error_body(precondition: Option<&str>) -> String {
+    let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"DAV:\">");
+    if let Some(p) = precondition {
+        body.push_str(&format!("<D:{}/>", p));
+    }
+    body.push_str("</D:error>\n");
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl ReportHandler for Echo {
+        fn report(&self, req: &ReportRequest) -> Result<(StatusCode, String), StatusCode> {
+            Ok((StatusCode::MULTI_STATUS, req.body.name.clone()))
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let mut reports = Reports::new();
+        let body = br#"<?xml version="1.0"?><D:version-tree xmlns:D="DAV:"/>"#;
+
+        let (status, text) = reports.handle("/", None, body);
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(text.contains("<D:supported-report/>"));
+
+        reports.register("DAV:", "version-tree", Box::new(Echo));
+        assert_eq!(
+            reports.handle("/", None, body),
+            (StatusCode::MULTI_STATUS, "version-tree".to_string())
+        );
+
+        assert_eq!(reports.handle("/", None, b"garbage").0, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,168 @@
+//! Request IDs.
+//!
+//! Every request gets an ID. It is sent back in the `X-Request-Id`
+//! response header, and it is in the log lines of the request, so that
+//! a failed login in the log can be matched with the request that the
+//! client (or the proxy in front of us) saw.
+//!
+//! A request from a trusted proxy (a loopback address, as for
+//! X-Forwarded-For) keeps the `X-Request-Id` that the proxy sent, if it
+//! is 1 to 128 letters, digits, and `-`, `_`, `.`, `:` or `@`. All
+//! other requests get a new ID, a UUID.
+//!
+//! The ID is a task-local of the request, and `Logger` puts it in front
+//! of everything that is logged while the request is handled, like
+//! "[ID] auth: ...". PAM runs in the task of the request, or with
+//! `[pam] timeout` in a blocking thread that is given the ID with
+//! `current` and `sync_scope`, so its messages have the ID too. Other
+//! work on other tasks or threads does not, the slow request log adds
+//! the ID itself.
+//!
+use std::future::Future;
+
+use http::HeaderMap;
+
+/// The request and response header.
+pub const HEADER: &str = "x-request-id";
+
+// Longest ID from a proxy that we keep.
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// Does an ID from a proxy look sane.
+fn valid(id: &str) -> bool {
+    let ok = |c: char| c.is_ascii_alphanumeric() || "-_.:@".contains(c);
+    !id.is_empty() && id.len() <= MAX_LEN && id.chars().all(ok)
+}
+
+/// The ID of a request.
+pub fn for_request(headers: &HeaderMap, trusted: bool) -> String {
+    let from_proxy = headers.get(HEADER).and_then(|v| v.to_str().ok()).filter(|id| valid(id));
+    match from_proxy {
+        Some(id) if trusted => id.to_string(),
+        _ => uuid::Uuid::new_v4().to_simple().to_string(),
+    }
+}
+
+/// Run the request future `fut` with request ID `id`.
+pub async fn scope<F: Future>(id: String, fut: F) -> F::Output {
+    REQUEST_ID.scope(id, fut).await
+}
+
+/// The ID of the request that is being handled, if any.
+#[cfg(feature = "pam")]
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Run `f` with request ID `id`, for work that is handed to another
+/// thread. The ID comes from `current`.
+#[cfg(feature = "pam")]
+pub fn sync_scope<F, R>(id: Option<String>, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    match id {
+        Some(id) => REQUEST_ID.sync_scope(id, f),
+        None => f(),
+    }
+}
+
+/// A logger that puts the request ID in front of the messages.
+pub struct Logger<L>(pub L);
+
+impl<L: log::Log> log::Log for Logger<L> {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.0.enabled(record.metadata()) {
+            return;
+        }
+        let res = REQUEST_ID.try_with(|id| {
+            // format_args!() only lives until the end of the statement.
+            self.0.log(
+                &log::Record::builder()
+                    .metadata(record.metadata().clone())
+                    .args(format_args!("[{}] {}", id, record.args()))
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            );
+        });
+        if res.is_err() {
+            self.0.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+    use std::sync::{Mutex, Once};
+
+    lazy_static! {
+        static ref LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    }
+
+    struct Capture;
+
+    impl log::Log for Capture {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Info || metadata.target() == crate::bodylog::TARGET
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGGED.lock().unwrap().push(format!("{}: {}", record.target(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Keep the info messages (and the debug messages of `bodylog`) that
+    /// are logged from now on, for `logged`.
+    pub fn capture_log() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_boxed_logger(Box::new(Logger(Capture))).unwrap();
+            log::set_max_level(log::LevelFilter::Debug);
+        });
+    }
+
+    /// The captured messages that contain `text`.
+    pub fn logged(text: &str) -> Vec<String> {
+        let logged = LOGGED.lock().unwrap();
+        logged.iter().filter(|m| m.contains(text)).cloned().collect()
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let mut headers = HeaderMap::new();
+        let id = for_request(&headers, true);
+        assert_eq!(id.len(), 32);
+        assert_ne!(for_request(&headers, true), id);
+
+        headers.insert(HEADER, "proxy-1234.5".parse().unwrap());
+        assert_eq!(for_request(&headers, true), "proxy-1234.5");
+        assert_ne!(for_request(&headers, false), "proxy-1234.5");
+        headers.insert(HEADER, "bad id".parse().unwrap());
+        assert_ne!(for_request(&headers, true), "bad id");
+        headers.insert(HEADER, "x".repeat(MAX_LEN + 1).parse().unwrap());
+        assert_eq!(for_request(&headers, true).len(), 32);
+
+        capture_log();
+        scope("test-request-id".to_string(), async { info!("inside") }).await;
+        let logged = logged("test-request-id");
+        assert_eq!(logged, vec!["webdav_server::requestid::tests: [test-request-id] inside"]);
+    }
+}
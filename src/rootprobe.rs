@@ -0,0 +1,106 @@
+//! Is the directory of a location there.
+//!
+//! When the filesystem behind a location goes away (an NFS server that is
+//! down, a mount that is gone), every request fails on its own, with a
+//! 404 or a 500 for each file, and clients think that their files are
+//! gone. With `root-probe-interval` in a location, the directory of the
+//! location is checked first, and while it is not there, every request
+//! gets "503 Service Unavailable" with a Retry-After of the interval.
+//!
+//! The directory is stat()ed at most once per interval, by the first
+//! request that comes in after that. A stat that fails, that is not of a
+//! directory, or that does not return within `STAT_TIMEOUT` (a hard NFS
+//! mount hangs) means down. Until a hanging stat returns, no new one is
+//! started, and the directory stays down.
+//!
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How long a stat of the directory may take.
+const STAT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+struct Root {
+    checked: Instant,
+    up:      bool,
+    // a stat is running.
+    probing: bool,
+}
+
+/// The state of the directories of one location.
+#[derive(Debug, Clone)]
+pub struct RootProbe {
+    interval: Duration,
+    roots:    Arc<Mutex<HashMap<PathBuf, Root>>>,
+}
+
+impl RootProbe {
+    pub fn new(interval: Duration) -> RootProbe {
+        RootProbe {
+            interval,
+            roots: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// For Retry-After.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Is `dir` there, as of the last check.
+    pub async fn available(&self, dir: &Path) -> bool {
+        {
+            let mut roots = self.roots.lock().unwrap();
+            if let Some(root) = roots.get_mut(dir) {
+                if root.probing || root.checked.elapsed() < self.interval {
+                    return root.up;
+                }
+                root.probing = true;
+            } else {
+                let root = Root {
+                    checked: Instant::now(),
+                    up:      true,
+                    probing: true,
+                };
+                roots.insert(dir.to_path_buf(), root);
+            }
+        }
+
+        // the probe itself records what it found, also after a timeout.
+        let (roots, path) = (self.roots.clone(), dir.to_path_buf());
+        let probe = tokio::task::spawn_blocking(move || {
+            let up = std::fs::metadata(&path).map(|m| m.is_dir()).unwrap_or(false);
+            if let Some(root) = roots.lock().unwrap().get_mut(&path) {
+                match (root.up, up) {
+                    (true, false) => warn!("root-probe: {:?} is not available", path),
+                    (false, true) => info!("root-probe: {:?} is available again", path),
+                    _ => {},
+                }
+                root.up = up;
+                root.checked = Instant::now();
+                root.probing = false;
+            }
+            up
+        });
+        match tokio::time::timeout(STAT_TIMEOUT, probe).await {
+            Ok(Ok(up)) => up,
+            _ => {
+                let mut roots = self.roots.lock().unwrap();
+                match roots.get_mut(dir) {
+                    Some(root) if root.probing => {
+                        if root.up {
+                            warn!("root-probe: {:?}: stat did not return within {:?}", dir, STAT_TIMEOUT);
+                        }
+                        root.up = false;
+                        root.checked = Instant::now();
+                        false
+                    },
+                    Some(root) => root.up,
+                    None => false,
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Shared folders.
+//!
+//! A location with `shared-users` or `shared-groups` is one tree for a
+//! team: everyone logs in as themselves, and only the members get in.
+//! Others get "403 Forbidden", and without credentials it is a 401, even
+//! if `auth` says that reads are open.
+//!
+//! A user is a member if they are in `shared-users`, or in one of the
+//! `shared-groups`: listed as a member in the group database, or with the
+//! group as their primary or (with `supplementary-groups`) supplementary
+//! group, if acct-type is set. Groups are looked up on every request, so
+//! changes to the group database apply right away.
+//!
+//! A group that does not exist has no members. A lookup that fails
+//! otherwise (a networked NSS backend that does not answer) is retried
+//! as `[unix] nss-retries` and `nss-retry-delay` say. If it still fails,
+//! and none of the other groups lets the user in, the request gets
+//! "503 Service Unavailable" instead of a "403 Forbidden" that the user
+//! cannot do anything about.
+//!
+//! With `setuid` and `shared-owner`, the files are read and written as
+//! that account instead of the user who is logged in, so that everything
+//! in the tree belongs to the team account. Every request that changes
+//! something is logged to the audit log (log target
+//! "webdav_server::audit"), with the user who made it.
+//!
+use std::io;
+
+use tokio::task::block_in_place;
+
+use crate::auth::Identity;
+use crate::config::Location;
+use crate::unixuser::{self, Group, Retry};
+
+/// Is this location a shared folder.
+pub fn is_shared(location: &Location) -> bool {
+    !location.shared_users.is_empty() || !location.shared_groups.is_empty()
+}
+
+/// Is `id` a member of the shared folder at `location`. An error means
+/// that a group could not be looked up.
+pub async fn is_member(location: &Location, id: &Identity, retry: &Retry) -> io::Result<bool> {
+    is_member_with(location, id, retry, |name| block_in_place(|| Group::by_name(name))).await
+}
+
+// is_member, with the groups from `lookup`.
+async fn is_member_with<F>(
+    location: &Location,
+    id: &Identity,
+    retry: &Retry,
+    mut lookup: F,
+) -> io::Result<bool>
+where
+    F: FnMut(&str) -> io::Result<Group>,
+{
+    if location.shared_users.contains(&id.username) {
+        return Ok(true);
+    }
+    let mut failed = None;
+    for name in &location.shared_groups {
+        match retry.run(name, || lookup(name)).await {
+            Ok(group) if in_group(&group, id) => return Ok(true),
+            Ok(_) => {},
+            Err(e) if unixuser::is_not_found(&e) => debug!("shared: group {}: {}", name, e),
+            Err(e) => {
+                error!("shared: group {}: {}", name, e);
+                failed = Some(e);
+            },
+        }
+    }
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(false),
+    }
+}
+
+// Is `id` in the group.
+fn in_group(group: &Group, id: &Identity) -> bool {
+    group.members.contains(&id.username) ||
+        id.gid == Some(group.gid) ||
+        id.groups.contains(&group.gid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_group() {
+        let group = Group {
+            gid:     100,
+            members: vec!["alice".to_string()],
+        };
+        assert!(in_group(&group, &Identity::new("alice")));
+        assert!(!in_group(&group, &Identity::new("bob")));
+        let bob = Identity {
+            gid: Some(100),
+            ..Identity::new("bob")
+        };
+        assert!(in_group(&group, &bob));
+        let carol = Identity {
+            gid: Some(5),
+            groups: vec![7, 100],
+            ..Identity::new("carol")
+        };
+        assert!(in_group(&group, &carol));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_retry() {
+        let location: Location = toml::from_str(
+            "route = [ \"/*path\" ]\nhandler = \"filesystem\"\ndirectory = \"/srv\"\n\
+             shared-groups = [ \"staff\", \"missing\" ]\n",
+        )
+        .unwrap();
+        let retry = Retry {
+            retries: 2,
+            delay:   std::time::Duration::from_millis(1),
+        };
+        let alice = Identity::new("alice");
+        let staff = || {
+            Ok(Group {
+                gid:     100,
+                members: vec!["alice".to_string()],
+            })
+        };
+        let missing = || Err(io::Error::from_raw_os_error(libc::ENOENT));
+        let down = || Err(io::Error::from_raw_os_error(libc::EAGAIN));
+
+        // fails twice, then answers.
+        let mut tries = 0;
+        let flaky = |name: &str| {
+            tries += 1;
+            match name {
+                "staff" if tries > 2 => staff(),
+                "staff" => down(),
+                _ => missing(),
+            }
+        };
+        assert!(is_member_with(&location, &alice, &retry, flaky).await.unwrap());
+        assert_eq!(tries, 3);
+
+        // "not found" is not retried, and means "not a member".
+        let mut tries = 0;
+        let absent = |_: &str| {
+            tries += 1;
+            missing()
+        };
+        assert!(!is_member_with(&location, &alice, &retry, absent).await.unwrap());
+        assert_eq!(tries, 2);
+
+        // a backend that stays down is an error, after the retries.
+        let mut tries = 0;
+        let broken = |name: &str| {
+            tries += 1;
+            if name == "staff" { down() } else { missing() }
+        };
+        let err = is_member_with(&location, &alice, &retry, broken).await.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+        assert_eq!(tries, 4);
+
+        // but a group that does answer still lets the user in.
+        let other = |name: &str| if name == "staff" { down() } else { staff() };
+        assert!(is_member_with(&location, &alice, &retry, other).await.unwrap());
+    }
+}
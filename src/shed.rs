@@ -0,0 +1,129 @@
+//! Load shedding.
+//!
+//! The limits in `limits` each guard one resource, and each one makes
+//! requests wait or fail on its own: an upload waits for memory, a write
+//! waits for a slot, and a server that is near all its limits gets
+//! slower for everyone. With the `shed-*` thresholds in `[server]`, a new
+//! request is refused up front, with "503 Service Unavailable" and a
+//! Retry-After of `shed-retry-after` secs, while the server is over one
+//! of them:
+//!
+//! - `shed-connections`: open connections.
+//! - `shed-memory`: bytes in use of the `memory-budget` buffers.
+//! - `shed-writes`: writes in progress and waiting for a slot.
+//!
+//! Only new requests are refused, before they are authenticated or look
+//! at the disk. Requests that were let in run to the end, so the load goes
+//! down. The admin API, the public capabilities document (a health
+//! check), OPTIONS and UNLOCK (which frees a lock) are never refused.
+//! The refused requests are counted per threshold, in the admin API's
+//! /metrics and in `webdav_shed_requests_total{reason}`.
+//!
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Default for `shed-retry-after` (secs).
+pub const DEFAULT_RETRY_AFTER: u64 = 5;
+
+/// The load, right now.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Load {
+    pub connections: usize,
+    pub memory:      usize,
+    pub writes:      usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Shedder {
+    connections: Option<usize>,
+    memory:      Option<usize>,
+    writes:      Option<usize>,
+    retry_after: Duration,
+    shed:        Arc<Mutex<HashMap<&'static str, u64>>>,
+}
+
+impl Shedder {
+    /// From the `shed-*` settings. Without thresholds, nothing is shed.
+    pub fn new(
+        connections: Option<usize>,
+        memory: Option<usize>,
+        writes: Option<usize>,
+        retry_after: Option<u64>,
+    ) -> Shedder
+    {
+        Shedder {
+            connections,
+            memory,
+            writes,
+            retry_after: Duration::from_secs(retry_after.unwrap_or(DEFAULT_RETRY_AFTER)),
+            shed: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Is there a threshold at all.
+    pub fn enabled(&self) -> bool {
+        self.connections.is_some() || self.memory.is_some() || self.writes.is_some()
+    }
+
+    /// For Retry-After.
+    pub fn retry_after(&self) -> Duration {
+        self.retry_after
+    }
+
+    /// Is a new request of `method` refused under `load`. Returns the
+    /// threshold that it is over, and counts it.
+    pub fn check(&self, method: &http::Method, load: &Load) -> Option<&'static str> {
+        if *method == http::Method::OPTIONS || method.as_str() == "UNLOCK" {
+            return None;
+        }
+        let over = |max: Option<usize>, value: usize| max.map(|max| value >= max).unwrap_or(false);
+        let reason = if over(self.connections, load.connections) {
+            "connections"
+        } else if over(self.memory, load.memory) {
+            "memory"
+        } else if over(self.writes, load.writes) {
+            "writes"
+        } else {
+            return None;
+        };
+        *self.shed.lock().unwrap().entry(reason).or_insert(0) += 1;
+        Some(reason)
+    }
+
+    /// Number of refused requests, per threshold.
+    pub fn shed(&self) -> HashMap<String, u64> {
+        let shed = self.shed.lock().unwrap();
+        shed.iter().map(|(k, &v)| (k.to_string(), v)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shedder() {
+        let get = http::Method::GET;
+        let load = |connections, memory, writes| {
+            Load {
+                connections,
+                memory,
+                writes,
+            }
+        };
+        let none = Shedder::new(None, None, None, None);
+        assert!(!none.enabled() && none.check(&get, &load(1000, 1 << 30, 1000)).is_none());
+
+        let shedder = Shedder::new(Some(100), Some(1000), Some(10), None);
+        assert_eq!(shedder.retry_after(), Duration::from_secs(DEFAULT_RETRY_AFTER));
+        assert_eq!(shedder.check(&get, &load(99, 999, 9)), None);
+        assert_eq!(shedder.check(&get, &load(100, 0, 0)), Some("connections"));
+        assert_eq!(shedder.check(&get, &load(0, 1000, 10)), Some("memory"));
+        assert_eq!(shedder.check(&get, &load(0, 0, 10)), Some("writes"));
+        assert_eq!(shedder.check(&http::Method::OPTIONS, &load(100, 1000, 10)), None);
+        assert_eq!(shedder.check(&"UNLOCK".parse().unwrap(), &load(100, 1000, 10)), None);
+        let shed = shedder.shed();
+        assert_eq!((shed["connections"], shed["memory"], shed["writes"]), (1, 1, 1));
+    }
+}
@@ -0,0 +1,185 @@
+//! Slow request log.
+//!
+//! A request that takes longer than `slow-request` seconds (from the
+//! moment it comes in until the last byte of the response has been sent)
+//! is logged, with a breakdown of where the time went:
+//!
+//! - auth: authenticating the user (PAM, htpasswd)
+//! - fs: the webdav handler, until the response headers are ready. That
+//!   is mostly filesystem access (a PROPFIND of a large directory)
+//! - send: sending the response body (a large GET to a slow client)
+//!
+//! and the number of bytes received and sent. The slow requests are
+//! counted per method, see `metrics`. The entry is logged when the
+//! response has been sent, after the request is done, so the request
+//! ID is added here and not by `requestid::Logger`.
+//!
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+
+use crate::metrics::Metrics;
+
+/// log target.
+pub const TARGET: &str = "webdav_server::slow";
+
+/// Default threshold, in seconds.
+pub const DEFAULT_THRESHOLD: u64 = 30;
+
+/// Where the time of a request went.
+#[derive(Debug, Clone)]
+pub struct Timing {
+    start:    Instant,
+    pub auth: Duration,
+    pub fs:   Duration,
+    bytes_in: Arc<AtomicU64>,
+}
+
+impl Timing {
+    pub fn new() -> Timing {
+        Timing {
+            start:    Instant::now(),
+            auth:     Duration::from_secs(0),
+            fs:       Duration::from_secs(0),
+            bytes_in: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Count the bytes of the request body.
+    pub fn count_request(&self, body: hyper::Body) -> hyper::Body {
+        let bytes_in = self.bytes_in.clone();
+        hyper::Body::wrap_stream(body.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                bytes_in.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+        }))
+    }
+
+    /// Wrap the response body. When it has been sent (or dropped), the
+    /// request is logged if it took longer than `threshold`.
+    pub fn finish(self, body: hyper::Body, request: Request, threshold: Duration) -> hyper::Body {
+        hyper::Body::wrap_stream(Tail {
+            body,
+            request,
+            threshold,
+            headers: self.start.elapsed(),
+            timing: self,
+            bytes_out: 0,
+        })
+    }
+}
+
+/// What is logged about a request.
+pub struct Request {
+    pub method:  String,
+    pub path:    String,
+    pub user:    Option<String>,
+    pub id:      String,
+    pub metrics: Metrics,
+}
+
+struct Tail {
+    body:      hyper::Body,
+    request:   Request,
+    threshold: Duration,
+    timing:    Timing,
+    // time until the response headers were ready.
+    headers:   Duration,
+    bytes_out: u64,
+}
+
+impl Tail {
+    fn message(&self, total: Duration) -> String {
+        format!(
+            concat!(
+                "[{}] slow request: {} {} user {} took {:.3}s (auth {:.3}s, fs {:.3}s, send {:.3}s), ",
+                "{} bytes in, {} bytes out"
+            ),
+            self.request.id,
+            self.request.method,
+            self.request.path,
+            self.request.user.as_deref().unwrap_or("-"),
+            total.as_secs_f64(),
+            self.timing.auth.as_secs_f64(),
+            self.timing.fs.as_secs_f64(),
+            total.checked_sub(self.headers).unwrap_or_default().as_secs_f64(),
+            self.timing.bytes_in.load(Ordering::Relaxed),
+            self.bytes_out,
+        )
+    }
+}
+
+impl Stream for Tail {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &res {
+            this.bytes_out += chunk.len() as u64;
+        }
+        res
+    }
+}
+
+impl Drop for Tail {
+    fn drop(&mut self) {
+        let total = self.timing.start.elapsed();
+        if total >= self.threshold {
+            warn!(target: TARGET, "{}", self.message(total));
+            self.request.metrics.slow_request(&self.request.method);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_slow_request() {
+        let metrics = Metrics::new();
+        let request = |method: &str| Request {
+            method:  method.to_string(),
+            path:    "/dir/".to_string(),
+            user:    Some("alice".to_string()),
+            id:      "1234".to_string(),
+            metrics: metrics.clone(),
+        };
+
+        // fast: not counted.
+        let body = Timing::new().finish(hyper::Body::from("data"), request("GET"), Duration::from_secs(60));
+        hyper::body::to_bytes(body).await.unwrap();
+        assert!(metrics.slow_requests().is_empty());
+
+        // slow: counted per method.
+        let timing = Timing::new();
+        hyper::body::to_bytes(timing.count_request(hyper::Body::from("request"))).await.unwrap();
+        let body = timing.finish(hyper::Body::from("response"), request("PROPFIND"), Duration::from_secs(0));
+        hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(metrics.slow_requests().get("PROPFIND"), Some(&1));
+
+        // the message.
+        let mut timing = Timing::new();
+        timing.auth = Duration::from_millis(1500);
+        timing.bytes_in.store(7, Ordering::Relaxed);
+        let tail = Tail {
+            body: hyper::Body::empty(),
+            request: request("PROPFIND"),
+            threshold: Duration::from_secs(3600),
+            timing,
+            headers: Duration::from_millis(500),
+            bytes_out: 8,
+        };
+        assert_eq!(
+            tail.message(Duration::from_secs(2)),
+            "[1234] slow request: PROPFIND /dir/ user alice took 2.000s \
+             (auth 1.500s, fs 0.000s, send 1.500s), 7 bytes in, 8 bytes out"
+        );
+    }
+}
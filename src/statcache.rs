@@ -0,0 +1,234 @@
+//! Stat cache.
+//!
+//! Caches file metadata and directory listings of a location for a
+//! short time. This helps a lot with repeated PROPFINDs on slow
+//! (networked) filesystems.
+//!
+//! Changes made through the server invalidate the cache entries of the
+//! path, its parent directory, and everything below it. Changes made
+//! outside of the server are only seen once the entries have expired.
+//!
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use webdav_handler::fs::{DavMetaData, FsResult};
+
+// Upper limit on the number of cached paths, per location.
+const MAX_ENTRIES: usize = 100_000;
+
+/// A directory listing: names and metadata of the entries.
+pub type DirList = Arc<Vec<(Vec<u8>, FsResult<Box<dyn DavMetaData>>)>>;
+
+/// What is cached for a path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// metadata()
+    Stat,
+    /// symlink_metadata()
+    Lstat,
+    /// read_dir(), entries have metadata()
+    ReadDir,
+    /// read_dir(), entries have symlink_metadata()
+    ReadDirLstat,
+}
+
+#[derive(Debug, Clone)]
+enum Data {
+    Meta(Box<dyn DavMetaData>),
+    Dir(DirList),
+}
+
+#[derive(Debug)]
+struct Entry {
+    uid:  u32,
+    kind: Kind,
+    time: Instant,
+    data: Data,
+}
+
+#[derive(Debug)]
+struct Inner {
+    ttl:    Duration,
+    map:    Mutex<BTreeMap<PathBuf, Vec<Entry>>>,
+    hits:   AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Stat cache of one location.
+#[derive(Debug, Clone)]
+pub struct StatCache {
+    inner:  Arc<Inner>,
+    lookup: bool,
+}
+
+/// Counters, for the admin API.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub hits:    u64,
+    pub misses:  u64,
+    pub entries: usize,
+}
+
+impl StatCache {
+    /// New cache, entries expire after `ttl`.
+    pub fn new(ttl: Duration) -> StatCache {
+        StatCache {
+            inner:  Arc::new(Inner {
+                ttl,
+                map:    Mutex::new(BTreeMap::new()),
+                hits:   AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            }),
+            lookup: true,
+        }
+    }
+
+    /// A handle that does not return cached data, but still invalidates.
+    /// Used for methods that change things, which should not act on stale data.
+    pub fn invalidate_only(mut self) -> StatCache {
+        self.lookup = false;
+        self
+    }
+
+    fn get(&self, path: &Path, uid: u32, kind: Kind) -> Option<Data> {
+        if !self.lookup {
+            return None;
+        }
+        let map = self.inner.map.lock().unwrap();
+        let data = map.get(path).and_then(|entries| {
+            entries
+                .iter()
+                .find(|e| e.uid == uid && e.kind == kind && e.time.elapsed() < self.inner.ttl)
+                .map(|e| e.data.clone())
+        });
+        let counter = if data.is_some() { &self.inner.hits } else { &self.inner.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        data
+    }
+
+    fn insert(&self, path: PathBuf, uid: u32, kind: Kind, data: Data) {
+        let mut map = self.inner.map.lock().unwrap();
+        if map.len() >= MAX_ENTRIES {
+            let ttl = self.inner.ttl;
+            map.retain(|_, entries| {
+                entries.retain(|e| e.time.elapsed() < ttl);
+                !entries.is_empty()
+            });
+            if map.len() >= MAX_ENTRIES {
+                map.clear();
+            }
+        }
+        let entries = map.entry(path).or_default();
+        entries.retain(|e| e.uid != uid || e.kind != kind);
+        entries.push(Entry {
+            uid,
+            kind,
+            time: Instant::now(),
+            data,
+        });
+    }
+
+    /// Cached metadata (`Kind::Stat` or `Kind::Lstat`).
+    pub fn get_meta(&self, path: &Path, uid: u32, kind: Kind) -> Option<Box<dyn DavMetaData>> {
+        match self.get(path, uid, kind)? {
+            Data::Meta(meta) => Some(meta),
+            Data::Dir(_) => None,
+        }
+    }
+
+    /// Cache metadata (`Kind::Stat` or `Kind::Lstat`).
+    pub fn insert_meta(&self, path: PathBuf, uid: u32, kind: Kind, meta: Box<dyn DavMetaData>) {
+        self.insert(path, uid, kind, Data::Meta(meta));
+    }
+
+    /// Cached directory listing (`Kind::ReadDir` or `Kind::ReadDirLstat`).
+    pub fn get_dir(&self, path: &Path, uid: u32, kind: Kind) -> Option<DirList> {
+        match self.get(path, uid, kind)? {
+            Data::Dir(list) => Some(list),
+            Data::Meta(_) => None,
+        }
+    }
+
+    /// Cache a directory listing. The metadata of the entries is cached as well.
+    pub fn insert_dir(&self, path: PathBuf, uid: u32, kind: Kind, list: DirList) {
+        let entry_kind = if kind == Kind::ReadDir { Kind::Stat } else { Kind::Lstat };
+        for (name, meta) in list.iter() {
+            if let Ok(meta) = meta {
+                self.insert_meta(path.join(OsStr::from_bytes(name)), uid, entry_kind, meta.clone());
+            }
+        }
+        self.insert(path, uid, kind, Data::Dir(list));
+    }
+
+    /// Forget about `path`, its parent, and everything below it.
+    pub fn invalidate(&self, path: &Path) {
+        let mut map = self.inner.map.lock().unwrap();
+        if let Some(parent) = path.parent() {
+            map.remove(parent);
+        }
+        let below: Vec<PathBuf> = map
+            .range(path.to_path_buf()..)
+            .take_while(|(p, _)| p.starts_with(path))
+            .map(|(p, _)| p.clone())
+            .collect();
+        for p in &below {
+            map.remove(p);
+        }
+    }
+
+    /// Forget everything. Returns the number of paths that were cached.
+    pub fn clear(&self) -> usize {
+        let mut map = self.inner.map.lock().unwrap();
+        let n = map.len();
+        map.clear();
+        n
+    }
+
+    /// Hit / miss counters.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            hits:    self.inner.hits.load(Ordering::Relaxed),
+            misses:  self.inner.misses.load(Ordering::Relaxed),
+            entries: self.inner.map.lock().unwrap().len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webdav_handler::fs::DavFileSystem;
+    use webdav_handler::memfs::MemFs;
+
+    #[tokio::test]
+    async fn test_statcache() {
+        let fs = MemFs::new();
+        let root = webdav_handler::davpath::DavPath::new("/").unwrap();
+        let meta: Box<dyn DavMetaData> = fs.metadata(&root).await.unwrap();
+
+        let cache = StatCache::new(Duration::from_secs(60));
+        let path = |p: &str| PathBuf::from(p);
+        cache.insert_meta(path("/a/b"), 0, Kind::Stat, meta.clone());
+        cache.insert_meta(path("/a/b/c"), 0, Kind::Stat, meta.clone());
+        cache.insert_meta(path("/a/bc"), 0, Kind::Stat, meta.clone());
+        cache.insert_meta(path("/a"), 0, Kind::Stat, meta.clone());
+
+        assert!(cache.get_meta(&path("/a/b"), 0, Kind::Stat).is_some());
+        assert!(cache.get_meta(&path("/a/b"), 0, Kind::Lstat).is_none());
+        assert!(cache.get_meta(&path("/a/b"), 1000, Kind::Stat).is_none());
+        assert!(cache.clone().invalidate_only().get_meta(&path("/a/b"), 0, Kind::Stat).is_none());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 4));
+
+        cache.invalidate(&path("/a/b"));
+        assert!(cache.get_meta(&path("/a"), 0, Kind::Stat).is_none());
+        assert!(cache.get_meta(&path("/a/b"), 0, Kind::Stat).is_none());
+        assert!(cache.get_meta(&path("/a/b/c"), 0, Kind::Stat).is_none());
+        assert!(cache.get_meta(&path("/a/bc"), 0, Kind::Stat).is_some());
+    }
+}
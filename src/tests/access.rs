@@ -0,0 +1,414 @@
+//! Authentication and access control.
+//!
+use super::*;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_write_without_credentials() {
+    let dir = tempdir();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+
+    // Reads do not need authentication.
+    let resp = server.route(request("GET", "/alice/", None), addr).await.unwrap();
+    assert_ne!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // A write does: 401 with a challenge.
+    let resp = server.route(request("PUT", "/alice/file", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(challenge(&resp), Some("Basic realm=\"Test\""));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_write_with_credentials() {
+    let dir = tempdir();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+
+    // Rejected: 401 with a challenge.
+    let req = request("PUT", "/alice/file", Some(("alice", "wrong")));
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(challenge(&resp).is_some());
+
+    // Valid, but not for this path: 403, no challenge.
+    let req = request("PUT", "/bob/file", Some(("alice", "secret")));
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(challenge(&resp).is_none());
+
+    let req = request("PUT", "/alice/file", Some(("alice", "secret")));
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_access_errors() {
+    let dir = tempdir();
+    std::fs::create_dir(dir.join("bob")).unwrap();
+    std::fs::write(dir.join("bob/file"), "data").unwrap();
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    // bob's file exists, but alice may not see it.
+    let accurate = server(&dir, "");
+    let resp = accurate.route(request("GET", "/bob/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = accurate.route(request("GET", "/alice/nope", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let opaque = server(&dir, "access-errors = \"opaque\"");
+    for method in &["GET", "HEAD", "PROPFIND", "DELETE"] {
+        let resp = opaque.route(request(method, "/bob/file", creds), addr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", method);
+    }
+    assert!(dir.join("bob/file").exists());
+    let resp = opaque.route(request("GET", "/alice/nope", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_client_cert() {
+    let dir = tempdir();
+    let server = server(&dir, "auth-type = \"tls-client-cert\"");
+    let addr = ADDR.parse().unwrap();
+    let put = |uri: &str, user: Option<&str>| {
+        let mut req = request("PUT", uri, None);
+        req.extensions_mut().insert(ConnAuth::with_client_cert(user.map(|u| u.to_string())));
+        req
+    };
+
+    let resp = server.route(put("/alice/file", Some("alice")), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let resp = server.route(put("/alice/file", Some("bob")), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // no certificate: no Basic challenge, that would not help.
+    let resp = server.route(put("/alice/file", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(challenge(&resp), None);
+    let resp = server.route(request("GET", "/alice/file", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // with client certificates required, no user means no connection.
+    let ca = dir.join("ca.pem");
+    let tls = format!("tls_client_ca = \"{}\"\ntls_client_auth = \"required\"", ca.display());
+    let server = server_with(&dir, &tls, "");
+    let alice = clientcert::tests::der(clientcert::tests::ALICE);
+    assert!(server.tls_conn_auth(Some(alice), addr).await.is_ok());
+    assert!(server.tls_conn_auth(Some(b"garbage".to_vec()), addr).await.is_err());
+    assert!(server.tls_conn_auth(None, addr).await.is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_allow_policy() {
+    let dir = tempdir();
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+    std::fs::write(dir.join("old"), "data").unwrap();
+
+    let no_create = server(&dir, "allow-create = false");
+    let resp = no_create.route(request("PUT", "/alice/new", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(!dir.join("new").exists());
+    let resp = no_create.route(request("PUT", "/alice/old", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    let no_overwrite = server(&dir, "allow-overwrite = false");
+    let resp = no_overwrite.route(request("PUT", "/alice/old", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = no_overwrite.route(request("PUT", "/alice/new", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let mut req = request("COPY", "/alice/new", creds);
+    req.headers_mut().insert("destination", "/alice/old".parse().unwrap());
+    assert_eq!(no_overwrite.route(req, addr).await.unwrap().status(), StatusCode::FORBIDDEN);
+
+    let no_delete = server(&dir, "allow-delete = false");
+    let resp = no_delete.route(request("DELETE", "/alice/new", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let mut req = request("MOVE", "/alice/new", creds);
+    req.headers_mut().insert("destination", "/alice/moved".parse().unwrap());
+    assert_eq!(no_delete.route(req, addr).await.unwrap().status(), StatusCode::FORBIDDEN);
+    assert!(dir.join("new").exists());
+    let resp = no_delete.route(request("MKCOL", "/alice/dir", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let no_mkcol = server(&dir, "allow-mkcol = false");
+    let resp = no_mkcol.route(request("MKCOL", "/alice/dir2", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(!dir.join("dir2").exists());
+    let resp = no_mkcol.route(request("DELETE", "/alice/new", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_auth_failure_delay() {
+    let dir = tempdir();
+    let server = server_with(&dir, "auth-failure-delay = 0.3", "");
+    async fn put(server: &Server, pass: &str) -> (StatusCode, Duration) {
+        let start = Instant::now();
+        let req = request("PUT", "/alice/file", Some(("alice", pass)));
+        let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+        (resp.status(), start.elapsed())
+    }
+
+    let (status, first) = put(&server, "wrong").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(first >= Duration::from_millis(300), "{:?}", first);
+    let (_, second) = put(&server, "wrong").await;
+    assert!(second >= Duration::from_millis(600), "{:?}", second);
+
+    // a successful login resets the delay.
+    let (status, ok) = put(&server, "secret").await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert!(ok < Duration::from_millis(300), "{:?}", ok);
+    let (_, again) = put(&server, "wrong").await;
+    assert!(again >= Duration::from_millis(300) && again < Duration::from_millis(600), "{:?}", again);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_shared_folder() {
+    crate::requestid::tests::capture_log();
+    let dir = tempdir();
+    let users: String = [("alice", "secret"), ("bob", "bobsecret"), ("carol", "carolsecret")]
+        .iter()
+        .map(|(user, pass)| format!("{}:{}\n", user, pwhash::sha512_crypt::hash(pass).unwrap()))
+        .collect();
+    std::fs::write(dir.join("htpasswd"), users).unwrap();
+    // one tree for everyone, at /team/.
+    let toml = format!(
+        r#"
+        [server]
+        [pam]
+        service = "other"
+        [accounts]
+        auth-type = "htpasswd.test"
+        [htpasswd.test]
+        htpasswd = "{}"
+        [[location]]
+        route = [ "/team/*path" ]
+        methods = [ "webdav-rw" ]
+        handler = "filesystem"
+        directory = "{}"
+        shared-users = [ "alice", "bob" ]
+        "#,
+        dir.join("htpasswd").display(),
+        dir.display(),
+    );
+    let mut config: config::Config = toml::from_str(&toml).unwrap();
+    config::build_routes("test", &mut config).unwrap();
+    let config = Arc::new(config);
+    let server = Server::new(config.clone(), auth::Auth::new(config).unwrap());
+    let addr = ADDR.parse().unwrap();
+    let alice = Some(("alice", "secret"));
+    let bob = Some(("bob", "bobsecret"));
+    let carol = Some(("carol", "carolsecret"));
+
+    let resp = server.route(request("PUT", "/team/by-alice", alice), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let resp = server.route(request("GET", "/team/by-alice", bob), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = server.route(request("PUT", "/team/by-bob", bob), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.join("by-alice").is_file() && dir.join("by-bob").is_file());
+
+    // reads need authentication too, and only members get in.
+    let resp = server.route(request("GET", "/team/by-alice", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = server.route(request("GET", "/team/by-alice", carol), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // the writes are in the audit log, with the real user.
+    let logged = crate::requestid::tests::logged("shared: ");
+    let logged = |text: &str| logged.iter().filter(|l| l.contains(text)).count();
+    assert_eq!(logged("shared: alice PUT /team/by-alice from 127.0.0.1: 201"), 1);
+    assert_eq!(logged("shared: bob PUT /team/by-bob from 127.0.0.1: 201"), 1);
+    assert_eq!(logged("GET /team/by-alice"), 0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_authenticated_extension() {
+    use futures::future::{BoxFuture, FutureExt};
+    use transcode::{TranscodeRequest, Transcoded};
+
+    // a hook that sends who asked, instead of the file.
+    struct Whoami;
+    impl TranscodeHook for Whoami {
+        fn transcode<'a>(
+            &'a self,
+            req: &'a TranscodeRequest<'a>,
+            _file: hyper::Body,
+        ) -> BoxFuture<'a, io::Result<Transcoded>>
+        {
+            let body = hyper::Body::from(req.user.unwrap_or("-").to_string());
+            futures::future::ready(Ok(Transcoded::Body("text/plain".to_string(), body))).boxed()
+        }
+    }
+
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let mut server = server(&dir, "");
+    server.transcoders = Arc::new(vec![Arc::new(Whoami) as Arc<dyn TranscodeHook>]);
+    let get = |creds| {
+        let server = server.clone();
+        async move {
+            let req = request("GET", "/alice/file", creds);
+            let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            hyper::body::to_bytes(resp.into_body()).await.unwrap()
+        }
+    };
+
+    // only there after a successful authentication.
+    assert_eq!(&get(Some(("alice", "secret"))).await[..], b"alice");
+    assert_eq!(&get(None).await[..], b"-");
+
+    let mut req = request("GET", "/", None);
+    assert!(auth::authenticated(&req).is_none());
+    req.extensions_mut().insert(auth::Authenticated(Identity::new("bob")));
+    assert_eq!(auth::authenticated(&req).unwrap().username, "bob");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_allow_deny_from() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let deny = server_with(&dir, "deny-from = [ \"192.0.2.0/24\" ]", "");
+    let allow = server_with(&dir, "allow-from = [ \"10.0.0.0/8\", \"192.0.2.7\" ]", "");
+    let client = |ip: &str| format!("{}:4321", ip).parse::<SocketAddr>().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    // refused before authentication: wrong credentials do not matter.
+    let req = request("GET", "/alice/file", Some(("alice", "x")));
+    let resp = deny.route(req, client("192.0.2.1")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(resp.headers()["connection"], "close");
+    let resp = deny.route(request("PUT", "/alice/new", creds), client("203.0.113.1")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let resp = allow.route(request("GET", "/alice/file", None), client("10.1.2.3")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = allow.route(request("GET", "/alice/file", None), client("192.0.2.8")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // through a proxy on localhost.
+    let mut req = request("GET", "/alice/file", None);
+    req.headers_mut().insert("x-forwarded-for", "192.0.2.7, 127.0.0.1".parse().unwrap());
+    let resp = allow.route(req, client("127.0.0.1")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let mut req = request("GET", "/alice/file", None);
+    req.headers_mut().insert("x-forwarded-for", "203.0.113.1".parse().unwrap());
+    let resp = allow.route(req, client("127.0.0.1")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_auth_failure_min_time() {
+    let dir = tempdir();
+    let server = server_with(&dir, "auth-failure-min-time = 0.3", "");
+    async fn put(server: &Server, user: &str, pass: &str) -> (StatusCode, Duration) {
+        let start = Instant::now();
+        let req = request("PUT", "/alice/file", Some((user, pass)));
+        let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+        (resp.status(), start.elapsed())
+    }
+
+    // an unknown user and a wrong password take as long.
+    let (status, unknown) = put(&server, "mallory", "x").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(unknown >= Duration::from_millis(300), "{:?}", unknown);
+    let (status, wrong) = put(&server, "alice", "wrong").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(wrong >= Duration::from_millis(300), "{:?}", wrong);
+    // and without escalation, the next one too.
+    let (_, again) = put(&server, "alice", "wrong").await;
+    assert!(again >= Duration::from_millis(300) && again < Duration::from_millis(600), "{:?}", again);
+
+    let (status, ok) = put(&server, "alice", "secret").await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert!(ok < Duration::from_millis(300), "{:?}", ok);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_home_directories() {
+    let dir = tempdir();
+    std::fs::create_dir_all(dir.join("a/dav")).unwrap();
+    let hash = pwhash::sha512_crypt::hash("secret").unwrap();
+    let htpasswd = format!("webdav-home-a:{}\nwebdav-home-b:{}\n", hash, hash);
+    std::fs::write(dir.join("htpasswd"), htpasswd).unwrap();
+    for user in ["a", "b"] {
+        let mut pwd = Identity::new(format!("webdav-home-{}", user));
+        pwd.home = Some(dir.join(user));
+        cache::cached::insert_unixuser(pwd);
+    }
+    let home_server = |create: bool| {
+        let toml = format!(
+            r#"
+            [server]
+            [accounts]
+            auth-type = "htpasswd.test"
+            acct-type = "unix"
+            [htpasswd.test]
+            htpasswd = "{}/htpasswd"
+            [[location]]
+            route = [ "/*path" ]
+            methods = [ "webdav-rw" ]
+            auth = "true"
+            handler = "filesystem"
+            directory = "~/dav"
+            create-home = {}
+            "#,
+            dir.display(),
+            create
+        );
+        let mut config: config::Config = toml::from_str(&toml).unwrap();
+        config::build_routes("test", &mut config).unwrap();
+        let config = Arc::new(config);
+        Server::new(config.clone(), auth::Auth::new(config).unwrap())
+    };
+    let a = Some(("webdav-home-a", "secret"));
+    let b = Some(("webdav-home-b", "secret"));
+    async fn put(server: &Server, creds: Option<(&str, &str)>) -> StatusCode {
+        let req = request("PUT", "/f.txt", creds);
+        server.route(req, ADDR.parse().unwrap()).await.unwrap().status()
+    }
+
+    // the home directory of b does not exist.
+    let server = home_server(false);
+    assert_eq!(put(&server, a).await, StatusCode::CREATED);
+    assert_eq!(put(&server, b).await, StatusCode::FORBIDDEN);
+    assert!(dir.join("a/dav/f.txt").exists() && !dir.join("b").exists());
+
+    let server = home_server(true);
+    assert_eq!(put(&server, b).await, StatusCode::CREATED);
+    assert!(dir.join("b/dav/f.txt").exists());
+    std::fs::write(dir.join("b/dav/f.txt"), "bob").unwrap();
+    let resp = server.route(request("GET", "/f.txt", a), ADDR.parse().unwrap()).await.unwrap();
+    assert_eq!(&hyper::body::to_bytes(resp.into_body()).await.unwrap()[..], b"data");
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(dir.join("b")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,374 @@
+//! LOCK, UNLOCK, and writes to locked resources.
+//!
+use super::*;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_lock_refresh() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    std::fs::write(dir.join("other"), "data").unwrap();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+    let lockinfo = r#"<?xml version="1.0" encoding="utf-8"?>
+        <D:lockinfo xmlns:D="DAV:">
+          <D:lockscope><D:exclusive/></D:lockscope>
+          <D:locktype><D:write/></D:locktype>
+        </D:lockinfo>"#;
+
+    let mut req = request("LOCK", "/alice/file", creds);
+    req.headers_mut().insert("timeout", "Second-60".parse().unwrap());
+    req.headers_mut().insert("content-length", lockinfo.len().into());
+    *req.body_mut() = hyper::Body::from(lockinfo);
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let token = resp.headers()["lock-token"].to_str().unwrap().to_string();
+
+    let refresh = |token: &str| {
+        let mut req = request("LOCK", "/alice/file", creds);
+        req.headers_mut().insert("timeout", "Second-300".parse().unwrap());
+        req.headers_mut().insert("if", format!("({})", token).parse().unwrap());
+        server.route(req, addr)
+    };
+
+    // the same lock, with the new timeout.
+    let resp = refresh(&token).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body = String::from_utf8_lossy(&body);
+    assert!(body.contains(token.trim_matches(|c| c == '<' || c == '>')), "{}", body);
+    assert!(body.contains("Second-300"), "{}", body);
+    assert_eq!(server.locks.list().len(), 1);
+
+    // a lock of someone else.
+    let ls = server.locks.locksystem(dir.to_str().unwrap());
+    let path = DavPath::new("/other").unwrap();
+    let lock = ls.lock(&path, Some("bob"), None, None, false, false).unwrap();
+    let resp = refresh(&format!("<{}>", lock.token)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_if_lock_token() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    std::fs::write(dir.join("other"), "data").unwrap();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    let lockinfo = r#"<?xml version="1.0" encoding="utf-8"?>
+        <D:lockinfo xmlns:D="DAV:">
+          <D:lockscope><D:exclusive/></D:lockscope>
+          <D:locktype><D:write/></D:locktype>
+        </D:lockinfo>"#;
+    let lock = |path: &str| {
+        let mut req = request("LOCK", path, creds);
+        req.headers_mut().insert("content-length", lockinfo.len().into());
+        *req.body_mut() = hyper::Body::from(lockinfo);
+        req
+    };
+    let resp = server.route(lock("/alice/file"), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let token = resp.headers().get("lock-token").unwrap().to_str().unwrap().to_string();
+    assert!(token.starts_with("<urn:uuid:"), "{}", token);
+    let if_token = format!("({})", token);
+
+    // Without the token: 423 Locked, for all of PUT, COPY onto, MOVE and DELETE.
+    let resp = server.route(request("PUT", "/alice/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::LOCKED);
+    let mut req = request("COPY", "/alice/other", creds);
+    req.headers_mut().insert("destination", "/alice/file".parse().unwrap());
+    req.headers_mut().insert("overwrite", "T".parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::LOCKED);
+    let mut req = request("MOVE", "/alice/file", creds);
+    req.headers_mut().insert("destination", "/alice/moved".parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::LOCKED);
+    let resp = server.route(request("DELETE", "/alice/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::LOCKED);
+
+    // A token that is not ours does not help: the If header is false, 412.
+    let mut req = request("DELETE", "/alice/file", creds);
+    let wrong = "(<urn:uuid:00000000-0000-0000-0000-000000000000>)";
+    req.headers_mut().insert("if", wrong.parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+    assert!(dir.join("file").exists());
+
+    // With the token it works.
+    let mut req = request("PUT", "/alice/file", creds);
+    req.headers_mut().insert("if", if_token.parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let mut req = request("DELETE", "/alice/file", creds);
+    req.headers_mut().insert("if", if_token.parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert!(!dir.join("file").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_lockdiscovery() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let server = server_with(&dir, "", "");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    let body = concat!(
+        r#"<?xml version="1.0"?><D:lockinfo xmlns:D="DAV:">"#,
+        "<D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype>",
+        "<D:owner>Alice</D:owner></D:lockinfo>"
+    );
+    let mut req = request("LOCK", "/alice/file", creds).map(|_| hyper::Body::from(body));
+    req.headers_mut().insert("timeout", "Second-3600".parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let token = resp.headers()["lock-token"].to_str().unwrap();
+    let token = token.trim_matches(|c| c == '<' || c == '>').to_string();
+
+    async fn propfind(server: &Server, creds: Option<(&str, &str)>) -> String {
+        let body = concat!(
+            r#"<?xml version="1.0"?><D:propfind xmlns:D="DAV:"><D:prop>"#,
+            "<D:lockdiscovery/><D:supportedlock/></D:prop></D:propfind>"
+        );
+        let mut req = request("PROPFIND", "/alice/file", creds).map(|_| hyper::Body::from(body));
+        req.headers_mut().insert("depth", "0".parse().unwrap());
+        let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        String::from_utf8_lossy(&body).to_string()
+    }
+
+    // the lock holder sees the token and the time that is left.
+    let body = propfind(&server, creds).await;
+    assert!(body.contains(&token));
+    let timeout = body.split("<D:timeout>Second-").nth(1).and_then(|t| t.split('<').next());
+    let timeout: u64 = timeout.unwrap().parse().unwrap();
+    assert!(timeout > 0 && timeout <= 600);
+    let entry = concat!(
+        "<D:lockentry><D:lockscope><D:exclusive></D:exclusive></D:lockscope>",
+        "<D:locktype><D:write></D:write></D:locktype></D:lockentry>"
+    );
+    assert!(body.contains(entry));
+    assert!(!body.contains("<D:write></D:write></D:lockscope>"));
+
+    // others see the lock, but not the token.
+    let body = propfind(&server, None).await;
+    assert!(body.contains("<D:owner>Alice</D:owner>"));
+    assert!(!body.contains(&token));
+    assert!(body.contains(entry));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_move_onto_locked() {
+    let dir = tempdir();
+    for name in &["a", "b", "c", "dest"] {
+        std::fs::write(dir.join(name), name).unwrap();
+    }
+    let server = server(&dir, "chunked-uploads = \"uploads\"");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    let lockinfo = concat!(
+        r#"<?xml version="1.0"?><D:lockinfo xmlns:D="DAV:">"#,
+        "<D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockinfo>"
+    );
+    let req = request("LOCK", "/alice/dest", creds).map(|_| hyper::Body::from(lockinfo));
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let token = resp.headers()["lock-token"].to_str().unwrap().to_string();
+
+    let move_to_dest = |src: &str, if_hdr: Option<String>| {
+        let mut req = request("MOVE", src, creds);
+        req.headers_mut().insert("destination", "/alice/dest".parse().unwrap());
+        req.headers_mut().insert("overwrite", "T".parse().unwrap());
+        if let Some(if_hdr) = if_hdr {
+            req.headers_mut().insert("if", if_hdr.parse().unwrap());
+        }
+        server.route(req, addr)
+    };
+
+    // without the token of the destination: 423, nothing moves.
+    let resp = move_to_dest("/alice/a", None).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::LOCKED);
+    let wrong = "(<urn:uuid:00000000-0000-0000-0000-000000000000>)".to_string();
+    let resp = move_to_dest("/alice/a", Some(wrong)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::LOCKED);
+    assert_eq!(std::fs::read_to_string(dir.join("dest")).unwrap(), "dest");
+    assert!(dir.join("a").exists());
+
+    // with it, untagged or tagged with the destination.
+    let resp = move_to_dest("/alice/a", Some(format!("({})", token))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(std::fs::read_to_string(dir.join("dest")).unwrap(), "a");
+    assert!(!dir.join("a").exists());
+    let tagged = format!("<http://localhost/alice/dest> ({})", token);
+    let resp = move_to_dest("/alice/b", Some(tagged)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(std::fs::read_to_string(dir.join("dest")).unwrap(), "b");
+
+    // the lock on the destination is still there.
+    let resp = move_to_dest("/alice/c", None).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::LOCKED);
+
+    // a chunked upload onto the destination needs the token as well.
+    let resp = server.route(request("MKCOL", "/alice/uploads/web-1", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let mut req = request("PUT", "/alice/uploads/web-1/1", creds).map(|_| hyper::Body::from("chunked"));
+    req.headers_mut().insert("content-length", 7.into());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let resp = move_to_dest("/alice/uploads/web-1/.file", None).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::LOCKED);
+    let resp = move_to_dest("/alice/uploads/web-1/.file", Some(format!("({})", token))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(std::fs::read_to_string(dir.join("dest")).unwrap(), "chunked");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_lock_owner() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file1"), "data").unwrap();
+    std::fs::write(dir.join("file2"), "data").unwrap();
+    let server = server_with(&dir, "", "");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    let owners = &[
+        (
+            "file1",
+            "<D:owner><D:href>mailto:alice@example.com?a=1&amp;b=&lt;2</D:href></D:owner>",
+            "<D:owner><D:href>mailto:alice@example.com?a=1&amp;b=&lt;2</D:href></D:owner>",
+        ),
+        (
+            "file2",
+            r#"<D:owner xmlns:x="urn:x"><x:name>Alice &lt;/D:owner&gt;</x:name></D:owner>"#,
+            r#"<D:owner xmlns:x="urn:x"><x:name>Alice &lt;/D:owner></x:name></D:owner>"#,
+        ),
+    ];
+    for (file, owner, expected) in owners {
+        let body = format!(
+            concat!(
+                r#"<?xml version="1.0"?><D:lockinfo xmlns:D="DAV:">"#,
+                "<D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype>",
+                "{}</D:lockinfo>"
+            ),
+            owner
+        );
+        let path = format!("/alice/{}", file);
+        let req = request("LOCK", &path, creds).map(|_| hyper::Body::from(body));
+        let resp = server.route(req, addr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // the owner comes back as it was sent, escaped where needed.
+        let body = concat!(
+            r#"<?xml version="1.0"?><D:propfind xmlns:D="DAV:"><D:prop>"#,
+            "<D:lockdiscovery/></D:prop></D:propfind>"
+        );
+        let mut req = request("PROPFIND", &path, None).map(|_| hyper::Body::from(body));
+        req.headers_mut().insert("depth", "0".parse().unwrap());
+        let resp = server.route(req, addr).await.unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        assert_eq!(body.matches(expected).count(), 1, "{}", body);
+        assert!(xmltree::Element::parse(body.as_bytes()).is_ok());
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_max_locks_per_user() {
+    let dir = tempdir();
+    for name in &["a", "b", "c"] {
+        std::fs::write(dir.join(name), "data").unwrap();
+    }
+    let server = server_with(&dir, "[locks]\nmax-per-user = 2\n", "");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+    let lockinfo = r#"<?xml version="1.0" encoding="utf-8"?>
+        <D:lockinfo xmlns:D="DAV:">
+          <D:lockscope><D:exclusive/></D:lockscope>
+          <D:locktype><D:write/></D:locktype>
+        </D:lockinfo>"#;
+    let lock = |path: &str| {
+        let req = request("LOCK", path, creds).map(|_| hyper::Body::from(lockinfo));
+        server.route(req, addr)
+    };
+
+    let resp = lock("/alice/a").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let token = resp.headers()["lock-token"].to_str().unwrap().to_string();
+    assert_eq!(lock("/alice/b").await.unwrap().status(), StatusCode::OK);
+    assert_eq!(lock("/alice/c").await.unwrap().status(), StatusCode::INSUFFICIENT_STORAGE);
+    assert_eq!(server.locks.list().len(), 2);
+    assert_eq!(server.locks.per_user().get("alice"), Some(&2));
+
+    // the locks that are there still work.
+    let mut req = request("PUT", "/alice/a", creds);
+    req.headers_mut().insert("if", format!("({})", token).parse().unwrap());
+    assert_eq!(server.route(req, addr).await.unwrap().status(), StatusCode::NO_CONTENT);
+    let mut req = request("UNLOCK", "/alice/a", creds);
+    req.headers_mut().insert("lock-token", token.parse().unwrap());
+    assert_eq!(server.route(req, addr).await.unwrap().status(), StatusCode::NO_CONTENT);
+    assert_eq!(lock("/alice/c").await.unwrap().status(), StatusCode::OK);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_lock_depth_infinity() {
+    let dir = tempdir();
+    std::fs::create_dir_all(dir.join("dir/sub")).unwrap();
+    std::fs::write(dir.join("dir/sub/old"), "data").unwrap();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+    let lockinfo = concat!(
+        r#"<?xml version="1.0"?><D:lockinfo xmlns:D="DAV:">"#,
+        "<D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockinfo>"
+    );
+    let mut req = request("LOCK", "/alice/dir/", creds).map(|_| hyper::Body::from(lockinfo));
+    req.headers_mut().insert("depth", "infinity".parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let token = resp.headers()["lock-token"].to_str().unwrap().to_string();
+
+    // without the token, nothing below it can be changed.
+    for (method, path) in [
+        ("PUT", "/alice/dir/file"),
+        ("PUT", "/alice/dir/sub/old"),
+        ("DELETE", "/alice/dir/sub/"),
+        ("MKCOL", "/alice/dir/sub/new/"),
+    ] {
+        let resp = server.route(request(method, path, creds), addr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::LOCKED, "{} {}", method, path);
+    }
+    let mut req = request("MOVE", "/alice/dir/sub/old", creds);
+    req.headers_mut().insert("destination", "/alice/moved".parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::LOCKED);
+    assert!(!dir.join("dir/file").exists() && dir.join("dir/sub/old").exists());
+
+    // with it, they can.
+    let mut req = request("PUT", "/alice/dir/sub/file", creds);
+    req.headers_mut().insert("if", format!("({})", token).parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    // outside of it, a PUT does not need it.
+    let resp = server.route(request("PUT", "/alice/other", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,88 @@
+//! Tests of the request handling, through `Server::route`.
+//!
+//! The helpers are here, the tests are in the submodules, by what they
+//! are about. Unit tests of the other modules are in those modules.
+//!
+use super::*;
+use headers::{Authorization, HeaderMapExt};
+
+mod access;
+mod locking;
+mod properties;
+mod reads;
+mod serving;
+mod writes;
+
+const ADDR: &str = "127.0.0.1:1234";
+
+// A server with one location, /:user/*path, "auth = write" (unless
+// `extra` sets auth), and an htpasswd file with user "alice", password
+// "secret". `extra` is added to the location.
+fn server(dir: &Path, extra: &str) -> Server {
+    server_with(dir, "", extra)
+}
+
+// Same, and `server_extra` is added to the [server] section.
+fn server_with(dir: &Path, server_extra: &str, extra: &str) -> Server {
+    let htpasswd = dir.join("htpasswd");
+    let hash = pwhash::sha512_crypt::hash("secret").unwrap();
+    std::fs::write(&htpasswd, format!("alice:{}\n", hash)).unwrap();
+    let auth = match extra.lines().any(|l| l.trim_start().starts_with("auth =")) {
+        true => "",
+        false => "auth = \"write\"",
+    };
+    let toml = format!(
+        r#"
+        [server]
+        {}
+        [pam]
+        service = "other"
+        [accounts]
+        auth-type = "htpasswd.test"
+        realm = "Test"
+        [htpasswd.test]
+        htpasswd = "{}"
+        [[location]]
+        route = [ "/:user/*path" ]
+        methods = [ "webdav-rw" ]
+        {}
+        handler = "filesystem"
+        directory = "{}"
+        {}
+        "#,
+        server_extra,
+        htpasswd.display(),
+        auth,
+        dir.display(),
+        extra
+    );
+    let mut config: config::Config = toml::from_str(&toml).unwrap();
+    config::build_routes("test", &mut config).unwrap();
+    let config = Arc::new(config);
+    let auth = auth::Auth::new(config.clone()).unwrap();
+    Server::new(config, auth)
+}
+
+fn tempdir() -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("webdav-test-{}", uuid::Uuid::new_v4().to_simple()));
+    std::fs::create_dir(&dir).unwrap();
+    dir
+}
+
+fn request(method: &str, uri: &str, creds: Option<(&str, &str)>) -> HttpRequest {
+    let body = if method == "PUT" { "data" } else { "" };
+    let mut req = http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-length", body.len())
+        .body(hyper::Body::from(body))
+        .unwrap();
+    if let Some((user, pass)) = creds {
+        req.headers_mut().typed_insert(Authorization::basic(user, pass));
+    }
+    req
+}
+
+fn challenge(resp: &hyper::Response<hyper::Body>) -> Option<&str> {
+    resp.headers().get("www-authenticate").and_then(|h| h.to_str().ok())
+}
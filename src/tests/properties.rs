@@ -0,0 +1,555 @@
+//! PROPFIND and PROPPATCH.
+//!
+use super::*;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_propfind_etag() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+
+    let resp = server.route(request("HEAD", "/alice/file", None), addr).await.unwrap();
+    let etag = resp.headers()["etag"].to_str().unwrap().to_string();
+    let propfind = |header: &'static str, etag: &str| {
+        let mut req = request("PROPFIND", "/alice/file", None);
+        req.headers_mut().insert("depth", "0".parse().unwrap());
+        req.headers_mut().insert(header, etag.parse().unwrap());
+        server.route(req, addr)
+    };
+
+    // unchanged.
+    let resp = propfind("if-none-match", &etag).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.headers()["etag"], etag.as_str());
+    let resp = propfind("if-match", &etag).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+
+    // changed.
+    let resp = propfind("if-none-match", "\"1-2\"").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let resp = propfind("if-match", "\"1-2\"").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_propfind_modes() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+    let propfind = |xml: &'static str| {
+        let mut req = request("PROPFIND", "/alice/file", None);
+        req.headers_mut().insert("depth", "0".parse().unwrap());
+        req.headers_mut().insert("content-length", xml.len().into());
+        *req.body_mut() = hyper::Body::from(xml);
+        let resp = server.route(req, addr);
+        async {
+            let resp = resp.await.unwrap();
+            assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            String::from_utf8_lossy(&body).to_string()
+        }
+    };
+
+    // propname: names only.
+    let body = propfind(r#"<D:propfind xmlns:D="DAV:"><D:propname/></D:propfind>"#).await;
+    assert!(body.contains("<D:getcontentlength></D:getcontentlength>"), "{}", body);
+    assert!(body.contains("<D:getlastmodified></D:getlastmodified>"), "{}", body);
+    assert!(body.contains("<D:resourcetype></D:resourcetype>"), "{}", body);
+
+    // allprop, and an empty body: everything, with values.
+    for xml in &[r#"<D:propfind xmlns:D="DAV:"><D:allprop/></D:propfind>"#, ""] {
+        let body = propfind(xml).await;
+        assert!(body.contains("<D:getcontentlength>4</D:getcontentlength>"), "{}", body);
+        assert!(body.contains("<D:getlastmodified>"), "{}", body);
+        assert!(body.contains("<D:getetag>"), "{}", body);
+    }
+
+    // prop: just these, 404 for the ones that do not exist.
+    let xml = r#"<D:propfind xmlns:D="DAV:" xmlns:X="urn:x">
+        <D:prop><D:getcontentlength/><X:nope/></D:prop></D:propfind>"#;
+    let body = propfind(xml).await;
+    assert!(body.contains("<D:getcontentlength>4</D:getcontentlength>"), "{}", body);
+    assert!(!body.contains("getlastmodified"), "{}", body);
+    assert!(body.contains("nope"), "{}", body);
+    assert!(body.contains("404 Not Found"), "{}", body);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_proppatch_limit() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let server = server_with(&dir, "max-proppatch-props = 2", "");
+    let addr = ADDR.parse().unwrap();
+    let proppatch = |n: usize| {
+        let props: String = (0..n).map(|i| format!("<Z:p{}>x</Z:p{}>", i, i)).collect();
+        let body = format!(
+            concat!(
+                r#"<?xml version="1.0"?><D:propertyupdate xmlns:D="DAV:" xmlns:Z="urn:z">"#,
+                "<D:set><D:prop>{}</D:prop></D:set></D:propertyupdate>"
+            ),
+            props
+        );
+        let mut req = request("PROPPATCH", "/alice/file", Some(("alice", "secret")));
+        req.headers_mut().insert("content-length", body.len().into());
+        *req.body_mut() = hyper::Body::from(body);
+        req
+    };
+
+    let resp = server.route(proppatch(3), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    // passed on to the webdav handler (the filesystem has no
+    // property store, so that says 501).
+    let resp = server.route(proppatch(2), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_IMPLEMENTED);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_propfind_propstat() {
+    let dir = tempdir();
+    std::fs::create_dir(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("sub/file"), "data").unwrap();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+
+    let xml = r#"<D:propfind xmlns:D="DAV:" xmlns:X="urn:x">
+        <D:prop><D:getcontentlength/><D:resourcetype/><X:nope/></D:prop></D:propfind>"#;
+    let mut req = request("PROPFIND", "/alice/sub/", None);
+    req.headers_mut().insert("depth", "1".parse().unwrap());
+    req.headers_mut().insert("content-length", xml.len().into());
+    *req.body_mut() = hyper::Body::from(xml);
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let ms = xmltree::Element::parse(&body[..]).unwrap();
+
+    // href -> status -> names of the properties.
+    let mut results = std::collections::BTreeMap::new();
+    let elements = |e: &xmltree::Element| {
+        e.children.iter().filter_map(|n| n.as_element()).cloned().collect::<Vec<_>>()
+    };
+    for response in elements(&ms) {
+        let children = elements(&response);
+        let href = children.iter().find(|e| e.name == "href").unwrap().get_text().unwrap().to_string();
+        let mut statuses = std::collections::BTreeMap::new();
+        for propstat in children.iter().filter(|e| e.name == "propstat") {
+            let parts = elements(propstat);
+            let status = parts.iter().find(|e| e.name == "status").unwrap().get_text().unwrap();
+            let prop = parts.iter().find(|e| e.name == "prop").unwrap();
+            let names: Vec<String> = elements(prop).iter().map(|e| e.name.clone()).collect();
+            statuses.insert(status.to_string(), names);
+        }
+        results.insert(href, statuses);
+    }
+
+    // every resource gets the properties that exist in a 200 propstat,
+    // and the others in a 404 propstat.
+    let sub = &results["/alice/sub/"];
+    assert_eq!(sub["HTTP/1.1 200 OK"], ["resourcetype"]);
+    assert_eq!(sub["HTTP/1.1 404 Not Found"], ["getcontentlength", "nope"]);
+    let file = &results["/alice/sub/file"];
+    assert_eq!(file["HTTP/1.1 200 OK"], ["getcontentlength", "resourcetype"]);
+    assert_eq!(file["HTTP/1.1 404 Not Found"], ["nope"]);
+    assert_eq!(results.len(), 2);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_depth() {
+    let dir = tempdir();
+    std::fs::create_dir(dir.join("dir")).unwrap();
+    std::fs::write(dir.join("dir/file"), "data").unwrap();
+    let server = server_with(&dir, "max-depth = 0", "");
+    let addr = ADDR.parse().unwrap();
+    let depth = |method: &str, path: &str, depth: &str| {
+        let mut req = request(method, path, Some(("alice", "secret")));
+        req.headers_mut().insert("depth", depth.parse().unwrap());
+        let dest = format!("http://{}/alice/copy", ADDR);
+        req.headers_mut().insert("destination", dest.parse().unwrap());
+        server.route(req, addr)
+    };
+
+    // not a depth, or not one that the method takes.
+    let resp = depth("COPY", "/alice/dir/", "2").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = depth("COPY", "/alice/dir/", "1").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = depth("MOVE", "/alice/dir/", "0").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = depth("LOCK", "/alice/dir/", "1").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = depth("PROPFIND", "/alice/dir/", "garbage").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(!dir.join("copy").exists());
+    assert!(dir.join("dir/file").exists());
+
+    // deeper than max-depth.
+    let resp = depth("PROPFIND", "/alice/dir/", "1").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("propfind-finite-depth"));
+    let resp = depth("PROPFIND", "/alice/dir/", "0").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+
+    let resp = depth("COPY", "/alice/dir/", "0").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.join("copy").is_dir() && !dir.join("copy/file").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_propfind_buffer() {
+    use hyper::body::HttpBody;
+
+    let dir = tempdir();
+    for i in 0..100 {
+        std::fs::write(dir.join(format!("file{}", i)), "data").unwrap();
+    }
+    async fn chunks(server: &Server) -> (usize, String) {
+        let mut req = request("PROPFIND", "/alice/", None);
+        req.headers_mut().insert("depth", "1".parse().unwrap());
+        let mut body = server.route(req, ADDR.parse().unwrap()).await.unwrap().into_body();
+        let (mut n, mut data) = (0, Vec::new());
+        while let Some(chunk) = body.data().await {
+            n += 1;
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        (n, String::from_utf8(data).unwrap())
+    }
+
+    let (unbuffered, all) = chunks(&server_with(&dir, "propfind-buffer = 0", "")).await;
+    assert!(unbuffered > 100, "{}", unbuffered);
+    let (buffered, data) = chunks(&server(&dir, "")).await;
+    assert!(buffered < 10, "{}", buffered);
+    // the files and the htpasswd file.
+    assert_eq!(data.matches("<D:response>").count(), 102);
+    assert_eq!(all.matches("<D:response>").count(), 102);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "quota")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_apple_quota() {
+    let dir = tempdir();
+    std::fs::write(dir.join("f.txt"), "data").unwrap();
+    async fn propfind(server: &Server) -> String {
+        let xml = concat!(
+            r#"<?xml version="1.0"?><D:propfind xmlns:D="DAV:"><D:prop>"#,
+            "<D:quota-available-bytes/><D:quota-used-bytes/><D:quota/><D:quotaused/>",
+            "</D:prop></D:propfind>"
+        );
+        let mut req = request("PROPFIND", "/alice/", Some(("alice", "secret")));
+        req.headers_mut().insert("content-length", xml.len().into());
+        req.headers_mut().insert("depth", "1".parse().unwrap());
+        *req.body_mut() = hyper::Body::from(xml);
+        let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+    let ok = |body: &str, prop: &str| {
+        let re = format!("<D:{0}>[0-9]+</D:{0}>", prop);
+        regex::Regex::new(&re).unwrap().find_iter(body).count()
+    };
+
+    // without the flag, only the RFC 4331 properties.
+    let body = propfind(&server(&dir, "")).await;
+    assert_eq!(ok(&body, "quota-used-bytes"), 3);
+    assert_eq!(ok(&body, "quotaused"), 0);
+    assert!(body.contains("<D:quotaused></D:quotaused>"));
+
+    // with it, both, for every resource, and nothing is "not found".
+    let body = propfind(&server(&dir, "macos-compat = true")).await;
+    assert_eq!(ok(&body, "quota-available-bytes"), 3);
+    assert_eq!(ok(&body, "quota"), 3);
+    assert_eq!(ok(&body, "quotaused"), 3);
+    assert!(!body.contains("404"));
+    assert!(xmltree::Element::parse(body.as_bytes()).is_ok());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_date_properties() {
+    use nix::sys::time::{TimeVal, TimeValLike};
+    let dir = tempdir();
+    std::fs::write(dir.join("f.txt"), "data").unwrap();
+    let mtime = TimeVal::seconds(1_600_000_000);
+    nix::sys::stat::utimes(&dir.join("f.txt"), &mtime, &mtime).unwrap();
+    let server = server(&dir, "");
+
+    let xml = concat!(
+        r#"<D:propfind xmlns:D="DAV:">"#,
+        "<D:prop><D:creationdate/><D:getlastmodified/></D:prop></D:propfind>"
+    );
+    let mut req = request("PROPFIND", "/alice/f.txt", None);
+    req.headers_mut().insert("depth", "0".parse().unwrap());
+    req.headers_mut().insert("content-length", xml.len().into());
+    *req.body_mut() = hyper::Body::from(xml);
+    let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body = String::from_utf8_lossy(&body);
+    let prop = |name: &str| {
+        let re = regex::Regex::new(&format!("<D:{0}>([^<]*)</D:{0}>", name)).unwrap();
+        re.captures(&body).unwrap()[1].to_string()
+    };
+
+    // RFC 3339 for the creation date, the birth time or the mtime.
+    let created = prop("creationdate");
+    assert!(regex::Regex::new(r"^\d{4}-\d\d-\d\dT\d\d:\d\d:\d\dZ$").unwrap().is_match(&created));
+    let created = time::strptime(&created, "%Y-%m-%dT%H:%M:%SZ").unwrap().to_timespec().sec;
+    assert!(created >= 1_600_000_000);
+    // an HTTP-date for the modification time.
+    assert_eq!(prop("getlastmodified"), "Sun, 13 Sep 2020 12:26:40 GMT");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_dav_prefix() {
+    let dir = tempdir();
+    std::fs::write(dir.join("f.txt"), "data").unwrap();
+    async fn propfind(server: &Server) -> String {
+        let xml = concat!(
+            r#"<?xml version="1.0"?><D:propfind xmlns:D="DAV:" xmlns:X="urn:x"><D:prop>"#,
+            r#"<D:getetag/><X:color/><nons xmlns=""/></D:prop></D:propfind>"#
+        );
+        let mut req = request("PROPFIND", "/alice/", Some(("alice", "secret")));
+        req.headers_mut().insert("content-length", xml.len().into());
+        req.headers_mut().insert("depth", "1".parse().unwrap());
+        *req.body_mut() = hyper::Body::from(xml);
+        let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+    // the multistatus of RFC 4918, section 14.16, and the properties
+    // in their own namespaces.
+    fn check(body: &str) {
+        use xmltree::Element;
+        let tree = Element::parse(body.as_bytes()).unwrap();
+        let dav = |e: &Element, name: &str| e.namespace.as_deref() == Some("DAV:") && e.name == name;
+        let children = |e: &Element| {
+            e.children.iter().filter_map(|n| n.as_element()).cloned().collect::<Vec<_>>()
+        };
+        assert!(dav(&tree, "multistatus"));
+        let responses = children(&tree);
+        assert!(responses.len() >= 2);
+        for response in &responses {
+            assert!(dav(response, "response"));
+            let parts = children(response);
+            assert!(dav(&parts[0], "href"));
+            let mut names = Vec::new();
+            for propstat in &parts[1..] {
+                let parts = children(propstat);
+                assert!(dav(propstat, "propstat") && dav(&parts[0], "prop") && dav(&parts[1], "status"));
+                for prop in children(&parts[0]) {
+                    names.push((prop.namespace.clone(), prop.name.clone()));
+                }
+            }
+            names.sort();
+            let expected = vec![
+                (None, "nons".to_string()),
+                (Some("DAV:".to_string()), "getetag".to_string()),
+                (Some("urn:x".to_string()), "color".to_string()),
+            ];
+            assert_eq!(names, expected);
+        }
+    }
+
+    let body = propfind(&server(&dir, "")).await;
+    assert!(body.contains("<D:multistatus"));
+    check(&body);
+    let body = propfind(&server_with(&dir, "dav-prefix = \"d\"", "")).await;
+    assert!(body.contains("<d:multistatus xmlns:d=\"DAV:\"") && !body.contains("<D:"));
+    check(&body);
+    let body = propfind(&server_with(&dir, "dav-prefix = \"\"", "")).await;
+    assert!(body.contains("<multistatus xmlns=\"DAV:\"") && body.contains("<response>"));
+    check(&body);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_propfind_max_responses() {
+    let dir = tempdir();
+    std::fs::create_dir(dir.join("big")).unwrap();
+    for i in 0..20 {
+        std::fs::write(dir.join("big").join(format!("f{:02}", i)), "x").unwrap();
+    }
+    let server = server_with(&dir, "propfind-max-responses = 5", "");
+    async fn propfind(server: &Server, depth: &str) -> String {
+        let mut req = request("PROPFIND", "/alice/big/", Some(("alice", "secret")));
+        req.headers_mut().insert("depth", depth.parse().unwrap());
+        let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    // five responses, and the marker for the collection.
+    let body = propfind(&server, "1").await;
+    let tree = xmltree::Element::parse(body.as_bytes()).unwrap();
+    let responses: Vec<_> = tree.children.iter().filter_map(|n| n.as_element()).collect();
+    assert_eq!(responses.len(), 6);
+    let last = responses[5];
+    assert_eq!(last.get_child("href").unwrap().get_text().unwrap(), "/alice/big/");
+    let status = last.get_child("status").unwrap().get_text().unwrap();
+    assert_eq!(status, "HTTP/1.1 507 Insufficient Storage");
+    let error = last.get_child("error").unwrap();
+    assert!(error.get_child("number-of-matches-within-limits").is_some());
+
+    // a small directory, and Depth: 0, are not truncated.
+    let body = propfind(&server_with(&dir, "", ""), "1").await;
+    assert_eq!(body.matches("<D:response>").count(), 21);
+    let body = propfind(&server, "0").await;
+    assert!(!body.contains("507"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_allprop_policy() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let forbid = server(&dir, "allprop = \"forbid\"");
+    let live = server(&dir, "allprop = \"live\"");
+    let addr = ADDR.parse().unwrap();
+    let propfind = |server: &Server, xml: &'static str| {
+        let mut req = request("PROPFIND", "/alice/file", None);
+        req.headers_mut().insert("depth", "0".parse().unwrap());
+        req.headers_mut().insert("content-length", xml.len().into());
+        *req.body_mut() = hyper::Body::from(xml);
+        let server = server.clone();
+        async move {
+            let resp = server.route(req, addr).await.unwrap();
+            let status = resp.status();
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            (status, String::from_utf8_lossy(&body).to_string())
+        }
+    };
+    let allprop = r#"<D:propfind xmlns:D="DAV:"><D:allprop/></D:propfind>"#;
+
+    for xml in &[allprop, ""] {
+        let (status, _) = propfind(&forbid, xml).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+    let prop = r#"<D:propfind xmlns:D="DAV:"><D:prop><D:getcontentlength/></D:prop></D:propfind>"#;
+    let (status, body) = propfind(&forbid, prop).await;
+    assert_eq!(status, StatusCode::MULTI_STATUS);
+    assert!(body.contains("<D:getcontentlength>4</D:getcontentlength>"), "{}", body);
+    let propname = r#"<D:propfind xmlns:D="DAV:"><D:propname/></D:propfind>"#;
+    let (status, body) = propfind(&forbid, propname).await;
+    assert_eq!(status, StatusCode::MULTI_STATUS);
+    assert!(body.contains("<D:getetag></D:getetag>"), "{}", body);
+
+    // the live properties, by name, and what was included.
+    let include = concat!(
+        r#"<D:propfind xmlns:D="DAV:" xmlns:X="urn:x"><D:allprop/>"#,
+        r#"<D:include><X:color/></D:include></D:propfind>"#
+    );
+    for xml in &[allprop, "", include] {
+        let (status, body) = propfind(&live, xml).await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(body.contains("<D:getcontentlength>4</D:getcontentlength>"), "{}", body);
+        assert!(body.contains("<D:getetag>"), "{}", body);
+        assert_eq!(body.contains("color"), *xml == include, "{}", body);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_versioning_clients() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    let xml = concat!(
+        r#"<D:propfind xmlns:D="DAV:"><D:prop><D:checked-in/><D:version-name/>"#,
+        r#"<D:supported-method-set/><D:getetag/></D:prop></D:propfind>"#
+    );
+    let mut req = request("PROPFIND", "/alice/file", creds);
+    req.headers_mut().insert("depth", "0".parse().unwrap());
+    req.headers_mut().insert("content-length", xml.len().into());
+    *req.body_mut() = hyper::Body::from(xml);
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let tree = xmltree::Element::parse(&body[..]).unwrap();
+    let response = tree.get_child("response").unwrap();
+    let propstats: Vec<_> = response.children.iter().filter_map(|n| n.as_element()).skip(1).collect();
+    assert_eq!(propstats.len(), 2);
+    let status = |p: &xmltree::Element| p.get_child("status").unwrap().get_text().unwrap().to_string();
+    let missing = propstats.iter().find(|p| status(p).contains("404")).unwrap();
+    let prop = missing.get_child("prop").unwrap();
+    let names: Vec<_> = prop.children.iter().filter_map(|n| n.as_element()).map(|e| &e.name).collect();
+    assert_eq!(names, vec!["checked-in", "version-name", "supported-method-set"]);
+
+    let resp = server.route(request("OPTIONS", "/alice/file", creds), addr).await.unwrap();
+    assert!(!resp.headers()["dav"].to_str().unwrap().contains("version-control"));
+    for method in &["VERSION-CONTROL", "CHECKOUT", "CHECKIN", "UNCHECKOUT", "LABEL"] {
+        let resp = server.route(request(method, "/alice/file", creds), addr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_IMPLEMENTED, "{}", method);
+    }
+    assert_eq!(std::fs::read(dir.join("file")).unwrap(), b"data");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_etag_strength_mixed() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "0123456789").unwrap();
+    let server = server(&dir, r#"etag-strength = "mixed""#);
+    let addr = ADDR.parse().unwrap();
+    let get = |headers: &[(&'static str, &str)]| {
+        let mut req = request("GET", "/alice/file", Some(("alice", "secret")));
+        for (name, value) in headers {
+            req.headers_mut().insert(*name, value.parse().unwrap());
+        }
+        req
+    };
+
+    // plain GET: weak, and good enough for a 304.
+    let resp = server.route(get(&[]), addr).await.unwrap();
+    let weak = resp.headers()["etag"].to_str().unwrap().to_string();
+    assert!(weak.starts_with("W/\""));
+    let strong = weak.trim_start_matches("W/").to_string();
+    let resp = server.route(get(&[("if-none-match", &weak)]), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+    // a range has the strong ETag, and If-Range needs it.
+    let resp = server.route(get(&[("range", "bytes=2-4")]), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.headers()["etag"], strong.as_str());
+    let resp = server.route(get(&[("range", "bytes=2-4"), ("if-range", &strong)]), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    let resp = server.route(get(&[("range", "bytes=2-4"), ("if-range", &weak)]), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers()["etag"].to_str().unwrap().starts_with("W/"));
+
+    // updates with If-Match: the strong one.
+    let mut req = request("PUT", "/alice/file", Some(("alice", "secret")));
+    req.headers_mut().insert("if-match", weak.parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+    let mut req = request("PUT", "/alice/file", Some(("alice", "secret")));
+    req.headers_mut().insert("if-match", strong.parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
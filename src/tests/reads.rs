@@ -0,0 +1,530 @@
+//! GET and HEAD: ranges, conditional requests, caching, indexes.
+//!
+use super::*;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_vary() {
+    let dir = tempdir();
+    std::fs::write(dir.join("f.txt"), "data").unwrap();
+    std::fs::write(dir.join("f.txt.gz"), "gzipped").unwrap();
+    let server = server(&dir, "precompressed = true");
+    let addr = ADDR.parse().unwrap();
+    let header = |resp: &hyper::Response<hyper::Body>, name| {
+        resp.headers().get(name).map(|v| v.to_str().unwrap().to_string())
+    };
+
+    // Anonymous: depends on Accept-Encoding, and on Authorization
+    // (credentials are optional for reads), but can be cached.
+    let mut req = request("GET", "/alice/f.txt", None);
+    req.headers_mut().insert("accept-encoding", "gzip".parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(header(&resp, "content-encoding").as_deref(), Some("gzip"));
+    assert_eq!(header(&resp, "vary").as_deref(), Some("Authorization, Accept-Encoding"));
+    assert_eq!(header(&resp, "cache-control"), None);
+
+    // Authenticated: private.
+    let req = request("GET", "/alice/f.txt", Some(("alice", "secret")));
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "vary").as_deref(), Some("Authorization, Accept-Encoding"));
+    assert_eq!(header(&resp, "cache-control").as_deref(), Some("private"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_multiple_ranges() {
+    let dir = tempdir();
+    std::fs::write(dir.join("f.txt"), "0123456789abcdefghij").unwrap();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+    let get = |range: String| {
+        let mut req = request("GET", "/alice/f.txt", None);
+        req.headers_mut().insert("range", range.parse().unwrap());
+        server.route(req, addr)
+    };
+
+    // two ranges.
+    let resp = get("bytes=2-3,-2".to_string()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    let ct = resp.headers()["content-type"].to_str().unwrap().to_string();
+    let boundary = ct.strip_prefix("multipart/byteranges; boundary=").unwrap().to_string();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let expected = format!(
+        concat!(
+            "\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 2-3/20\r\n\r\n23",
+            "\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 18-19/20\r\n\r\nij",
+            "\r\n--{b}--\r\n"
+        ),
+        b = boundary
+    );
+    assert_eq!(body, expected);
+
+    // ranges that overlap are merged.
+    let resp = get("bytes=0-4,3-7,8-9".to_string()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.headers()["content-range"], "bytes 0-9/20");
+    assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "0123456789");
+
+    // too many.
+    let ranges: Vec<String> = (0..100).map(|n| format!("{}-{}", n, n)).collect();
+    let range = format!("bytes={}", ranges.join(","));
+    let resp = get(range).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_autoindex_language() {
+    let dir = tempdir();
+    std::fs::create_dir(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("sub/a file.txt"), "data").unwrap();
+    std::fs::write(dir.join("nl.hbs"), "Inhoud van {{path}}:{{#each entries}} {{href}}{{/each}}").unwrap();
+    let extra = format!(
+        "autoindex = true\nautoindex-templates = {{ nl = \"{}\" }}",
+        dir.join("nl.hbs").display()
+    );
+    let server = server(&dir, &extra);
+    let addr = ADDR.parse().unwrap();
+    let get = |lang: &str| {
+        let mut req = request("GET", "/alice/sub/", None);
+        req.headers_mut().insert("accept-language", lang.parse().unwrap());
+        req
+    };
+
+    let resp = server.route(get("nl-BE, en;q=0.5"), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("content-language").unwrap(), "nl");
+    assert_eq!(resp.headers().get("vary").unwrap(), "Authorization, Accept-Language");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"Inhoud van /alice/sub/: /alice/sub/a%20file.txt");
+
+    // no match: the built-in English template.
+    let resp = server.route(get("fr"), addr).await.unwrap();
+    assert_eq!(resp.headers().get("content-language").unwrap(), "en");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body = String::from_utf8_lossy(&body);
+    assert!(body.contains("<title>Index of /alice/sub/</title>"), "{}", body);
+    assert!(body.contains(r#"<a href="/alice/sub/a%20file.txt">a file.txt</a>"#), "{}", body);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_charset() {
+    let dir = tempdir();
+    std::fs::write(dir.join("notes.txt"), "caf\u{e9}").unwrap();
+    std::fs::write(dir.join("wide.txt"), b"\xff\xfeh\0i\0").unwrap();
+    std::fs::write(dir.join("photo.jpg"), b"\xff\xd8\xff\xe0").unwrap();
+    let server = server(&dir, "charset = \"utf-8\"\ncharset-sniff = true");
+    let addr = ADDR.parse().unwrap();
+    let content_type = |resp: &hyper::Response<hyper::Body>| {
+        resp.headers().get("content-type").unwrap().to_str().unwrap().to_string()
+    };
+
+    let resp = server.route(request("GET", "/alice/notes.txt", None), addr).await.unwrap();
+    assert_eq!(content_type(&resp), "text/plain; charset=utf-8");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(&body[..], "caf\u{e9}".as_bytes());
+
+    let resp = server.route(request("GET", "/alice/photo.jpg", None), addr).await.unwrap();
+    assert_eq!(content_type(&resp), "image/jpeg");
+
+    let resp = server.route(request("GET", "/alice/wide.txt", None), addr).await.unwrap();
+    assert_eq!(content_type(&resp), "text/plain; charset=utf-16le");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"\xff\xfeh\0i\0");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_if_range() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "0123456789").unwrap();
+    crate::crossfs::tests::set_mtime(&dir.join("file"), 1_000_000_000);
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+    let get = |range: &str, if_range: &str| {
+        let mut req = request("GET", "/alice/file", Some(("alice", "secret")));
+        req.headers_mut().insert("range", range.parse().unwrap());
+        req.headers_mut().insert("if-range", if_range.parse().unwrap());
+        req
+    };
+
+    let req = request("GET", "/alice/file", Some(("alice", "secret")));
+    let resp = server.route(req, addr).await.unwrap();
+    let etag = resp.headers().get("etag").unwrap().to_str().unwrap().to_string();
+    let modified = resp.headers().get("last-modified").unwrap().to_str().unwrap().to_string();
+
+    for if_range in [etag.as_str(), modified.as_str()] {
+        let resp = server.route(get("bytes=2-4", if_range), addr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"234");
+    }
+
+    // the file changed: all of it.
+    let changed = [r#""1-abc""#, "Sun, 06 Nov 1994 08:49:37 GMT"];
+    for if_range in changed.iter() {
+        for range in ["bytes=2-4", "bytes=0-1,5-6"] {
+            let resp = server.route(get(range, if_range), addr).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"0123456789");
+        }
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_range_not_satisfiable() {
+    let dir = tempdir();
+    std::fs::write(dir.join("f.txt"), "0123456789abcdefghij").unwrap();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+    let get = |range: &str, accept: Option<&str>| {
+        let mut req = request("GET", "/alice/f.txt", None);
+        req.headers_mut().insert("range", range.parse().unwrap());
+        if let Some(accept) = accept {
+            req.headers_mut().insert("accept", accept.parse().unwrap());
+        }
+        server.route(req, addr)
+    };
+
+    for range in &["bytes=20-30", "bytes=100-", "bytes=30-40,50-60", "bytes=-0"] {
+        for accept in &[None, Some("text/html")] {
+            let resp = get(range, *accept).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE, "{}", range);
+            assert_eq!(resp.headers()["content-range"], "bytes */20", "{} {:?}", range, accept);
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_get_if_match() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    std::fs::create_dir(dir.join("dir")).unwrap();
+    let server = server(&dir, "autoindex = true");
+    let addr = ADDR.parse().unwrap();
+    let get = |method: &str, path: &str, if_match: &str| {
+        let mut req = request(method, path, None);
+        req.headers_mut().insert("if-match", if_match.parse().unwrap());
+        server.route(req, addr)
+    };
+
+    for path in &["/alice/file", "/alice/dir/"] {
+        // the directory index has no ETag header, take it from PROPFIND.
+        let mut req = request("PROPFIND", path, None);
+        req.headers_mut().insert("depth", "0".parse().unwrap());
+        let resp = server.route(req, addr).await.unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        let etag = body.split("<D:getetag>").nth(1).unwrap().split("</D:getetag>").next().unwrap();
+        let etag = format!("\"{}\"", etag);
+        for method in &["GET", "HEAD"] {
+            assert_eq!(get(method, path, &etag).await.unwrap().status(), StatusCode::OK);
+            assert_eq!(get(method, path, "*").await.unwrap().status(), StatusCode::OK);
+            let resp = get(method, path, "\"1-other\"").await.unwrap();
+            assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED, "{} {}", method, path);
+        }
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_index_files() {
+    let dir = tempdir();
+    for (file, data) in &[
+        ("readme/README.md", "readme"),
+        ("both/index.html", "index"),
+        ("both/README.md", "readme"),
+        ("subdir/README.md", "readme"),
+    ] {
+        let path = dir.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+    std::fs::create_dir_all(dir.join("empty")).unwrap();
+    std::fs::create_dir_all(dir.join("subdir/index.html")).unwrap();
+    let server = server(&dir, "autoindex = true\nindexfile = [ \"index.html\", \"README.md\" ]");
+    let addr = ADDR.parse().unwrap();
+    let get = |method: &str, path: &str| server.route(request(method, path, None), addr);
+    let body = |resp: hyper::Response<hyper::Body>| async move {
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        String::from_utf8_lossy(&body).to_string()
+    };
+
+    // the first one that is there, and is a file.
+    assert_eq!(body(get("GET", "/alice/both/").await.unwrap()).await, "index");
+    assert_eq!(body(get("GET", "/alice/readme/").await.unwrap()).await, "readme");
+    assert_eq!(body(get("GET", "/alice/subdir/").await.unwrap()).await, "readme");
+    assert_eq!(get("HEAD", "/alice/both/").await.unwrap().status(), StatusCode::OK);
+
+    // none: the directory index.
+    let resp = get("GET", "/alice/empty/").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(body(resp).await.contains("<html"));
+
+    // PROPFIND sees the directory.
+    let mut req = request("PROPFIND", "/alice/both/", None);
+    req.headers_mut().insert("depth", "1".parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    assert_eq!(body(resp).await.matches("<D:response>").count(), 3);
+
+    // one name, without an autoindex.
+    let server = self::server(&dir, "indexfile = \"index.html\"");
+    let resp = server.route(request("GET", "/alice/both/", None), addr).await.unwrap();
+    assert_eq!(body(resp).await, "index");
+    let resp = server.route(request("GET", "/alice/readme/", None), addr).await.unwrap();
+    assert!(!resp.status().is_success());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_ranges_off() {
+    let dir = tempdir();
+    std::fs::write(dir.join("f.txt"), "0123456789").unwrap();
+    let addr = ADDR.parse().unwrap();
+    async fn get(server: &Server, method: &str, range: &str) -> HttpResult {
+        let mut req = request(method, "/alice/f.txt", None);
+        req.headers_mut().insert("range", range.parse().unwrap());
+        req.headers_mut().insert("if-range", "\"1-other\"".parse().unwrap());
+        server.route(req, ADDR.parse().unwrap()).await
+    }
+
+    // the default: a range, with If-Range that does not match the whole file.
+    let server = server(&dir, "");
+    let resp = get(&server, "GET", "bytes=2-3").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["accept-ranges"], "bytes");
+    let mut req = request("GET", "/alice/f.txt", None);
+    req.headers_mut().insert("range", "bytes=2-3".parse().unwrap());
+    assert_eq!(server.route(req, addr).await.unwrap().status(), StatusCode::PARTIAL_CONTENT);
+
+    // off: no ranges, and the client is told so.
+    let server = self::server(&dir, "ranges = false");
+    for range in &["bytes=2-3", "bytes=2-3,5-6", "bytes=100-"] {
+        let resp = get(&server, "GET", range).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["accept-ranges"], "none");
+        assert!(!resp.headers().contains_key("content-range"));
+        assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "0123456789");
+    }
+    let resp = get(&server, "HEAD", "bytes=2-3").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["accept-ranges"], "none");
+    assert_eq!(resp.headers()["content-length"], "10");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_read_ahead() {
+    let dir = tempdir();
+    let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    std::fs::write(dir.join("big"), &data).unwrap();
+    let server = server(&dir, "read-ahead = true\nread-ahead-window = 16384");
+    let resp = server.route(request("GET", "/alice/big", None), ADDR.parse().unwrap()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-length"], "200000");
+    assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), data);
+
+    let mut req = request("GET", "/alice/big", None);
+    req.headers_mut().insert("range", "bytes=100-199".parse().unwrap());
+    let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), &data[100..200]);
+    assert_eq!(server.memory.used(), 0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_if_range_date() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "0123456789").unwrap();
+    crate::crossfs::tests::set_mtime(&dir.join("file"), 1_000_000_000);
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+    let get = |if_range: Option<&str>| {
+        let mut req = request("GET", "/alice/file", Some(("alice", "secret")));
+        if let Some(if_range) = if_range {
+            req.headers_mut().insert("range", "bytes=5-".parse().unwrap());
+            req.headers_mut().insert("if-range", if_range.parse().unwrap());
+        }
+        req
+    };
+    let resume = |if_range: String| {
+        let server = &server;
+        async move {
+            let resp = server.route(get(Some(&if_range)), addr).await.unwrap();
+            let status = resp.status();
+            (status, hyper::body::to_bytes(resp.into_body()).await.unwrap())
+        }
+    };
+
+    let resp = server.route(get(None), addr).await.unwrap();
+    let modified = resp.headers().get("last-modified").unwrap().to_str().unwrap().to_string();
+    assert_eq!(modified, "Sun, 09 Sep 2001 01:46:40 GMT");
+
+    // not modified since: the rest of the file.
+    let (status, body) = resume(modified.clone()).await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(&body[..], b"56789");
+
+    // modified since, or the date of another version: all of it.
+    std::fs::write(dir.join("file"), "abcdefghij").unwrap();
+    crate::crossfs::tests::set_mtime(&dir.join("file"), 1_000_000_060);
+    let (status, body) = resume(modified).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(&body[..], b"abcdefghij");
+    let (status, _) = resume("Sun, 09 Sep 2001 01:48:40 GMT".to_string()).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = resume("Sun, 09 Sep 2001 01:47:40 GMT".to_string()).await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(&body[..], b"fghij");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_probe_cache() {
+    let dir = tempdir();
+    async fn probe(server: &Server, creds: Option<(&str, &str)>) -> String {
+        let xml = r#"<propfind xmlns="DAV:"><prop><getlastmodified/></prop></propfind>"#;
+        let mut req = request("PROPFIND", "/alice/", creds);
+        req.headers_mut().insert("content-length", xml.len().into());
+        req.headers_mut().insert("depth", "0".parse().unwrap());
+        *req.body_mut() = hyper::Body::from(xml);
+        let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+    let alice = Some(("alice", "secret"));
+    let cached = server(&dir, "probe-cache = 60");
+    let full = server(&dir, "");
+    crate::crossfs::tests::set_mtime(&dir, 1_000_000_000);
+
+    // the cached path gives what the full path gives.
+    let first = probe(&cached, alice).await;
+    assert!(first.contains("Sun, 09 Sep 2001 01:46:40 GMT"));
+    assert_eq!(first, probe(&full, alice).await);
+    assert_eq!(probe(&cached, None).await, probe(&full, None).await);
+
+    // a change outside of the server is not seen yet.
+    crate::crossfs::tests::set_mtime(&dir, 1_000_000_060);
+    assert_eq!(probe(&cached, alice).await, first);
+    assert_eq!(probe(&cached, None).await, first);
+    assert_ne!(probe(&full, alice).await, first);
+
+    // a change through the server is.
+    let req = request("PUT", "/alice/x", alice);
+    let resp = cached.route(req, ADDR.parse().unwrap()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let now = probe(&cached, alice).await;
+    assert_ne!(now, first);
+    assert_eq!(now, probe(&full, alice).await);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_cache_control() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let anonymous = server(&dir, "auth = \"false\"");
+    let public = server(&dir, "cache-control = \"public, max-age=600\"");
+    let private = server(&dir, "auth = \"true\"\ncache-control = \"private, no-store\"");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+    let cache_control = |resp: &hyper::Response<hyper::Body>| {
+        resp.headers().get("cache-control").map(|v| v.to_str().unwrap().to_string())
+    };
+
+    let resp = anonymous.route(request("GET", "/alice/file", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(cache_control(&resp).as_deref(), Some("public"));
+
+    let resp = public.route(request("HEAD", "/alice/file", None), addr).await.unwrap();
+    assert_eq!(cache_control(&resp).as_deref(), Some("public, max-age=600"));
+    // logged in: private, and it depends on Authorization.
+    let resp = public.route(request("GET", "/alice/file", creds), addr).await.unwrap();
+    assert_eq!(cache_control(&resp).as_deref(), Some("max-age=600, private"));
+    assert!(resp.headers()["vary"].to_str().unwrap().contains("Authorization"));
+    // not for errors, or other methods.
+    let resp = public.route(request("GET", "/alice/nope", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(cache_control(&resp), None);
+    let resp = public.route(request("PROPFIND", "/alice/file", None), addr).await.unwrap();
+    assert!(!cache_control(&resp).unwrap_or_default().contains("max-age"));
+
+    let resp = private.route(request("GET", "/alice/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(cache_control(&resp).as_deref(), Some("private, no-store"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_root_probe() {
+    // no credentials, the htpasswd file goes away with the directory.
+    let addr = ADDR.parse().unwrap();
+    let top = tempdir();
+    let dir = top.join("root");
+    std::fs::create_dir(&dir).unwrap();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let server = server(&dir, "root-probe-interval = 1");
+
+    let resp = server.route(request("GET", "/alice/file", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // gone: 503, once the interval is over.
+    let away = top.join("away");
+    std::fs::rename(&dir, &away).unwrap();
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    for _ in 0..2 {
+        let resp = server.route(request("GET", "/alice/file", None), addr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()["retry-after"], "1");
+    }
+    let resp = server.route(request("PROPFIND", "/alice/", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // back.
+    std::fs::rename(&away, &dir).unwrap();
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let resp = server.route(request("GET", "/alice/file", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    std::fs::remove_dir_all(&top).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_conn_encoding() {
+    let dir = tempdir();
+    std::fs::write(dir.join("f.txt"), "data").unwrap();
+    std::fs::write(dir.join("f.txt.gz"), "gzipped").unwrap();
+    let server = server(&dir, "precompressed = true");
+
+    // one connection, the client changes its mind halfway.
+    let conn = ConnEncoding::new();
+    for accept in ["gzip", "gzip", "identity", "gzip;q=0", "gzip"] {
+        let mut req = request("GET", "/alice/f.txt", None);
+        req.headers_mut().insert("accept-encoding", accept.parse().unwrap());
+        req.extensions_mut().insert(conn.clone());
+        let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+        let gzip = resp.headers().get("content-encoding").is_some();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(gzip, accept == "gzip", "{}", accept);
+        assert_eq!(&body[..], if gzip { &b"gzipped"[..] } else { b"data" });
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
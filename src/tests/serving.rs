@@ -0,0 +1,802 @@
+//! What is not about one method: headers, limits, metrics, the admin API.
+//!
+use super::*;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_normalized_paths() {
+    let dir = tempdir();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+    let status = |req| async { server.route(req, addr).await.unwrap().status() };
+
+    let req = request("PUT", "/alice//sub/./../file", creds);
+    assert_eq!(status(req).await, StatusCode::CREATED);
+    assert!(dir.join("file").exists());
+    let req = request("GET", "/bob/%2e%2e/alice/%2E/file", None);
+    assert_eq!(status(req).await, StatusCode::OK);
+
+    // The ACLs see the normalized path.
+    let req = request("PUT", "/bob/../alice/file2", creds);
+    assert_eq!(status(req).await, StatusCode::CREATED);
+    let req = request("PUT", "/alice/%2e%2e/bob/file", creds);
+    assert_eq!(status(req).await, StatusCode::FORBIDDEN);
+
+    // Above the root, or an encoded slash.
+    let req = request("GET", "/alice/%2e%2e/%2e%2e/etc/passwd", None);
+    assert_eq!(status(req).await, StatusCode::BAD_REQUEST);
+    let req = request("GET", "/alice/../../etc/passwd", None);
+    assert_eq!(status(req).await, StatusCode::BAD_REQUEST);
+    let req = request("PUT", "/alice/a%2Fb", creds);
+    assert_eq!(status(req).await, StatusCode::BAD_REQUEST);
+
+    // The Destination header too.
+    let mut req = request("MOVE", "/alice/file", creds);
+    req.headers_mut().insert("destination", "/alice/x/%2e%2e//moved".parse().unwrap());
+    assert_eq!(status(req).await, StatusCode::CREATED);
+    assert!(dir.join("moved").exists());
+    let mut req = request("MOVE", "/alice/moved", creds);
+    req.headers_mut().insert("destination", "/alice/%2e%2e/%2e%2e/moved".parse().unwrap());
+    assert_eq!(status(req).await, StatusCode::BAD_REQUEST);
+
+    // strict-paths: refused instead of rewritten.
+    let strict = server_with(&dir, "strict-paths = true", "");
+    let resp = strict.route(request("GET", "/alice//moved", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = strict.route(request("GET", "/alice/moved", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_public_url() {
+    let dir = tempdir();
+    std::fs::create_dir(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("sub/file"), "data").unwrap();
+    let server = server_with(&dir, r#"public-url = "https://example.com/dav""#, "");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    // The proxy stripped /dav, the hrefs have it.
+    let mut req = request("PROPFIND", "/alice/sub/", creds);
+    req.headers_mut().insert("depth", "1".parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body = String::from_utf8_lossy(&body);
+    assert!(body.contains("<D:href>/dav/alice/sub/</D:href>"), "{}", body);
+    assert!(body.contains("<D:href>/dav/alice/sub/file</D:href>"), "{}", body);
+
+    // Redirects are absolute.
+    let resp = server.route(request("GET", "/alice/sub", None), addr).await.unwrap();
+    let location = resp.headers().get("location").and_then(|l| l.to_str().ok());
+    assert_eq!(location, Some("https://example.com/dav/alice/sub/"));
+
+    // The Destination has the prefix.
+    let mut req = request("MOVE", "/alice/sub/file", creds);
+    let dest = "https://example.com/dav/alice/sub/moved".parse().unwrap();
+    req.headers_mut().insert("destination", dest);
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.join("sub/moved").is_file());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_request_rate() {
+    let dir = tempdir();
+    let admin = "[admin]\nroute = \"/admin\"\nusers = [ \"alice\" ]";
+    let server = server_with(&dir, "max-request-rate = 0.01\nrequest-burst = 2", admin);
+    let addr = ADDR.parse().unwrap();
+
+    for _ in 0..2 {
+        let resp = server.route(request("GET", "/alice/", None), addr).await.unwrap();
+        assert_ne!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+    let resp = server.route(request("GET", "/alice/", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry: u64 = resp.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+    assert!(retry > 90 && retry <= 100, "{}", retry);
+
+    // the admin API still works.
+    let req = request("GET", "/admin/metrics", Some(("alice", "secret")));
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let metrics: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(metrics["request_tokens"].as_f64().unwrap() < 0.1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_server_header() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+    let header = |resp: &http::Response<hyper::Body>, name: &str| {
+        resp.headers().get(name).map(|v| v.to_str().unwrap().to_string())
+    };
+
+    let default = server(&dir, "");
+    let resp = default.route(request("GET", "/alice/file", creds), addr).await.unwrap();
+    assert_eq!(header(&resp, "server").as_deref(), Some("webdav-server-rs"));
+    assert_eq!(header(&resp, "x-powered-by"), None);
+
+    let custom = server_with(&dir, "identification = \"files\"\npowered-by = \"rust\"", "");
+    let reqs = [request("OPTIONS", "/alice/file", creds), request("GET", "/alice/nope", creds)];
+    for req in reqs {
+        let resp = custom.route(req, addr).await.unwrap();
+        assert_eq!(header(&resp, "server").as_deref(), Some("files"));
+        assert_eq!(header(&resp, "x-powered-by").as_deref(), Some("rust"));
+    }
+
+    let none = server_with(&dir, "identification = \"\"", "");
+    let reqs = [
+        request("GET", "/alice/file", creds),
+        request("OPTIONS", "/alice/", creds),
+        request("GET", "/alice/file", None),
+        request("GET", "/nowhere", None),
+    ];
+    for req in reqs {
+        let resp = none.route(req, addr).await.unwrap();
+        assert_eq!(header(&resp, "server"), None);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_prometheus() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let extra = "[metrics]\nsink = \"prometheus\"\n[admin]\nroute = \"/admin\"\nusers = [ \"alice\" ]";
+    let server = server(&dir, extra);
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    let resp = server.route(request("GET", "/alice/file", creds), addr).await.unwrap();
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let resp = server.route(request("FROB", "/alice/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+    let resp = server.route(request("GET", "/admin/prometheus", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let text = String::from_utf8_lossy(&body);
+    assert!(text.contains(r#"webdav_requests_total{method="GET",status="200"} 1"#));
+    assert!(text.contains(r#"webdav_requests_total{method="other",status="405"} 1"#));
+    assert!(text.contains(r#"webdav_request_duration_seconds_count{method="GET"} 1"#));
+
+    // without a sink, there is nothing to scrape.
+    let plain = server_with(&dir, "", "[admin]\nroute = \"/admin\"\nusers = [ \"alice\" ]");
+    let resp = plain.route(request("GET", "/admin/prometheus", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_error_pages() {
+    let dir = tempdir();
+    let page = dir.join("404.html");
+    std::fs::write(&page, "<h1>Nothing here</h1>").unwrap();
+    let extra = format!("[error-pages]\n404 = \"{}\"", page.display());
+    let server = server(&dir, &extra);
+    let addr = ADDR.parse().unwrap();
+    let browser = |method: &str| {
+        let mut req = request(method, "/alice/nonexistent", None);
+        req.headers_mut().insert("accept", "text/html,*/*;q=0.8".parse().unwrap());
+        server.route(req, addr)
+    };
+
+    let resp = browser("GET").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(resp.headers()["content-type"], "text/html; charset=utf-8");
+    assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "<h1>Nothing here</h1>");
+
+    // no page for 416, the built-in one.
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let ranges: Vec<String> = (0..100).map(|n| format!("{}-{}", n, n)).collect();
+    let mut req = request("GET", "/alice/file", None);
+    req.headers_mut().insert("accept", "text/html".parse().unwrap());
+    req.headers_mut().insert("range", format!("bytes={}", ranges.join(",")).parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("<h1>416 Range Not Satisfiable</h1>"));
+
+    // WebDAV clients get the normal response.
+    let mut req = request("PROPFIND", "/alice/nonexistent", None);
+    req.headers_mut().insert("accept", "text/html".parse().unwrap());
+    req.headers_mut().insert("depth", "0".parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let content_type = resp.headers().get("content-type").map(|v| v.to_str().unwrap());
+    assert_ne!(content_type, Some("text/html; charset=utf-8"));
+    let resp = server.route(request("GET", "/alice/nonexistent", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(!String::from_utf8_lossy(&body).contains("Nothing here"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sockopts() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let _client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (conn, _) = listener.accept().await.unwrap();
+    let sock = socket2::SockRef::from(&conn);
+
+    // default: TCP_NODELAY, no keepalive.
+    let cfg: config::Server = toml::from_str("").unwrap();
+    set_sockopts(&conn, &cfg).unwrap();
+    assert!(sock.nodelay().unwrap());
+    assert!(!sock.keepalive().unwrap());
+
+    let opts = concat!(
+        "tcp-nodelay = false\n",
+        "tcp-keepalive = 60\n",
+        "tcp-keepalive-interval = 10\n",
+        "tcp-keepalive-retries = 6"
+    );
+    let cfg: config::Server = toml::from_str(opts).unwrap();
+    set_sockopts(&conn, &cfg).unwrap();
+    assert!(!sock.nodelay().unwrap());
+    assert!(sock.keepalive().unwrap());
+    assert_eq!(sock.keepalive_time().unwrap(), Duration::from_secs(60));
+    #[cfg(target_os = "linux")]
+    {
+        assert_eq!(sock.keepalive_interval().unwrap(), Duration::from_secs(10));
+        assert_eq!(sock.keepalive_retries().unwrap(), 6);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_request_id() {
+    requestid::tests::capture_log();
+    let dir = tempdir();
+    let server = server(&dir, "");
+
+    // a failed login: the ID is in the response, and in the log.
+    let req = request("PUT", "/alice/file", Some(("alice", "wrong")));
+    let resp = server.route(req, "192.0.2.1:1234".parse().unwrap()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let id = resp.headers().get("x-request-id").unwrap().to_str().unwrap().to_string();
+    let logged = requestid::tests::logged(&id);
+    assert_eq!(logged.len(), 1);
+    assert!(logged[0].starts_with("webdav_server::auth: ["));
+    assert!(logged[0].contains("auth: alice from 192.0.2.1: failed (401 Unauthorized)"));
+
+    // a proxy on localhost can pass on its ID, other clients cannot.
+    let mut req = request("GET", "/alice/", None);
+    req.headers_mut().insert("x-request-id", "proxy-42".parse().unwrap());
+    let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+    assert_eq!(resp.headers().get("x-request-id").unwrap(), "proxy-42");
+    let mut req = request("GET", "/alice/", None);
+    req.headers_mut().insert("x-request-id", "proxy-42".parse().unwrap());
+    let resp = server.route(req, "192.0.2.1:1234".parse().unwrap()).await.unwrap();
+    assert_ne!(resp.headers().get("x-request-id").unwrap(), "proxy-42");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_header_limits() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let server = server_with(&dir, "max-header-size = 1024\nmax-headers = 20", "");
+    let addr = ADDR.parse().unwrap();
+
+    let resp = server.route(request("GET", "/alice/file", Some(("alice", "secret"))), addr);
+    assert_eq!(resp.await.unwrap().status(), StatusCode::OK);
+
+    // too many headers.
+    let mut req = request("GET", "/alice/file", Some(("alice", "secret")));
+    for _ in 0..20 {
+        req.headers_mut().append("x-many", "1".parse().unwrap());
+    }
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+
+    // too large.
+    let mut req = request("GET", "/alice/file", Some(("alice", "secret")));
+    req.headers_mut().insert("x-large", "x".repeat(1024).parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_keep_alive_off() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let dav_server = server_with(&dir, "keep-alive = false", "");
+    let (mut client, conn) = tokio::io::duplex(65536);
+    let service = service_fn(move |req| {
+        let dav_server = dav_server.clone();
+        async move { dav_server.route(req, ADDR.parse().unwrap()).await }
+    });
+    let conn = tokio::spawn(hyper::server::conn::Http::new().serve_connection(conn, service));
+
+    // two requests at once, only the first one is answered.
+    let req = "GET /alice/file HTTP/1.1\r\nHost: x\r\n\r\n";
+    client.write_all(format!("{}{}", req, req).as_bytes()).await.unwrap();
+    let mut resp = String::new();
+    client.read_to_string(&mut resp).await.unwrap();
+    conn.await.unwrap().unwrap();
+    assert_eq!(resp.matches("HTTP/1.1 200 OK").count(), 1, "{}", resp);
+    assert!(resp.contains("connection: close\r\n"), "{}", resp);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_encoded_headers() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let server = server_with(&dir, r#"public-url = "https://example.com/dav""#, "");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    // A raw UTF-8 Destination, and one with lowercase percent-encoding.
+    let mut req = request("MOVE", "/alice/file", creds);
+    let dest = http::HeaderValue::from_bytes("https://example.com/dav/alice/\u{e9}t\u{e9}".as_bytes());
+    req.headers_mut().insert("destination", dest.unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.join("\u{e9}t\u{e9}").is_file());
+    let mut req = request("MOVE", "/alice/%C3%A9t%C3%A9", creds);
+    req.headers_mut().insert("destination", "/dav/alice/%c3%a9t%c3%a9%20%e2%82%ac".parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.join("\u{e9}t\u{e9} \u{20ac}").is_file());
+
+    let lockinfo = concat!(
+        r#"<?xml version="1.0"?><D:lockinfo xmlns:D="DAV:">"#,
+        "<D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockinfo>"
+    );
+    let path = "/alice/%C3%A9t%C3%A9%20%E2%82%AC";
+    let req = request("LOCK", path, creds).map(|_| hyper::Body::from(lockinfo));
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let token = resp.headers()["lock-token"].to_str().unwrap();
+    let token = token.trim_matches(|c| c == '<' || c == '>').replace(':', "%3A");
+
+    // The resource tag is raw UTF-8 with the public prefix, the token is encoded.
+    let resp = server.route(request("PUT", path, creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::LOCKED);
+    let mut req = request("PUT", path, creds);
+    let tagged = format!("<https://example.com/dav/alice/\u{e9}t\u{e9} \u{20ac}> (<{}>)", token);
+    req.headers_mut().insert("if", http::HeaderValue::from_bytes(tagged.as_bytes()).unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let mut req = request("PUT", path, creds);
+    req.headers_mut().insert("if", format!("(<{}>)", token).parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_capabilities() {
+    let dir = tempdir();
+    let addr = ADDR.parse().unwrap();
+    async fn get_caps(server: &Server) -> (StatusCode, serde_json::Value) {
+        let req = request("GET", capabilities::PATH, None);
+        let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    // off by default.
+    let (status, _) = get_caps(&server_with(&dir, "", "")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // public, without credentials.
+    let public = server_with(&dir, r#"capabilities = "public""#, "");
+    let (status, caps) = get_caps(&public).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(caps["locations"][0]["locking"], true);
+    assert_eq!(caps["locations"][0]["precompressed"], false);
+    assert_eq!(caps["locations"][0]["auth_type"], "htpasswd");
+    let resp = public.route(request("PUT", capabilities::PATH, None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+    // a feature that is turned on shows up.
+    let extra = "precompressed = true\nverify-checksum = true";
+    let (_, caps) = get_caps(&server_with(&dir, r#"capabilities = "public""#, extra)).await;
+    assert_eq!(caps["locations"][0]["precompressed"], true);
+    assert_eq!(caps["locations"][0]["verify_checksum"], true);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_trace_refused() {
+    let dir = tempdir();
+    let server = server(&dir, "[admin]\nroute = \"/.admin\"\nusers = [ \"alice\" ]");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    for (method, path) in &[("TRACE", "/alice/"), ("TRACK", "/alice/"), ("TRACE", "/.admin/metrics")] {
+        let resp = server.route(request(method, path, creds), addr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED, "{} {}", method, path);
+    }
+
+    let resp = server.route(request("OPTIONS", "/alice/", creds), addr).await.unwrap();
+    let allow = resp.headers().get("allow").unwrap().to_str().unwrap();
+    assert!(allow.contains("PROPFIND"), "{}", allow);
+    assert!(!allow.contains("TRACE") && !allow.contains("TRACK"), "{}", allow);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_star_preconditions() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+    let req = |method: &str, path: &str, header: &'static str| {
+        let mut req = request(method, path, Some(("alice", "secret")));
+        req.headers_mut().insert(header, "*".parse().unwrap());
+        req.headers_mut().insert("destination", "/alice/copy".parse().unwrap());
+        req
+    };
+    let status = |req| async { server.route(req, addr).await.unwrap().status() };
+
+    // create only if it does not exist.
+    assert_eq!(status(req("PUT", "/alice/file", "if-none-match")).await, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "data");
+    assert_eq!(status(req("PUT", "/alice/new", "if-none-match")).await, StatusCode::CREATED);
+
+    // modify only if it exists.
+    for method in &["PUT", "DELETE", "COPY", "MOVE"] {
+        let resp = status(req(method, "/alice/missing", "if-match")).await;
+        assert_eq!(resp, StatusCode::PRECONDITION_FAILED, "{}", method);
+    }
+    assert!(!dir.join("missing").exists());
+    assert_eq!(status(req("PUT", "/alice/file", "if-match")).await, StatusCode::NO_CONTENT);
+    assert_eq!(status(req("COPY", "/alice/file", "if-match")).await, StatusCode::CREATED);
+    assert_eq!(status(req("DELETE", "/alice/new", "if-match")).await, StatusCode::NO_CONTENT);
+    let resp = status(req("DELETE", "/alice/file", "if-none-match")).await;
+    assert_eq!(resp, StatusCode::PRECONDITION_FAILED);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_path_limits() {
+    let dir = tempdir();
+    std::fs::write(dir.join("f.txt"), "data").unwrap();
+    let server = server_with(&dir, "max-path-length = 20\nmax-path-segments = 3", "");
+    let creds = Some(("alice", "secret"));
+    async fn status(server: &Server, req: HttpRequest) -> StatusCode {
+        server.route(req, ADDR.parse().unwrap()).await.unwrap().status()
+    }
+    assert_eq!(status(&server, request("GET", "/alice/f.txt", creds)).await, StatusCode::OK);
+
+    // checked after normalization: "/alice/a/../f.txt" is 2 segments.
+    let req = request("GET", "/alice/a/b/../../f.txt", creds);
+    assert_eq!(status(&server, req).await, StatusCode::OK);
+
+    let long = format!("/alice/{}", "x".repeat(20));
+    assert_eq!(status(&server, request("GET", &long, creds)).await, StatusCode::URI_TOO_LONG);
+    let deep = "/alice/a/b/c";
+    assert_eq!(status(&server, request("PUT", deep, creds)).await, StatusCode::FORBIDDEN);
+
+    // the Destination too.
+    let mut req = request("COPY", "/alice/f.txt", creds);
+    req.headers_mut().insert("destination", long.parse().unwrap());
+    assert_eq!(status(&server, req).await, StatusCode::URI_TOO_LONG);
+    assert!(!dir.join("x".repeat(20)).exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_keep_alive_versions() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let dav_server = server(&dir, "");
+    // send `first` and then a request that closes, see what comes back.
+    let exchange = |first: &'static str| {
+        let dav_server = dav_server.clone();
+        async move {
+            let (mut client, conn) = tokio::io::duplex(65536);
+            let service = service_fn(move |req| {
+                let dav_server = dav_server.clone();
+                async move { dav_server.route(req, ADDR.parse().unwrap()).await }
+            });
+            let conn = tokio::spawn(hyper::server::conn::Http::new().serve_connection(conn, service));
+            let last = "GET /alice/file HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n";
+            client.write_all(format!("{}{}", first, last).as_bytes()).await.unwrap();
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            conn.await.unwrap().unwrap();
+            resp
+        }
+    };
+
+    // HTTP/1.0: closed after the response, unless it asks for keep-alive.
+    let resp = exchange("GET /alice/file HTTP/1.0\r\n\r\n").await;
+    assert_eq!(resp.matches(" 200 OK").count(), 1, "{}", resp);
+    assert!(resp.starts_with("HTTP/1.0 200 OK") && resp.contains("connection: close\r\n"), "{}", resp);
+    let resp = exchange("GET /alice/file HTTP/1.0\r\nConnection: TE, Keep-Alive\r\n\r\n").await;
+    assert_eq!(resp.matches(" 200 OK").count(), 2, "{}", resp);
+    assert!(resp.contains("connection: keep-alive\r\n"), "{}", resp);
+    // a body of unknown length ends with the connection.
+    let resp = exchange("PROPFIND /alice/ HTTP/1.0\r\nConnection: keep-alive\r\nDepth: 0\r\n\r\n").await;
+    assert!(resp.starts_with("HTTP/1.0 207 Multi-Status"), "{}", resp);
+    assert!(resp.contains("connection: close\r\n") && !resp.contains(" 200 OK\r\n"), "{}", resp);
+
+    // HTTP/1.1: kept open, unless it asks for close.
+    let resp = exchange("GET /alice/file HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert_eq!(resp.matches("HTTP/1.1 200 OK").count(), 2, "{}", resp);
+    let resp = exchange("GET /alice/file HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    assert_eq!(resp.matches("HTTP/1.1 200 OK").count(), 1, "{}", resp);
+    assert!(resp.contains("connection: close\r\n"), "{}", resp);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_admin_transfers() {
+    use hyper::body::HttpBody;
+    let dir = tempdir();
+    std::fs::write(dir.join("big"), vec![b'x'; 1 << 20]).unwrap();
+    let server = server(&dir, "[admin]\nroute = \"/admin\"\nusers = [ \"alice\" ]");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+    let transfers = |server: &Server| {
+        let server = server.clone();
+        async move {
+            let resp = server.route(request("GET", "/admin/transfers", creds), addr).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        }
+    };
+
+    let resp = server.route(request("GET", "/admin/transfers", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = server.route(request("GET", "/alice/big", creds), addr).await.unwrap();
+    let mut body = resp.into_body();
+    body.data().await.unwrap().unwrap();
+    let list = transfers(&server).await;
+    assert_eq!(list.as_array().unwrap().len(), 1);
+    assert_eq!(list[0]["method"], "GET");
+    assert_eq!(list[0]["path"], "/alice/big");
+    assert_eq!(list[0]["user"], "alice");
+    assert_eq!(list[0]["bytes_total"], 1 << 20);
+    let first = list[0]["bytes_done"].as_u64().unwrap();
+    assert!(first > 0 && first < 1 << 20, "{}", first);
+
+    body.data().await.unwrap().unwrap();
+    let list = transfers(&server).await;
+    let second = list[0]["bytes_done"].as_u64().unwrap();
+    assert!(second > first, "{} {}", first, second);
+
+    // gone when the body is dropped.
+    drop(body);
+    assert_eq!(transfers(&server).await.as_array().unwrap().len(), 0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_error_content_length() {
+    let dir = tempdir();
+    let page = dir.join("404.html");
+    std::fs::write(&page, "<h1>Nothing here</h1>").unwrap();
+    let extra = format!("[error-pages]\n404 = \"{}\"", page.display());
+    let server = server(&dir, &extra);
+    let addr = ADDR.parse().unwrap();
+    let get = |method: &str, accept: &str| {
+        let mut req = request(method, "/alice/nonexistent", None);
+        req.headers_mut().insert("accept", accept.parse().unwrap());
+        server.route(req, addr)
+    };
+    let length = |resp: &hyper::Response<hyper::Body>| -> usize {
+        resp.headers()["content-length"].to_str().unwrap().parse().unwrap()
+    };
+
+    let resp = get("GET", "text/html").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(length(&resp), 21);
+    assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap().len(), 21);
+    let resp = get("HEAD", "text/html").await.unwrap();
+    assert_eq!(length(&resp), 21);
+    assert!(hyper::body::to_bytes(resp.into_body()).await.unwrap().is_empty());
+
+    // the error body of the server itself.
+    let resp = server.route(request("FOO", "/alice/", None), addr).await.unwrap();
+    assert!(resp.status().is_client_error());
+    let len = length(&resp);
+    assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap().len(), len);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_control_socket() {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let dir = tempdir();
+    let server = server(&dir, "stat-cache = 60");
+    let ls = server.locks.locksystem("/home/a");
+    let lock = ls.lock(&DavPath::new("/file").unwrap(), Some("a"), None, None, false, false).unwrap();
+    let statcache = server.statcaches[0].as_ref().unwrap();
+    statcache.insert_dir(dir.clone(), 0, statcache::Kind::ReadDir, Arc::new(vec![]));
+
+    let path = dir.join("control.sock");
+    let socket = control::ControlSocket::bind(path.to_str().unwrap()).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode, 0o600);
+    let drain = CancelToken::default();
+    let (srv, drain2) = (server.clone(), drain.clone());
+    tokio::spawn(socket.serve(move |command, uid| srv.control(command, uid, &drain2)));
+
+    let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let (read, mut write) = stream.into_split();
+    write.write_all(b"list-locks\nflush-cache\nreload\nlist-locks /home/b\n").await.unwrap();
+    let mut lines = BufReader::new(read).lines();
+    let mut answers = Vec::new();
+    for _ in 0..4 {
+        let line = lines.next_line().await.unwrap().unwrap();
+        answers.push(serde_json::from_str::<serde_json::Value>(&line).unwrap());
+    }
+    assert_eq!(answers[0][0]["token"], lock.token.as_str());
+    assert_eq!(answers[0][0]["path"], "/home/a/file");
+    assert_eq!(answers[1]["statcache"], 1);
+    assert_eq!(statcache.stats().entries, 0);
+    assert!(answers[2]["error"].is_string());
+    assert_eq!(answers[3], serde_json::json!([]));
+    assert!(!drain.is_cancelled());
+
+    // a leftover socket is replaced, other files are not.
+    assert!(control::ControlSocket::bind(path.to_str().unwrap()).is_ok());
+    let file = dir.join("file");
+    std::fs::write(&file, "data").unwrap();
+    assert!(control::ControlSocket::bind(file.to_str().unwrap()).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_log_bodies() {
+    requestid::tests::capture_log();
+    let dir = tempdir();
+    let server = server_with(&dir, "log-bodies = [ \"propfind\" ]\nlog-body-max = 40", "");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    let mut req = request("PROPFIND", "/alice/", creds);
+    req.headers_mut().insert("depth", "0".parse().unwrap());
+    let body = concat!(
+        r#"<D:propfind xmlns:D="DAV:"><D:prop><D:getetag/>"#,
+        r#"<X:logtest xmlns:X="urn:logtest"/></D:prop></D:propfind>"#
+    );
+    req.headers_mut().insert("content-length", body.len().into());
+    *req.body_mut() = body.into();
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    // truncated, and without the credentials.
+    let logged = requestid::tests::logged("request: PROPFIND /alice/");
+    let line = logged.iter().find(|l| l.contains(&format!("{} bytes", body.len()))).unwrap();
+    assert!(line.contains(r#"<D:propfind xmlns:D=\"DAV:\"><D:prop><D:ge..."#));
+    assert!(line.contains("authorization: [redacted]") && !line.contains("logtest"));
+    assert!(!requestid::tests::logged("response: PROPFIND /alice/").is_empty());
+
+    let mut req = request("PUT", "/alice/file", creds);
+    req.headers_mut().insert("content-length", 16.into());
+    *req.body_mut() = "secret-file-body".into();
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(requestid::tests::logged("secret-file-body").is_empty());
+    assert!(requestid::tests::logged("PUT /alice/file").is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_max_uri_length() {
+    let dir = tempdir();
+    std::fs::write(dir.join("f.txt"), "data").unwrap();
+    let server = server_with(&dir, "max-uri-length = 40", "");
+    let creds = Some(("alice", "secret"));
+    async fn status(server: &Server, req: HttpRequest) -> StatusCode {
+        server.route(req, ADDR.parse().unwrap()).await.unwrap().status()
+    }
+
+    // the query counts, and the target as sent, before normalization.
+    let under = format!("/alice/f.txt?{}", "q".repeat(40 - 13));
+    assert_eq!(status(&server, request("GET", &under, creds)).await, StatusCode::OK);
+    let over = format!("{}q", under);
+    assert_eq!(status(&server, request("GET", &over, creds)).await, StatusCode::URI_TOO_LONG);
+    let dotted = format!("/alice/{}/../f.txt", "a".repeat(30));
+    assert_eq!(status(&server, request("GET", &dotted, creds)).await, StatusCode::URI_TOO_LONG);
+
+    // before authentication.
+    assert_eq!(status(&server, request("GET", &over, None)).await, StatusCode::URI_TOO_LONG);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_duplicate_headers() {
+    let dir = tempdir();
+    let creds = Some(("alice", "secret"));
+    let smuggled = |extra: &[(&'static str, &'static str)]| {
+        let mut req = request("PUT", "/alice/f.txt", creds);
+        for (name, value) in extra {
+            req.headers_mut().append(*name, value.parse().unwrap());
+        }
+        req
+    };
+    let cases: &[&[(&str, &str)]] = &[
+        &[("transfer-encoding", "chunked")],
+        &[("content-length", "5")],
+        &[("host", "a.example.com"), ("host", "b.example.com")],
+    ];
+
+    let server = server(&dir, "");
+    for extra in cases {
+        let resp = server.route(smuggled(extra), ADDR.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{:?}", extra);
+        assert_eq!(resp.headers()["connection"], "close");
+    }
+    assert!(!dir.join("f.txt").exists());
+    let resp = server.route(smuggled(&[("host", "a")]), ADDR.parse().unwrap()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let server = server_with(&dir, "duplicate-headers = \"allow\"", "");
+    let req = smuggled(&[("host", "a"), ("host", "b")]);
+    assert_eq!(server.route(req, ADDR.parse().unwrap()).await.unwrap().status(), StatusCode::NO_CONTENT);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_shed_load() {
+    let dir = tempdir();
+    std::fs::write(dir.join("f.txt"), "data").unwrap();
+    let server = server_with(&dir, "shed-memory = 1000\nshed-retry-after = 7", "");
+    let creds = Some(("alice", "secret"));
+    let addr = ADDR.parse().unwrap();
+
+    // in flight before the overload.
+    let inflight = server.route(request("GET", "/alice/f.txt", creds), addr).await.unwrap();
+    assert_eq!(inflight.status(), StatusCode::OK);
+
+    let overload = server.memory.reserve(1000).await;
+    let resp = server.route(request("GET", "/alice/f.txt", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers()["retry-after"], "7");
+    let resp = server.route(request("PUT", "/alice/g.txt", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(!dir.join("g.txt").exists());
+    let resp = server.route(request("OPTIONS", "/alice/", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(inflight.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"data");
+    assert_eq!(server.shedder.shed().get("memory"), Some(&2));
+
+    drop(overload);
+    let resp = server.route(request("GET", "/alice/f.txt", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,843 @@
+//! PUT, MKCOL, COPY and MOVE, and the limits on uploads.
+//!
+use super::*;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_put_length() {
+    let dir = tempdir();
+    let server = server(&dir, "");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    // HTTP/1.0 without Content-Length: 411.
+    let mut req = request("PUT", "/alice/file", creds);
+    *req.version_mut() = http::Version::HTTP_10;
+    req.headers_mut().remove("content-length");
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::LENGTH_REQUIRED);
+    assert!(!dir.join("file").exists());
+
+    // With Content-Length, or chunked (HTTP/1.1), it is fine.
+    let mut req = request("PUT", "/alice/file", creds);
+    *req.version_mut() = http::Version::HTTP_10;
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let mut req = request("PUT", "/alice/file2", creds);
+    req.headers_mut().remove("content-length");
+    req.headers_mut().insert("transfer-encoding", "chunked".parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(std::fs::read(dir.join("file2")).unwrap(), b"data");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// Resident set size of the test process, in bytes.
+fn rss() -> usize {
+    let statm = std::fs::read_to_string("/proc/self/statm").unwrap();
+    let pages: usize = statm.split_whitespace().nth(1).unwrap().parse().unwrap();
+    pages * 4096
+}
+
+// `uploads` PUTs of `size` bytes at the same time, with a
+// max-upload-buffer of 256 KiB. Returns the peak of the buffered
+// bytes, and how much the RSS of the process grew.
+async fn put_bounded(uploads: usize, size: usize) -> (usize, usize) {
+    static CHUNK: [u8; 65536] = [0u8; 65536];
+
+    let dir = tempdir();
+    let server = server_with(&dir, "max-upload-buffer = 262144", "");
+    let addr = ADDR.parse().unwrap();
+    let start = rss();
+
+    // Watch memory use while the uploads run.
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let watcher = {
+        let (done, uploads) = (done.clone(), server.uploads.clone());
+        tokio::spawn(async move {
+            let (mut peak_rss, mut peak_buffered) = (0, 0);
+            while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                peak_rss = peak_rss.max(rss());
+                peak_buffered = peak_buffered.max(uploads.in_use());
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            (peak_rss, peak_buffered)
+        })
+    };
+
+    let puts = (0..uploads).map(|n| {
+        let chunks = (0..size / CHUNK.len()).map(|_| Ok::<_, io::Error>(bytes::Bytes::from_static(&CHUNK)));
+        let mut req = request("PUT", &format!("/alice/file{}", n), Some(("alice", "secret")));
+        req.headers_mut().insert("content-length", size.into());
+        *req.body_mut() = hyper::Body::wrap_stream(futures::stream::iter(chunks));
+        server.route(req, addr)
+    });
+    for resp in futures::future::join_all(puts).await {
+        assert_eq!(resp.unwrap().status(), StatusCode::CREATED);
+    }
+    done.store(true, std::sync::atomic::Ordering::SeqCst);
+    let (peak_rss, peak_buffered) = watcher.await.unwrap();
+
+    for n in 0..uploads {
+        let meta = std::fs::metadata(dir.join(format!("file{}", n))).unwrap();
+        assert_eq!(meta.len() as usize, size);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+    (peak_buffered, peak_rss.saturating_sub(start))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_put_bounded_buffer() {
+    let (peak_buffered, _) = put_bounded(4, 4 * 1024 * 1024).await;
+    assert!(peak_buffered <= 262144);
+}
+
+// Writes 512 MB, and looks at the RSS of the whole test process, so
+// other tests that run at the same time throw it off. Run it alone:
+// cargo test test_put_bounded_memory -- --ignored --test-threads=1
+#[ignore]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_put_bounded_memory() {
+    const MB: usize = 1024 * 1024;
+    let (peak_buffered, grown) = put_bounded(4, 128 * MB).await;
+    assert!(peak_buffered <= 262144);
+    assert!(grown < 64 * MB, "rss grew by {}", grown);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_auto_mkcol() {
+    let creds = Some(("alice", "secret"));
+    let addr = ADDR.parse().unwrap();
+
+    // Default: parents must exist.
+    let dir = tempdir();
+    let strict = server(&dir, "");
+    let resp = strict.route(request("PUT", "/alice/a/b/c/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    assert!(!dir.join("a").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // auto-mkcol: parents are created, for PUT and for COPY.
+    let dir = tempdir();
+    let auto = server(&dir, "auto-mkcol = true");
+    let resp = auto.route(request("PUT", "/alice/a/b/c/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.join("a/b/c/file").is_file());
+
+    let mut req = request("COPY", "/alice/a/b/c/file", creds);
+    let dest = "http://localhost/alice/x/y%20z/file".parse().unwrap();
+    req.headers_mut().insert("destination", dest);
+    let resp = auto.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.join("x/y z/file").is_file());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // more missing parents than auto-mkcol-max-depth: none are created.
+    let dir = tempdir();
+    let limited = server(&dir, "auto-mkcol = true\nauto-mkcol-max-depth = 2");
+    let resp = limited.route(request("PUT", "/alice/a/b/c/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    assert!(!dir.join("a").exists());
+    let resp = limited.route(request("PUT", "/alice/a/b/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let resp = limited.route(request("PUT", "/alice/a/b/c/d/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.join("a/b/c/d/file").is_file());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_upload_filter() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "old").unwrap();
+    let sock = uploadfilter::tests::fake_clamd();
+    let server = server(&dir, &format!("upload-filter = \"clamd:{}\"", sock.display()));
+    let addr = ADDR.parse().unwrap();
+    let put = |path, data: &'static str| {
+        let mut req = request("PUT", path, Some(("alice", "secret")));
+        req.headers_mut().insert("content-length", data.len().into());
+        *req.body_mut() = hyper::Body::from(data);
+        server.route(req, addr)
+    };
+    let names = || {
+        let entries = std::fs::read_dir(&dir).unwrap();
+        let mut names: Vec<_> = entries.map(|e| e.unwrap().file_name()).collect();
+        names.sort();
+        names
+    };
+
+    let resp = put("/alice/new", "clean").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(std::fs::read_to_string(dir.join("new")).unwrap(), "clean");
+
+    // a rejected upload leaves the old file alone.
+    let resp = put("/alice/file", "an EICAR test").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "old");
+    let resp = put("/alice/file", "new").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "new");
+
+    // no temporary files left.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(names(), vec!["file", "htpasswd", "new"]);
+
+    std::fs::remove_file(&sock).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_extended_mkcol() {
+    let dir = tempdir();
+    let addr = ADDR.parse().unwrap();
+    let mkcol = |path: &str, body: &str| {
+        let mut req = request("MKCOL", path, Some(("alice", "secret")));
+        if !body.is_empty() {
+            req.headers_mut().insert("content-type", "application/xml".parse().unwrap());
+            req.headers_mut().insert("content-length", body.len().into());
+            *req.body_mut() = hyper::Body::from(body.to_string());
+        }
+        req
+    };
+    let collection = concat!(
+        r#"<?xml version="1.0"?><D:mkcol xmlns:D="DAV:"><D:set><D:prop>"#,
+        "<D:resourcetype><D:collection/></D:resourcetype></D:prop></D:set></D:mkcol>"
+    );
+    let dead = concat!(
+        r#"<?xml version="1.0"?><D:mkcol xmlns:D="DAV:" xmlns:Z="urn:z"><D:set><D:prop>"#,
+        "<D:resourcetype><D:collection/></D:resourcetype><Z:color>blue</Z:color>",
+        "</D:prop></D:set></D:mkcol>"
+    );
+
+    // without extended-mkcol, a body is refused.
+    let plain = server(&dir, "");
+    let resp = plain.route(mkcol("/alice/a", collection), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert!(!dir.join("a").exists());
+
+    let server = server(&dir, "extended-mkcol = true");
+    let resp = server.route(mkcol("/alice/plain", ""), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.join("plain").is_dir());
+    let resp = server.route(mkcol("/alice/a", collection), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.join("a").is_dir());
+
+    // there is no property store, so the MKCOL is refused, and
+    // nothing is created.
+    let resp = server.route(mkcol("/alice/b", dead), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body = String::from_utf8_lossy(&body);
+    assert!(body.contains("mkcol-response"));
+    assert!(body.contains(r#"<P:color xmlns:P="urn:z"/></D:prop><D:status>HTTP/1.1 403"#));
+    assert!(!dir.join("b").exists());
+    // also when it is there already.
+    std::fs::create_dir(dir.join("b")).unwrap();
+    let resp = server.route(mkcol("/alice/b", dead), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(dir.join("b").is_dir());
+
+    let other = r#"<D:propertyupdate xmlns:D="DAV:"/>"#;
+    let resp = server.route(mkcol("/alice/c", other), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_move_across_filesystems() {
+    use crate::crossfs::tests::{get_xattr, other_fs, set_mtime, set_xattr};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let other = match other_fs() {
+        Some(other) => other,
+        None => return,
+    };
+    let dir = tempdir();
+    std::os::unix::fs::symlink(&other, dir.join("other")).unwrap();
+    let file = dir.join("file");
+    std::fs::write(&file, "data").unwrap();
+    std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+    set_xattr(&file, "user.color", b"blue");
+    set_mtime(&file, 1_000_000_000);
+
+    let server = server(&dir, "");
+    let mut req = request("MOVE", "/alice/file", Some(("alice", "secret")));
+    req.headers_mut().insert("destination", "/alice/other/file".parse().unwrap());
+    let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    assert!(!file.exists());
+    let moved = other.join("file");
+    assert_eq!(std::fs::read_to_string(&moved).unwrap(), "data");
+    let meta = std::fs::metadata(&moved).unwrap();
+    assert_eq!(meta.mtime(), 1_000_000_000);
+    assert_eq!(meta.mode() & 0o7777, 0o600);
+    assert_eq!(get_xattr(&moved, "user.color").as_deref(), Some(&b"blue"[..]));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    std::fs::remove_dir_all(&other).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_chunked_upload() {
+    let dir = tempdir();
+    let server = server(&dir, "chunked-uploads = \"uploads\"");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+    let put = |path: &str, body: &'static str| {
+        let mut req = request("PUT", path, creds);
+        req.headers_mut().insert("content-length", body.len().into());
+        *req.body_mut() = hyper::Body::from(body);
+        req
+    };
+    let assemble = |id: &str, dest: &str, total: Option<usize>| {
+        let mut req = request("MOVE", &format!("/alice/uploads/{}/.file", id), creds);
+        req.headers_mut().insert("destination", dest.parse().unwrap());
+        if let Some(total) = total {
+            req.headers_mut().insert("oc-total-length", total.into());
+        }
+        req
+    };
+
+    // the upload directory is created when needed.
+    let resp = server.route(request("MKCOL", "/alice/uploads/web-1", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    // chunks are in the order of their numbers, not their names.
+    for (n, data) in &[("2", "lo, "), ("10", "world"), ("1", "hel")] {
+        let resp = server.route(put(&format!("/alice/uploads/web-1/{}", n), data), addr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
+
+    let resp = server.route(assemble("web-1", "/alice/file", Some(11)), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = server.route(assemble("web-1", "/alice/nodir/file", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+    let resp = server.route(assemble("web-1", "/alice/file", Some(12)), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(resp.headers().contains_key("oc-etag"));
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "hello, world");
+    assert!(!dir.join("uploads/web-1").exists());
+    let tmp = std::fs::read_dir(&dir).unwrap().flatten();
+    assert!(!tmp.map(|e| e.file_name()).any(|n| n.to_string_lossy().starts_with(".chunked-")));
+
+    // replacing a file, unless Overwrite: F.
+    let resp = server.route(request("MKCOL", "/alice/uploads/web-2", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let resp = server.route(put("/alice/uploads/web-2/1", "new"), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let mut req = assemble("web-2", "/alice/file", None);
+    req.headers_mut().insert("overwrite", "F".parse().unwrap());
+    let resp = server.route(req, addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+    let resp = server.route(assemble("web-2", "/alice/file", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "new");
+
+    // not a chunk.
+    let resp = server.route(request("MKCOL", "/alice/uploads/web-3", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let resp = server.route(put("/alice/uploads/web-3/one", "x"), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let resp = server.route(assemble("web-3", "/alice/other", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(!dir.join("other").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_upload_rules() {
+    let dir = tempdir();
+    let extra = concat!(
+        "deny-upload = [ \"php\" ]\n",
+        "allow-upload = [ \"jpg\", \"txt\" ]\n",
+        "deny-upload-types = [ \"text/html\" ]"
+    );
+    let server = server(&dir, extra);
+    let addr = ADDR.parse().unwrap();
+    let put = |path: &str, content_type: &str| {
+        let mut req = request("PUT", path, Some(("alice", "secret")));
+        req.headers_mut().insert("content-length", 4.into());
+        req.headers_mut().insert("content-type", content_type.parse().unwrap());
+        *req.body_mut() = hyper::Body::from("data");
+        server.route(req, addr)
+    };
+
+    let resp = put("/alice/photo.JPG", "image/jpeg").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.join("photo.JPG").exists());
+
+    for name in &["shell.php.jpg", "index.php", "notes.pdf"] {
+        let resp = put(&format!("/alice/{}", name), "image/jpeg").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{}", name);
+        assert!(!dir.join(name).exists());
+    }
+    let resp = put("/alice/page.txt", "text/html; charset=utf-8").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert!(!dir.join("page.txt").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_put_status() {
+    let dir = tempdir();
+    let server = server(&dir, "auto-mkcol = true");
+    let addr = ADDR.parse().unwrap();
+    let put = |path: &str, data: &'static str| {
+        let mut req = request("PUT", path, Some(("alice", "secret")));
+        req.headers_mut().insert("content-length", data.len().into());
+        *req.body_mut() = hyper::Body::from(data);
+        server.route(req, addr)
+    };
+
+    // a new file is created, an existing one overwritten.
+    let resp = put("/alice/file", "data").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let resp = put("/alice/file", "new data").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "new data");
+
+    // also when auto-mkcol created the parents first.
+    let resp = put("/alice/a/b/file", "data").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let resp = put("/alice/a/b/file", "data").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_verify_checksum() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "old").unwrap();
+    let sock = uploadfilter::tests::fake_clamd();
+    let extra = format!("verify-checksum = true\nupload-filter = \"clamd:{}\"", sock.display());
+    let server = server(&dir, &extra);
+    let addr = ADDR.parse().unwrap();
+    let put = |path, data: &'static str, header: Option<(&'static str, &str)>| {
+        let mut req = request("PUT", path, Some(("alice", "secret")));
+        req.headers_mut().insert("content-length", data.len().into());
+        if let Some((name, value)) = header {
+            req.headers_mut().insert(name, value.parse().unwrap());
+        }
+        *req.body_mut() = hyper::Body::from(data);
+        server.route(req, addr)
+    };
+    // md5 and sha-256 of "hello world".
+    let md5 = Some(("content-md5", "XrY7u+Ae7tCTyyK7j1rNww=="));
+    let sha256 = Some(("digest", "sha-256=uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek="));
+
+    // a corrupted upload leaves the old file alone.
+    let resp = put("/alice/file", "hello w0rld", md5).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "old");
+    let resp = put("/alice/file", "hello world", sha256).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "hello world");
+
+    let resp = put("/alice/new", "data", Some(("content-md5", "garbage"))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(!dir.join("new").exists());
+    let resp = put("/alice/new", "data", None).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    // the upload filter still runs.
+    let digest = "md5=hh1jI0aJxqOlb/tdJmyokg==";
+    let resp = put("/alice/virus", "an EICAR test", Some(("digest", digest))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // the temporary files are gone before the response.
+    let mut names: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+    names.sort();
+    assert_eq!(names, vec!["file", "htpasswd", "new"]);
+
+    std::fs::remove_file(&sock).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_windows_names() {
+    let dir = tempdir();
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+    async fn put(server: &Server, path: &str) -> StatusCode {
+        let req = request("PUT", path, Some(("alice", "secret"))).map(|_| hyper::Body::from("data"));
+        server.route(req, ADDR.parse().unwrap()).await.unwrap().status()
+    }
+
+    let allow = server(&dir, "");
+    assert_eq!(put(&allow, "/alice/con").await, StatusCode::CREATED);
+    assert_eq!(put(&allow, "/alice/name.").await, StatusCode::CREATED);
+    assert!(dir.join("con").exists() && dir.join("name.").exists());
+
+    let reject = server(&dir, "windows-names = \"reject\"");
+    assert_eq!(put(&reject, "/alice/con").await, StatusCode::FORBIDDEN);
+    assert_eq!(put(&reject, "/alice/other.").await, StatusCode::FORBIDDEN);
+    assert_eq!(put(&reject, "/alice/other").await, StatusCode::CREATED);
+    assert!(!dir.join("other.").exists());
+
+    let escape = server(&dir, "windows-names = \"escape\"");
+    assert_eq!(put(&escape, "/alice/CON").await, StatusCode::CREATED);
+    assert_eq!(put(&escape, "/alice/file.").await, StatusCode::CREATED);
+    assert!(dir.join("_CON").exists() && dir.join("file_").exists());
+    assert!(!dir.join("CON").exists() && !dir.join("file.").exists());
+    let resp = escape.route(request("GET", "/alice/CON", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "data");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_max_concurrent_writes() {
+    let dir = tempdir();
+    // PUTs with a body that is sent later. `done` counts the responses.
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let done = Arc::new(AtomicUsize::new(0));
+    let put = |server: &Server, n: usize| {
+        let (tx, body) = hyper::Body::channel();
+        let mut req = request("PUT", &format!("/alice/file{}", n), Some(("alice", "secret")));
+        *req.body_mut() = body;
+        let (server, done) = (server.clone(), done.clone());
+        let resp = tokio::spawn(async move {
+            let resp = server.route(req, ADDR.parse().unwrap()).await.unwrap();
+            done.fetch_add(1, Ordering::SeqCst);
+            resp.status()
+        });
+        (tx, resp)
+    };
+    async fn wait_for(what: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if what() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out");
+    }
+
+    // Without a queue, the writes over the limit get a 503 right away.
+    let server = server_with(&dir, "max-concurrent-writes = 2\nwrite-queue-timeout = 0", "");
+    let puts: Vec<_> = (0..6).map(|n| put(&server, n)).collect();
+    wait_for(|| server.writes.active() == 2 && done.load(Ordering::SeqCst) == 4).await;
+    let mut status = Vec::new();
+    for (mut tx, resp) in puts {
+        let _ = tx.send_data("data".into()).await;
+        drop(tx);
+        status.push(resp.await.unwrap());
+    }
+    assert_eq!(status.iter().filter(|s| **s == StatusCode::CREATED).count(), 2, "{:?}", status);
+    assert_eq!(status.iter().filter(|s| **s == StatusCode::SERVICE_UNAVAILABLE).count(), 4);
+    assert_eq!(server.writes.active(), 0);
+
+    // With a queue they wait for their turn.
+    let server = server_with(&dir, "max-concurrent-writes = 2", "");
+    let puts: Vec<_> = (10..16).map(|n| put(&server, n)).collect();
+    wait_for(|| server.writes.active() == 2 && server.writes.waiting() == 4).await;
+    let mut status = Vec::new();
+    let resps: Vec<_> = puts
+        .into_iter()
+        .map(|(mut tx, resp)| {
+            tx.try_send_data("data".into()).unwrap();
+            resp
+        })
+        .collect();
+    for resp in resps {
+        assert!(server.writes.active() <= 2);
+        status.push(resp.await.unwrap());
+    }
+    assert!(status.iter().all(|s| *s == StatusCode::CREATED), "{:?}", status);
+    assert_eq!(server.writes.waiting(), 0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_memory_budget() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let server = server_with(&dir, "memory-budget = 200", "");
+    let addr = ADDR.parse().unwrap();
+    let propfind = |body: String| {
+        let mut req = request("PROPFIND", "/alice/", Some(("alice", "secret")));
+        req.headers_mut().insert("depth", "1".parse().unwrap());
+        req.headers_mut().insert("content-length", body.len().into());
+        *req.body_mut() = hyper::Body::from(body);
+        server.route(req, addr)
+    };
+    let allprop = r#"<?xml version="1.0"?><D:propfind xmlns:D="DAV:"><D:allprop/></D:propfind>"#;
+
+    // a body larger than the budget.
+    let resp = propfind(format!("{}{}", allprop, " ".repeat(200))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(resp.headers().contains_key("retry-after"));
+
+    // the response buffer is capped at the budget, and it is all given back.
+    let resp = propfind(allprop.to_string()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("/alice/file"));
+    let mut req = request("PUT", "/alice/upload", Some(("alice", "secret")));
+    req.headers_mut().insert("content-length", 1000.into());
+    *req.body_mut() = hyper::Body::from(vec![b'x'; 1000]);
+    assert_eq!(server.route(req, addr).await.unwrap().status(), StatusCode::CREATED);
+    assert_eq!(server.memory.used(), 0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_unexpected_body() {
+    let dir = tempdir();
+    std::fs::write(dir.join("f.txt"), "data").unwrap();
+    fn get(body: &str) -> HttpRequest {
+        let mut req = request("GET", "/alice/f.txt", None);
+        req.headers_mut().insert("content-length", body.len().into());
+        *req.body_mut() = hyper::Body::from(body.to_string());
+        req
+    }
+    let addr = ADDR.parse().unwrap();
+
+    // the default: the body is read and ignored.
+    let server = server(&dir, "");
+    let resp = server.route(get("ignored"), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "data");
+
+    let server = server_with(&dir, "unexpected-body = \"reject\"", "");
+    let resp = server.route(get("rejected"), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(server.route(get(""), addr).await.unwrap().status(), StatusCode::OK);
+
+    // too large to read: the connection is closed.
+    let resp = server.route(get(&"x".repeat(limits::MAX_DRAIN + 1)), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(resp.headers()["connection"], "close");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_one_filesystem() {
+    let other = match crate::crossfs::tests::other_fs() {
+        Some(other) => other,
+        None => return,
+    };
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    std::fs::write(other.join("elsewhere"), "data").unwrap();
+    std::os::unix::fs::symlink(&other, dir.join("other")).unwrap();
+    let plain = server(&dir, "");
+    let server = server(&dir, "one-filesystem = true");
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+    let copy = |to: &str| {
+        let mut req = request("COPY", "/alice/file", creds);
+        req.headers_mut().insert("destination", to.parse().unwrap());
+        req
+    };
+
+    let resp = plain.route(request("GET", "/alice/other/elsewhere", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = server.route(request("GET", "/alice/other/elsewhere", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = server.route(request("PUT", "/alice/other/new", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = server.route(copy("/alice/other/copy"), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(!other.join("copy").exists() && !other.join("new").exists());
+
+    // on the same device, as usual.
+    let resp = server.route(request("GET", "/alice/file", None), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = server.route(copy("/alice/copy"), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    std::fs::remove_dir_all(&other).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_put_range() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "0123456789").unwrap();
+    async fn put(server: &Server) -> HttpResult {
+        let mut req = request("PUT", "/alice/file", Some(("alice", "secret")));
+        req.headers_mut().insert("range", "bytes=2-5".parse().unwrap());
+        server.route(req, ADDR.parse().unwrap()).await
+    }
+
+    // by default, the Range header is ignored: the whole file.
+    let server = server(&dir, "");
+    assert_eq!(put(&server).await.unwrap().status(), StatusCode::NO_CONTENT);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "data");
+
+    std::fs::write(dir.join("file"), "0123456789").unwrap();
+    let reject = server_with(&dir, "", "put-range = \"reject\"");
+    assert_eq!(put(&reject).await.unwrap().status(), StatusCode::BAD_REQUEST);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "0123456789");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_write_windows() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "data").unwrap();
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+    let now = time::now_utc();
+    let min = (now.tm_hour * 60 + now.tm_min) as u32;
+    let hhmm = |m: u32| format!("{:02}:{:02}", (m % 1440) / 60, m % 60);
+    let config = |from: u32, to: u32| {
+        let window = format!("{}-{}", hhmm(from), hhmm(to));
+        format!("write-windows = [ \"{}\" ]\nwrite-windows-timezone = \"utc\"", window)
+    };
+
+    // now is in the window (which may go past midnight).
+    let inside = server_with(&dir, "", &config(min + 1440 - 60, min + 60));
+    let resp = inside.route(request("PUT", "/alice/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    let outside = server_with(&dir, "", &config(min + 60, min + 120));
+    let resp = outside.route(request("PUT", "/alice/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = outside.route(request("DELETE", "/alice/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = outside.route(request("GET", "/alice/file", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_checksum_mismatch_atomic() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "old version").unwrap();
+    let server = server(&dir, "verify-checksum = true\nfsync = true");
+    let addr = ADDR.parse().unwrap();
+    let put = |data: &'static str| {
+        let mut req = request("PUT", "/alice/file", Some(("alice", "secret")));
+        // sha-256 of "hello world".
+        let digest = "sha-256=uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=";
+        req.headers_mut().insert("digest", digest.parse().unwrap());
+        req.headers_mut().insert("content-length", data.len().into());
+        *req.body_mut() = hyper::Body::from(data);
+        server.route(req, addr)
+    };
+    let names = || {
+        let names = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name());
+        let mut names: Vec<_> = names.collect();
+        names.sort();
+        names
+    };
+
+    let resp = put("hello world, but longer").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "old version");
+    assert_eq!(names(), vec!["file", "htpasswd"]);
+
+    let resp = put("hello world").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "hello world");
+    assert_eq!(names(), vec!["file", "htpasswd"]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_chunked_upload_parents() {
+    let dir = tempdir();
+    let extra = "chunked-uploads = \"uploads\"\nauto-mkcol-max-depth = 2";
+    let server = server(&dir, extra);
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+
+    // more missing parents than auto-mkcol-max-depth: none are created.
+    let resp = server.route(request("MKCOL", "/alice/uploads/a/b/web-1", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    assert!(!dir.join("uploads").exists());
+
+    // not below a lock of someone else.
+    let ls = server.locks.locksystem(dir.to_str().unwrap());
+    let path = DavPath::new("/uploads/").unwrap();
+    let lock = ls.lock(&path, Some("bob"), None, None, false, true).unwrap();
+    let resp = server.route(request("MKCOL", "/alice/uploads/web-2", creds), addr).await.unwrap();
+    assert_ne!(resp.status(), StatusCode::CREATED);
+    assert!(!dir.join("uploads").exists());
+    ls.unlock(&path, &lock.token).unwrap();
+
+    let resp = server.route(request("MKCOL", "/alice/uploads/web-2", creds), addr).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.join("uploads/web-2").is_dir());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_chunked_upload_filter() {
+    let dir = tempdir();
+    std::fs::write(dir.join("file"), "old").unwrap();
+    let sock = uploadfilter::tests::fake_clamd();
+    let extra = format!(
+        "chunked-uploads = \"uploads\"\nverify-checksum = true\ndeny-upload-types = [ \"text/html\" ]\n\
+         upload-filter = \"clamd:{}\"",
+        sock.display()
+    );
+    let server = server(&dir, &extra);
+    let addr = ADDR.parse().unwrap();
+    let creds = Some(("alice", "secret"));
+    let upload = |id: &'static str, chunks: &'static [&'static str]| {
+        let server = &server;
+        async move {
+            let path = format!("/alice/uploads/{}", id);
+            let resp = server.route(request("MKCOL", &path, creds), addr).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::CREATED);
+            for (n, data) in chunks.iter().enumerate() {
+                let mut req = request("PUT", &format!("{}/{}", path, n + 1), creds);
+                req.headers_mut().insert("content-length", data.len().into());
+                *req.body_mut() = hyper::Body::from(*data);
+                let resp = server.route(req, addr).await.unwrap();
+                assert_eq!(resp.status(), StatusCode::CREATED);
+            }
+        }
+    };
+    let assemble = |id: &str, header: Option<(&'static str, &str)>| {
+        let mut req = request("MOVE", &format!("/alice/uploads/{}/.file", id), creds);
+        req.headers_mut().insert("destination", "/alice/file".parse().unwrap());
+        if let Some((name, value)) = header {
+            req.headers_mut().insert(name, value.parse().unwrap());
+        }
+        server.route(req, addr)
+    };
+
+    // every chunk is clean, the file they make is not.
+    upload("web-1", &["an EI", "CAR test"]).await;
+    let resp = assemble("web-1", None).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "old");
+
+    // the checksum is that of the whole file.
+    upload("web-2", &["hello ", "w0rld"]).await;
+    let resp = assemble("web-2", Some(("content-md5", "XrY7u+Ae7tCTyyK7j1rNww=="))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "old");
+
+    let resp = assemble("web-2", Some(("content-type", "text/html"))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let resp = assemble("web-2", None).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), "hello w0rld");
+
+    // no temporary files left.
+    let mut names: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+    names.sort();
+    assert_eq!(names, vec!["file", "htpasswd", "uploads"]);
+
+    std::fs::remove_file(&sock).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
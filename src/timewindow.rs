@@ -0,0 +1,206 @@
+//! Time windows for writes.
+//!
+//! With `write-windows` in a location, requests that change something
+//! (every method but GET, HEAD, OPTIONS and PROPFIND) are only let
+//! through inside one of the windows, and get "403 Forbidden" outside
+//! them. Reads always work. A window is a time range, on every day or
+//! on some days of the week:
+//!
+//! - "08:00-18:00": every day.
+//! - "mon-fri 08:00-18:00", "sat,sun 10:00-12:00": on those days.
+//! - "fri 22:00-02:00": from friday 22:00 to saturday 02:00. A window
+//!   that ends before it starts goes past midnight, and the days are
+//!   the days it starts on.
+//! - "00:00-24:00": the whole day.
+//!
+//! The time is the wall clock time in `write-windows-timezone`: "local"
+//! (the default, the timezone of the server, TZ in its environment),
+//! "utc", or a fixed offset like "+01:00". In local time, the windows
+//! follow DST: "08:00-18:00" is 08:00 to 18:00 on the clock both in
+//! winter and summer. On the day the clock jumps, a window is shorter
+//! or longer by the jump, and one that starts or ends in the hour that
+//! is skipped starts or ends at the jump.
+//!
+// Minutes of the day, and days of the week, 0 is sunday like in `Tm`.
+const DAY: u32 = 24 * 60;
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Zone {
+    Local,
+    Offset(i32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Window {
+    // bit N is day N.
+    days:  u8,
+    start: u32,
+    end:   u32,
+}
+
+impl Window {
+    fn parse(s: &str) -> Result<Window, String> {
+        let mut words = s.split_whitespace();
+        let (days, range) = match (words.next(), words.next(), words.next()) {
+            (Some(range), None, None) => (0x7f, range),
+            (Some(days), Some(range), None) => (parse_days(days)?, range),
+            _ => return Err("expected [days] HH:MM-HH:MM".to_string()),
+        };
+        let (start, end) = range.split_once('-').ok_or("expected HH:MM-HH:MM")?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == DAY {
+            return Err("a window cannot start at 24:00".to_string());
+        }
+        Ok(Window { days, start, end })
+    }
+
+    // Is minute `min` of day `wday` in this window.
+    fn contains(&self, wday: u32, min: u32) -> bool {
+        let on = |day: u32| self.days & (1 << (day % 7)) != 0;
+        if self.start < self.end {
+            on(wday) && min >= self.start && min < self.end
+        } else {
+            (on(wday) && min >= self.start) || (on(wday + 6) && min < self.end)
+        }
+    }
+}
+
+// "mon", "mon-fri", "sat,sun", "mon-wed,fri".
+fn parse_days(s: &str) -> Result<u8, String> {
+    let day = |d: &str| {
+        let d = d.to_ascii_lowercase();
+        DAYS.iter().position(|n| *n == d).ok_or(format!("{}: not a day", d))
+    };
+    let mut days = 0u8;
+    for part in s.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (day(first)?, day(last)?),
+            None => (day(part)?, day(part)?),
+        };
+        // "fri-mon" goes around the week.
+        let mut d = first;
+        loop {
+            days |= 1 << d;
+            if d == last {
+                break;
+            }
+            d = (d + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+// "HH:MM", up to "24:00".
+fn parse_time(s: &str) -> Result<u32, String> {
+    let err = || format!("{}: not a time (HH:MM)", s);
+    let (h, m) = s.split_once(':').ok_or_else(err)?;
+    let (h, m) = (h.parse::<u32>().map_err(|_| err())?, m.parse::<u32>().map_err(|_| err())?);
+    if m >= 60 || h * 60 + m > DAY {
+        return Err(err());
+    }
+    Ok(h * 60 + m)
+}
+
+fn parse_zone(s: &str) -> Result<Zone, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "local" => return Ok(Zone::Local),
+        "utc" => return Ok(Zone::Offset(0)),
+        _ => {},
+    }
+    let err = || format!("{}: expected local, utc, or +HH:MM", s);
+    let (sign, rest) = match s.split_at(1) {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => return Err(err()),
+    };
+    let min = parse_time(rest).map_err(|_| err())?;
+    if min > 14 * 60 {
+        return Err(err());
+    }
+    Ok(Zone::Offset(sign * min as i32 * 60))
+}
+
+/// The write windows of a location.
+#[derive(Debug, Clone)]
+pub struct WriteWindows {
+    windows: Vec<Window>,
+    zone:    Zone,
+}
+
+impl WriteWindows {
+    /// From `write-windows` and `write-windows-timezone`. `None` if there
+    /// are no windows: writes are always allowed.
+    pub fn new(windows: &[String], timezone: Option<&str>) -> Result<Option<WriteWindows>, String> {
+        let zone = parse_zone(timezone.unwrap_or("local"))?;
+        if windows.is_empty() {
+            return Ok(None);
+        }
+        let windows = windows
+            .iter()
+            .map(|w| Window::parse(w).map_err(|e| format!("{:?}: {}", w, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(WriteWindows { windows, zone }))
+    }
+
+    /// Are writes allowed now.
+    pub fn allowed_now(&self) -> bool {
+        let tm = match self.zone {
+            Zone::Local => time::now(),
+            Zone::Offset(offset) => {
+                let now = time::now_utc().to_timespec();
+                time::at_utc(time::Timespec::new(now.sec + offset as i64, 0))
+            },
+        };
+        self.allowed(tm.tm_wday as u32, (tm.tm_hour * 60 + tm.tm_min) as u32)
+    }
+
+    // Are writes allowed at minute `min` of day `wday`.
+    fn allowed(&self, wday: u32, min: u32) -> bool {
+        self.windows.iter().any(|w| w.contains(wday, min))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn windows(w: &[&str]) -> WriteWindows {
+        let w: Vec<String> = w.iter().map(|s| s.to_string()).collect();
+        WriteWindows::new(&w, Some("utc")).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_windows() {
+        let (mon, fri, sat, sun) = (1, 5, 6, 0);
+        let at = |h: u32, m: u32| h * 60 + m;
+
+        let office = windows(&["mon-fri 08:00-18:00"]);
+        assert!(office.allowed(mon, at(8, 0)) && office.allowed(fri, at(17, 59)));
+        assert!(!office.allowed(mon, at(18, 0)) && !office.allowed(mon, at(7, 59)));
+        assert!(!office.allowed(sat, at(12, 0)));
+
+        // past midnight, on the days it starts on.
+        let night = windows(&["fri 22:00-02:00"]);
+        assert!(night.allowed(fri, at(23, 0)) && night.allowed(sat, at(1, 59)));
+        assert!(!night.allowed(fri, at(1, 0)) && !night.allowed(sat, at(2, 0)));
+        let nightly = windows(&["22:00-06:00"]);
+        assert!(nightly.allowed(sun, at(5, 0)) && nightly.allowed(sat, at(22, 0)));
+        assert!(!nightly.allowed(sun, at(12, 0)));
+
+        let weekend = windows(&["sat,sun 00:00-24:00", "mon 09:00-10:00"]);
+        assert!(weekend.allowed(sun, at(23, 59)) && weekend.allowed(mon, at(9, 30)));
+        assert!(!weekend.allowed(mon, at(0, 0)));
+        assert!(windows(&["fri-mon 10:00-11:00"]).allowed(sun, at(10, 0)));
+
+        let parse = |w: &str| Window::parse(w);
+        assert!(parse("08:00").is_err() && parse("8:00-25:00").is_err());
+        assert!(parse("mon-xyz 08:00-09:00").is_err() && parse("24:00-08:00").is_err());
+        assert!(parse("mon 08:00-09:00 extra").is_err() && parse("08:60-09:00").is_err());
+        assert_eq!(parse_zone("+01:30"), Ok(Zone::Offset(5400)));
+        assert_eq!(parse_zone("-05:00"), Ok(Zone::Offset(-18000)));
+        assert_eq!(parse_zone("Local"), Ok(Zone::Local));
+        assert!(parse_zone("Europe/Amsterdam").is_err() && parse_zone("+15:00").is_err());
+        assert!(WriteWindows::new(&[], None).unwrap().is_none());
+    }
+}
@@ -1,10 +1,42 @@
+//! TLS configuration.
+//!
+//! Session resumption: TLS 1.2 session IDs are kept in a memory cache of
+//! `tls_session_cache` entries. TLS 1.2 and 1.3 session tickets are
+//! encrypted with keys that are rotated every half `tls_ticket_lifetime`,
+//! and are valid for one rotation after that, so a ticket works for at
+//! most `tls_ticket_lifetime` seconds.
+//!
+//! OCSP stapling: the (DER) OCSP response is read from a file, which is
+//! kept up-to-date by something else. We re-read it periodically.
+//!
+//! Client certificates: with `tls_client_ca`, clients can authenticate
+//! with a certificate signed by one of the CAs in that file. It is
+//! optional unless `tls_client_auth` is "required", in which case the
+//! handshake fails without one. See `clientcert` for what happens next.
+//!
 use std::fs::File;
 use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use tokio_rustls::rustls::internal::pemfile;
-use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use tokio_rustls::rustls::{ClientHello, NoClientAuth, ProducesTickets, ResolvesServerCert, ServerConfig};
+use tokio_rustls::rustls::RootCertStore;
+use tokio_rustls::rustls::{NoServerSessionStorage, ServerSessionMemoryCache, Ticketer};
 
-use crate::config::Server;
+use crate::config::{ClientAuth, Server};
+
+// Default number of entries in the session cache.
+const DEFAULT_SESSION_CACHE: usize = 256;
+
+// Default lifetime of session tickets: 12 hours, the most that the
+// rustls ticketer supports (its keys rotate every 6 hours).
+pub const DEFAULT_TICKET_LIFETIME: u32 = 43200;
+
+// Default interval at which the OCSP response file is re-read.
+const DEFAULT_OCSP_REFRESH: u64 = 3600;
 
 pub fn tls_config(cfg: &Server) -> io::Result<ServerConfig> {
     let pkey_fn = cfg.tls_key.as_ref().ok_or_else(|| {
@@ -30,10 +62,185 @@ pub fn tls_config(cfg: &Server) -> io::Result<ServerConfig> {
     let cert = pemfile::certs(&mut cert_file).map_err(|_| {
         io::Error::new(io::ErrorKind::InvalidData, format!("{}: invalid data", cert_fn))
     })?;
-    let mut config = ServerConfig::new(NoClientAuth::new());
-    config.set_single_cert(cert, pkey.pop().unwrap()).map_err(|e| {
-        io::Error::new(io::ErrorKind::InvalidData, format!("{}/{}: {}", pkey_fn, cert_fn, e))
-    })?;
+    let verifier = match (client_roots(cfg)?, cfg.tls_client_auth) {
+        (None, _) => NoClientAuth::new(),
+        (Some(roots), Some(ClientAuth::Required)) => AllowAnyAuthenticatedClient::new(roots),
+        (Some(roots), _) => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+    };
+    let mut config = ServerConfig::new(verifier);
+    match cfg.tls_ocsp.as_ref() {
+        Some(ocsp_fn) => {
+            let key = sign::any_supported_type(&pkey[0]).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: invalid private key", pkey_fn))
+            })?;
+            let stapled = Arc::new(Stapled::new(CertifiedKey::new(cert, Arc::new(key)), ocsp_fn)?);
+            let refresh = Duration::from_secs(cfg.tls_ocsp_refresh.unwrap_or(DEFAULT_OCSP_REFRESH));
+            tokio::spawn(stapled.clone().refresh(refresh));
+            config.cert_resolver = stapled;
+        },
+        None => {
+            config.set_single_cert(cert, pkey.pop().unwrap()).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}/{}: {}", pkey_fn, cert_fn, e))
+            })?;
+        },
+    }
+
+    // session resumption.
+    match cfg.tls_session_cache.unwrap_or(DEFAULT_SESSION_CACHE) {
+        0 => config.set_persistence(Arc::new(NoServerSessionStorage {})),
+        n => config.set_persistence(ServerSessionMemoryCache::new(n)),
+    }
+    let lifetime = cfg.tls_ticket_lifetime.unwrap_or(DEFAULT_TICKET_LIFETIME);
+    if lifetime > 0 {
+        config.ticketer = Arc::new(Tickets::new(lifetime));
+    }
+
     Ok(config)
 }
 
+// The CAs for client certificates, if any.
+fn client_roots(cfg: &Server) -> io::Result<Option<RootCertStore>> {
+    let ca_fn = match cfg.tls_client_ca.as_ref() {
+        Some(ca_fn) => ca_fn,
+        None => return Ok(None),
+    };
+    let ca_file = File::open(ca_fn).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", ca_fn, e)))?;
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut io::BufReader::new(ca_file)) {
+        Ok((n, _)) if n > 0 => Ok(Some(roots)),
+        _ => {
+            let msg = format!("{}: no valid CA certificates", ca_fn);
+            Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+        },
+    }
+}
+
+// Stapled OCSP response, re-read from a file periodically.
+struct Stapled {
+    key:  CertifiedKey,
+    file: String,
+    ocsp: RwLock<Option<Vec<u8>>>,
+}
+
+impl Stapled {
+    fn new(key: CertifiedKey, file: &str) -> io::Result<Stapled> {
+        let ocsp = read_ocsp(file)?;
+        Ok(Stapled {
+            key,
+            file: file.to_string(),
+            ocsp: RwLock::new(ocsp),
+        })
+    }
+
+    // If the file cannot be read, keep stapling the response we have.
+    async fn refresh(self: Arc<Self>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match read_ocsp(&self.file) {
+                Ok(ocsp) => *self.ocsp.write().unwrap() = ocsp,
+                Err(e) => warn!("tls: {}: {} (keeping the previous OCSP response)", self.file, e),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for Stapled {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        let mut key = self.key.clone();
+        key.ocsp = self.ocsp.read().unwrap().clone();
+        Some(key)
+    }
+}
+
+// An empty file means "no OCSP response".
+fn read_ocsp(file: &str) -> io::Result<Option<Vec<u8>>> {
+    let data = std::fs::read(file).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file, e)))?;
+    Ok(Some(data).filter(|d| !d.is_empty()))
+}
+
+// Session ticket keys, rotated every half lifetime. The previous
+// keys are kept for decryption only.
+struct Tickets {
+    lifetime: u32,
+    keys:     Mutex<TicketKeys>,
+}
+
+struct TicketKeys {
+    current:  Arc<dyn ProducesTickets>,
+    previous: Option<Arc<dyn ProducesTickets>>,
+    switched: Instant,
+}
+
+impl Tickets {
+    fn new(lifetime: u32) -> Tickets {
+        Tickets {
+            lifetime,
+            keys: Mutex::new(TicketKeys {
+                current:  Ticketer::new(),
+                previous: None,
+                switched: Instant::now(),
+            }),
+        }
+    }
+
+    fn keys(&self, now: Instant) -> (Arc<dyn ProducesTickets>, Option<Arc<dyn ProducesTickets>>) {
+        let mut keys = self.keys.lock().unwrap();
+        let half = Duration::from_secs(u64::from(self.lifetime / 2).max(1));
+        let elapsed = now.saturating_duration_since(keys.switched);
+        if elapsed >= half * 2 {
+            keys.previous = None;
+            keys.current = Ticketer::new();
+            keys.switched = now;
+        } else if elapsed >= half {
+            keys.previous = Some(keys.current.clone());
+            keys.current = Ticketer::new();
+            keys.switched = now;
+        }
+        (keys.current.clone(), keys.previous.clone())
+    }
+}
+
+impl ProducesTickets for Tickets {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.keys(Instant::now()).0.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let (current, previous) = self.keys(Instant::now());
+        current.decrypt(cipher).or_else(|| previous?.decrypt(cipher))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticket_rotation() {
+        let tickets = Tickets::new(3600);
+        let start = Instant::now();
+        let (current, _) = tickets.keys(start);
+        let ticket = current.encrypt(b"session").unwrap();
+
+        // after half the lifetime, the old keys still decrypt.
+        let (current, previous) = tickets.keys(start + Duration::from_secs(1800));
+        assert!(current.decrypt(&ticket).is_none());
+        assert_eq!(previous.unwrap().decrypt(&ticket).unwrap(), b"session");
+
+        // after the full lifetime they are gone.
+        let (current, previous) = tickets.keys(start + Duration::from_secs(3600));
+        assert!(current.decrypt(&ticket).is_none());
+        assert!(previous.unwrap().decrypt(&ticket).is_none());
+    }
+}
+
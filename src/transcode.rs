@@ -0,0 +1,177 @@
+//! Transcoding of GET responses.
+//!
+//! A `TranscodeHook` can send something else than the file on a GET:
+//! a thumbnail of an image, or the file in another format. It sees the
+//! request (the query string, the Accept header, the authenticated user)
+//! and decides what to send. The result is streamed; neither the file nor the result is
+//! read into memory here.
+//!
+//! The hook runs after authentication and after the webdav handler has
+//! checked the conditional headers, and only on a "200 OK" with the
+//! whole file: ranges, HEAD, collections and errors are sent as they
+//! are, and so are precompressed files (with a Content-Encoding). When
+//! the body is replaced, Content-Length, ETag and Accept-Ranges are
+//! removed, they were about the file.
+//!
+//! Every location has the `Passthrough` hook for now, which sends the
+//! file unchanged.
+//!
+use std::io;
+
+use futures::future::{BoxFuture, FutureExt};
+use http::header::{ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG};
+use http::{Response, StatusCode};
+
+/// The GET request, as passed to a transcode hook.
+#[allow(dead_code)]
+pub struct TranscodeRequest<'a> {
+    /// Request path (not stripped of the location prefix).
+    pub path:         &'a str,
+    /// The query string, if any.
+    pub query:        Option<&'a str>,
+    /// Value of the Accept: header, if any.
+    pub accept:       Option<&'a str>,
+    /// The authenticated user, if any (see `auth::authenticated`).
+    pub user:         Option<&'a str>,
+    /// Content-Type of the file.
+    pub content_type: Option<&'a str>,
+}
+
+/// What a hook made of the file.
+pub enum Transcoded {
+    /// Send the file as it is.
+    Unchanged(hyper::Body),
+    /// Send this body, with this Content-Type, instead.
+    #[allow(dead_code)]
+    Body(String, hyper::Body),
+}
+
+/// A transcode hook.
+pub trait TranscodeHook: Send + Sync {
+    /// Request headers that the hook looks at, for the Vary: header.
+    fn vary(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Transcode `file`, the body of the file, for `req`.
+    fn transcode<'a>(
+        &'a self,
+        req: &'a TranscodeRequest<'a>,
+        file: hyper::Body,
+    ) -> BoxFuture<'a, io::Result<Transcoded>>;
+}
+
+/// The default hook, it sends the file unchanged.
+pub struct Passthrough;
+
+impl TranscodeHook for Passthrough {
+    fn transcode<'a>(
+        &'a self,
+        _req: &'a TranscodeRequest<'a>,
+        file: hyper::Body,
+    ) -> BoxFuture<'a, io::Result<Transcoded>>
+    {
+        futures::future::ready(Ok(Transcoded::Unchanged(file))).boxed()
+    }
+}
+
+/// Run `hook` on the response to a GET of `path`.
+pub async fn apply(
+    hook: &dyn TranscodeHook,
+    path: &str,
+    query: Option<&str>,
+    accept: Option<&str>,
+    user: Option<&str>,
+    resp: Response<hyper::Body>,
+) -> io::Result<Response<hyper::Body>>
+{
+    if resp.status() != StatusCode::OK || resp.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(resp);
+    }
+    let (mut parts, body) = resp.into_parts();
+    let content_type = parts.headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    let req = TranscodeRequest {
+        path,
+        query,
+        accept,
+        user,
+        content_type,
+    };
+    let body = match hook.transcode(&req, body).await? {
+        Transcoded::Unchanged(body) => body,
+        Transcoded::Body(content_type, body) => {
+            let content_type = content_type
+                .parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "transcode: bad content type"))?;
+            parts.headers.insert(CONTENT_TYPE, content_type);
+            for name in &[CONTENT_LENGTH, ETAG, ACCEPT_RANGES] {
+                parts.headers.remove(name);
+            }
+            body
+        },
+    };
+    Ok(Response::from_parts(parts, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    // "?upper" sends the file in upper case.
+    struct Upper;
+
+    impl TranscodeHook for Upper {
+        fn transcode<'a>(
+            &'a self,
+            req: &'a TranscodeRequest<'a>,
+            file: hyper::Body,
+        ) -> BoxFuture<'a, io::Result<Transcoded>>
+        {
+            let res = match req.query {
+                Some("upper") => {
+                    let stream = file.map(|chunk| chunk.map(|c| c.to_ascii_uppercase()));
+                    Transcoded::Body("text/x-upper".to_string(), hyper::Body::wrap_stream(stream))
+                },
+                _ => Transcoded::Unchanged(file),
+            };
+            futures::future::ready(Ok(res)).boxed()
+        }
+    }
+
+    fn file(status: StatusCode) -> Response<hyper::Body> {
+        Response::builder()
+            .status(status)
+            .header("content-type", "text/plain")
+            .header("content-length", "5")
+            .header("etag", "\"1-2\"")
+            .body(hyper::Body::from("hello"))
+            .unwrap()
+    }
+
+    async fn body(resp: Response<hyper::Body>) -> String {
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        String::from_utf8_lossy(&body).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_transcode() {
+        let resp = apply(&Upper, "/f.txt", Some("upper"), None, None, file(StatusCode::OK)).await.unwrap();
+        assert_eq!(resp.headers()["content-type"], "text/x-upper");
+        assert!(resp.headers().get("content-length").is_none());
+        assert!(resp.headers().get("etag").is_none());
+        assert_eq!(body(resp).await, "HELLO");
+
+        // not asked for, not a whole file, or the default hook.
+        let resp = apply(&Upper, "/f.txt", None, None, None, file(StatusCode::OK)).await.unwrap();
+        assert_eq!(resp.headers()["content-length"], "5");
+        assert_eq!(body(resp).await, "hello");
+        let partial = file(StatusCode::PARTIAL_CONTENT);
+        let resp = apply(&Upper, "/f.txt", Some("upper"), None, None, partial).await.unwrap();
+        assert_eq!(body(resp).await, "hello");
+        let resp = apply(&Passthrough, "/f.txt", Some("upper"), None, None, file(StatusCode::OK)).await;
+        let resp = resp.unwrap();
+        assert_eq!(resp.headers()["etag"], "\"1-2\"");
+        assert_eq!(body(resp).await, "hello");
+    }
+}
@@ -0,0 +1,148 @@
+//! Transfers in progress, for the admin API.
+//!
+//! A GET response body, or a PUT request body, is wrapped while it is
+//! streamed. Every chunk adds its size to a counter of the transfer, so
+//! the bytes are counted per chunk (what the webdav handler reads from
+//! the file, or what hyper got from the socket), not per byte. The
+//! transfer is listed at `ROUTE/transfers` until the body is dropped:
+//! at the end, or when the client goes away.
+//!
+//! The total is the Content-Length, if there is one. The rate is the
+//! average since the start, in bytes per second.
+//!
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use bytes::Bytes;
+use futures::Stream;
+use serde_json::{json, Value};
+
+// A transfer in progress.
+struct Entry {
+    method: String,
+    path:   String,
+    user:   Option<String>,
+    total:  Option<u64>,
+    done:   Arc<AtomicU64>,
+    start:  Instant,
+}
+
+#[derive(Clone, Default)]
+pub struct Transfers {
+    next:    Arc<AtomicU64>,
+    entries: Arc<Mutex<BTreeMap<u64, Entry>>>,
+}
+
+impl Transfers {
+    pub fn new() -> Transfers {
+        Transfers::default()
+    }
+
+    /// Count what goes through `body`, as long as it is being sent.
+    pub fn wrap(
+        &self,
+        body: hyper::Body,
+        method: &http::Method,
+        path: &str,
+        user: Option<&str>,
+        total: Option<u64>,
+    ) -> hyper::Body
+    {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let done = Arc::new(AtomicU64::new(0));
+        let entry = Entry {
+            method: method.to_string(),
+            path: path.to_string(),
+            user: user.map(|u| u.to_string()),
+            total,
+            done: done.clone(),
+            start: Instant::now(),
+        };
+        self.entries.lock().unwrap().insert(id, entry);
+        hyper::Body::wrap_stream(Transfer {
+            body,
+            id,
+            done,
+            transfers: self.clone(),
+        })
+    }
+
+    /// The transfers in progress, oldest first.
+    pub fn list(&self) -> Value {
+        let entries = self.entries.lock().unwrap();
+        let list = entries.iter().map(|(id, e)| {
+            let done = e.done.load(Ordering::Relaxed);
+            let elapsed = e.start.elapsed().as_secs_f64();
+            let rate = if elapsed > 0.0 { (done as f64 / elapsed) as u64 } else { 0 };
+            json!({
+                "id": id,
+                "method": e.method,
+                "path": e.path,
+                "user": e.user,
+                "bytes_done": done,
+                "bytes_total": e.total,
+                "rate": rate,
+                "elapsed": elapsed,
+            })
+        });
+        Value::Array(list.collect())
+    }
+}
+
+struct Transfer {
+    body:      hyper::Body,
+    id:        u64,
+    done:      Arc<AtomicU64>,
+    transfers: Transfers,
+}
+
+impl Stream for Transfer {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &res {
+            this.done.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        self.transfers.entries.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::HttpBody;
+
+    #[tokio::test]
+    async fn test_transfers() {
+        let transfers = Transfers::new();
+        let chunks: Vec<Result<_, std::io::Error>> = vec![Ok("abc"), Ok("defg")];
+        let body = hyper::Body::wrap_stream(futures::stream::iter(chunks));
+        let mut body = transfers.wrap(body, &http::Method::GET, "/a/f", Some("alice"), Some(7));
+        assert_eq!(transfers.list()[0]["bytes_done"], 0);
+
+        body.data().await.unwrap().unwrap();
+        let list = transfers.list();
+        assert_eq!(list[0]["method"], "GET");
+        assert_eq!(list[0]["path"], "/a/f");
+        assert_eq!(list[0]["user"], "alice");
+        assert_eq!(list[0]["bytes_done"], 3);
+        assert_eq!(list[0]["bytes_total"], 7);
+        body.data().await.unwrap().unwrap();
+        assert_eq!(transfers.list()[0]["bytes_done"], 7);
+
+        drop(body);
+        assert_eq!(transfers.list().as_array().unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,209 @@
+//! Trash (soft delete).
+//!
+//! When a location has a trash directory configured, DELETE does not
+//! remove files, but moves them to `TRASH/STAMP/original/path` below the
+//! directory that is served. STAMP is `SECONDS-RANDOM`, where SECONDS
+//! is the time of the DELETE since the epoch. All files of one DELETE
+//! request end up below the same STAMP, so a deleted directory can be
+//! restored as a whole.
+//!
+//! The `Sweeper` knows about all trash directories that have been used,
+//! and periodically purges entries that are older than the retention period.
+//! It does the same for the directories of chunked uploads.
+//!
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{FsError, FsResult};
+
+use crate::suid::UgidSwitch;
+
+// How often the sweeper runs.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The trash of one request.
+#[derive(Debug, Clone)]
+pub struct Trash {
+    name:  String,
+    stamp: String,
+}
+
+impl Trash {
+    /// `name` is the name of the trash directory, in the root of the location.
+    pub fn new(name: &str) -> Trash {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let random = uuid::Uuid::new_v4().to_simple().to_string();
+        Trash {
+            name:  name.to_string(),
+            stamp: format!("{}-{}", now.as_secs(), &random[..8]),
+        }
+    }
+
+    /// Name of the trash directory.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Is `path` the trash directory, or something in it.
+    pub fn contains(&self, path: &DavPath) -> bool {
+        let rest = match path.as_bytes().strip_prefix(b"/") {
+            Some(rest) => rest,
+            None => return false,
+        };
+        match rest.strip_prefix(self.name.as_bytes()) {
+            Some(rest) => rest.is_empty() || rest.starts_with(b"/"),
+            None => false,
+        }
+    }
+
+    /// The directories that need to exist before `path` can be moved to
+    /// the trash, and the path in the trash. All of them are relative to
+    /// the root of the location.
+    pub fn paths(&self, path: &DavPath) -> FsResult<(Vec<DavPath>, DavPath)> {
+        let url = path.as_url_string();
+        let mut segs: Vec<&str> = url.split('/').filter(|s| !s.is_empty()).collect();
+        let last = segs.pop().ok_or(FsError::Forbidden)?;
+        let mut dir = format!("/{}", self.name);
+        let mut dirs = vec![dir.clone()];
+        for seg in std::iter::once(self.stamp.as_str()).chain(segs) {
+            dir = format!("{}/{}", dir, seg);
+            dirs.push(dir.clone());
+        }
+        let to = format!("{}/{}", dir, last);
+        let davpath = |s: &str| DavPath::new(s).map_err(|_| FsError::GeneralFailure);
+        let dirs = dirs.iter().map(|d| davpath(d)).collect::<FsResult<Vec<_>>>()?;
+        Ok((dirs, davpath(&to)?))
+    }
+}
+
+// A trash directory, as known by the sweeper.
+struct TrashDir {
+    retention: Duration,
+    creds:     Option<(u32, u32, Vec<u32>)>,
+    purge:     fn(&Path, Duration),
+}
+
+/// Purges old entries from trash directories.
+#[derive(Clone, Default)]
+pub struct Sweeper {
+    dirs: Arc<Mutex<HashMap<PathBuf, TrashDir>>>,
+}
+
+impl Sweeper {
+    pub fn new() -> Sweeper {
+        Sweeper::default()
+    }
+
+    /// Remember a trash directory, with the credentials to access it.
+    pub fn register(&self, dir: PathBuf, retention: Duration, creds: Option<(u32, u32, &[u32])>) {
+        self.register_with(dir, retention, creds, purge);
+    }
+
+    /// Remember a directory that is purged with `purge` instead.
+    pub fn register_with(
+        &self,
+        dir: PathBuf,
+        retention: Duration,
+        creds: Option<(u32, u32, &[u32])>,
+        purge: fn(&Path, Duration),
+    )
+    {
+        let mut dirs = self.dirs.lock().unwrap();
+        dirs.entry(dir).or_insert_with(|| {
+            let creds = creds.map(|(uid, gid, groups)| (uid, gid, groups.to_vec()));
+            TrashDir {
+                retention,
+                creds,
+                purge,
+            }
+        });
+    }
+
+    /// Run forever.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let dirs: Vec<_> = {
+                let dirs = self.dirs.lock().unwrap();
+                dirs.iter()
+                    .map(|(dir, t)| (dir.clone(), t.retention, t.creds.clone(), t.purge))
+                    .collect()
+            };
+            for (dir, retention, creds, purge) in dirs {
+                let _ = tokio::task::spawn_blocking(move || {
+                    let creds = creds.as_ref().map(|(u, g, gr)| (*u, *g, gr.as_slice()));
+                    let _guard = UgidSwitch::new(creds).guard();
+                    purge(&dir, retention);
+                })
+                .await;
+            }
+        }
+    }
+}
+
+// Remove the entries in `dir` that are older than `retention`.
+fn purge(dir: &Path, retention: Duration) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let secs = match name.to_str().and_then(stamp_secs) {
+            Some(secs) => secs,
+            None => continue,
+        };
+        if now.saturating_sub(secs) < retention.as_secs() {
+            continue;
+        }
+        match std::fs::remove_dir_all(entry.path()) {
+            Ok(_) => debug!("trash: purged {:?}", entry.path()),
+            Err(e) => warn!("trash: purge {:?}: {}", entry.path(), e),
+        }
+    }
+}
+
+// Time of a trash entry from its name (SECONDS-RANDOM).
+fn stamp_secs(name: &str) -> Option<u64> {
+    let (secs, random) = name.split_once('-')?;
+    if random.is_empty() {
+        return None;
+    }
+    secs.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trash() {
+        let trash = Trash::new(".trash");
+        assert!(trash.contains(&DavPath::new("/.trash").unwrap()));
+        assert!(trash.contains(&DavPath::new("/.trash/x").unwrap()));
+        assert!(!trash.contains(&DavPath::new("/.trashcan").unwrap()));
+
+        let (dirs, to) = trash.paths(&DavPath::new("/a/b%20c/file").unwrap()).unwrap();
+        let stamp = format!("/.trash/{}", trash.stamp);
+        let dirs: Vec<String> = dirs.iter().map(|d| d.as_url_string()).collect();
+        assert_eq!(
+            dirs,
+            vec![
+                "/.trash".to_string(),
+                stamp.clone(),
+                format!("{}/a", stamp),
+                format!("{}/a/b%20c", stamp)
+            ]
+        );
+        assert_eq!(to.as_url_string(), format!("{}/a/b%20c/file", stamp));
+        assert!(trash.paths(&DavPath::new("/").unwrap()).is_err());
+
+        assert_eq!(stamp_secs(&trash.stamp[..]).map(|s| s > 0), Some(true));
+        assert_eq!(stamp_secs("1234"), None);
+    }
+}
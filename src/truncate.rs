@@ -0,0 +1,141 @@
+//! Truncation of large PROPFIND responses.
+//!
+//! `max-depth` keeps clients from walking a whole tree in one request,
+//! but a single directory can still have so many entries that its
+//! Depth: 1 listing is too large to be useful. With
+//! `propfind-max-responses`, a PROPFIND response (Depth: 1 or deeper)
+//! ends after that many DAV:response elements. The webdav handler stops
+//! reading the directory, and the multistatus gets one more response:
+//! the request URI, with "507 Insufficient Storage" and the
+//! DAV:number-of-matches-within-limits error, as in RFC 4918, section
+//! 9.1 and RFC 5323. The multistatus stays valid.
+//!
+//! WebDAV has no pagination, so a client that sees the marker can only
+//! ask for less (the members of subdirectories, or Depth: 0 of single
+//! resources). This is an extension of this server, clients that do not
+//! know it see a resource they cannot list.
+//!
+use bytes::Bytes;
+use hyper::body::HttpBody;
+
+const END: &[u8] = b"</D:response>";
+const HREF: &str = "<D:href>";
+const MULTISTATUS_END: &str = "</D:multistatus>";
+
+// Where `what` is in `data`.
+fn find(data: &[u8], what: &[u8]) -> Option<usize> {
+    data.windows(what.len()).position(|w| w == what)
+}
+
+// The last response, that says that this is not all of it.
+fn marker(href: &str, max: usize) -> String {
+    format!(
+        concat!(
+            "<D:response><D:href>{}</D:href><D:status>HTTP/1.1 507 Insufficient Storage</D:status>",
+            "<D:error><D:number-of-matches-within-limits/></D:error>",
+            "<D:responsedescription>truncated after {} responses</D:responsedescription>",
+            "</D:response>{}"
+        ),
+        href, max, MULTISTATUS_END
+    )
+}
+
+// The stream: what is left of the body, what was not sent yet, the responses
+// that were sent, and the href of the first one (the request URI).
+struct State {
+    body:  hyper::Body,
+    data:  Vec<u8>,
+    count: usize,
+    href:  Option<String>,
+}
+
+impl State {
+    // Take the complete responses from `data`, up to the limit. Returns
+    // what to send, and whether that is the end.
+    fn take(&mut self, max: usize) -> (Bytes, bool) {
+        let mut out = Vec::new();
+        while let Some(pos) = find(&self.data, END) {
+            if self.count == max {
+                let href = self.href.take().unwrap_or_default();
+                out.extend_from_slice(marker(&href, max).as_bytes());
+                return (Bytes::from(out), true);
+            }
+            let rest = self.data.split_off(pos + END.len());
+            let response = std::mem::replace(&mut self.data, rest);
+            if self.href.is_none() {
+                let text = String::from_utf8_lossy(&response);
+                let href = text.split(HREF).nth(1).and_then(|h| h.split('<').next());
+                self.href = href.map(|h| h.to_string());
+            }
+            out.extend_from_slice(&response);
+            self.count += 1;
+        }
+        (Bytes::from(out), false)
+    }
+}
+
+/// End the multistatus in `body` after `max` DAV:response elements.
+pub fn truncate(body: hyper::Body, max: usize) -> hyper::Body {
+    let state = State {
+        body,
+        data: Vec::new(),
+        count: 0,
+        href: None,
+    };
+    let stream = futures::stream::unfold(Some(state), move |state| {
+        async move {
+            let mut state = state?;
+            match state.body.data().await {
+                Some(Ok(chunk)) => state.data.extend_from_slice(&chunk),
+                Some(Err(e)) => return Some((Err(e), None)),
+                None if state.data.is_empty() => return None,
+                None => return Some((Ok(Bytes::from(state.data)), None)),
+            }
+            // dropping the body stops the webdav handler.
+            match state.take(max) {
+                (out, true) => Some((Ok(out), None)),
+                (out, false) => Some((Ok(out), Some(state))),
+            }
+        }
+    });
+    hyper::Body::wrap_stream(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn multistatus(n: usize) -> Vec<Result<String, std::io::Error>> {
+        let mut chunks = vec![Ok(r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:">"#.to_string())];
+        for i in 0..n {
+            let response = format!("<D:response><D:href>/d/{}</D:href></D:response>", i);
+            // split in the middle of the end tag.
+            let (a, b) = response.split_at(response.len() - 5);
+            chunks.push(Ok(a.to_string()));
+            chunks.push(Ok(b.to_string()));
+        }
+        chunks.push(Ok(MULTISTATUS_END.to_string()));
+        chunks
+    }
+
+    async fn truncated(n: usize, max: usize) -> String {
+        let body = hyper::Body::wrap_stream(futures::stream::iter(multistatus(n)));
+        let body = hyper::body::to_bytes(truncate(body, max)).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_truncate() {
+        // under the limit, or at it: as it was.
+        let all: String = multistatus(3).into_iter().map(|c| c.unwrap()).collect();
+        assert_eq!(truncated(3, 3).await, all);
+        assert_eq!(truncated(3, 10).await, all);
+
+        let body = truncated(10, 3).await;
+        assert_eq!(body.matches("<D:response>").count(), 4);
+        assert!(body.contains("<D:href>/d/2</D:href>") && !body.contains("/d/3"));
+        assert!(body.ends_with(&marker("/d/0", 3)));
+        let tree = xmltree::Element::parse(body.as_bytes()).unwrap();
+        assert_eq!(tree.children.len(), 4);
+    }
+}
@@ -1,11 +1,12 @@
-use std;
 use std::ffi::{CStr, OsStr};
 use std::io;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use tokio::task::block_in_place;
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct User {
     pub name:   String,
@@ -20,7 +21,7 @@ pub struct User {
 
 unsafe fn cptr_to_osstr<'a>(c: *const libc::c_char) -> &'a OsStr {
     let bytes = CStr::from_ptr(c).to_bytes();
-    OsStr::from_bytes(&bytes)
+    OsStr::from_bytes(bytes)
 }
 
 unsafe fn cptr_to_path<'a>(c: *const libc::c_char) -> &'a Path {
@@ -94,7 +95,7 @@ impl User {
                 //
                 // Only supplementary or auxilary groups, filter out primary.
                 //
-                groups_vec.extend(groups.iter().map(|&g| g as u32).filter(|&g| g != user.gid));
+                groups_vec.extend(groups.iter().copied().filter(|&g| g != user.gid));
                 user.groups = groups_vec;
             }
         }
@@ -133,3 +134,102 @@ impl User {
         block_in_place(move || User::by_name(name, with_groups))
     }
 }
+
+/// A group from the group database.
+#[derive(Debug)]
+pub struct Group {
+    pub gid:     u32,
+    pub members: Vec<String>,
+}
+
+impl Group {
+    pub fn by_name(name: &str) -> Result<Group, io::Error> {
+        let cname = match std::ffi::CString::new(name) {
+            Ok(gn) => gn,
+            Err(_) => return Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        };
+        // large groups need a large buffer.
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let mut grp: libc::group = unsafe { std::mem::zeroed() };
+            let mut result: *mut libc::group = std::ptr::null_mut();
+            let ret = unsafe {
+                libc::getgrnam_r(
+                    cname.as_ptr(),
+                    &mut grp as *mut _,
+                    buf.as_mut_ptr() as *mut _,
+                    buf.len() as libc::size_t,
+                    &mut result as *mut _,
+                )
+            };
+            if ret == libc::ERANGE && buf.len() < 1 << 20 {
+                buf.resize(buf.len() * 4, 0);
+                continue;
+            }
+            if ret != 0 {
+                return Err(io::Error::from_raw_os_error(ret));
+            }
+            if result.is_null() {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            let mut members = Vec::new();
+            let mut mem = grp.gr_mem;
+            while !mem.is_null() && unsafe { !(*mem).is_null() } {
+                members.push(unsafe { CStr::from_ptr(*mem) }.to_string_lossy().into_owned());
+                mem = unsafe { mem.add(1) };
+            }
+            return Ok(Group {
+                gid: grp.gr_gid,
+                members,
+            });
+        }
+    }
+}
+
+/// Default number of retries of a failed lookup.
+pub const DEFAULT_NSS_RETRIES: u32 = 2;
+/// Default wait (secs) before the first retry.
+pub const DEFAULT_NSS_RETRY_DELAY: f64 = 0.1;
+
+/// How to retry a lookup that failed, not with "not found" but because
+/// NSS could not answer (an LDAP or SSSD server that is down, a timeout).
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    pub retries: u32,
+    pub delay:   Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            retries: DEFAULT_NSS_RETRIES,
+            delay:   Duration::from_secs_f64(DEFAULT_NSS_RETRY_DELAY),
+        }
+    }
+}
+
+impl Retry {
+    /// Run `lookup` until it succeeds, fails with ENOENT, or has failed
+    /// `retries` more times. The wait doubles after every try.
+    pub async fn run<T, F>(&self, what: &str, mut lookup: F) -> Result<T, io::Error>
+    where F: FnMut() -> Result<T, io::Error> {
+        let mut delay = self.delay;
+        let mut tries = 0;
+        loop {
+            match lookup() {
+                Err(e) if !is_not_found(&e) && tries < self.retries => {
+                    debug!("unixuser: {}: {}, retrying in {:?}", what, e, delay);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    tries += 1;
+                },
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Did a lookup fail because the user or group does not exist.
+pub fn is_not_found(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOENT)
+}
@@ -0,0 +1,408 @@
+//! Upload filters (virus scanners).
+//!
+//! An `UploadFilter` sees the body of a PUT while it is written, and
+//! decides whether the file may be stored. So that a rejected upload
+//! never shows up (and does not destroy the file it would replace), the
+//! body goes to a temporary file next to the target, `.upload-UUID.tmp`,
+//! which is renamed to the target when the body is complete and the
+//! filter allows it, and removed otherwise. The file is never buffered
+//! in memory: every chunk goes to the filter and then to disk.
+//!
+//! The order at the end is fixed: the temporary file is flushed (and
+//! with `fsync`, synced), then the filter gives its verdict, and only
+//! if both succeed is it renamed. Otherwise it is removed before the
+//! response is sent, and the target was never touched. Only when the
+//! upload does not get that far (the client goes away) is the file
+//! removed in the background, when it is dropped.
+//!
+//! A filter can stop an upload half way through, at the end, or both.
+//! A rejected upload gets a "403 Forbidden". If the filter itself fails
+//! (the scanner is down), the upload is rejected as well, with a 500.
+//!
+//! Partial PUTs (with Content-Range) change a file in place and are
+//! not filtered.
+//!
+//! The only filter for now is `clamd`, which streams the file to ClamAV
+//! with the INSTREAM command.
+//!
+use std::io;
+use std::io::SeekFrom;
+use std::sync::Arc;
+
+use bytes::{Buf, Bytes};
+use futures::future::{BoxFuture, FutureExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::etag::EtagFs;
+
+/// What the filter thinks of an upload.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Deny(String),
+}
+
+/// Something that looks at uploads.
+pub trait UploadFilter: Send + Sync {
+    /// Start looking at an upload to `path`.
+    fn start<'a>(&'a self, path: &'a DavPath) -> BoxFuture<'a, io::Result<Box<dyn UploadScan>>>;
+}
+
+/// One upload that is being looked at.
+pub trait UploadScan: Send + Sync {
+    /// The next chunk of the body. `Deny` stops the upload right away.
+    fn data<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, io::Result<Verdict>>;
+
+    /// The body is complete.
+    fn finish(self: Box<Self>) -> BoxFuture<'static, io::Result<Verdict>>;
+}
+
+/// Build a filter from the `upload-filter` setting.
+pub fn from_config(spec: &str) -> Result<Arc<dyn UploadFilter>, String> {
+    match spec.split_once(':') {
+        Some(("clamd", addr)) if !addr.is_empty() => Ok(Arc::new(Clamd::new(addr))),
+        _ => Err(format!("{}: unknown upload filter (expected clamd:ADDRESS)", spec)),
+    }
+}
+
+/// Several filters, in order. An upload is allowed if all of them allow it.
+pub struct Chain(pub Vec<Arc<dyn UploadFilter>>);
+
+impl UploadFilter for Chain {
+    fn start<'a>(&'a self, path: &'a DavPath) -> BoxFuture<'a, io::Result<Box<dyn UploadScan>>> {
+        async move {
+            let mut scans = Vec::new();
+            for filter in &self.0 {
+                scans.push(filter.start(path).await?);
+            }
+            Ok(Box::new(ChainScan(scans)) as Box<dyn UploadScan>)
+        }
+        .boxed()
+    }
+}
+
+struct ChainScan(Vec<Box<dyn UploadScan>>);
+
+impl UploadScan for ChainScan {
+    fn data<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, io::Result<Verdict>> {
+        async move {
+            for scan in self.0.iter_mut() {
+                match scan.data(data).await? {
+                    Verdict::Allow => {},
+                    deny => return Ok(deny),
+                }
+            }
+            Ok(Verdict::Allow)
+        }
+        .boxed()
+    }
+
+    fn finish(self: Box<Self>) -> BoxFuture<'static, io::Result<Verdict>> {
+        async move {
+            for scan in self.0 {
+                match scan.finish().await? {
+                    Verdict::Allow => {},
+                    deny => return Ok(deny),
+                }
+            }
+            Ok(Verdict::Allow)
+        }
+        .boxed()
+    }
+}
+
+/// ClamAV, through the clamd socket. `addr` is the path of a unix
+/// socket, or HOST:PORT.
+pub struct Clamd {
+    addr: String,
+}
+
+impl Clamd {
+    pub fn new(addr: &str) -> Clamd {
+        Clamd { addr: addr.to_string() }
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> Stream for T {}
+
+struct ClamdScan {
+    addr: String,
+    conn: Box<dyn Stream>,
+}
+
+impl UploadFilter for Clamd {
+    fn start<'a>(&'a self, _path: &'a DavPath) -> BoxFuture<'a, io::Result<Box<dyn UploadScan>>> {
+        async move {
+            let mut conn: Box<dyn Stream> = if self.addr.starts_with('/') {
+                Box::new(tokio::net::UnixStream::connect(&self.addr).await?)
+            } else {
+                Box::new(tokio::net::TcpStream::connect(&self.addr).await?)
+            };
+            conn.write_all(b"zINSTREAM\0").await?;
+            let scan = ClamdScan {
+                addr: self.addr.clone(),
+                conn,
+            };
+            Ok(Box::new(scan) as Box<dyn UploadScan>)
+        }
+        .boxed()
+    }
+}
+
+impl UploadScan for ClamdScan {
+    fn data<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, io::Result<Verdict>> {
+        async move {
+            // every chunk is sent with its length as a 32 bit number.
+            for chunk in data.chunks(u32::MAX as usize) {
+                self.conn.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+                self.conn.write_all(chunk).await?;
+            }
+            Ok(Verdict::Allow)
+        }
+        .boxed()
+    }
+
+    fn finish(mut self: Box<Self>) -> BoxFuture<'static, io::Result<Verdict>> {
+        async move {
+            self.conn.write_all(&[0, 0, 0, 0]).await?;
+            let mut reply = Vec::new();
+            self.conn.read_to_end(&mut reply).await?;
+            let reply = String::from_utf8_lossy(&reply);
+            let reply = reply.trim_end_matches('\0').trim();
+            // "stream: OK", "stream: Eicar-Signature FOUND", "... ERROR".
+            match reply.strip_prefix("stream: ") {
+                Some("OK") => Ok(Verdict::Allow),
+                Some(r) if r.ends_with(" FOUND") => Ok(Verdict::Deny(r.to_string())),
+                _ => {
+                    let msg = format!("clamd {}: {}", self.addr, reply);
+                    Err(io::Error::other(msg))
+                },
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Open `path` for a PUT through `filter`: the body is written to a
+/// temporary file, which replaces `path` when the filter allows it.
+pub async fn open(
+    fs: &EtagFs,
+    path: &DavPath,
+    options: OpenOptions,
+    filter: &dyn UploadFilter,
+) -> FsResult<Box<dyn DavFile>>
+{
+    // check what open() of the target would have checked.
+    match fs.metadata(path).await {
+        Ok(meta) if meta.is_dir() => return Err(FsError::Forbidden),
+        Ok(_) if options.create_new => return Err(FsError::Exists),
+        Err(FsError::NotFound) if !options.create && !options.create_new => return Err(FsError::NotFound),
+        Ok(_) | Err(FsError::NotFound) => {},
+        Err(e) => return Err(e),
+    }
+
+    let url = path.as_url_string();
+    let dir = url.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("");
+    let tmp = format!("{}/.upload-{}.tmp", dir, uuid::Uuid::new_v4().to_simple());
+    let tmp = DavPath::new(&tmp).map_err(|_| FsError::GeneralFailure)?;
+
+    let scan = filter.start(path).await.map_err(|e| {
+        error!("upload filter: {:?}: {}", path, e);
+        FsError::GeneralFailure
+    })?;
+    let tmp_options = OpenOptions {
+        write: true,
+        create_new: true,
+        ..OpenOptions::default()
+    };
+    let file = fs.open(&tmp, tmp_options).await?;
+    Ok(Box::new(FilteredFile {
+        file,
+        scan: Some(scan),
+        fs: fs.clone(),
+        tmp,
+        target: path.clone(),
+        done: false,
+    }))
+}
+
+// A PUT to a temporary file, through a filter.
+struct FilteredFile {
+    file:   Box<dyn DavFile>,
+    scan:   Option<Box<dyn UploadScan>>,
+    fs:     EtagFs,
+    tmp:    DavPath,
+    target: DavPath,
+    done:   bool,
+}
+
+impl std::fmt::Debug for FilteredFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("FilteredFile").field("tmp", &self.tmp).finish()
+    }
+}
+
+impl FilteredFile {
+    // Remove the temporary file, and return `err`.
+    async fn discard(&mut self, err: FsError) -> FsError {
+        if let Err(e) = self.fs.remove_file(&self.tmp).await {
+            debug!("upload filter: remove {:?}: {:?}", self.tmp, e);
+        }
+        self.done = true;
+        err
+    }
+
+    // What to return when the filter did not allow the upload.
+    fn rejected(&self, verdict: io::Result<Verdict>) -> FsError {
+        match verdict {
+            Ok(Verdict::Deny(reason)) => {
+                info!("upload filter: {:?} rejected: {}", self.target, reason);
+                FsError::Forbidden
+            },
+            Ok(Verdict::Allow) => FsError::GeneralFailure,
+            Err(e) => {
+                error!("upload filter: {:?}: {}", self.target, e);
+                FsError::GeneralFailure
+            },
+        }
+    }
+}
+
+impl Drop for FilteredFile {
+    fn drop(&mut self) {
+        // the upload did not complete, remove what we have.
+        if !self.done {
+            let (fs, tmp) = (self.fs.clone(), self.tmp.clone());
+            if let Ok(rt) = tokio::runtime::Handle::try_current() {
+                rt.spawn(async move {
+                    let _ = fs.remove_file(&tmp).await;
+                });
+            }
+        }
+    }
+}
+
+impl DavFile for FilteredFile {
+    fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        self.file.metadata()
+    }
+
+    fn write_buf(&mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
+        let bytes = buf.copy_to_bytes(buf.remaining());
+        self.write_bytes(bytes)
+    }
+
+    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<'_, ()> {
+        async move {
+            let scan = self.scan.as_mut().ok_or(FsError::GeneralFailure)?;
+            match scan.data(&buf).await {
+                Ok(Verdict::Allow) => self.file.write_bytes(buf).await,
+                verdict => {
+                    let err = self.rejected(verdict);
+                    Err(self.discard(err).await)
+                },
+            }
+        }
+        .boxed()
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, Bytes> {
+        self.file.read_bytes(count)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        self.file.seek(pos)
+    }
+
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        async move {
+            if let Err(e) = self.file.flush().await {
+                return Err(self.discard(e).await);
+            }
+            let scan = self.scan.take().ok_or(FsError::GeneralFailure)?;
+            match scan.finish().await {
+                Ok(Verdict::Allow) => match self.fs.rename(&self.tmp, &self.target).await {
+                    Ok(()) => {
+                        self.done = true;
+                        Ok(())
+                    },
+                    Err(e) => Err(self.discard(e).await),
+                },
+                verdict => {
+                    let err = self.rejected(verdict);
+                    Err(self.discard(err).await)
+                },
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Start a clamd that finds a virus in everything that contains "EICAR".
+    /// Returns the path of its socket.
+    pub fn fake_clamd() -> PathBuf {
+        let sock = std::env::temp_dir().join(format!("clamd-{}.sock", uuid::Uuid::new_v4().to_simple()));
+        let listener = tokio::net::UnixListener::bind(&sock).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut cmd = [0u8; 10];
+                    conn.read_exact(&mut cmd).await?;
+                    assert_eq!(&cmd, b"zINSTREAM\0");
+                    let mut data = Vec::new();
+                    loop {
+                        let len = conn.read_u32().await? as usize;
+                        if len == 0 {
+                            break;
+                        }
+                        let mut chunk = vec![0u8; len];
+                        conn.read_exact(&mut chunk).await?;
+                        data.extend(chunk);
+                    }
+                    let infected = data.windows(5).any(|w| w == b"EICAR");
+                    let reply: &[u8] = if infected {
+                        b"stream: Eicar-Signature FOUND\0"
+                    } else {
+                        b"stream: OK\0"
+                    };
+                    conn.write_all(reply).await
+                });
+            }
+        });
+        sock
+    }
+
+    #[tokio::test]
+    async fn test_clamd() {
+        let sock = fake_clamd();
+        let filter = from_config(&format!("clamd:{}", sock.display())).unwrap();
+        let path = DavPath::new("/file").unwrap();
+        let scan = |chunks: &'static [&'static [u8]]| {
+            let filter = filter.clone();
+            let path = path.clone();
+            async move {
+                let mut scan = filter.start(&path).await.unwrap();
+                for chunk in chunks {
+                    assert_eq!(scan.data(chunk).await.unwrap(), Verdict::Allow);
+                }
+                scan.finish().await.unwrap()
+            }
+        };
+        assert_eq!(scan(&[b"hello ", b"world"]).await, Verdict::Allow);
+        let verdict = scan(&[b"X5O!P%@AP[4\\PZX54(P^)7CC)7}$EIC", b"AR-STANDARD"]).await;
+        assert_eq!(verdict, Verdict::Deny("Eicar-Signature FOUND".to_string()));
+
+        assert!(from_config("clamd:").is_err());
+        assert!(from_config("icap://localhost").is_err());
+        std::fs::remove_file(&sock).unwrap();
+    }
+}
@@ -1,17 +1,34 @@
 use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
+use futures::future::{self, FutureExt};
+use futures::StreamExt;
 use webdav_handler::davpath::DavPath;
 use webdav_handler::fs::*;
 use webdav_handler::localfs::LocalFs;
 
+use crate::crossfs;
+use crate::etag::EtagFs;
+use crate::fsync::Fsync;
+use crate::statcache::{DirList, Kind, StatCache};
 use crate::suid::UgidSwitch;
+use crate::trash::Trash;
+use crate::uploadfilter::{self, UploadFilter};
 
 #[derive(Clone)]
 pub struct UserFs {
-    pub fs:  LocalFs,
-    basedir: PathBuf,
-    uid:     u32,
+    pub fs:        EtagFs,
+    basedir:       PathBuf,
+    uid:           u32,
+    quota_uid:     Option<u32>,
+    precompressed: Option<Precompressed>,
+    statcache:     Option<StatCache>,
+    trash:         Option<Trash>,
+    upload_filter: Option<Arc<dyn UploadFilter>>,
 }
 
 impl UserFs {
@@ -23,74 +40,415 @@ impl UserFs {
         macos: bool,
     ) -> Box<UserFs>
     {
-        // uid is used for quota() calls.
+        // uid is used for quota() calls, and as part of the stat cache key.
         let uid = target_creds.as_ref().map(|ugid| ugid.0).unwrap_or(0);
 
         // set up the LocalFs hooks for uid switching.
-        let switch = UgidSwitch::new(target_creds.clone());
+        let switch = UgidSwitch::new(target_creds);
         let blocking_guard = Box::new(move || Box::new(switch.guard()) as Box<dyn Any>);
 
         Box::new(UserFs {
             basedir: dir.as_ref().to_path_buf(),
-            fs:      *LocalFs::new_with_fs_access_guard(
+            fs:      EtagFs::new(*LocalFs::new_with_fs_access_guard(
                 dir,
                 public,
                 case_insensitive,
                 macos,
                 Some(blocking_guard),
-            ),
-            uid:     uid,
+            )),
+            uid,
+            quota_uid: None,
+            precompressed: None,
+            statcache: None,
+            trash: None,
+            upload_filter: None,
         })
     }
+
+    /// Serve precompressed sidecar files, and hide them from listings.
+    pub fn set_precompressed(&mut self, precompressed: Option<Precompressed>) {
+        self.precompressed = precompressed;
+    }
+
+    /// Report the quota of this uid (the authenticated user) instead
+    /// of the uid that the filesystem is accessed as.
+    pub fn set_quota_uid(&mut self, uid: Option<u32>) {
+        self.quota_uid = uid;
+    }
+
+    /// Use a stat cache.
+    pub fn set_statcache(&mut self, statcache: Option<StatCache>) {
+        self.statcache = statcache;
+    }
+
+    /// Pass the body of PUT requests through a filter.
+    pub fn set_upload_filter(&mut self, filter: Option<Arc<dyn UploadFilter>>) {
+        self.upload_filter = filter;
+    }
+
+    /// Include the inode number in ETags.
+    pub fn set_etag_inode(&mut self, inode: bool) {
+        self.fs.set_inode(inode);
+    }
+
+    /// Sync writes to disk before they are done.
+    pub fn set_fsync(&mut self, fsync: bool) {
+        self.fs.set_fsync(Some(Fsync::new(&self.basedir)).filter(|_| fsync));
+    }
+
+    /// Move deleted files to the trash instead of removing them.
+    pub fn set_trash(&mut self, trash: Option<Trash>) {
+        self.trash = trash;
+    }
+
+    // path on the filesystem, used as stat cache key.
+    fn fspath(&self, path: &DavPath) -> PathBuf {
+        self.basedir.join(path.as_rel_ospath())
+    }
+
+    async fn cached_metadata(
+        &self,
+        path: &DavPath,
+        sc: &StatCache,
+        kind: Kind,
+    ) -> FsResult<Box<dyn DavMetaData>>
+    {
+        let fspath = self.fspath(path);
+        if let Some(meta) = sc.get_meta(&fspath, self.uid, kind) {
+            return Ok(meta);
+        }
+        let meta = match kind {
+            Kind::Lstat => self.fs.symlink_metadata(path).await?,
+            _ => self.fs.metadata(path).await?,
+        };
+        sc.insert_meta(fspath, self.uid, kind, meta.clone());
+        Ok(meta)
+    }
+
+    async fn cached_read_dir(
+        &self,
+        path: &DavPath,
+        meta: ReadDirMeta,
+        sc: &StatCache,
+    ) -> FsResult<FsStream<Box<dyn DavDirEntry>>>
+    {
+        let kind = match meta {
+            ReadDirMeta::Data => Kind::ReadDir,
+            ReadDirMeta::DataSymlink => Kind::ReadDirLstat,
+            ReadDirMeta::None => return self.fs.read_dir(path, meta).await,
+        };
+        let fspath = self.fspath(path);
+        let list = match sc.get_dir(&fspath, self.uid, kind) {
+            Some(list) => list,
+            None => {
+                let mut entries = self.fs.read_dir(path, meta).await?;
+                let mut list = Vec::new();
+                while let Some(entry) = entries.next().await {
+                    list.push((entry.name(), entry.metadata().await));
+                }
+                let list: DirList = Arc::new(list);
+                sc.insert_dir(fspath, self.uid, kind, list.clone());
+                list
+            },
+        };
+        let entries = (0..list.len()).map(move |idx| {
+            Box::new(CachedDirEntry {
+                list: list.clone(),
+                idx,
+            }) as Box<dyn DavDirEntry>
+        });
+        Ok(Box::pin(futures::stream::iter(entries)))
+    }
+
+    // Invalidate the stat cache of the file, both now and when it is closed.
+    async fn open_write(
+        &self,
+        path: &DavPath,
+        options: OpenOptions,
+        sc: &StatCache,
+    ) -> FsResult<Box<dyn DavFile>>
+    {
+        let fspath = self.fspath(path);
+        sc.invalidate(&fspath);
+        let file = self.fs.open(path, options).await?;
+        Ok(Box::new(WriteFile {
+            file,
+            cache: sc.clone(),
+            path: fspath,
+        }))
+    }
+
+    // A PUT through the upload filter.
+    async fn open_filtered(
+        &self,
+        path: &DavPath,
+        options: OpenOptions,
+        filter: &dyn UploadFilter,
+    ) -> FsResult<Box<dyn DavFile>>
+    {
+        let file = uploadfilter::open(&self.fs, path, options, filter).await?;
+        match self.statcache {
+            Some(ref sc) => {
+                let fspath = self.fspath(path);
+                sc.invalidate(&fspath);
+                Ok(Box::new(WriteFile {
+                    file,
+                    cache: sc.clone(),
+                    path: fspath,
+                }))
+            },
+            None => Ok(file),
+        }
+    }
+
+    // Instead of removing a file, move it to the trash. The webdav handler
+    // removes the contents of a directory before the directory itself, so
+    // directories are empty here. They are re-created in the trash.
+    async fn move_to_trash(&self, path: &DavPath, trash: &Trash, is_dir: bool) -> FsResult<()> {
+        let (dirs, to) = trash.paths(path)?;
+        for dir in dirs.iter().chain(Some(&to).filter(|_| is_dir)) {
+            match self.fs.create_dir(dir).await {
+                Ok(()) | Err(FsError::Exists) => {},
+                Err(e) => return Err(e),
+            }
+        }
+        if is_dir {
+            self.fs.remove_dir(path).await?;
+        } else {
+            self.fs.rename(path, &to).await?;
+        }
+        debug!("trash: moved {:?} to {:?}", path, to);
+        if let Some(ref sc) = self.statcache {
+            sc.invalidate(&self.fspath(&to));
+        }
+        Ok(())
+    }
+
+    // A rename to another filesystem fails with EXDEV: copy, with the
+    // metadata, and remove the source.
+    async fn move_across(&self, from: &DavPath, to: &DavPath) -> FsResult<()> {
+        let (src, dst) = (self.fspath(from), self.fspath(to));
+        debug!("rename: {:?} -> {:?}: different filesystems, copying", src, dst);
+        let res = self.fs.blocking(move || crossfs::move_tree(&src, &dst)).await;
+        res.map_err(|e| {
+            debug!("rename: {:?} -> {:?}: {}", from, to, e);
+            FsError::from(e)
+        })
+    }
+
+    // Invalidate the stat cache for `paths` after `fut` has completed.
+    fn invalidate_after<'a, T: Send + 'a>(
+        &'a self,
+        paths: Vec<&'a DavPath>,
+        fut: FsFuture<'a, T>,
+    ) -> FsFuture<'a, T>
+    {
+        match self.statcache {
+            Some(ref sc) => {
+                async move {
+                    let res = fut.await;
+                    for path in paths {
+                        sc.invalidate(&self.fspath(path));
+                    }
+                    res
+                }
+                .boxed()
+            },
+            None => fut,
+        }
+    }
+
+    // Open the original file, and if the client accepts an encoding for
+    // which there is an up-to-date sidecar file, open that instead.
+    async fn open_precompressed(
+        &self,
+        path: &DavPath,
+        options: OpenOptions,
+        pc: &Precompressed,
+    ) -> FsResult<Box<dyn DavFile>>
+    {
+        let mut file = self.fs.open(path, options).await?;
+        let meta = file.metadata().await?;
+        if !meta.is_file() || path.is_collection() || path.as_bytes().ends_with(b".hbs") {
+            return Ok(file);
+        }
+        for &(encoding, suffix) in ENCODINGS.iter().filter(|(e, _)| pc.accept.contains(e)) {
+            if let Some(sidecar) = self.open_sidecar(path, suffix, options, &*meta).await {
+                debug!("open: {:?}: serving {} sidecar", path, encoding);
+                *pc.encoding.lock().unwrap() = Some(encoding);
+                let len = sidecar.1.len();
+                return Ok(Box::new(SidecarFile {
+                    file: sidecar.0,
+                    meta: SidecarMeta { meta, len, encoding },
+                }));
+            }
+        }
+        Ok(file)
+    }
+
+    async fn open_sidecar(
+        &self,
+        path: &DavPath,
+        suffix: &str,
+        options: OpenOptions,
+        meta: &dyn DavMetaData,
+    ) -> Option<(Box<dyn DavFile>, Box<dyn DavMetaData>)>
+    {
+        let path = DavPath::new(&format!("{}{}", path.as_url_string(), suffix)).ok()?;
+        let mut file = self.fs.open(&path, options).await.ok()?;
+        let smeta = file.metadata().await.ok()?;
+        // a sidecar that is older than the original is stale.
+        if !smeta.is_file() || smeta.modified().ok()? < meta.modified().ok()? {
+            return None;
+        }
+        Some((file, smeta))
+    }
+}
+
+/// Create the missing parent directories of `path`. If more than `max`
+/// are missing, none are created, and it fails with `Forbidden`.
+pub async fn create_parents(fs: &dyn DavFileSystem, path: &DavPath, max: Option<usize>) -> FsResult<()> {
+    let url = path.as_url_string();
+    let mut segs: Vec<&str> = url.split('/').filter(|s| !s.is_empty()).collect();
+    segs.pop();
+    let mut dirs = Vec::new();
+    let mut dir = String::new();
+    for seg in segs {
+        dir = format!("{}/{}", dir, seg);
+        dirs.push(DavPath::new(&dir).map_err(|_| FsError::GeneralFailure)?);
+    }
+    // the first one that is missing, everything below it is too.
+    let mut missing = dirs.len();
+    for (idx, dirpath) in dirs.iter().enumerate() {
+        match fs.metadata(dirpath).await {
+            Ok(meta) if meta.is_dir() => continue,
+            Ok(_) => return Err(FsError::Exists),
+            Err(FsError::NotFound) => {},
+            Err(e) => return Err(e),
+        }
+        missing = idx;
+        break;
+    }
+    if max.map(|max| dirs.len() - missing > max).unwrap_or(false) {
+        debug!("create_parents: {:?}: {} missing directories", path, dirs.len() - missing);
+        return Err(FsError::Forbidden);
+    }
+    for dirpath in &dirs[missing..] {
+        match fs.create_dir(dirpath).await {
+            Ok(()) | Err(FsError::Exists) => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
 }
 
 impl DavFileSystem for UserFs {
-    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
-        self.fs.metadata(path)
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        match self.statcache {
+            Some(ref sc) => self.cached_metadata(path, sc, Kind::Stat).boxed(),
+            None => self.fs.metadata(path),
+        }
     }
 
-    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
-        self.fs.symlink_metadata(path)
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        match self.statcache {
+            Some(ref sc) => self.cached_metadata(path, sc, Kind::Lstat).boxed(),
+            None => self.fs.symlink_metadata(path),
+        }
     }
 
     fn read_dir<'a>(
         &'a self,
         path: &'a DavPath,
         meta: ReadDirMeta,
-    ) -> FsFuture<FsStream<Box<dyn DavDirEntry>>>
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
     {
-        self.fs.read_dir(path, meta)
+        // the trash directory is hidden from the listing of the root.
+        let trash = match self.trash {
+            Some(ref t) if path.as_bytes() == b"/" => Some(t.name().as_bytes().to_vec()),
+            _ => None,
+        };
+        if self.precompressed.is_none() && self.statcache.is_none() && trash.is_none() {
+            return self.fs.read_dir(path, meta);
+        }
+        async move {
+            let entries = match self.statcache {
+                Some(ref sc) => self.cached_read_dir(path, meta, sc).await?,
+                None => self.fs.read_dir(path, meta).await?,
+            };
+            if self.precompressed.is_none() && trash.is_none() {
+                return Ok(entries);
+            }
+            // hide sidecar files and the trash.
+            let entries: Vec<_> = entries.collect().await;
+            let names: HashSet<Vec<u8>> = match self.precompressed {
+                Some(_) => entries.iter().map(|e| e.name()).collect(),
+                None => HashSet::new(),
+            };
+            let entries = entries.into_iter().filter(move |e| {
+                let name = e.name();
+                Some(&name) != trash.as_ref() && !is_sidecar(&name, &names)
+            });
+            Ok(Box::pin(futures::stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
     }
 
-    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<Box<dyn DavFile>> {
-        self.fs.open(path, options)
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        let o = &options;
+        let writing = o.write || o.append || o.truncate || o.create || o.create_new;
+        if let Some(filter) = self.upload_filter.as_ref().filter(|_| o.write && o.truncate) {
+            return self.open_filtered(path, options, &**filter).boxed();
+        }
+        match (&self.statcache, &self.precompressed) {
+            (Some(sc), _) if writing => self.open_write(path, options, sc).boxed(),
+            (_, Some(pc)) if !writing && !pc.accept.is_empty() => {
+                self.open_precompressed(path, options, pc).boxed()
+            },
+            _ => self.fs.open(path, options),
+        }
     }
 
-    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
-        self.fs.create_dir(path)
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.invalidate_after(vec![path], self.fs.create_dir(path))
     }
 
-    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
-        self.fs.remove_dir(path)
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        match self.trash {
+            Some(ref t) if !t.contains(path) => {
+                self.invalidate_after(vec![path], self.move_to_trash(path, t, true).boxed())
+            },
+            _ => self.invalidate_after(vec![path], self.fs.remove_dir(path)),
+        }
     }
 
-    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
-        self.fs.remove_file(path)
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        match self.trash {
+            Some(ref t) if !t.contains(path) => {
+                self.invalidate_after(vec![path], self.move_to_trash(path, t, false).boxed())
+            },
+            _ => self.invalidate_after(vec![path], self.fs.remove_file(path)),
+        }
     }
 
-    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<()> {
-        self.fs.rename(from, to)
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        let fut = async move {
+            match self.fs.rename(from, to).await {
+                Err(FsError::IsRemote) => self.move_across(from, to).await,
+                res => res,
+            }
+        };
+        self.invalidate_after(vec![from, to], fut.boxed())
     }
 
-    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<()> {
-        self.fs.copy(from, to)
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.invalidate_after(vec![to], self.fs.copy(from, to))
     }
 
     #[cfg(feature = "quota")]
-    fn get_quota<'a>(&'a self) -> FsFuture<(u64, Option<u64>)> {
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
         use crate::cache;
         use fs_quota::*;
-        use futures::future::FutureExt;
         use std::time::Duration;
 
         lazy_static::lazy_static! {
@@ -98,8 +456,9 @@ impl DavFileSystem for UserFs {
         }
 
         async move {
+            let uid = self.quota_uid.unwrap_or(self.uid);
             let mut key = self.basedir.clone();
-            key.push(&self.uid.to_string());
+            key.push(uid.to_string());
             let r = match QCACHE.get(&key) {
                 Some(r) => {
                     debug!("get_quota for {:?}: from cache", key);
@@ -107,11 +466,12 @@ impl DavFileSystem for UserFs {
                 },
                 None => {
                     let path = self.basedir.clone();
-                    let uid = self.uid;
                     let r = self
                         .fs
                         .blocking(move || {
-                            FsQuota::check(&path, Some(uid)).map_err(|_| FsError::GeneralFailure)
+                            let user = FsQuota::user(&path, Some(uid));
+                            user_or_system_quota(user, || FsQuota::system(&path))
+                                .map_err(|_| FsError::GeneralFailure)
                         })
                         .await?;
                     debug!("get_quota for {:?}: insert to cache", key);
@@ -123,3 +483,279 @@ impl DavFileSystem for UserFs {
         .boxed()
     }
 }
+
+// The quota of the user, if quotas are enabled and the user has a limit.
+// Otherwise the space on the filesystem.
+#[cfg(feature = "quota")]
+fn user_or_system_quota(
+    user: Result<fs_quota::FsQuota, fs_quota::FqError>,
+    system: impl FnOnce() -> Result<fs_quota::FsQuota, fs_quota::FqError>,
+) -> Result<fs_quota::FsQuota, fs_quota::FqError>
+{
+    match user {
+        Ok(q) if q.bytes_limit.is_some() => Ok(q),
+        Ok(_) | Err(fs_quota::FqError::NoQuota) => system(),
+        Err(e) => Err(e),
+    }
+}
+
+// Content-Encoding and suffix of sidecar files, in order of preference.
+const ENCODINGS: &[(&str, &str)] = &[("br", ".br"), ("gzip", ".gz")];
+
+/// Serving of precompressed sidecar files (`file.html.br`, `file.html.gz`).
+#[derive(Debug, Clone, Default)]
+pub struct Precompressed {
+    // encodings the client accepts.
+    accept:   Accept,
+    // encoding of the sidecar file that was opened, if any.
+    encoding: Arc<Mutex<Option<&'static str>>>,
+}
+
+impl Precompressed {
+    /// `accept_encoding` is the value of the `Accept-Encoding` header. If it
+    /// is `None`, no sidecars are served, but they are still hidden. With
+    /// `conn`, a header that is the same as on the last request is not
+    /// parsed again.
+    pub fn new(accept_encoding: Option<&str>, conn: Option<&ConnEncoding>) -> Precompressed {
+        let accept = match (accept_encoding, conn) {
+            (Some(hdr), Some(conn)) => conn.accept(hdr),
+            (Some(hdr), None) => Arc::new(parse_accept_encoding(hdr)),
+            (None, _) => Arc::default(),
+        };
+        Precompressed {
+            accept,
+            encoding: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The Content-Encoding of the sidecar file that was served, if any.
+    pub fn encoding(&self) -> Option<&'static str> {
+        *self.encoding.lock().unwrap()
+    }
+}
+
+// The sidecar encodings that a client accepts.
+type Accept = Arc<Vec<&'static str>>;
+
+/// The last Accept-Encoding header of a connection, parsed. Clients send
+/// the same one on every request, so it is only parsed again when it
+/// changes. Which sidecar fits is still decided per request.
+///
+/// This is stored in the request extensions by the connection handler.
+#[derive(Debug, Clone, Default)]
+pub struct ConnEncoding {
+    last: Arc<Mutex<Option<(String, Accept)>>>,
+}
+
+impl ConnEncoding {
+    pub fn new() -> ConnEncoding {
+        ConnEncoding::default()
+    }
+
+    fn accept(&self, hdr: &str) -> Accept {
+        let mut last = self.last.lock().unwrap();
+        match last.as_ref() {
+            Some((prev, accept)) if prev == hdr => accept.clone(),
+            _ => {
+                let accept = Arc::new(parse_accept_encoding(hdr));
+                *last = Some((hdr.to_string(), accept.clone()));
+                accept
+            },
+        }
+    }
+}
+
+// Find the sidecar encodings that an Accept-Encoding header allows.
+fn parse_accept_encoding(hdr: &str) -> Vec<&'static str> {
+    let mut qvalues = HashMap::new();
+    for item in hdr.split(',') {
+        let mut params = item.split(';').map(|s| s.trim());
+        let coding = params.next().unwrap_or("").to_ascii_lowercase();
+        let q = params
+            .filter_map(|p| p.strip_prefix("q="))
+            .next()
+            .map(|q| q.parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        qvalues.insert(coding, q);
+    }
+    ENCODINGS
+        .iter()
+        .map(|(e, _)| *e)
+        .filter(|e| qvalues.get(*e).or_else(|| qvalues.get("*")).map(|&q| q > 0.0).unwrap_or(false))
+        .collect()
+}
+
+// Is `name` a sidecar file of one of the other `names`.
+fn is_sidecar(name: &[u8], names: &HashSet<Vec<u8>>) -> bool {
+    ENCODINGS.iter().any(|(_, suffix)| {
+        name.strip_suffix(suffix.as_bytes())
+            .map(|base| names.contains(base))
+            .unwrap_or(false)
+    })
+}
+
+// Metadata of a sidecar file. Everything but the length is taken from
+// the original file. The ETag gets the encoding appended, since
+// different encodings of a resource must have different ETags.
+#[derive(Debug, Clone)]
+struct SidecarMeta {
+    meta:     Box<dyn DavMetaData>,
+    len:      u64,
+    encoding: &'static str,
+}
+
+impl DavMetaData for SidecarMeta {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        self.meta.modified()
+    }
+
+    fn is_dir(&self) -> bool {
+        false
+    }
+
+    fn etag(&self) -> Option<String> {
+        self.meta.etag().map(|t| format!("{}-{}", t, self.encoding))
+    }
+}
+
+// An opened sidecar file. Read-only.
+#[derive(Debug)]
+struct SidecarFile {
+    file: Box<dyn DavFile>,
+    meta: SidecarMeta,
+}
+
+impl DavFile for SidecarFile {
+    fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        Box::pin(future::ok(Box::new(self.meta.clone()) as Box<dyn DavMetaData>))
+    }
+
+    fn write_buf(&mut self, _buf: Box<dyn bytes::Buf + Send>) -> FsFuture<'_, ()> {
+        Box::pin(future::err(FsError::Forbidden))
+    }
+
+    fn write_bytes(&mut self, _buf: bytes::Bytes) -> FsFuture<'_, ()> {
+        Box::pin(future::err(FsError::Forbidden))
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, bytes::Bytes> {
+        self.file.read_bytes(count)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        self.file.seek(pos)
+    }
+
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        self.file.flush()
+    }
+}
+
+// Directory entry from a cached directory listing.
+struct CachedDirEntry {
+    list: DirList,
+    idx:  usize,
+}
+
+impl DavDirEntry for CachedDirEntry {
+    fn name(&self) -> Vec<u8> {
+        self.list[self.idx].0.clone()
+    }
+
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        Box::pin(future::ready(self.list[self.idx].1.clone()))
+    }
+}
+
+// A file opened for writing. Invalidates the stat cache when closed.
+#[derive(Debug)]
+struct WriteFile {
+    file:  Box<dyn DavFile>,
+    cache: StatCache,
+    path:  PathBuf,
+}
+
+impl Drop for WriteFile {
+    fn drop(&mut self) {
+        self.cache.invalidate(&self.path);
+    }
+}
+
+impl DavFile for WriteFile {
+    fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        self.file.metadata()
+    }
+
+    fn write_buf(&mut self, buf: Box<dyn bytes::Buf + Send>) -> FsFuture<'_, ()> {
+        self.file.write_buf(buf)
+    }
+
+    fn write_bytes(&mut self, buf: bytes::Bytes) -> FsFuture<'_, ()> {
+        self.file.write_bytes(buf)
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, bytes::Bytes> {
+        self.file.read_bytes(count)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        self.file.seek(pos)
+    }
+
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precompressed() {
+        assert_eq!(parse_accept_encoding("gzip, deflate, br"), vec!["br", "gzip"]);
+        assert_eq!(parse_accept_encoding("br;q=0, gzip;q=0.5"), vec!["gzip"]);
+        assert_eq!(parse_accept_encoding("*;q=0.1, gzip;q=0"), vec!["br"]);
+        assert!(parse_accept_encoding("identity").is_empty());
+
+        // parsed once per connection, until the header changes.
+        let conn = ConnEncoding::new();
+        let first = Precompressed::new(Some("gzip, br"), Some(&conn)).accept;
+        let again = Precompressed::new(Some("gzip, br"), Some(&conn)).accept;
+        assert!(Arc::ptr_eq(&first, &again) && *first == ["br", "gzip"]);
+        let changed = Precompressed::new(Some("gzip"), Some(&conn)).accept;
+        assert!(!Arc::ptr_eq(&first, &changed) && *changed == ["gzip"]);
+        assert!(Precompressed::new(None, Some(&conn)).accept.is_empty());
+
+        let names: HashSet<Vec<u8>> = vec![b"a.html".to_vec(), b"a.html.br".to_vec(), b"b.gz".to_vec()]
+            .into_iter()
+            .collect();
+        assert!(is_sidecar(b"a.html.br", &names));
+        assert!(!is_sidecar(b"b.gz", &names));
+        assert!(!is_sidecar(b"a.html", &names));
+    }
+
+    #[cfg(feature = "quota")]
+    #[test]
+    fn test_user_or_system_quota() {
+        use fs_quota::{FqError, FsQuota};
+        let quota = |used, limit| FsQuota {
+            bytes_used:  used,
+            bytes_limit: limit,
+            files_used:  0,
+            files_limit: None,
+        };
+        let system = || Ok(quota(500, Some(1000)));
+        let q = user_or_system_quota(Ok(quota(10, Some(100))), system).unwrap();
+        assert_eq!((q.bytes_used, q.bytes_limit), (10, Some(100)));
+        let q = user_or_system_quota(Ok(quota(10, None)), system).unwrap();
+        assert_eq!((q.bytes_used, q.bytes_limit), (500, Some(1000)));
+        let q = user_or_system_quota(Err(FqError::NoQuota), system).unwrap();
+        assert_eq!(q.bytes_used, 500);
+        assert!(user_or_system_quota(Err(FqError::PermissionDenied), system).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! Vary and Cache-Control.
+//!
+//! A cache between us and the client must know which request headers a
+//! response depends on, or it might give one client's response to another.
+//! While a request is handled, the headers that made a difference are
+//! collected here (through `RequestContext::vary`), and added to the `Vary`
+//! header of the response at the end:
+//!
+//! - `Accept-Encoding`, if precompressed files might be served
+//! - `Accept-Language`, for localized directory indexes
+//! - `Authorization`, if the response depends on who the user is
+//!
+//! A response for an authenticated user also gets `Cache-Control: private`,
+//! so that shared caches do not store it at all.
+//!
+//! A successful GET or HEAD gets the `cache-control` of the location, or
+//! `public` in a location without authentication. For an authenticated
+//! user `public` is dropped from it, and `private` added, so a location
+//! with `cache-control = "public, max-age=600"` is still only cached by
+//! the browser of a user who logged in.
+//!
+use http::header::{HeaderMap, HeaderValue, CACHE_CONTROL, VARY};
+
+/// Request headers that the response depends on.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Vary {
+    headers:       Vec<&'static str>,
+    private:       bool,
+    cache_control: Option<String>,
+}
+
+impl Vary {
+    /// The response depends on this request header.
+    pub fn add(&mut self, header: &'static str) {
+        if !self.headers.iter().any(|h| h.eq_ignore_ascii_case(header)) {
+            self.headers.push(header);
+        }
+    }
+
+    /// The response is for one user only.
+    pub fn private(&mut self) {
+        self.add("Authorization");
+        self.private = true;
+    }
+
+    /// The Cache-Control of the response, unless it has one.
+    pub fn cache_control(&mut self, value: &str) {
+        self.cache_control = Some(value.to_string());
+    }
+
+    /// Add `Vary` and `Cache-Control` to the response headers. Values
+    /// that are already there (from the webdav handler) are kept.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        let existing: Vec<String> = headers
+            .get_all(VARY)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+        if existing.iter().any(|v| v == "*") {
+            return;
+        }
+        let mut vary = existing.clone();
+        for h in &self.headers {
+            if !vary.iter().any(|v| v.eq_ignore_ascii_case(h)) {
+                vary.push(h.to_string());
+            }
+        }
+        if vary.len() > existing.len() {
+            headers.insert(VARY, HeaderValue::from_str(&vary.join(", ")).unwrap());
+        }
+
+        if let Some(cc) = self.cache_control.as_ref().filter(|_| !headers.contains_key(CACHE_CONTROL)) {
+            headers.insert(CACHE_CONTROL, HeaderValue::from_str(cc).unwrap());
+        }
+        if self.private {
+            let cc = headers.get(CACHE_CONTROL).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+            let value = match cc {
+                None => "private".to_string(),
+                Some(cc) if cc.contains("private") || cc.contains("no-store") => return,
+                Some(cc) => {
+                    let mut directives: Vec<_> = cc
+                        .split(',')
+                        .map(|d| d.trim())
+                        .filter(|d| !d.is_empty() && !d.eq_ignore_ascii_case("public"))
+                        .collect();
+                    directives.push("private");
+                    directives.join(", ")
+                },
+            };
+            headers.insert(CACHE_CONTROL, HeaderValue::from_str(&value).unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vary() {
+        let mut headers = HeaderMap::new();
+        headers.append(VARY, "Accept-Encoding".parse().unwrap());
+        let mut vary = Vary::default();
+        vary.add("accept-encoding");
+        vary.add("Accept-Language");
+        vary.private();
+        vary.apply(&mut headers);
+        assert_eq!(headers.get(VARY).unwrap(), "Accept-Encoding, Accept-Language, Authorization");
+        assert_eq!(headers.get(CACHE_CONTROL).unwrap(), "private");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, "max-age=60".parse().unwrap());
+        vary.apply(&mut headers);
+        assert_eq!(headers.get(CACHE_CONTROL).unwrap(), "max-age=60, private");
+
+        let mut headers = HeaderMap::new();
+        vary.cache_control("public, max-age=600");
+        vary.apply(&mut headers);
+        assert_eq!(headers.get(CACHE_CONTROL).unwrap(), "max-age=600, private");
+
+        let mut headers = HeaderMap::new();
+        let mut public = Vary::default();
+        public.cache_control("public");
+        public.apply(&mut headers);
+        assert_eq!(headers.get(CACHE_CONTROL).unwrap(), "public");
+
+        let mut headers = HeaderMap::new();
+        Vary::default().apply(&mut headers);
+        assert!(headers.is_empty());
+    }
+}
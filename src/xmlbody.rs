@@ -0,0 +1,142 @@
+//! XML request bodies.
+//!
+//! PROPFIND, PROPPATCH, LOCK and REPORT have an XML body. The first
+//! three are parsed by the webdav handler, REPORT is parsed by us, and
+//! so is the body of an extended MKCOL.
+//! Both use `xmltree` on top of `xml-rs`, which does not resolve
+//! external entities, and does not expand entities that are declared
+//! in a DTD (a reference to one is a parse error).
+//!
+//! We do not rely on that alone. Before a request is passed on, its body
+//! is read here, with a maximum size, and bodies that contain a DTD
+//! are refused. WebDAV never needs one.
+//!
+//! A PROPPATCH body can also have a lot of property instructions in
+//! those 64K, and each of them gets an entry in the multistatus response.
+//! The number of properties in set and remove is limited as well.
+//!
+use futures::StreamExt;
+use http::StatusCode;
+use webdav_handler::DavMethod;
+
+use crate::limits::{MemoryBudget, Reservation};
+
+/// Maximum size of a XML body. The webdav handler has the same limit
+/// built in, so this can only be lowered.
+pub const MAX_BODY: usize = 65536;
+
+/// Default maximum number of properties in a PROPPATCH.
+pub const DEFAULT_MAX_PROPPATCH: usize = 1000;
+
+/// Does this method have a XML request body.
+pub fn is_xml_method(method: &http::Method, davmethod: DavMethod) -> bool {
+    match davmethod {
+        DavMethod::PropFind | DavMethod::PropPatch | DavMethod::Lock | DavMethod::MkCol => true,
+        _ => method.as_str() == "REPORT",
+    }
+}
+
+/// Read the request body, up to a maximum size. It counts against
+/// `memory` until the reservation is dropped; if it does not fit, it is
+/// a "503 Service Unavailable".
+pub async fn read_body(
+    mut body: hyper::Body,
+    max_size: usize,
+    memory: &MemoryBudget,
+) -> Result<(Vec<u8>, Reservation), StatusCode>
+{
+    let mut data = Vec::new();
+    let mut reservation = memory.reservation();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if data.len() + chunk.len() > max_size {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        if !reservation.grow(chunk.len()) {
+            debug!("xmlbody: over memory-budget");
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok((data, reservation))
+}
+
+/// Refuse bodies with a document type declaration (and so, entity declarations).
+pub fn check(body: &[u8]) -> Result<(), StatusCode> {
+    let found = |pat: &[u8]| body.windows(pat.len()).any(|w| w == pat);
+    if found(b"<!DOCTYPE") || found(b"<!ENTITY") {
+        debug!("xmlbody: refusing body with a DTD");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(())
+}
+
+/// Number of properties that a PROPPATCH body sets or removes. A body
+/// that does not parse is left to the webdav handler.
+pub fn proppatch_props(body: &[u8]) -> Option<usize> {
+    let root = xmltree::Element::parse(body).ok()?;
+    let is_dav = |e: &xmltree::Element, name: &str| e.name == name && e.namespace.as_deref() == Some("DAV:");
+    let count = root
+        .children
+        .iter()
+        .filter_map(|n| n.as_element())
+        .filter(|e| is_dav(e, "set") || is_dav(e, "remove"))
+        .flat_map(|e| e.children.iter().filter_map(|n| n.as_element()))
+        .filter(|e| is_dav(e, "prop"))
+        .map(|e| e.children.iter().filter(|n| n.as_element().is_some()).count())
+        .sum();
+    Some(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOMB: &[u8] = br#"<?xml version="1.0"?>
+<!DOCTYPE lolz [
+  <!ENTITY lol "lol">
+  <!ENTITY lol1 "&lol;&lol;&lol;&lol;&lol;&lol;&lol;&lol;&lol;&lol;">
+  <!ENTITY lol2 "&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;&lol1;">
+]>
+<D:propfind xmlns:D="DAV:"><D:prop><D:displayname>&lol2;</D:displayname></D:prop></D:propfind>"#;
+
+    #[test]
+    fn test_entity_bomb() {
+        assert_eq!(check(BOMB), Err(StatusCode::BAD_REQUEST));
+        // and if it got through, the parser would refuse it as well.
+        assert!(xmltree::Element::parse(BOMB).is_err());
+
+        let propfind = br#"<?xml version="1.0"?><D:propfind xmlns:D="DAV:"><D:allprop/></D:propfind>"#;
+        assert!(check(propfind).is_ok());
+    }
+
+    #[test]
+    fn test_proppatch_props() {
+        let body = br#"<?xml version="1.0"?>
+<D:propertyupdate xmlns:D="DAV:" xmlns:Z="urn:z">
+  <D:set><D:prop><Z:a>1</Z:a><Z:b/></D:prop></D:set>
+  <D:remove><D:prop><Z:c/></D:prop></D:remove>
+  <Z:set><D:prop><Z:d/></D:prop></Z:set>
+</D:propertyupdate>"#;
+        assert_eq!(proppatch_props(body), Some(3));
+        assert_eq!(proppatch_props(b"<D:propertyupdate"), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_body() {
+        let memory = MemoryBudget::new(Some(150));
+        let body = hyper::Body::from(vec![b'x'; 100]);
+        assert_eq!(read_body(body, 50, &memory).await.unwrap_err(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = hyper::Body::from(vec![b'x'; 100]);
+        let (data, reservation) = read_body(body, 100, &memory).await.unwrap();
+        assert_eq!((data.len(), memory.used()), (100, 100));
+
+        // the memory is taken until the reservation goes.
+        let body = hyper::Body::from(vec![b'x'; 100]);
+        assert_eq!(read_body(body, 100, &memory).await.unwrap_err(), StatusCode::SERVICE_UNAVAILABLE);
+        drop(reservation);
+        let body = hyper::Body::from(vec![b'x'; 100]);
+        assert!(read_body(body, 100, &memory).await.is_ok());
+        assert_eq!(memory.used(), 0);
+    }
+}